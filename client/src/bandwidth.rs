@@ -0,0 +1,38 @@
+use std::{sync::Mutex, time::Duration};
+
+use tokio::time::{sleep_until, Instant};
+
+/// A bandwidth budget shared between every upload in the process.
+#[derive(Debug)]
+pub struct Bandwidth {
+    /// Bytes per second, or None for unlimited.
+    rate: Option<u64>,
+    /// The earliest time the next chunk is allowed to start.
+    next: Mutex<Instant>,
+}
+
+impl Bandwidth {
+    pub fn new(rate: Option<u64>) -> Self {
+        Self {
+            rate: rate.filter(|r| *r > 0),
+            next: Mutex::new(Instant::now()),
+        }
+    }
+
+    /// Waits until `bytes` can be sent without going over the budget.
+    ///
+    /// Each caller reserves its share of the schedule up front, so chunks from different uploads
+    /// are spaced out instead of all firing at once when the budget frees up.
+    pub async fn acquire(&self, bytes: u64) {
+        let Some(rate) = self.rate else {
+            return;
+        };
+        let start = {
+            let mut next = self.next.lock().unwrap();
+            let start = (*next).max(Instant::now());
+            *next = start + Duration::from_secs_f64(bytes as f64 / rate as f64);
+            start
+        };
+        sleep_until(start).await;
+    }
+}
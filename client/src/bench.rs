@@ -0,0 +1,132 @@
+//! `bullseye-client bench`: measures how fast chunks can be sent to the server's benchmark sink,
+//! to help pick a chunk size, concurrency and fsync policy.
+
+use std::time::{Duration, Instant};
+
+use anyhow::{bail, Context, Result};
+use clap::Args;
+use common::payloads::{BenchmarkSinkResponse, ErrorablePayload, SinkMode};
+use futures_util::{stream, StreamExt, TryStreamExt};
+use reqwest::{Client, StatusCode};
+
+use crate::{network::NetworkArgs, sibling_url, CHUNK_SIZE};
+
+#[derive(Args, Debug)]
+pub struct BenchArgs {
+    /// The upload endpoint, as passed to --base-url when uploading. The sink is next to it.
+    #[arg(short, long)]
+    base_url: String,
+    #[command(flatten)]
+    network: NetworkArgs,
+    /// How big each chunk is, in bytes.
+    #[arg(long, default_value_t = CHUNK_SIZE)]
+    chunk_size: u64,
+    /// How many chunks to send at the same time.
+    #[arg(long, default_value_t = 1)]
+    concurrency: usize,
+    /// How much to send in total, in bytes.
+    #[arg(long, default_value_t = 1024 * 1024 * 1024)]
+    total: u64,
+    /// What the server does with the chunks: discard, write or fsync.
+    #[arg(long, default_value = "discard")]
+    mode: SinkMode,
+}
+
+/// Gets the latency that `p` percent of chunks were faster than. `sorted` must not be empty.
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    let rank = (p / 100.0 * sorted.len() as f64).ceil() as usize;
+    sorted[rank.clamp(1, sorted.len()) - 1]
+}
+
+/// Makes a chunk's worth of data that won't compress, in case anything along the way tries.
+fn make_data(len: usize) -> Vec<u8> {
+    let mut state: u64 = 0x2545_f491_4f6c_dd1d;
+    (0..len)
+        .map(|_| {
+            // xorshift64
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state as u8
+        })
+        .collect()
+}
+
+async fn send(client: &Client, url: &str, data: &'static [u8]) -> Result<Duration> {
+    let start = Instant::now();
+    let res = client.put(url).body(data).send().await?;
+    let status = res.status();
+    let text = res.text().await?;
+    let elapsed = start.elapsed();
+    if status == StatusCode::NOT_FOUND {
+        bail!("the server's benchmark sink isn't turned on (set BULLSEYE_BENCHMARK_SINK)");
+    }
+    match serde_json::from_str(&text) {
+        Ok(ErrorablePayload::<BenchmarkSinkResponse>::Ok(n)) if n == data.len() as u64 => {
+            Ok(elapsed)
+        }
+        _ => bail!("unexpected response from the sink ({status}): {text}"),
+    }
+}
+
+pub async fn run(args: BenchArgs) -> Result<()> {
+    if args.chunk_size == 0 || args.total == 0 {
+        bail!("--chunk-size and --total must be more than 0");
+    }
+    let (builder, endpoint) = args.network.apply(Client::builder(), &args.base_url)?;
+    let client = builder.build()?;
+    let mut url = sibling_url(&endpoint, "benchmark/sink").context("bad --base-url")?;
+    url.query_pairs_mut()
+        .append_pair("mode", args.mode.as_str());
+    let url = url.to_string();
+    let chunk_size = args.chunk_size.min(args.total);
+    // Every request sends (part of) the same buffer, so it lives for the rest of the process.
+    let data: &'static [u8] = make_data(chunk_size.try_into()?).leak();
+    let chunks = args.total.div_ceil(chunk_size);
+
+    let start = Instant::now();
+    let mut latencies: Vec<Duration> = stream::iter(0..chunks)
+        .map(|i| {
+            let len = chunk_size.min(args.total - i * chunk_size) as usize;
+            send(&client, &url, &data[..len])
+        })
+        .buffer_unordered(args.concurrency.max(1))
+        .try_collect()
+        .await?;
+    let elapsed = start.elapsed();
+    latencies.sort();
+
+    let mib = args.total as f64 / (1024.0 * 1024.0);
+    println!(
+        "Sent {mib:.1} MiB ({chunks} chunks, {} at a time) in {:.2}s: {:.1} MiB/s",
+        args.concurrency.max(1),
+        elapsed.as_secs_f64(),
+        mib / elapsed.as_secs_f64()
+    );
+    println!(
+        "Chunk latency: p50 {:?}, p90 {:?}, p99 {:?}, max {:?}",
+        percentile(&latencies, 50.0),
+        percentile(&latencies, 90.0),
+        percentile(&latencies, 99.0),
+        latencies[latencies.len() - 1]
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::percentile;
+
+    #[test]
+    fn test_percentile() {
+        let latencies: Vec<Duration> = (1..=100).map(Duration::from_millis).collect();
+        assert_eq!(percentile(&latencies, 50.0), Duration::from_millis(50));
+        assert_eq!(percentile(&latencies, 99.0), Duration::from_millis(99));
+        assert_eq!(percentile(&latencies, 100.0), Duration::from_millis(100));
+        assert_eq!(percentile(&latencies, 0.0), Duration::from_millis(1));
+        let one = [Duration::from_secs(1)];
+        assert_eq!(percentile(&one, 90.0), Duration::from_secs(1));
+    }
+}
@@ -0,0 +1,136 @@
+//! Operator commands that talk to the server's admin endpoints. The token is the server's
+//! `BULLSEYE_ADMIN_TOKEN`, taken from the same variable here unless `--token` is passed.
+
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Parser, Subcommand};
+use common::{
+    data::Status,
+    payloads::{ErrorablePayload, RequeuePayload, RequeueResponse},
+};
+use reqwest::{Client, StatusCode, Url};
+
+const TOKEN_ENV: &str = "BULLSEYE_ADMIN_TOKEN";
+
+#[derive(Parser, Debug)]
+#[command(name = "bullseye-admin")]
+struct Args {
+    /// The server's root URL, like `http://localhost:8080`.
+    #[arg(short, long)]
+    server: Url,
+
+    /// Defaults to `BULLSEYE_ADMIN_TOKEN`.
+    #[arg(long)]
+    token: Option<String>,
+
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Requeue every failed or dead-lettered upload that matches, in one go. Failed uploads go
+    /// back to the first stage of their pipeline.
+    Requeue {
+        /// Like FAILED_CHECKSUM or DEAD_LETTER.
+        #[arg(long, value_parser = parse_status)]
+        status: Status,
+        /// Only uploads that have been sitting there for at least this long, like 90s, 30m, 6h or
+        /// 2d.
+        #[arg(long, value_parser = parse_duration, default_value = "0")]
+        older_than: u64,
+        #[arg(long)]
+        project: Option<String>,
+        #[arg(long)]
+        pipeline: Option<String>,
+    },
+}
+
+fn parse_status(s: &str) -> Result<Status> {
+    serde_json::from_value(serde_json::Value::String(s.to_uppercase()))
+        .map_err(|_| anyhow!("unknown status {s}"))
+}
+
+/// Parses a number of seconds, optionally with a unit (s, m, h or d).
+fn parse_duration(s: &str) -> Result<u64> {
+    let (number, unit) = match s.find(|c: char| !c.is_ascii_digit()) {
+        Some(i) => s.split_at(i),
+        None => (s, "s"),
+    };
+    let scale = match unit {
+        "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => bail!("unknown unit {unit:?}; use s, m, h or d"),
+    };
+    let number: u64 = number.parse().context("not a duration")?;
+    number.checked_mul(scale).context("duration too long")
+}
+
+#[tokio::main]
+async fn main() -> Result<()> {
+    let args = Args::parse();
+    let token = match args.token {
+        Some(token) => token,
+        None => {
+            std::env::var(TOKEN_ENV).with_context(|| format!("pass --token or set {TOKEN_ENV}"))?
+        }
+    };
+    let client = Client::new();
+    match args.command {
+        Command::Requeue {
+            status,
+            older_than,
+            project,
+            pipeline,
+        } => {
+            let payload = RequeuePayload {
+                status,
+                older_than,
+                project,
+                pipeline,
+            };
+            let res = client
+                .post(args.server.join("admin/requeue")?)
+                .bearer_auth(&token)
+                .json(&payload)
+                .send()
+                .await?;
+            match res.status() {
+                StatusCode::NOT_FOUND => bail!("the server's admin endpoints aren't enabled"),
+                StatusCode::UNAUTHORIZED => bail!("the server refused the token"),
+                _ => (),
+            }
+            let response: ErrorablePayload<RequeueResponse> = res
+                .json()
+                .await
+                .context("couldn't decode the server's response")?;
+            match response {
+                ErrorablePayload::Ok(r) => {
+                    println!("requeued {} uploads", r.requeued);
+                    for pipeline in r.skipped_pipelines {
+                        println!("skipped the uploads in pipeline {pipeline}, which the server doesn't know or has no stages");
+                    }
+                }
+                ErrorablePayload::Err(e) => bail!("the requeue failed: {e}"),
+                other => bail!("unexpected response: {other:?}"),
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_duration;
+
+    #[test]
+    fn durations() {
+        assert_eq!(parse_duration("90").unwrap(), 90);
+        assert_eq!(parse_duration("30m").unwrap(), 1800);
+        assert_eq!(parse_duration("6h").unwrap(), 21600);
+        assert_eq!(parse_duration("2d").unwrap(), 172800);
+        assert!(parse_duration("6w").is_err());
+        assert!(parse_duration("h").is_err());
+    }
+}
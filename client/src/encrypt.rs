@@ -0,0 +1,168 @@
+//! Client-side encryption. With `--passphrase-file` or `--key-file`, files are encrypted before
+//! they're sent (see `common::crypt::ClientKey`), so the server only ever has the ciphertext, and
+//! the hashes it checks are the ciphertext's. `bullseye-client decrypt` turns an upload's data
+//! back into the file.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use anyhow::{bail, Context, Result};
+use clap::Args as ClapArgs;
+use common::{
+    crypt::{ClientEncryption, ClientKey, Decrypting, FileKey, KeySource},
+    data::File,
+    StreamHasher,
+};
+use tokio::task::spawn_blocking;
+
+use crate::{
+    memory::MemoryBudget,
+    transfer::{self, Source},
+    CHUNK_SIZE,
+};
+
+#[derive(ClapArgs, Debug, Clone, Default)]
+pub struct KeyArgs {
+    /// Encrypt files before sending them, with a key made from the project's passphrase, which is
+    /// in this file. Everybody who uploads to the project with the same passphrase gets the same
+    /// key.
+    #[arg(long, value_name = "FILE", conflicts_with = "key_file")]
+    pub passphrase_file: Option<PathBuf>,
+    /// Encrypt files before sending them, with the key in this file, as 64 hex digits (for
+    /// example from `openssl rand -hex 32`).
+    #[arg(long, value_name = "FILE")]
+    pub key_file: Option<PathBuf>,
+}
+
+/// Reads a passphrase or key file, without the line break at the end.
+fn read_secret(path: &Path) -> Result<String> {
+    let secret =
+        fs::read_to_string(path).with_context(|| format!("couldn't read {}", path.display()))?;
+    Ok(secret.trim_end_matches(['\r', '\n']).to_string())
+}
+
+impl KeyArgs {
+    /// Makes the key to encrypt new uploads to `project` with, if one was asked for.
+    pub fn key(&self, project: &str) -> Result<Option<ClientKey>> {
+        if let Some(path) = &self.passphrase_file {
+            return Ok(Some(ClientKey::from_passphrase(
+                &read_secret(path)?,
+                project,
+            )?));
+        }
+        if let Some(path) = &self.key_file {
+            return Ok(Some(ClientKey::from_hex(&read_secret(path)?)?));
+        }
+        Ok(None)
+    }
+
+    /// Makes the key an upload was encrypted with again.
+    pub fn key_for(&self, encryption: &ClientEncryption) -> Result<FileKey> {
+        let key = match (&encryption.key, &self.passphrase_file, &self.key_file) {
+            (KeySource::Passphrase { .. }, Some(path), _) => {
+                ClientKey::derive(&read_secret(path)?, encryption.key.clone())?
+            }
+            (KeySource::Keyfile, _, Some(path)) => ClientKey::from_hex(&read_secret(path)?)?,
+            (KeySource::Passphrase { .. }, ..) => {
+                bail!("the upload was encrypted with a passphrase; pass --passphrase-file")
+            }
+            (KeySource::Keyfile, ..) => {
+                bail!("the upload was encrypted with a key file; pass --key-file")
+            }
+        };
+        Ok(key.file_key(encryption)?)
+    }
+}
+
+/// Hashes the first `size` bytes of `source` as they're sent when encrypted with `key`.
+pub async fn hash(source: Source, size: u64, key: FileKey, memory: MemoryBudget) -> Result<String> {
+    let chunks = transfer::chunks(&[(0, size)], CHUNK_SIZE);
+    let mut chunks = transfer::read(source, chunks, memory, Some(key));
+    let mut hasher = StreamHasher::default();
+    while let Some(chunk) = chunks.recv().await {
+        let chunk = chunk?;
+        hasher = spawn_blocking(move || {
+            hasher.update(&chunk.data);
+            hasher
+        })
+        .await?;
+    }
+    Ok(hasher.finish())
+}
+
+/// Gets the parameters to encrypt `file`, which is read from `source`, with `key`, and changes
+/// its hash to the ciphertext's.
+pub async fn encrypt(
+    key: &ClientKey,
+    source: &Source,
+    file: &mut File,
+    memory: &MemoryBudget,
+) -> Result<ClientEncryption> {
+    let encryption = key.encryption(&file.hash);
+    let file_key = key.file_key(&encryption)?;
+    file.hash = hash(source.clone(), file.size, file_key, memory.clone()).await?;
+    Ok(encryption)
+}
+
+#[derive(ClapArgs, Debug)]
+pub struct DecryptArgs {
+    /// A JSON file with the upload's details, as `GET /upload/{id}` returns them, or just the
+    /// `encryption` from its metadata.
+    details: PathBuf,
+    /// The upload's data, as `GET /upload/{id}/data` returns it.
+    input: PathBuf,
+    /// Where to write the decrypted file.
+    output: PathBuf,
+    #[command(flatten)]
+    key: KeyArgs,
+}
+
+/// Finds the encryption parameters in an upload's details, however much of them there is.
+fn find_encryption(details: &serde_json::Value) -> Result<ClientEncryption> {
+    let encryption = ["/payload/metadata/encryption", "/metadata/encryption"]
+        .iter()
+        .find_map(|p| details.pointer(p))
+        .unwrap_or(details);
+    serde_json::from_value(encryption.clone())
+        .context("there are no encryption parameters there; was the upload encrypted?")
+}
+
+pub async fn decrypt(args: DecryptArgs) -> Result<()> {
+    let details = fs::read(&args.details)
+        .with_context(|| format!("couldn't read {}", args.details.display()))?;
+    let encryption = find_encryption(&serde_json::from_slice(&details)?)?;
+    let key = args.key.key_for(&encryption)?;
+    let (input, output) = (args.input, args.output);
+    spawn_blocking(move || -> io::Result<()> {
+        let mut reader = Decrypting::new(fs::File::open(input)?, Some(key))?;
+        let mut out = fs::File::create(output)?;
+        io::copy(&mut reader, &mut out)?;
+        out.sync_all()
+    })
+    .await??;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use common::crypt::ClientKey;
+    use serde_json::json;
+
+    use super::find_encryption;
+
+    #[test]
+    fn finds_encryption() {
+        let encryption = ClientKey::from_hex(&"ab".repeat(32))
+            .unwrap()
+            .encryption("abc");
+        let value = serde_json::to_value(&encryption).unwrap();
+        let details = json!({"id": "x", "metadata": {"uploader": "u", "encryption": value}});
+        let response = json!({"status": "ok", "payload": details});
+        for v in [&value, &details, &response] {
+            assert_eq!(find_encryption(v).unwrap(), encryption);
+        }
+        find_encryption(&json!({"metadata": {"uploader": "u"}})).unwrap_err();
+    }
+}
@@ -0,0 +1,158 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+
+/// Only this many files are remembered. The oldest entries are dropped first.
+const MAX_ENTRIES: usize = 1024;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+struct Entry {
+    size: u64,
+    /// Modification time in nanoseconds since the epoch.
+    mtime: u128,
+    hash: String,
+    /// When the entry was written, for eviction.
+    added: u64,
+}
+
+/// A small on-disk cache of file hashes, keyed by path, size and mtime, so that retrying an
+/// upload of a huge file doesn't mean hashing it all over again.
+#[derive(Debug)]
+pub struct HashCache {
+    path: Option<PathBuf>,
+    entries: Mutex<HashMap<PathBuf, Entry>>,
+}
+
+fn mtime(m: &fs::Metadata) -> io::Result<u128> {
+    let t = m.modified()?;
+    Ok(t.duration_since(UNIX_EPOCH).map_err(io::Error::other)?.as_nanos())
+}
+
+impl HashCache {
+    /// Where the cache lives if the user doesn't say otherwise.
+    pub fn default_path() -> Option<PathBuf> {
+        let mut base = match std::env::var_os("XDG_CACHE_HOME") {
+            Some(p) if !p.is_empty() => PathBuf::from(p),
+            _ => {
+                let mut home = PathBuf::from(std::env::var_os("HOME")?);
+                home.push(".cache");
+                home
+            }
+        };
+        base.push("bullseye");
+        base.push("hashes.json");
+        Some(base)
+    }
+
+    /// Loads the cache from `path`. A missing or corrupt cache is treated as empty.
+    /// If `path` is None, the cache is disabled.
+    pub fn load(path: Option<PathBuf>) -> Self {
+        let entries = match &path {
+            Some(p) => match fs::read(p) {
+                Ok(data) => serde_json::from_slice(&data).unwrap_or_else(|e| {
+                    warn!("ignoring corrupt hash cache {}: {e}", p.display());
+                    HashMap::new()
+                }),
+                Err(_) => HashMap::new(),
+            },
+            None => HashMap::new(),
+        };
+        Self {
+            path,
+            entries: Mutex::new(entries),
+        }
+    }
+
+    /// Returns the cached hash of the file, if it hasn't changed since it was hashed.
+    pub fn get(&self, file: &Path, metadata: &fs::Metadata) -> Option<String> {
+        self.path.as_ref()?;
+        let key = file.canonicalize().ok()?;
+        let mtime = mtime(metadata).ok()?;
+        let entries = self.entries.lock().unwrap();
+        let entry = entries.get(&key)?;
+        if entry.size == metadata.len() && entry.mtime == mtime {
+            debug!("using cached hash for {}", key.display());
+            Some(entry.hash.clone())
+        } else {
+            None
+        }
+    }
+
+    /// Remembers the hash of the file and writes the cache back to disk.
+    pub fn insert(&self, file: &Path, metadata: &fs::Metadata, hash: String) -> io::Result<()> {
+        let Some(path) = &self.path else {
+            return Ok(());
+        };
+        let key = file.canonicalize()?;
+        let entry = Entry {
+            size: metadata.len(),
+            mtime: mtime(metadata)?,
+            hash,
+            added: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_err(io::Error::other)?
+                .as_secs(),
+        };
+        let serialized = {
+            let mut entries = self.entries.lock().unwrap();
+            entries.insert(key, entry);
+            while entries.len() > MAX_ENTRIES {
+                let oldest = entries
+                    .iter()
+                    .min_by_key(|(_, e)| e.added)
+                    .map(|(k, _)| k.clone())
+                    .unwrap();
+                entries.remove(&oldest);
+            }
+            serde_json::to_vec(&*entries)?
+        };
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        // Write to a temporary file first so a crash can't leave a truncated cache behind.
+        let mut tmp = path.clone().into_os_string();
+        tmp.push(format!(".{}.tmp", std::process::id()));
+        fs::write(&tmp, serialized)?;
+        fs::rename(tmp, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io::Write};
+
+    use super::HashCache;
+
+    #[test]
+    fn test_cache_invalidation() {
+        let dir = std::env::temp_dir().join(format!("bullseye-hash-cache-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let cache_path = dir.join("hashes.json");
+        let file = dir.join("file");
+        fs::write(&file, "hello").unwrap();
+
+        let cache = HashCache::load(Some(cache_path.clone()));
+        let m = fs::metadata(&file).unwrap();
+        assert_eq!(cache.get(&file, &m), None);
+        cache.insert(&file, &m, "abc".to_string()).unwrap();
+        assert_eq!(cache.get(&file, &m), Some("abc".to_string()));
+
+        // The cache survives a reload.
+        let cache = HashCache::load(Some(cache_path));
+        assert_eq!(cache.get(&file, &m), Some("abc".to_string()));
+
+        // Changing the file invalidates the entry.
+        fs::File::options().append(true).open(&file).unwrap().write_all(b"!").unwrap();
+        let m = fs::metadata(&file).unwrap();
+        assert_eq!(cache.get(&file, &m), None);
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,30 +1,66 @@
 use anyhow::{anyhow, bail, Result};
 use async_stream::stream;
-use bytes::{Bytes, BytesMut};
-use clap::Parser;
+use clap::{ArgAction, Parser};
 use common::{
-    data::{File, Metadata, Status},
+    crypt::{ClientEncryption, ClientKey, FileKey},
+    data::{File, Metadata, Progress, Shard, Status},
+    errors::ErrorReference,
     hash_file,
     payloads::*,
+    signing::{new_nonce, SignedRequest},
 };
-use futures_util::{pin_mut, Stream, StreamExt};
+use futures_util::{pin_mut, stream, Stream, StreamExt, TryStreamExt};
 use kdam::{
     term::{self, Colorizer},
     tqdm, BarExt, Column, RichProgress, Spinner,
 };
-use reqwest::Client;
+use reqwest::{header::{CONTENT_LENGTH, LOCATION, RETRY_AFTER}, Client, StatusCode};
 use serde::{de::DeserializeOwned, Serialize};
 use std::{
     error::Error,
     fmt, fs,
-    io::{self, stderr, IsTerminal},
-    path::Path,
-    time::Duration,
+    io::{self, stderr, IsTerminal, Read, Seek},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, SystemTime},
 };
-use tokio::{fs::metadata, io::{AsyncBufReadExt, AsyncReadExt}, select, spawn, sync::watch, task::spawn_blocking, time::sleep};
-use tokio_util::{io::StreamReader, sync::CancellationToken};
+use tokio::{
+    fs::metadata,
+    io::AsyncBufReadExt,
+    select,
+    signal::ctrl_c,
+    spawn,
+    sync::watch,
+    task::spawn_blocking,
+    time::sleep,
+};
+use tokio_util::{bytes::Bytes, io::StreamReader, sync::CancellationToken};
+use tracing::{debug, error, info, info_span, trace, warn, Instrument};
+use tracing_subscriber::{filter::{LevelFilter, Targets}, fmt::layer, prelude::*};
 use url::Url;
 
+mod bandwidth;
+use bandwidth::Bandwidth;
+mod memory;
+use memory::MemoryBudget;
+mod network;
+use network::NetworkArgs;
+mod bench;
+mod encrypt;
+use encrypt::KeyArgs;
+mod hash_cache;
+use hash_cache::HashCache;
+mod notify;
+use notify::Notifier;
+mod resume;
+mod tar;
+use tar::Archive;
+mod tools;
+mod transfer;
+use transfer::{Chunk, Source};
+mod validate;
+use validate::Validator;
+
 #[allow(dead_code)] // the inner values are only there for Debug
 #[derive(Clone, Debug)]
 enum UploadError {
@@ -32,6 +68,55 @@ enum UploadError {
     BadStatusCode(u16),
     JsonDecodeError(String),
     BadResponse(String),
+    Cancelled,
+    /// The reference is the error's code and where it's explained, if the server sent them.
+    Rejected(Rejection, ErrorReference),
+    /// The server is too busy, and says to try again after this many seconds.
+    Unavailable { retry_after_secs: u64, reason: String, reference: ErrorReference },
+}
+
+impl UploadError {
+    /// Whether there's no point trying again.
+    fn is_permanent(&self) -> bool {
+        matches!(self, Self::Cancelled | Self::Rejected(..))
+    }
+}
+
+/// Checks whether an error is an UploadError that won't go away by retrying.
+fn is_permanent(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<UploadError>().is_some_and(UploadError::is_permanent)
+}
+
+/// Gets how long the server said to wait before trying again, if it was too busy.
+fn retry_after(e: &anyhow::Error) -> Option<u64> {
+    match e.downcast_ref::<UploadError>() {
+        Some(UploadError::Unavailable { retry_after_secs, .. }) => Some(*retry_after_secs),
+        _ => None,
+    }
+}
+
+/// Gets the reason the server gave for banning us, if that's what an error is.
+fn ban_reason(e: &anyhow::Error) -> Option<&str> {
+    match e.downcast_ref::<UploadError>() {
+        Some(UploadError::Rejected(Rejection::Banned { message }, _)) => Some(message),
+        _ => None,
+    }
+}
+
+/// Whether the server turned a chunk away because it already has it.
+fn already_received(e: &anyhow::Error) -> bool {
+    matches!(
+        e.downcast_ref::<UploadError>(),
+        Some(UploadError::Rejected(Rejection::AlreadyReceived, _))
+    )
+}
+
+/// Gets the ID of the upload that's already sending this file, if that's why we were turned away.
+fn duplicate_of(e: &anyhow::Error) -> Option<&str> {
+    match e.downcast_ref::<UploadError>() {
+        Some(UploadError::Rejected(Rejection::DuplicateUpload { id, .. }, _)) => Some(id),
+        _ => None,
+    }
 }
 
 impl fmt::Display for UploadError {
@@ -41,6 +126,11 @@ impl fmt::Display for UploadError {
             Self::BadStatusCode(s) => write!(f, "bad status code {s}"),
             Self::JsonDecodeError(s) => write!(f, "json decode error: {s}"),
             Self::BadResponse(s) => write!(f, "bad response: {s}"),
+            Self::Cancelled => write!(f, "cancelled"),
+            Self::Rejected(r, reference) => write!(f, "rejected by server: {r}{reference}"),
+            Self::Unavailable { retry_after_secs, reason, reference } => {
+                write!(f, "server unavailable ({reason}), try again in {retry_after_secs}s{reference}")
+            }
         }
     }
 }
@@ -59,27 +149,44 @@ impl From<serde_json::Error> for UploadError {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 struct Upload {
     base_url: String,
     id: String,
+    /// If the server gave us one, chunk and finish requests are signed with it.
+    secret: Option<String>,
+    /// If set, the server wants chunks to start at multiples of this, and not to go backwards.
+    chunk_size: Option<u64>,
 }
 
-/// Runs a function returning Result in a loop with exponentional backoff.
+/// Runs a function returning Result in a loop with exponentional backoff, or waiting as long as
+/// the server says to if it's too busy.
 /// Returns a successful response. Otherwise, bail!s.
 macro_rules! try_something {
     ($a:expr) => {
         const MAX_TRIES: u8 = 7;
         for i in 0..MAX_TRIES {
             let e = $a;
-            if let Ok(resp) = e {
-                return Ok(resp);
+            match e {
+                Ok(resp) => return Ok(resp),
+                Err(e) if is_permanent(&e) => return Err(e),
+                Err(_) => {}
             }
-            let to_sleep = 1 << i;
-            eprintln!("try {i} failed, sleeping {to_sleep}s: {:?}", e.unwrap_err());
+            let e = e.unwrap_err();
+            // If the server is too busy, it says how long to wait.
+            let to_sleep = match retry_after(&e) {
+                Some(to_sleep) => {
+                    warn!("try {i} failed, the server asked us to wait {to_sleep}s: {e}");
+                    to_sleep
+                }
+                None => {
+                    warn!("try {i} failed, sleeping {}s: {e:?}", 1 << i);
+                    1 << i
+                }
+            };
             sleep(Duration::from_secs(to_sleep)).await;
         }
-        eprintln!("max tries reached; returning error");
+        warn!("max tries reached; returning error");
         bail!("max tries reached");
     };
 }
@@ -93,15 +200,50 @@ impl Upload {
     ) -> Result<Resp> {
         let res = input?;
         let status_code = res.status().as_u16();
-        if status_code != expected_status {
-            dbg!(res.text().await?);
+        let retry_after = res
+            .headers()
+            .get(RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok());
+        let text = res.text().await?;
+        trace!("response body: {text}");
+        let response: serde_json::Result<ErrorablePayload<Resp>> = serde_json::from_str(&text);
+        if let Ok(ErrorablePayload::Rejected(r)) = response {
+            let reference = ErrorReference::from_body(&text);
+            // Uploader limits lift by themselves, so they're waited out like a busy server.
+            if let (true, Some(retry_after_secs)) = (r.is_temporary(), retry_after) {
+                let reason = r.to_string();
+                bail!(UploadError::Unavailable { retry_after_secs: retry_after_secs.min(MAX_RETRY_AFTER), reason, reference });
+            }
+            bail!(UploadError::Rejected(r, reference));
+        }
+        if let Ok(ErrorablePayload::Unavailable { retry_after_secs, reason }) = response {
+            let reference = ErrorReference::from_body(&text);
+            bail!(UploadError::Unavailable { retry_after_secs: retry_after_secs.min(MAX_RETRY_AFTER), reason, reference });
+        }
+        // Something in front of the server, like a proxy, might ask us to back off too.
+        if let (429 | 503, Some(retry_after_secs)) = (status_code, retry_after) {
+            let reason = match text.trim() {
+                "" => format!("status code {status_code}"),
+                text => text.chars().take(200).collect(),
+            };
+            let reference = ErrorReference::default();
+            bail!(UploadError::Unavailable { retry_after_secs: retry_after_secs.min(MAX_RETRY_AFTER), reason, reference });
+        }
+        // The server's errors have a status code to match, but say more than it does.
+        if let Ok(response @ (ErrorablePayload::NotFound | ErrorablePayload::Err(_))) = &response {
+            let reference = ErrorReference::from_body(&text);
+            bail!(UploadError::BadResponse(format!("{response:?}{reference}")));
+        }
+        // A retried POST /upload whose first try went through gets that upload back, with a 200.
+        let created_before = expected_status == 201 && status_code == 200;
+        if status_code != expected_status && !created_before {
+            debug!("unexpected status code {status_code}, body: {text}");
             bail!(UploadError::BadStatusCode(status_code));
         }
-        let text = res.text().await?;
-        let response: ErrorablePayload<Resp> = serde_json::from_str(&text)?;
-        match response {
+        match response? {
             ErrorablePayload::Ok(response_payload) => Ok(response_payload),
-            _ => Err(anyhow!(UploadError::BadResponse(format!("{response:?}")))),
+            response => Err(anyhow!(UploadError::BadResponse(format!("{response:?}")))),
         }
     }
 
@@ -111,6 +253,9 @@ impl Upload {
         payload: &Req,
         expected_status: u16,
     ) -> Result<Resp> {
+        if tracing::enabled!(tracing::Level::TRACE) {
+            trace!("POST {url}: {}", serde_json::to_string(payload)?);
+        }
         let res = client.post(url).json(&payload).send().await;
         Self::process_response(res, expected_status).await
     }
@@ -124,23 +269,70 @@ impl Upload {
         try_something!(Self::post(client, &url, &payload, expected_status).await);
     }
 
-    async fn put<Req: Into<reqwest::Body>, Resp: DeserializeOwned + fmt::Debug>(
+    async fn get<Resp: DeserializeOwned + fmt::Debug>(
         client: &Client,
         url: &String,
-        payload: Req,
         expected_status: u16,
     ) -> Result<Resp> {
-        let res = client.put(url).body(payload).send().await;
+        trace!("GET {url}");
+        let res = client.get(url).send().await;
         Self::process_response(res, expected_status).await
     }
 
-    async fn try_put<Resp: DeserializeOwned + fmt::Debug>(
+    async fn try_get<Resp: DeserializeOwned + fmt::Debug>(
         client: &Client,
         url: String,
-        payload: Bytes,
         expected_status: u16,
     ) -> Result<Resp> {
-        try_something!(Self::put(client, &url, payload.clone(), expected_status).await);
+        try_something!(Self::get(client, &url, expected_status).await);
+    }
+
+    async fn put_range<Resp: DeserializeOwned + fmt::Debug>(
+        client: &Client,
+        url: &String,
+        data: &Bytes,
+        expected_status: u16,
+    ) -> Result<Resp> {
+        let len = data.len();
+        trace!("PUT {url}");
+        // The server needs the length to check the signature, and can't tell it from a stream.
+        let mut res = client.put(url).header(CONTENT_LENGTH, len).body(data.clone()).send().await;
+        // The upload is owned by another server instance. reqwest doesn't follow redirects for
+        // PUTs itself.
+        let location = match &res {
+            Ok(redirect) if redirect.status() == StatusCode::TEMPORARY_REDIRECT => {
+                redirect.headers().get(LOCATION).and_then(|l| l.to_str().ok()).map(str::to_string)
+            }
+            _ => None,
+        };
+        if let Some(location) = location {
+            debug!("redirected to {location}");
+            // Read the rest of the redirect, or its connection can't be reused.
+            if let Ok(redirect) = res {
+                let _ = redirect.bytes().await;
+            }
+            res = client.put(&location).header(CONTENT_LENGTH, len).body(data.clone()).send().await;
+        }
+        Self::process_response(res, expected_status).await
+    }
+
+    /// Sends a chunk to the server. The chunk is kept in memory, so tries don't have to read it
+    /// again. Retries tell the server which attempt they are, for its statistics.
+    async fn try_put_range<Resp: DeserializeOwned + fmt::Debug>(
+        client: &Client,
+        url: Url,
+        data: &Bytes,
+        expected_status: u16,
+    ) -> Result<Resp> {
+        let mut attempt = 0;
+        try_something!({
+            let mut url = url.clone();
+            if attempt > 0 {
+                url.query_pairs_mut().append_pair("attempt", &attempt.to_string());
+            }
+            attempt += 1;
+            Self::put_range(client, &url.to_string(), data, expected_status).await
+        });
     }
 
     pub async fn new(
@@ -150,36 +342,136 @@ impl Upload {
         project: String,
         pipeline: String,
         metadata: Metadata,
+        shard: Option<Shard>,
     ) -> Result<Self> {
         let payload = UploadInitialisationPayload {
             file,
             project,
             pipeline,
             metadata,
+            chunk_size: Some(CHUNK_SIZE),
+            members: Vec::new(),
+            shard,
+            // The same for every retry, so a retry gets the upload back if the answer got lost.
+            nonce: Some(new_nonce()?),
         };
         let response: UploadInformation =
             Self::try_post(client, upload_endpoint, payload, 201).await?;
         Ok(Self {
             base_url: response.base_url,
             id: response.id,
+            secret: response.secret,
+            chunk_size: response.chunk_size,
+        })
+    }
+
+    /// Asks the server whether the project already has this file. Servers that can't tell are
+    /// assumed not to have it.
+    pub async fn exists(client: &Client, upload_endpoint: &str, file: &File, project: &str) -> bool {
+        let Some(mut url) = sibling_url(upload_endpoint, &format!("hash/{}", file.hash)) else {
+            return false;
+        };
+        url.query_pairs_mut()
+            .append_pair("project", project)
+            .append_pair("size", &file.size.to_string());
+        let exists: Result<HashLookupResponse> = Self::get(client, &url.to_string(), 200).await;
+        exists.unwrap_or_else(|e| {
+            warn!("couldn't check whether the server already has the file: {e}");
+            false
         })
     }
 
-    pub async fn upload_part(&self, client: &Client, offset: u64, part_data: Bytes) -> Result<()> {
+    /// Refers to an upload that has already been started, by its ID.
+    pub fn attach(upload_endpoint: &str, id: String, secret: Option<String>) -> Self {
+        Self {
+            base_url: format!("{}/{id}", upload_endpoint.trim_end_matches('/')),
+            id,
+            secret,
+            chunk_size: None,
+        }
+    }
+
+    /// Gets the command that carries on with the upload. The secret is included, since the
+    /// server won't take chunks without it, and so is the key, if the file's encrypted.
+    pub fn resume_command(&self, upload_endpoint: &str, path: &str, key: &KeyArgs) -> String {
+        let mut command = format!("bullseye-client resume -b {upload_endpoint} {} {path}", self.id);
+        if let Some(secret) = &self.secret {
+            command += &format!(" --secret {secret}");
+        }
+        if let Some(file) = &key.passphrase_file {
+            command += &format!(" --passphrase-file {}", file.display());
+        }
+        if let Some(file) = &key.key_file {
+            command += &format!(" --key-file {}", file.display());
+        }
+        command
+    }
+
+    /// Asks the server which parts of the file it already has.
+    pub async fn received(&self, client: &Client) -> Result<ReceivedRangesResponse> {
+        Self::try_get(client, self.base_url.clone() + "/ranges", 200).await
+    }
+
+    /// Adds a signature to a chunk, finish or abandon request's URL, if the server wants one.
+    fn sign(&self, url: &mut Url, action: &str, offset: u64, length: u64) {
+        let Some(secret) = &self.secret else {
+            return;
+        };
+        let expires = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+            + SIGNATURE_LIFETIME.as_secs();
+        let request = SignedRequest {
+            action,
+            id: &self.id,
+            offset,
+            length,
+            expires,
+        };
+        url.query_pairs_mut()
+            .append_pair("expires", &expires.to_string())
+            .append_pair("signature", &request.sign(secret));
+    }
+
+    pub async fn upload_part(&self, client: &Client, chunk: &Chunk) -> Result<()> {
         let nl = self.base_url.clone() + "/data";
-        let url = Url::parse_with_params(&nl, &[("offset", offset.to_string())]).unwrap();
-        let _: () = Self::try_put(client, url.to_string(), part_data, 201).await?;
-        Ok(())
+        let mut url = Url::parse_with_params(&nl, &[("offset", chunk.offset.to_string())]).unwrap();
+        self.sign(&mut url, "data", chunk.offset, chunk.data.len() as u64);
+        match Self::try_put_range::<()>(client, url, &chunk.data, 201).await {
+            // An earlier try went through, but we didn't hear back.
+            Err(e) if already_received(&e) => {
+                debug!("the server already has the chunk at {}", chunk.offset);
+                Ok(())
+            }
+            res => res,
+        }
     }
 
     pub async fn finish(&self, client: &Client) -> Result<()> {
-        let nl = self.base_url.clone() + "/finish";
-        let _: () = Self::try_post(client, nl.to_string(), "", 202).await?;
+        let mut url = Url::parse(&(self.base_url.clone() + "/finish")).unwrap();
+        self.sign(&mut url, "finish", 0, 0);
+        let _: FinishResponse = Self::try_post(client, url.to_string(), "", 202).await?;
+        Ok(())
+    }
+
+    /// Fetches the upload's row from the server.
+    pub async fn details(&self, client: &Client) -> Result<SingleUploadResponse> {
+        Self::try_get(client, self.base_url.clone(), 200).await
+    }
+
+    /// Tells the server to throw away the upload. Only tried once, since this is used when
+    /// we're about to exit.
+    pub async fn abandon(&self, client: &Client) -> Result<()> {
+        let mut url = Url::parse(&(self.base_url.clone() + "/abandon")).unwrap();
+        self.sign(&mut url, "abandon", 0, 0);
+        let _: () = Self::post(client, &url.to_string(), &"", 202).await?;
         Ok(())
     }
 
     pub async fn subscribe(&self, client: &Client) -> Result<impl Stream<Item = io::Result<UploadEvent>>> {
         let nl = self.base_url.clone() + "/events";
+        trace!("GET {nl}");
         let r = client.get(nl)
             .send()
             .await?;
@@ -200,6 +492,7 @@ impl Upload {
                         // EOF
                         break;
                     }
+                    trace!("event: {}", s.trim_end());
                     let v: UploadEvent = serde_json::from_str(&s)?;
                     yield Ok(v);
                 } else {
@@ -211,10 +504,19 @@ impl Upload {
     }
 }
 
-async fn get_file_metadata(fp: &Path) -> Result<File> {
+async fn get_file_metadata(fp: &Path, cache: &HashCache) -> Result<File> {
     let metadata = metadata(fp).await?;
-    let f = fs::File::open(fp)?;
-    let hash = spawn_blocking(|| hash_file(f)).await??;
+    let hash = match cache.get(fp, &metadata) {
+        Some(hash) => hash,
+        None => {
+            let f = fs::File::open(fp)?;
+            let hash = spawn_blocking(|| hash_file(f)).await??;
+            if let Err(e) = cache.insert(fp, &metadata, hash.clone()) {
+                warn!("failed to update hash cache: {e}");
+            }
+            hash
+        }
+    };
     Ok(File {
         name: fp.file_name().unwrap().to_str().unwrap().to_string(), // Why
         hash,
@@ -222,29 +524,54 @@ async fn get_file_metadata(fp: &Path) -> Result<File> {
     })
 }
 
-const CHUNK_SIZE: usize = 16 * 1024 * 1024;
-
-async fn read_chunk(file: &mut tokio::fs::File) -> Result<Bytes> {
-    let mut buf = BytesMut::with_capacity(CHUNK_SIZE);
-    file.read_buf(&mut buf).await?;
-    Ok(buf.freeze())
+/// Lays out the `--tar` archive of every file given, and hashes it.
+async fn tar_metadata(args: &Args, name: &str) -> Result<(Source, File)> {
+    let archive = Arc::new(Archive::new(&args.files()).await?);
+    info!("Hashing the archive...");
+    let hash = {
+        let archive = archive.clone();
+        spawn_blocking(move || archive.hash()).await??
+    };
+    let file = File {
+        name: name.to_string(),
+        hash,
+        size: archive.size(),
+    };
+    Ok((Source::Tar(archive), file))
 }
 
-async fn refresh_bar(mut bar: Option<RichProgress>, token: CancellationToken, status: watch::Receiver<Status>) -> Option<RichProgress> {
+const CHUNK_SIZE: u64 = 16 * 1024 * 1024;
+/// How long signatures are valid for. This covers all the retries of a request.
+const SIGNATURE_LIFETIME: Duration = Duration::from_secs(60 * 60);
+/// The longest we'll wait when the server says to try again later, so a misconfigured server
+/// can't park us for hours.
+const MAX_RETRY_AFTER: u64 = 10 * 60;
+
+/// Shows the item's status, and the processor's progress if it has reported any.
+async fn refresh_bar(mut bar: Option<RichProgress>, token: CancellationToken, status: watch::Receiver<(Status, Option<Progress>)>) -> Option<RichProgress> {
     let mut timer = tokio::time::interval(Duration::from_millis(100));
     timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Skip);
-    let mut prev = Status::Uploading;
+    let mut prev = (Status::Uploading, None);
     loop {
         select! {
             _ = timer.tick() => {
-                let s = status.borrow();
+                let cur = status.borrow();
+                let (s, progress) = &*cur;
                 if let Some(&mut ref mut bar) = bar.as_mut() { // Go home, Rust, you're drunk.
+                    let text = match progress {
+                        Some(p) => format!("{s} {:.1}%", p.percent()),
+                        None => s.to_string(),
+                    };
                     bar.columns.truncate(3);
-                    bar.columns.push(Column::Text(s.to_string().colorize("green")));
+                    bar.columns.push(Column::Text(text.colorize("green")));
                     let _ = bar.refresh();
-                } else if *s != prev {
-                    eprintln!("Item entered status {}.", *s);
-                    prev = s.clone();
+                } else if *cur != prev {
+                    if *s != prev.0 {
+                        info!("Item entered status {s}.");
+                    } else if let Some(p) = progress {
+                        debug!("{s}: {:.1}%", p.percent());
+                    }
+                    prev = cur.clone();
                 }
             }
             _ = token.cancelled() => {
@@ -256,18 +583,24 @@ async fn refresh_bar(mut bar: Option<RichProgress>, token: CancellationToken, st
 
 // Outside: Ok if upload OK, Err if any error.
 // Inside: Ok if upload OK, Err if hash verification failed.
+/// Sends the `missing` parts of the file, then finishes the upload and waits for the server to
+/// process it. If `expected_hash` is given, the chunks are hashed as they're sent, and the upload
+/// isn't finished if the file turns out to have changed since it was hashed. With `key`, the
+/// chunks are encrypted first.
 async fn iter_file(
-    client: &Client,
+    shared: &Shared,
     upload: Upload,
-    file: &mut tokio::fs::File,
+    source: Source,
+    key: Option<FileKey>,
     size: u64,
-    tty: bool,
+    missing: &[(u64, u64)],
+    expected_hash: Option<String>,
 ) -> Result<Result<(), ()>> {
-    let mut bytes_remaining = size;
-    let mut offset: u64 = 0;
+    let client = &shared.client;
+    let mut bytes_remaining: u64 = missing.iter().map(|(start, end)| end - start).sum();
     let mut bar: Option<RichProgress> = None;
-    eprintln!("Uploading {} bytes.", size);
-    if tty {
+    info!("Uploading {} bytes.", bytes_remaining);
+    if shared.tty {
         bar = Some(RichProgress::new(
             tqdm!(
                 total = size.try_into()?,
@@ -293,27 +626,60 @@ async fn iter_file(
             ],
         ));
     }
-    while bytes_remaining > 0 {
-        let chunk = read_chunk(file).await?;
-        let l = chunk.len() as u64;
-        upload.upload_part(client, offset, chunk).await?;
-        offset += l;
+    if let Some(&mut ref mut bar) = bar.as_mut() {
+        // Whatever the server already has.
+        let _ = bar.update_to((size - bytes_remaining) as usize);
+    }
+    let chunk_size = upload.chunk_size.unwrap_or(CHUNK_SIZE);
+    let mut chunks = transfer::read(
+        source,
+        transfer::chunks(missing, chunk_size),
+        shared.memory.clone(),
+        key,
+    );
+    if let Some(expected) = expected_hash {
+        chunks = transfer::verify(chunks, expected);
+    }
+    // With strict offsets, a chunk can only be retried until the one after it is written.
+    let parallel = match upload.chunk_size {
+        Some(_) => 1,
+        None => shared.args.parallel_chunks.max(1),
+    };
+    let upload = &upload;
+    let sends = stream::poll_fn(move |cx| chunks.poll_recv(cx))
+        .map(|chunk| async move {
+            let chunk = chunk?;
+            let l = chunk.data.len() as u64;
+            shared.bandwidth.acquire(l).await;
+            upload.upload_part(client, &chunk).await?;
+            Result::<u64>::Ok(l)
+        })
+        .buffer_unordered(parallel);
+    pin_mut!(sends);
+    while let Some(sent) = sends.next().await {
+        if shared.cancel.is_cancelled() {
+            if let Some(mut bar) = bar.take() {
+                bar.clear()?;
+            }
+            bail!(UploadError::Cancelled);
+        }
+        let l = sent?;
         bytes_remaining -= l;
         if let Some(&mut ref mut bar) = bar.as_mut() {
             let _ = bar.update(l as usize);
         } else {
-            eprintln!("uploaded {l}; {bytes_remaining} to go");
+            info!("uploaded {l}; {bytes_remaining} to go");
         }
     }
     if let Some(&mut ref mut bar) = bar.as_mut() {
         let _ = bar.update_to(0); // to get the little animation
         bar.write("Finalizing upload...".colorize("bold blue"))?;
     } else {
-        eprintln!("Finalizing upload...");
+        info!("Finalizing upload...");
     }
     upload.finish(client).await?;
     let token = CancellationToken::new();
-    let (sender, receiver) = watch::channel(Status::Uploading);
+    let (sender, receiver) = watch::channel((Status::Uploading, None));
     let f = spawn(refresh_bar(bar, token.clone(), receiver));
 
     let mut current_status = Status::Uploading;
@@ -322,7 +688,7 @@ async fn iter_file(
         let stream = match upload.subscribe(client).await {
             Ok(s) => s,
             Err(e) => {
-                dbg!(&e);
+                warn!("failed to subscribe to events: {e:?}");
                 sleep(Duration::from_secs(1 << tries)).await;
                 tries += 1;
                 if tries > 12 {
@@ -332,17 +698,49 @@ async fn iter_file(
             }
         };
         pin_mut!(stream);
-        while let Some(Ok(i)) = stream.next().await {
+        loop {
+            let next = select! {
+                next = stream.next() => next,
+                _ = shared.cancel.cancelled() => {
+                    token.cancel();
+                    if let Some(mut bar) = f.await? {
+                        bar.clear()?;
+                    }
+                    bail!(UploadError::Cancelled);
+                }
+            };
+            let Some(Ok(i)) = next else {
+                break;
+            };
             match i {
                 UploadEvent::StatusChange(s) => {
                     current_status = s.clone();
                     match s {
                         Status::Finished => break,
                         Status::Error(common::data::UploadError::Checksum) => return Ok(Err(())),
-                        Status::Error(_) => bail!("bad staus: {}", s),
-                        _ => sender.send(s)?,
+                        Status::DeadLetter => bail!("the server couldn't process the upload"),
+                        s if s.is_failure() => bail!("bad status: {s}"),
+                        s if s.is_terminal() => bail!("upload was deleted by the server"),
+                        _ => sender.send((s, None))?,
                     }
                 },
+                UploadEvent::Progress(p) => {
+                    sender.send_modify(|(_, progress)| *progress = Some(p));
+                },
+                // Not asked for.
+                UploadEvent::Row(_) => {},
+                // Only sent while uploading, and we've finished.
+                UploadEvent::Stalled(_) => {},
+                UploadEvent::Error(e) => {
+                    // Subscribe again, with the same backoff as failing to subscribe.
+                    warn!("event stream failed: {e}");
+                    sleep(Duration::from_secs(1 << tries)).await;
+                    tries += 1;
+                    if tries > 12 {
+                        bail!("event stream failed: {e}");
+                    }
+                    break;
+                },
             }
         }
     }
@@ -355,33 +753,416 @@ async fn iter_file(
     Ok(Ok(()))
 }
 
-async fn upload_file(client: &Client, args: Args, tty: bool) -> Result<Result<(), ()>> {
-    let fp = Path::new(&args.file);
-    let file = get_file_metadata(fp).await?;
+/// Gets the URL of another endpoint, relative to the upload endpoint.
+fn sibling_url(upload_endpoint: &str, path: &str) -> Option<Url> {
+    Url::parse(upload_endpoint).ok()?.join(path).ok()
+}
+
+/// Makes sure the server accepts the project and pipeline before anything is hashed or sent.
+/// If the server can't say, they're assumed to be fine.
+async fn check_target(client: &Client, endpoint: &str, args: &Args) -> Result<()> {
+    let Some(url) = sibling_url(endpoint, &format!("projects/{}/pipelines", args.project))
+    else {
+        return Ok(());
+    };
+    let res = async { client.get(url).send().await?.text().await }.await;
+    let response: ErrorablePayload<PipelinesResponse> =
+        match res.map(|text| serde_json::from_str(&text)) {
+            Ok(Ok(response)) => response,
+            Ok(Err(e)) => {
+                debug!("couldn't check the project and pipeline: {e}");
+                return Ok(());
+            }
+            Err(e) => {
+                warn!("couldn't check the project and pipeline: {e}");
+                return Ok(());
+            }
+        };
+    match response {
+        ErrorablePayload::Ok(p) if p.any_pipeline || p.pipelines.contains_key(&args.pipeline) => {
+            Ok(())
+        }
+        ErrorablePayload::Ok(p) => bail!(
+            "project {} doesn't use pipeline {}; it can use: {}",
+            args.project,
+            args.pipeline,
+            p.pipelines.into_keys().collect::<Vec<_>>().join(", ")
+        ),
+        ErrorablePayload::NotFound => bail!("the server doesn't know project {}", args.project),
+        response => {
+            warn!("couldn't check the project and pipeline: {response:?}");
+            Ok(())
+        }
+    }
+}
+
+/// State shared by every upload in the process.
+struct Shared {
+    client: Client,
+    /// The upload endpoint to send requests to. It's --base-url, unless that's a Unix socket.
+    endpoint: String,
+    args: Args,
+    tty: bool,
+    bandwidth: Bandwidth,
+    memory: MemoryBudget,
+    notifier: Notifier,
+    hashes: HashCache,
+    /// Files are encrypted with this before they're sent, if it's set.
+    key: Option<ClientKey>,
+    /// Cancelled when the user asks us to stop.
+    cancel: CancellationToken,
+}
+
+impl Shared {
+    fn new(args: Args, tty: bool) -> Result<Self> {
+        let builder = Client::builder().user_agent("UploadPacker/0.1 (proof-of-concept)");
+        let (builder, endpoint) = args.network.apply(builder, &args.base_url)?;
+        let client = builder.build()?;
+        let notifier = Notifier {
+            command: args.notify_command.clone(),
+            #[cfg(feature = "desktop-notify")]
+            desktop: args.notify_desktop,
+        };
+        let hashes = match args.no_hash_cache {
+            true => HashCache::load(None),
+            false => HashCache::load(args.hash_cache.clone().or_else(HashCache::default_path)),
+        };
+        let key = args.encryption.key(&args.project)?;
+        if key.is_some() {
+            info!("Files will be encrypted before they're sent.");
+        }
+        Ok(Self {
+            client,
+            endpoint,
+            tty,
+            bandwidth: Bandwidth::new(args.max_bandwidth),
+            memory: MemoryBudget::new(args.max_memory),
+            notifier,
+            hashes,
+            key,
+            args,
+            cancel: CancellationToken::new(),
+        })
+    }
+}
+
+/// One shard of a file being uploaded in several pieces, and where it's sent.
+#[derive(Clone, Debug)]
+struct ShardUpload {
+    shard: Shard,
+    /// The shard's own data.
+    file: File,
+    endpoint: String,
+    /// How the whole file is encrypted, if it is. The shard is that part of the ciphertext.
+    encryption: Option<ClientEncryption>,
+}
+
+/// Splits a file into shards of at most `shard_size` bytes, and hashes each of them. The shards
+/// take turns between the endpoints. If the file is encrypted, `whole` has the ciphertext's hash.
+async fn plan_shards(
+    shared: &Shared,
+    path: &Path,
+    whole: File,
+    encryption: Option<ClientEncryption>,
+    shard_size: u64,
+    endpoints: &[String],
+) -> Result<Vec<ShardUpload>> {
+    let group = uuidv7::create();
+    let count = whole.size.div_ceil(shard_size);
+    let key = match (&shared.key, &encryption) {
+        (Some(key), Some(encryption)) => Some(key.file_key(encryption)?),
+        _ => None,
+    };
+    let mut shards = Vec::with_capacity(count as usize);
+    for index in 0..count {
+        let offset = index * shard_size;
+        let size = shard_size.min(whole.size - offset);
+        let p = path.to_path_buf();
+        let hash = match &key {
+            Some(key) => encrypt::hash(Source::Part(p, offset), size, key.clone(), shared.memory.clone()).await?,
+            None => spawn_blocking(move || {
+                let mut f = fs::File::open(p)?;
+                f.seek(io::SeekFrom::Start(offset))?;
+                hash_file(f.take(size))
+            })
+            .await??,
+        };
+        shards.push(ShardUpload {
+            shard: Shard {
+                group: group.clone(),
+                index,
+                count,
+                offset,
+                whole: whole.clone(),
+            },
+            // The whole file's name, so the project's checks on it still work.
+            file: File { hash, name: whole.name.clone(), size },
+            endpoint: endpoints[index as usize % endpoints.len()].clone(),
+            encryption: encryption.clone(),
+        });
+    }
+    Ok(shards)
+}
+
+/// Uploads the file, or one shard of it.
+async fn upload_file(
+    shared: &Shared,
+    path: &str,
+    shard: Option<&ShardUpload>,
+    current: &mut Option<Upload>,
+) -> Result<Result<(), ()>> {
+    let client = &shared.client;
+    let args = shared.args.clone();
+    let fp = Path::new(path);
+    let (source, mut file) = match (&args.tar, shard) {
+        (_, Some(s)) => (Source::Part(fp.to_path_buf(), s.shard.offset), s.file.clone()),
+        (Some(name), None) => tar_metadata(&args, name).await?,
+        (None, None) => (Source::File(fp.to_path_buf()), get_file_metadata(fp, &shared.hashes).await?),
+    };
+    let encryption = match (&shared.key, shard) {
+        (_, Some(s)) => s.encryption.clone(),
+        (Some(key), None) => {
+            info!("Hashing the encrypted file...");
+            Some(encrypt::encrypt(key, &source, &mut file, &shared.memory).await?)
+        }
+        (None, None) => None,
+    };
+    let key = match (&shared.key, &encryption) {
+        (Some(key), Some(encryption)) => Some(key.file_key(encryption)?),
+        _ => None,
+    };
+    let endpoint = shard.map_or(&shared.endpoint, |s| &s.endpoint);
+    if args.skip_existing && shard.is_none() && Upload::exists(client, &shared.endpoint, &file, &args.project).await {
+        info!("The server already has this file; skipping it.");
+        return Ok(Ok(()));
+    }
     let upload = Upload::new(
         client,
-        args.base_url,
+        endpoint.clone(),
         file.clone(),
         args.project,
         args.pipeline,
         Metadata {
             uploader: args.uploader,
             items: args.items,
+            encryption,
         },
+        shard.map(|s| s.shard.clone()),
     )
     .await?;
-    eprintln!("Upload ID: {}", &upload.id);
-    let mut fh = tokio::fs::File::open(fp).await?;
-    fh.set_max_buf_size(CHUNK_SIZE);
-    iter_file(client, upload, &mut fh, file.size, tty).await
+    info!("Upload ID: {}", &upload.id);
+    *current = Some(upload.clone());
+    let res = iter_file(shared, upload.clone(), source, key, file.size, &[(0, file.size)], Some(file.hash.clone())).await?;
+    if res.is_ok() && shared.args.confirm_hash {
+        let row = upload.details(client).await?;
+        match row.server_hash() {
+            Some(h) if h == file.hash => info!("Server confirmed the upload's SHA-256: {h}"),
+            Some(h) => bail!("server computed hash {h}, but we computed {}", file.hash),
+            None => warn!("Server did not report a hash; could not confirm the upload."),
+        }
+    }
+    Ok(res)
+}
+
+/// Uploads a single file, retrying the whole upload a few times if it fails. Files bigger than
+/// --shard-size are uploaded as shards instead, which are retried one by one.
+async fn upload_with_retries(shared: &Shared, path: &str) -> Result<()> {
+    let notifier = &shared.notifier;
+    if let Some(validator) = shared.args.validate {
+        // With --tar, each file going in the archive.
+        let paths = match shared.args.tar {
+            Some(_) => shared.args.files(),
+            None => vec![path.to_string()],
+        };
+        for p in paths {
+            let fp = PathBuf::from(&p);
+            if let Err(e) = spawn_blocking(move || validator.validate(&fp)).await? {
+                let status = Status::Error(common::data::UploadError::Verify);
+                notifier.notify(&status, None, path).await;
+                bail!("validation of {p} failed: {e}");
+            }
+        }
+        info!("File passed validation.");
+    }
+    let mut current: Option<Upload> = None;
+    let res = match shared.args.shard_size {
+        Some(shard_size) => {
+            let whole = get_file_metadata(Path::new(path), &shared.hashes).await?;
+            match whole.size > shard_size {
+                true => upload_shards(shared, path, whole, shard_size).await,
+                false => retry_upload(shared, path, None, &mut current).await,
+            }
+        }
+        None => retry_upload(shared, path, None, &mut current).await,
+    };
+    let status = match &res {
+        Ok(()) => Status::Finished,
+        Err(_) if shared.cancel.is_cancelled() => return res,
+        Err(_) => Status::Error(common::data::UploadError::Other),
+    };
+    let upload_id = current.as_ref().map(|u| u.id.as_str());
+    notifier.notify(&status, upload_id, path).await;
+    res
+}
+
+/// Uploads a file as shards. Each shard is sent to its own endpoint, one at a time to each.
+async fn upload_shards(shared: &Shared, path: &str, mut whole: File, shard_size: u64) -> Result<()> {
+    let endpoints: Vec<String> = std::iter::once(&shared.endpoint)
+        .chain(&shared.args.shard_endpoints)
+        .cloned()
+        .collect();
+    let encryption = match &shared.key {
+        Some(key) => {
+            info!("Hashing the encrypted file...");
+            let source = Source::File(PathBuf::from(path));
+            Some(encrypt::encrypt(key, &source, &mut whole, &shared.memory).await?)
+        }
+        None => None,
+    };
+    info!("Hashing the shards...");
+    let shards = plan_shards(shared, Path::new(path), whole, encryption, shard_size, &endpoints).await?;
+    info!("Uploading the file as {} shards, in group {}.", shards.len(), shards[0].shard.group);
+    stream::iter(&shards)
+        .map(|shard| {
+            let span = info_span!("shard", index = shard.shard.index);
+            async move { retry_upload(shared, path, Some(shard), &mut None).await }.instrument(span)
+        })
+        .buffer_unordered(endpoints.len())
+        .try_collect::<Vec<()>>()
+        .await?;
+    Ok(())
 }
 
-#[derive(Parser, Debug, Clone)]
+/// Uploads a file, or one shard of it, retrying the whole upload a few times if it fails.
+async fn retry_upload(
+    shared: &Shared,
+    path: &str,
+    shard: Option<&ShardUpload>,
+    current: &mut Option<Upload>,
+) -> Result<()> {
+    // Resuming works on whole files.
+    let resumable = shared.args.tar.is_none() && shard.is_none();
+    for i in 0..5 {
+        if shared.cancel.is_cancelled() {
+            bail!(UploadError::Cancelled);
+        }
+        let res = upload_file(shared, path, shard, current).await;
+        match res {
+            Ok(Ok(())) => return Ok(()),
+            Err(e) if shared.cancel.is_cancelled() => {
+                if let Some(upload) = &current {
+                    if shared.args.abandon_on_cancel {
+                        match upload.abandon(&shared.client).await {
+                            Ok(()) => info!("Abandoned upload {}.", upload.id),
+                            Err(e) => warn!("failed to abandon upload {}: {e}", upload.id),
+                        }
+                    } else if shared.args.tar.is_some() {
+                        info!("Left upload {} on the server. Uploads made with --tar can't be resumed.", upload.id);
+                    } else if shard.is_some() {
+                        info!("Left upload {} on the server. Shards can't be resumed.", upload.id);
+                    } else {
+                        info!(
+                            "Left upload {} on the server. To carry on with it, run `{}`.",
+                            upload.id,
+                            upload.resume_command(&shared.args.base_url, path, &shared.args.encryption)
+                        );
+                    }
+                }
+                return Err(e);
+            }
+            Ok(Err(())) => warn!("hash verification failed, retrying"),
+            Err(e) if is_permanent(&e) => {
+                if let Some(reason) = ban_reason(&e) {
+                    error!("The server has banned uploader {}: {reason}", shared.args.uploader);
+                }
+                if let Some(id) = duplicate_of(&e).filter(|_| resumable) {
+                    let existing = Upload::attach(&shared.args.base_url, id.to_string(), None);
+                    error!(
+                        "This file is already being uploaded as {id}. To carry on with that upload, run `{}`.",
+                        existing.resume_command(&shared.args.base_url, path, &shared.args.encryption)
+                    );
+                }
+                return Err(e);
+            }
+            Err(e) => warn!("other failure ({e:?}), retrying"),
+        };
+        select! {
+            _ = sleep(Duration::from_secs(1 << i)) => {}
+            _ = shared.cancel.cancelled() => {}
+        }
+    }
+    bail!("upload failure")
+}
+
+#[derive(Parser, Debug, Clone, Default)]
 #[command(version, about, long_about = None)]
 struct Args {
     pub file: String,
     pub items: Vec<String>,
 
+    /// Another file to upload with the same items and metadata. Can be passed multiple times.
+    #[arg(long = "extra-file", value_name = "FILE")]
+    pub extra_files: Vec<String>,
+
+    /// Upload FILE and every --extra-file as one tar archive with this name, instead of one upload
+    /// each. The archive is built as it's sent, so it's never written to disk.
+    #[arg(long, value_name = "NAME")]
+    pub tar: Option<String>,
+
+    /// How many files to upload at the same time.
+    #[arg(long, default_value_t = 1)]
+    pub max_parallel_files: usize,
+
+    /// How many chunks of each file to send at the same time. Servers that want strict offsets
+    /// always get one at a time.
+    #[arg(long, default_value_t = 1)]
+    pub parallel_chunks: usize,
+
+    /// Bandwidth budget in bytes per second, shared by every file being uploaded.
+    #[arg(long)]
+    pub max_bandwidth: Option<u64>,
+
+    /// The most chunk data to hold in memory at once, in bytes, shared by every file being
+    /// uploaded. Files are read ahead only as far as this allows.
+    #[arg(long)]
+    pub max_memory: Option<u64>,
+
+    /// Where to cache file hashes between runs. Defaults to $XDG_CACHE_HOME/bullseye/hashes.json.
+    #[arg(long)]
+    pub hash_cache: Option<PathBuf>,
+
+    /// Always hash files from scratch instead of using the hash cache.
+    #[arg(long, conflicts_with = "hash_cache")]
+    pub no_hash_cache: bool,
+
+    /// Once the upload is finished, check that the server computed the same hash we did.
+    #[arg(long)]
+    pub confirm_hash: bool,
+
+    /// Before uploading a file, ask the server whether the project already has it, and if so,
+    /// don't upload it again.
+    #[arg(long)]
+    pub skip_existing: bool,
+
+    /// Check that the file is valid before uploading it.
+    #[arg(long, value_enum)]
+    pub validate: Option<Validator>,
+
+    /// Split files bigger than this many bytes into several uploads (shards) of at most this
+    /// size, which the server puts back together. For files too big for one upload, or for one
+    /// server.
+    #[arg(long, value_name = "BYTES", value_parser = clap::value_parser!(u64).range(1..), conflicts_with = "tar")]
+    pub shard_size: Option<u64>,
+
+    /// Another upload endpoint to send shards to. Shards take turns between --base-url and these,
+    /// and each is sent one shard at a time. Can be passed multiple times.
+    #[arg(long = "shard-endpoint", value_name = "URL", requires = "shard_size")]
+    pub shard_endpoints: Vec<String>,
+
+    /// When interrupted, tell the server to throw away the partial upload.
+    #[arg(long)]
+    pub abandon_on_cancel: bool,
+
     #[arg(long)]
     pub project: String,
 
@@ -391,32 +1172,163 @@ struct Args {
     #[arg(long)]
     pub uploader: String,
 
+    /// The server's upload endpoint, or unix:///path/to/socket for a server listening on a Unix
+    /// socket.
     #[arg(short, long)]
     pub base_url: String,
+
+    #[command(flatten)]
+    pub network: NetworkArgs,
+
+    #[command(flatten)]
+    pub encryption: KeyArgs,
+
+    /// Increase logging verbosity. Pass twice to also log request and response bodies.
+    #[arg(short, long, action = ArgAction::Count, conflicts_with = "quiet")]
+    pub verbose: u8,
+
+    /// Only log warnings and errors.
+    #[arg(short, long)]
+    pub quiet: bool,
+
+    /// Also write logs to this file.
+    #[arg(long)]
+    pub log_file: Option<PathBuf>,
+
+    /// Shell command to run once the upload finishes or fails for good. The status, upload ID,
+    /// and file are passed in BULLSEYE_STATUS, BULLSEYE_UPLOAD_ID, and BULLSEYE_FILE.
+    #[arg(long)]
+    pub notify_command: Option<String>,
+
+    /// Show a desktop notification once the upload finishes or fails for good.
+    #[cfg(feature = "desktop-notify")]
+    #[arg(long)]
+    pub notify_desktop: bool,
+}
+
+impl Args {
+    /// FILE and every --extra-file.
+    fn files(&self) -> Vec<String> {
+        std::iter::once(&self.file).chain(&self.extra_files).cloned().collect()
+    }
+}
+
+fn init_logging(args: &Args) -> Result<()> {
+    let level = match (args.quiet, args.verbose) {
+        (true, _) => LevelFilter::WARN,
+        (false, 0) => LevelFilter::INFO,
+        (false, 1) => LevelFilter::DEBUG,
+        (false, _) => LevelFilter::TRACE,
+    };
+    // Dependencies (hyper especially) are very chatty at the lower levels, so only let our own
+    // logs through unless they're important.
+    let filter = Targets::new()
+        .with_default(LevelFilter::WARN)
+        .with_target(env!("CARGO_CRATE_NAME"), level);
+    let file_layer = match &args.log_file {
+        Some(path) => {
+            let f = fs::File::options().create(true).append(true).open(path)?;
+            Some(layer().with_ansi(false).with_writer(Mutex::new(f)))
+        }
+        None => None,
+    };
+    tracing_subscriber::registry()
+        .with(layer().with_writer(stderr).with_target(false))
+        .with(file_layer)
+        .with(filter)
+        .init();
+    Ok(())
+}
+
+/// Exit code used when the user interrupts the upload.
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// SIGINT or SIGTERM, or Ctrl+C on Windows.
+struct Interrupts {
+    #[cfg(unix)]
+    sigterm: tokio::signal::unix::Signal,
+}
+
+impl Interrupts {
+    fn new() -> io::Result<Self> {
+        Ok(Self {
+            #[cfg(unix)]
+            sigterm: tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?,
+        })
+    }
+
+    async fn recv(&mut self) {
+        #[cfg(unix)]
+        select! {
+            _ = ctrl_c() => {}
+            _ = self.sigterm.recv() => {}
+        }
+        #[cfg(not(unix))]
+        let _ = ctrl_c().await;
+    }
+}
+
+/// Cancels the token on SIGINT or SIGTERM, so that uploads can stop cleanly.
+/// A second signal exits immediately.
+fn handle_signals(cancel: CancellationToken) -> Result<()> {
+    let mut interrupts = Interrupts::new()?;
+    spawn(async move {
+        interrupts.recv().await;
+        warn!("Interrupted; stopping after the current chunk. Interrupt again to exit immediately.");
+        cancel.cancel();
+        interrupts.recv().await;
+        let _ = term::show_cursor();
+        std::process::exit(EXIT_INTERRUPTED);
+    });
+    Ok(())
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    if std::env::args().nth(1).is_some_and(|arg| tools::TOOLS.contains(&arg.as_str())) {
+        return tools::run().await;
+    }
     let is_tty = stderr().is_terminal();
     term::init(is_tty);
     let args = Args::parse();
+    init_logging(&args)?;
     if args.items.is_empty() {
         bail!("Must have one or more items");
     }
 
-    let client = Client::builder()
-        .user_agent("UploadPacker/0.1 (proof-of-concept)")
-        .tcp_keepalive(Some(Duration::from_secs(30)))
-        .build()
-        .unwrap();
+    let files = match &args.tar {
+        Some(name) => vec![name.clone()],
+        None => args.files(),
+    };
+    let parallel = args.max_parallel_files.max(1);
+    // kdam bars trample over each other when several are drawn at once.
+    let one_at_a_time = (files.len() == 1 || parallel == 1) && args.shard_endpoints.is_empty();
+    let shared = Shared::new(args, is_tty && one_at_a_time)?;
+    check_target(&shared.client, &shared.endpoint, &shared.args).await?;
+    handle_signals(shared.cancel.clone())?;
+    let shared = &shared;
+    let results: Vec<(&String, Result<()>)> = stream::iter(&files)
+        .map(|path| {
+            let span = info_span!("upload", file = %path);
+            async move { (path, upload_with_retries(shared, path).await) }.instrument(span)
+        })
+        .buffer_unordered(parallel)
+        .collect()
+        .await;
 
-    for i in 0..5 {
-        match upload_file(&client, args.clone(), is_tty).await {
-            Ok(Ok(())) => return Ok(()),
-            Ok(Err(())) => eprintln!("hash verification failed, retrying"),
-            Err(e) => eprintln!("other failure ({e:?}), retrying"),
-        };
-        sleep(Duration::from_secs(1 << i)).await;
+    if shared.cancel.is_cancelled() {
+        let _ = term::show_cursor();
+        std::process::exit(EXIT_INTERRUPTED);
     }
-    bail!("upload failure")
+    let mut failed = 0;
+    for (path, res) in &results {
+        if let Err(e) = res {
+            error!("{path} failed: {e}");
+            failed += 1;
+        }
+    }
+    if failed > 0 {
+        bail!("{failed} of {} uploads failed", results.len());
+    }
+    Ok(())
 }
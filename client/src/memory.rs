@@ -0,0 +1,75 @@
+use std::sync::Arc;
+
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+/// The budget is counted in KiB, so it can be bigger than a semaphore's permits go.
+const UNIT: u64 = 1024;
+
+/// A limit on how much chunk data is held in memory, shared between every upload in the process.
+#[derive(Clone, Debug)]
+pub struct MemoryBudget {
+    /// In units, or None for unlimited.
+    units: Option<(Arc<Semaphore>, u64)>,
+}
+
+/// Memory taken out of the budget. It's given back when this is dropped.
+#[derive(Debug)]
+pub struct Reservation {
+    _permit: Option<OwnedSemaphorePermit>,
+}
+
+impl MemoryBudget {
+    pub fn new(bytes: Option<u64>) -> Self {
+        let units = bytes
+            .filter(|b| *b > 0)
+            .map(|b| b.div_ceil(UNIT).min(Semaphore::MAX_PERMITS as u64))
+            .map(|units| (Arc::new(Semaphore::new(units as usize)), units));
+        Self { units }
+    }
+
+    /// Waits until `bytes` more can be held without going over the budget. A chunk bigger than
+    /// the whole budget waits until nothing else is held, rather than forever.
+    pub async fn reserve(&self, bytes: u64) -> Reservation {
+        let Some((semaphore, total)) = &self.units else {
+            return Reservation { _permit: None };
+        };
+        let units = bytes.div_ceil(UNIT).min(*total).min(u32::MAX as u64) as u32;
+        let permit = semaphore
+            .clone()
+            .acquire_many_owned(units)
+            .await
+            .expect("the semaphore is never closed");
+        Reservation {
+            _permit: Some(permit),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MemoryBudget;
+
+    #[tokio::test]
+    async fn budget() {
+        let memory = MemoryBudget::new(Some(10 * 1024));
+        let first = memory.reserve(6 * 1024).await;
+        let second = memory.reserve(4 * 1024).await;
+        // There's no room left until something is given back.
+        let third = memory.reserve(1);
+        tokio::pin!(third);
+        assert!(futures_util::poll!(&mut third).is_pending());
+        drop(first);
+        let third = third.await;
+        // Bigger than the whole budget, so it waits for everything else to be given back.
+        let huge = memory.reserve(1 << 30);
+        tokio::pin!(huge);
+        drop(second);
+        assert!(futures_util::poll!(&mut huge).is_pending());
+        drop(third);
+        huge.await;
+        // Without a budget, nothing waits.
+        let unlimited = MemoryBudget::new(None);
+        let _a = unlimited.reserve(1 << 40).await;
+        let _b = unlimited.reserve(1 << 40).await;
+    }
+}
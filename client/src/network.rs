@@ -0,0 +1,209 @@
+//! Options for how the client reaches the server: which IP version to use, where to connect for a
+//! host instead of asking DNS, and which certificates to trust. These are for pinning uploads to
+//! one ingest node, or testing against staging servers with private DNS or their own CA.
+//!
+//! Also how connections are kept. Chunks reuse the same connections, so on high-latency links
+//! it's worth keeping them open between uploads and talking HTTP/2, which sends several chunks
+//! at once over one connection.
+//!
+//! A `unix://` base URL, like `unix:///run/bullseye.sock`, sends everything over that Unix socket
+//! instead, for pipelines running next to the server. Who can upload is then up to the socket's
+//! permissions. This isn't supported on Windows.
+
+use std::{
+    fs, io,
+    net::{IpAddr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+
+use anyhow::{anyhow, Context, Result};
+use clap::Args as ClapArgs;
+use reqwest::{
+    dns::{Addrs, Name, Resolve, Resolving},
+    Certificate, ClientBuilder,
+};
+
+#[derive(ClapArgs, Clone, Debug)]
+pub struct NetworkArgs {
+    /// Only connect to the server over IPv4.
+    #[arg(short = '4', long, conflicts_with = "ipv6")]
+    pub ipv4: bool,
+
+    /// Only connect to the server over IPv6.
+    #[arg(short = '6', long)]
+    pub ipv6: bool,
+
+    /// Connect to ADDR instead of looking HOST up, like curl's --resolve. The port is only there
+    /// for compatibility: the port in the URL is always the one used. Can be passed multiple
+    /// times.
+    #[arg(long, value_name = "HOST:PORT:ADDR", value_parser = parse_resolve)]
+    pub resolve: Vec<(String, IpAddr)>,
+
+    /// Also trust the CA certificates in this PEM file. Can be passed multiple times.
+    #[arg(long, value_name = "FILE")]
+    pub ca_cert: Vec<PathBuf>,
+
+    /// Only trust the certificates from --ca-cert, not the usual ones.
+    #[arg(long, requires = "ca_cert")]
+    pub no_default_ca: bool,
+
+    /// Talk HTTP/2 to the server straight away. Needed for HTTP/2 without TLS; over HTTPS it's
+    /// used anyway if the server offers it.
+    #[arg(long)]
+    pub http2: bool,
+
+    /// How long to keep idle connections to the server open for reuse, in seconds.
+    #[arg(long, value_name = "SECS", default_value_t = 90)]
+    pub idle_timeout: u64,
+
+    /// How often to check that connections to the server are alive, in seconds. Keeps NATs and
+    /// firewalls from dropping them between chunks.
+    #[arg(long, value_name = "SECS", default_value_t = 30)]
+    pub keepalive_interval: u64,
+}
+
+impl Default for NetworkArgs {
+    /// The same as clap's defaults.
+    fn default() -> Self {
+        Self {
+            ipv4: false,
+            ipv6: false,
+            resolve: Vec::new(),
+            ca_cert: Vec::new(),
+            no_default_ca: false,
+            http2: false,
+            idle_timeout: 90,
+            keepalive_interval: 30,
+        }
+    }
+}
+
+/// Parses `HOST:PORT:ADDR`, where ADDR can be an IPv6 address in brackets.
+fn parse_resolve(s: &str) -> Result<(String, IpAddr)> {
+    let mut parts = s.splitn(3, ':');
+    let (Some(host), Some(port), Some(addr)) = (parts.next(), parts.next(), parts.next()) else {
+        return Err(anyhow!("expected HOST:PORT:ADDR"));
+    };
+    if host.is_empty() {
+        return Err(anyhow!("the host is empty"));
+    }
+    port.parse::<u16>().context("bad port")?;
+    let addr = addr.trim_start_matches('[').trim_end_matches(']');
+    Ok((host.to_string(), addr.parse().context("bad address")?))
+}
+
+/// Resolves names as usual, but only keeps the addresses of one IP version.
+struct FamilyResolver {
+    ipv4: bool,
+}
+
+impl Resolve for FamilyResolver {
+    fn resolve(&self, name: Name) -> Resolving {
+        let ipv4 = self.ipv4;
+        Box::pin(async move {
+            let addrs: Vec<SocketAddr> = tokio::net::lookup_host((name.as_str(), 0))
+                .await?
+                .filter(|addr| addr.is_ipv4() == ipv4)
+                .collect();
+            if addrs.is_empty() {
+                let version = if ipv4 { 4 } else { 6 };
+                return Err(io::Error::other(format!(
+                    "{} has no IPv{version} address",
+                    name.as_str()
+                ))
+                .into());
+            }
+            Ok(Box::new(addrs.into_iter()) as Addrs)
+        })
+    }
+}
+
+/// The upload endpoint on a server listening on a Unix socket. The host doesn't matter.
+const UNIX_ENDPOINT: &str = "http://localhost/upload";
+
+/// Gets the socket in a `unix://` base URL.
+fn unix_socket(base_url: &str) -> Option<&Path> {
+    let path = base_url.strip_prefix("unix://")?;
+    Some(Path::new(path))
+}
+
+impl NetworkArgs {
+    /// Sets up a client builder with these options, for reaching the server at `base_url`.
+    /// Returns the builder and the upload endpoint to send requests to, which is `base_url`
+    /// unless it's a Unix socket.
+    pub fn apply(
+        &self,
+        mut builder: ClientBuilder,
+        base_url: &str,
+    ) -> Result<(ClientBuilder, String)> {
+        let keepalive = Duration::from_secs(self.keepalive_interval);
+        builder = builder
+            .pool_idle_timeout(Duration::from_secs(self.idle_timeout))
+            .tcp_keepalive(keepalive)
+            .http2_keep_alive_interval(keepalive)
+            .http2_keep_alive_while_idle(true)
+            .http2_adaptive_window(true);
+        if self.http2 {
+            builder = builder.http2_prior_knowledge();
+        }
+        if let Some(socket) = unix_socket(base_url) {
+            #[cfg(unix)]
+            return Ok((builder.unix_socket(socket), UNIX_ENDPOINT.to_string()));
+            #[cfg(not(unix))]
+            return Err(anyhow!(
+                "can't connect to {}: there are no Unix sockets here",
+                socket.display()
+            ));
+        }
+        if self.ipv4 || self.ipv6 {
+            builder = builder.dns_resolver(Arc::new(FamilyResolver { ipv4: self.ipv4 }));
+        }
+        for (host, addr) in &self.resolve {
+            // reqwest ignores the port and uses the URL's.
+            builder = builder.resolve(host, SocketAddr::new(*addr, 0));
+        }
+        for path in &self.ca_cert {
+            let pem =
+                fs::read(path).with_context(|| format!("couldn't read {}", path.display()))?;
+            let certs = Certificate::from_pem_bundle(&pem)
+                .with_context(|| format!("{} isn't a PEM certificate bundle", path.display()))?;
+            for cert in certs {
+                builder = builder.add_root_certificate(cert);
+            }
+        }
+        if self.no_default_ca {
+            builder = builder.tls_built_in_root_certs(false);
+        }
+        Ok((builder, base_url.to_string()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use super::{parse_resolve, unix_socket};
+
+    #[test]
+    fn test_parse_resolve() {
+        let (host, addr) = parse_resolve("ingest.example:443:192.0.2.1").unwrap();
+        assert_eq!(host, "ingest.example");
+        assert_eq!(addr.to_string(), "192.0.2.1");
+        let (_, addr) = parse_resolve("ingest.example:80:[2001:db8::1]").unwrap();
+        assert_eq!(addr.to_string(), "2001:db8::1");
+        parse_resolve("ingest.example:192.0.2.1").unwrap_err();
+        parse_resolve(":80:192.0.2.1").unwrap_err();
+        parse_resolve("ingest.example:http:192.0.2.1").unwrap_err();
+    }
+
+    #[test]
+    fn test_unix_socket() {
+        assert_eq!(
+            unix_socket("unix:///run/bullseye.sock"),
+            Some(Path::new("/run/bullseye.sock"))
+        );
+        assert_eq!(unix_socket("http://localhost/upload"), None);
+    }
+}
@@ -0,0 +1,56 @@
+use common::data::Status;
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+/// Tells the operator that an upload has reached a terminal status.
+#[derive(Clone, Debug)]
+pub struct Notifier {
+    /// Shell command to run. It receives the details in `BULLSEYE_*` environment variables.
+    pub command: Option<String>,
+    /// Whether to show a desktop notification as well.
+    #[cfg(feature = "desktop-notify")]
+    pub desktop: bool,
+}
+
+impl Notifier {
+    /// Sends the notification. Failures are logged, not returned; a broken hook shouldn't turn a
+    /// successful upload into a failed one.
+    pub async fn notify(&self, status: &Status, upload_id: Option<&str>, file: &str) {
+        if let Some(command) = &self.command {
+            debug!("running notify command {command:?}");
+            let res = Command::new("sh")
+                .arg("-c")
+                .arg(command)
+                .env("BULLSEYE_STATUS", status.to_string())
+                .env("BULLSEYE_UPLOAD_ID", upload_id.unwrap_or_default())
+                .env("BULLSEYE_FILE", file)
+                .status()
+                .await;
+            match res {
+                Ok(s) if s.success() => {}
+                Ok(s) => warn!("notify command exited with {s}"),
+                Err(e) => warn!("failed to run notify command: {e}"),
+            }
+        }
+        #[cfg(feature = "desktop-notify")]
+        if self.desktop {
+            let body = match upload_id {
+                Some(id) => format!("{file} ({id}) entered status {status}."),
+                None => format!("{file} entered status {status}."),
+            };
+            let res = tokio::task::spawn_blocking(move || {
+                notify_rust::Notification::new()
+                    .summary("Bullseye upload")
+                    .body(&body)
+                    .show()
+                    .map(|_| ())
+            })
+            .await;
+            match res {
+                Ok(Ok(())) => {}
+                Ok(Err(e)) => warn!("failed to show desktop notification: {e}"),
+                Err(e) => warn!("failed to show desktop notification: {e}"),
+            }
+        }
+    }
+}
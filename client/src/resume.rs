@@ -0,0 +1,140 @@
+//! `bullseye-client resume <upload-id> <file>`: carries on with an upload that was interrupted, or
+//! that another run started, sending only the parts of the file the server doesn't have yet.
+
+use std::{
+    io::{stderr, IsTerminal},
+    path::Path,
+};
+
+use anyhow::{bail, Result};
+use clap::{ArgAction, Args as ClapArgs};
+use common::data::Status;
+use kdam::term;
+use tracing::info;
+
+use crate::{
+    encrypt::{self, KeyArgs},
+    get_file_metadata, handle_signals, init_logging, iter_file,
+    network::NetworkArgs,
+    transfer::Source,
+    Args, Shared, Upload, EXIT_INTERRUPTED,
+};
+
+#[derive(ClapArgs, Debug)]
+pub struct ResumeArgs {
+    /// The ID of the upload, as logged when it was started.
+    id: String,
+    /// The file being uploaded. It has to be the same one the upload was started with.
+    file: String,
+    /// The upload endpoint, as passed to --base-url when uploading.
+    #[arg(short, long)]
+    base_url: String,
+    #[command(flatten)]
+    network: NetworkArgs,
+    /// The secret the server gave out when the upload was started, if it signs chunk requests.
+    #[arg(long)]
+    secret: Option<String>,
+    /// Bandwidth budget in bytes per second.
+    #[arg(long)]
+    max_bandwidth: Option<u64>,
+    /// The most chunk data to hold in memory at once, in bytes.
+    #[arg(long)]
+    max_memory: Option<u64>,
+    /// Increase logging verbosity. Pass twice to also log request and response bodies.
+    #[arg(short, long, action = ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+    /// Only log warnings and errors.
+    #[arg(short, long)]
+    quiet: bool,
+    /// The passphrase or key the file was encrypted with, if it was.
+    #[command(flatten)]
+    key: KeyArgs,
+}
+
+pub async fn run(resume: ResumeArgs) -> Result<()> {
+    let is_tty = stderr().is_terminal();
+    term::init(is_tty);
+    let args = Args {
+        file: resume.file,
+        base_url: resume.base_url,
+        network: resume.network,
+        max_bandwidth: resume.max_bandwidth,
+        max_memory: resume.max_memory,
+        verbose: resume.verbose,
+        quiet: resume.quiet,
+        ..Default::default()
+    };
+    init_logging(&args)?;
+    let shared = Shared::new(args, is_tty)?;
+    handle_signals(shared.cancel.clone())?;
+    let client = &shared.client;
+    let mut upload = Upload::attach(&shared.endpoint, resume.id, resume.secret);
+
+    let row = upload.details(client).await?;
+    if row.status() != &Status::Uploading {
+        bail!(
+            "upload {} is {}, so there's nothing to resume",
+            upload.id,
+            row.status()
+        );
+    }
+    let path = Path::new(&shared.args.file);
+    let source = Source::File(path.to_path_buf());
+    let mut file = get_file_metadata(path, &shared.hashes).await?;
+    // The server has the ciphertext, so that's what has to match.
+    let key = match &row.metadata().encryption {
+        Some(encryption) => {
+            let key = resume.key.key_for(encryption)?;
+            info!("Hashing the encrypted file...");
+            file.hash = encrypt::hash(
+                source.clone(),
+                file.size,
+                key.clone(),
+                shared.memory.clone(),
+            )
+            .await?;
+            Some(key)
+        }
+        None => None,
+    };
+    if file.size != row.size() || file.hash != row.file().hash {
+        bail!(
+            "{} isn't the file being uploaded as {}: it has SHA-256 {} and size {}, not {} and {}",
+            shared.args.file,
+            upload.id,
+            file.hash,
+            file.size,
+            row.file().hash,
+            row.size()
+        );
+    }
+    let ranges = upload.received(client).await?;
+    let missing: u64 = ranges.missing.iter().map(|(start, end)| end - start).sum();
+    info!(
+        "The server already has {} of {} bytes.",
+        ranges.size - missing,
+        ranges.size
+    );
+
+    upload.chunk_size = row.chunk_size();
+    let res = iter_file(
+        &shared,
+        upload.clone(),
+        source,
+        key,
+        file.size,
+        &ranges.missing,
+        None,
+    )
+    .await;
+    if shared.cancel.is_cancelled() {
+        let _ = term::show_cursor();
+        std::process::exit(EXIT_INTERRUPTED);
+    }
+    match res? {
+        Ok(()) => info!("Upload {} finished.", upload.id),
+        // The upload can't be retried; it has to be started again from scratch.
+        Err(()) => bail!("the file the server has doesn't match its hash"),
+    }
+    Ok(())
+}
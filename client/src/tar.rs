@@ -0,0 +1,244 @@
+//! Packs several files into a tar archive on the fly, for `--tar`. The archive is never written to
+//! disk: its layout is worked out from the files' sizes up front, so any part of it can be read on
+//! demand, straight from the files. The files have to stay the same until the upload is done.
+
+use std::{
+    io::{self, Read},
+    path::{Path, PathBuf},
+};
+
+use common::StreamHasher;
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt},
+};
+
+const BLOCK: u64 = 512;
+/// The archive ends with two empty blocks.
+const TRAILER: u64 = 2 * BLOCK;
+
+#[derive(Debug)]
+struct Member {
+    path: PathBuf,
+    header: [u8; BLOCK as usize],
+    /// Where the member's header starts in the archive. Its data comes right after.
+    offset: u64,
+    size: u64,
+}
+
+#[derive(Debug)]
+pub struct Archive {
+    members: Vec<Member>,
+    size: u64,
+}
+
+/// Where two ranges overlap, as offsets into each and a length.
+fn overlap(start: u64, len: u64, other: u64, other_len: u64) -> Option<(usize, usize, usize)> {
+    let lo = start.max(other);
+    let hi = (start + len).min(other + other_len);
+    (lo < hi).then(|| {
+        (
+            (lo - start) as usize,
+            (lo - other) as usize,
+            (hi - lo) as usize,
+        )
+    })
+}
+
+/// Writes `n` as zero-padded octal, followed by a NUL.
+fn octal(field: &mut [u8], n: u64) {
+    let len = field.len() - 1;
+    field[..len].copy_from_slice(format!("{n:0len$o}").as_bytes());
+    field[len] = 0;
+}
+
+/// Like `octal`, but numbers too big for the field, like sizes over 8 GiB, are written in GNU
+/// tar's base-256 format instead.
+fn numeric(field: &mut [u8], n: u64) {
+    if n < 1 << (3 * (field.len() - 1)) {
+        return octal(field, n);
+    }
+    field.fill(0);
+    field[0] = 0x80;
+    let len = field.len();
+    field[len - 8..].copy_from_slice(&n.to_be_bytes());
+}
+
+/// Builds a ustar header for a regular file.
+fn header(name: &str, size: u64, mtime: u64) -> io::Result<[u8; BLOCK as usize]> {
+    if name.len() > 100 {
+        return Err(io::Error::other(format!(
+            "{name}: the name is too long to go in a tar archive"
+        )));
+    }
+    let mut h = [0; BLOCK as usize];
+    h[..name.len()].copy_from_slice(name.as_bytes());
+    octal(&mut h[100..108], 0o644);
+    octal(&mut h[108..116], 0);
+    octal(&mut h[116..124], 0);
+    numeric(&mut h[124..136], size);
+    numeric(&mut h[136..148], mtime);
+    h[156] = b'0';
+    h[257..263].copy_from_slice(b"ustar\0");
+    h[263..265].copy_from_slice(b"00");
+    // The checksum is worked out as if its own field were spaces, and ends with a NUL and a space.
+    h[148..156].fill(b' ');
+    let sum: u64 = h.iter().map(|b| *b as u64).sum();
+    octal(&mut h[148..155], sum);
+    Ok(h)
+}
+
+impl Archive {
+    /// Lays out an archive of the files, in this order. Each one goes in under its file name, so
+    /// those have to be different.
+    pub async fn new(paths: &[impl AsRef<Path>]) -> io::Result<Self> {
+        let mut members: Vec<Member> = Vec::with_capacity(paths.len());
+        let mut offset = 0;
+        for path in paths {
+            let path = path.as_ref();
+            let metadata = fs::metadata(path).await?;
+            if !metadata.is_file() {
+                return Err(io::Error::other(format!("{} isn't a file", path.display())));
+            }
+            let name = path
+                .file_name()
+                .and_then(|n| n.to_str())
+                .ok_or_else(|| io::Error::other(format!("{}: bad file name", path.display())))?;
+            if members
+                .iter()
+                .any(|m| m.path.file_name() == path.file_name())
+            {
+                return Err(io::Error::other(format!(
+                    "more than one file is called {name}"
+                )));
+            }
+            let mtime = metadata
+                .modified()?
+                .duration_since(std::time::UNIX_EPOCH)
+                .map_or(0, |d| d.as_secs());
+            let size = metadata.len();
+            members.push(Member {
+                path: path.to_path_buf(),
+                header: header(name, size, mtime)?,
+                offset,
+                size,
+            });
+            offset += BLOCK + size.div_ceil(BLOCK) * BLOCK;
+        }
+        Ok(Self {
+            members,
+            size: offset + TRAILER,
+        })
+    }
+
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Fills `buf` with the archive's data starting at `offset`.
+    pub async fn read_at(&self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        let len = buf.len() as u64;
+        if offset + len > self.size {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        // Padding and the trailer.
+        buf.fill(0);
+        for m in &self.members {
+            if let Some((dst, src, n)) = overlap(offset, len, m.offset, BLOCK) {
+                buf[dst..dst + n].copy_from_slice(&m.header[src..src + n]);
+            }
+            if let Some((dst, src, n)) = overlap(offset, len, m.offset + BLOCK, m.size) {
+                let mut f = fs::File::open(&m.path).await?;
+                f.seek(io::SeekFrom::Start(src as u64)).await?;
+                f.read_exact(&mut buf[dst..dst + n]).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the files to work out the archive's SHA-256. This does blocking I/O.
+    pub fn hash(&self) -> io::Result<String> {
+        let mut hasher = StreamHasher::default();
+        let mut buf = vec![0; 1024 * 1024];
+        for m in &self.members {
+            hasher.update(&m.header);
+            let mut f = std::fs::File::open(&m.path)?.take(m.size);
+            let mut left = m.size;
+            while left > 0 {
+                let n = f.read(&mut buf)?;
+                if n == 0 {
+                    return Err(io::Error::other(format!(
+                        "{} got shorter while it was being read",
+                        m.path.display()
+                    )));
+                }
+                hasher.update(&buf[..n]);
+                left -= n as u64;
+            }
+            let padding = m.size.next_multiple_of(BLOCK) - m.size;
+            hasher.update(&[0; BLOCK as usize][..padding as usize]);
+        }
+        hasher.update(&[0; TRAILER as usize]);
+        Ok(hasher.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use common::hash_file;
+
+    use super::{header, Archive};
+
+    #[tokio::test]
+    async fn archive() {
+        let dir = std::env::temp_dir().join(format!("bullseye-tar-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let a = dir.join("a.warc");
+        let b = dir.join("b.json");
+        fs::write(&a, vec![b'a'; 700]).unwrap();
+        fs::write(&b, b"{}").unwrap();
+        let archive = Archive::new(&[&a, &b]).await.unwrap();
+        // A header and two blocks of data, a header and one block, and the trailer.
+        assert_eq!(archive.size(), 512 * 3 + 512 * 2 + 1024);
+
+        let mut whole = vec![0; archive.size() as usize];
+        archive.read_at(0, &mut whole).await.unwrap();
+        assert_eq!(&whole[..6], b"a.warc");
+        assert_eq!(&whole[257..263], b"ustar\0");
+        assert_eq!(&whole[512..1212], &[b'a'; 700][..]);
+        assert_eq!(&whole[1536..1542], b"b.json");
+        assert_eq!(&whole[2048..2050], b"{}");
+        assert!(whole[2050..].iter().all(|b| *b == 0));
+        assert_eq!(archive.hash().unwrap(), hash_file(&whole[..]).unwrap());
+
+        // Reading it in pieces that don't line up with the blocks gives the same data.
+        let mut pieces = Vec::new();
+        for start in (0..archive.size()).step_by(300) {
+            let mut buf = vec![0; 300.min(archive.size() - start) as usize];
+            archive.read_at(start, &mut buf).await.unwrap();
+            pieces.extend(buf);
+        }
+        assert_eq!(pieces, whole);
+
+        Archive::new(&[&a, &a]).await.unwrap_err();
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn checksum() {
+        let h = header("a", 1, 0).unwrap();
+        let sum: u64 = h[..148]
+            .iter()
+            .chain(&[b' '; 8])
+            .chain(&h[156..])
+            .map(|b| *b as u64)
+            .sum();
+        assert_eq!(&h[148..156], format!("{sum:06o}\0 ").as_bytes());
+        // Too big for octal.
+        let h = header("a", 1 << 40, 0).unwrap();
+        assert_eq!(h[124], 0x80);
+        assert_eq!(&h[128..136], &(1u64 << 40).to_be_bytes());
+    }
+}
@@ -0,0 +1,92 @@
+//! Subcommands: `bullseye-client completions <shell>` prints shell completions,
+//! `bullseye-client gen-man` prints a manpage, `bullseye-client bench` measures throughput (see
+//! `bench`), `bullseye-client resume` carries on with an upload (see `resume`), and
+//! `bullseye-client decrypt` decrypts an upload's data (see `encrypt`). They're picked out before
+//! the usual arguments are parsed, so to upload a file called `completions`, pass
+//! `./completions`.
+
+use std::io;
+
+use anyhow::Result;
+use clap::{builder::PossibleValuesParser, CommandFactory, Parser};
+use clap_complete::Shell;
+use clap_mangen::Man;
+use common::payloads::{ErrorablePayload, ProjectsResponse};
+use reqwest::Client;
+
+use crate::{
+    bench::{self, BenchArgs},
+    encrypt::{self, DecryptArgs},
+    resume::{self, ResumeArgs},
+    sibling_url, Args,
+};
+
+/// The subcommands, as they're written on the command line.
+pub const TOOLS: &[&str] = &["completions", "gen-man", "bench", "resume", "decrypt"];
+
+#[derive(Parser, Debug)]
+#[command(name = "bullseye-client")]
+enum Tool {
+    /// Print shell completions.
+    Completions {
+        shell: Shell,
+        /// Complete --project with the projects this server accepts. This is the same URL that
+        /// is passed to --base-url when uploading.
+        #[arg(short, long)]
+        base_url: Option<String>,
+    },
+    /// Print a manpage.
+    GenMan,
+    /// Measure upload throughput against the server's benchmark sink.
+    Bench(BenchArgs),
+    /// Carry on with an upload that was interrupted, sending only what the server doesn't have.
+    Resume(ResumeArgs),
+    /// Decrypt the data of an upload that was encrypted before it was sent.
+    Decrypt(DecryptArgs),
+}
+
+/// Asks the server which projects it accepts. Returns None if it accepts any project, or can't
+/// say. Logging isn't set up for these subcommands, so problems are printed straight to stderr.
+async fn fetch_projects(base_url: &str) -> Option<Vec<String>> {
+    let url = sibling_url(base_url, "projects")?;
+    let res = async { Client::new().get(url).send().await?.text().await }.await;
+    let response: ErrorablePayload<ProjectsResponse> =
+        match res.map(|text| serde_json::from_str(&text)) {
+            Ok(Ok(response)) => response,
+            Ok(Err(e)) => {
+                eprintln!("warning: couldn't decode the list of projects: {e}");
+                return None;
+            }
+            Err(e) => {
+                eprintln!("warning: couldn't fetch the list of projects: {e}");
+                return None;
+            }
+        };
+    match response {
+        ErrorablePayload::Ok(p) if !p.any_project => Some(p.projects.into_keys().collect()),
+        _ => None,
+    }
+}
+
+/// Runs the subcommand in the process's arguments.
+pub async fn run() -> Result<()> {
+    match Tool::parse() {
+        Tool::Completions { shell, base_url } => {
+            let mut cmd = Args::command();
+            if let Some(base_url) = base_url {
+                if let Some(projects) = fetch_projects(&base_url).await {
+                    cmd = cmd.mut_arg("project", |arg| {
+                        arg.value_parser(PossibleValuesParser::new(projects))
+                    });
+                }
+            }
+            let name = cmd.get_name().to_string();
+            clap_complete::generate(shell, &mut cmd, name, &mut io::stdout());
+        }
+        Tool::GenMan => Man::new(Args::command()).render(&mut io::stdout())?,
+        Tool::Bench(args) => bench::run(args).await?,
+        Tool::Resume(args) => resume::run(args).await?,
+        Tool::Decrypt(args) => encrypt::decrypt(args).await?,
+    }
+    Ok(())
+}
@@ -0,0 +1,186 @@
+//! Sends a file as a small pipeline: a reader task reads chunks from disk, an optional hasher
+//! checks them against the hash the upload was started with, and uploaders send them. The stages
+//! are joined by bounded channels, so the next chunk is read while the last one is still being
+//! sent, but only a few chunks are held in memory at once. Reading also waits for room in the
+//! process's memory budget (see `MemoryBudget`), which each chunk gives back once it's been sent.
+//! Chunks are encrypted as they're read, if there's a key (see `encrypt`).
+
+use std::{io, path::PathBuf, sync::Arc};
+
+use common::{crypt::FileKey, StreamHasher};
+use tokio::{
+    fs,
+    io::{AsyncReadExt, AsyncSeekExt},
+    spawn,
+    sync::mpsc::{channel, Receiver},
+    task::spawn_blocking,
+};
+use tokio_util::bytes::Bytes;
+
+use crate::{
+    memory::{MemoryBudget, Reservation},
+    tar::Archive,
+};
+
+/// How many chunks can wait between two stages.
+const QUEUE_LEN: usize = 1;
+
+/// Where the data being uploaded comes from.
+#[derive(Clone)]
+pub enum Source {
+    File(PathBuf),
+    /// Part of a file, starting at this offset, like one shard of it.
+    Part(PathBuf, u64),
+    /// Several files, archived as they're read.
+    Tar(Arc<Archive>),
+}
+
+enum Reader {
+    /// Offsets are from the second field.
+    File(fs::File, u64),
+    Tar(Arc<Archive>),
+}
+
+impl Source {
+    async fn open(&self) -> io::Result<Reader> {
+        Ok(match self {
+            Source::File(path) => Reader::File(fs::File::open(path).await?, 0),
+            Source::Part(path, start) => Reader::File(fs::File::open(path).await?, *start),
+            Source::Tar(archive) => Reader::Tar(archive.clone()),
+        })
+    }
+}
+
+impl Reader {
+    /// Where offset 0 is in the whole file.
+    fn start(&self) -> u64 {
+        match self {
+            Reader::File(_, start) => *start,
+            Reader::Tar(_) => 0,
+        }
+    }
+
+    async fn read_at(&mut self, offset: u64, buf: &mut [u8]) -> io::Result<()> {
+        match self {
+            Reader::File(f, start) => {
+                f.seek(io::SeekFrom::Start(*start + offset)).await?;
+                f.read_exact(buf).await.map(|_| ())
+            }
+            Reader::Tar(archive) => archive.read_at(offset, buf).await,
+        }
+    }
+}
+
+/// Part of the file, read into memory so it can be sent again if a try fails.
+pub struct Chunk {
+    pub offset: u64,
+    pub data: Bytes,
+    _reservation: Reservation,
+}
+
+/// Splits byte ranges into `(offset, length)` chunks of at most `chunk_size`. Chunks don't cross
+/// multiples of `chunk_size`, so they stay aligned for servers that want strict offsets.
+pub fn chunks(ranges: &[(u64, u64)], chunk_size: u64) -> Vec<(u64, u64)> {
+    let mut chunks = Vec::new();
+    for &(start, end) in ranges {
+        let mut offset = start;
+        while offset < end {
+            let next = ((offset / chunk_size + 1) * chunk_size).min(end);
+            chunks.push((offset, next - offset));
+            offset = next;
+        }
+    }
+    chunks
+}
+
+/// Reads the chunks from the source, in order, encrypting them with `key` if it's set. Stops at
+/// the first error, after passing it on, or when the receiver is dropped.
+pub fn read(
+    source: Source,
+    chunks: Vec<(u64, u64)>,
+    memory: MemoryBudget,
+    key: Option<FileKey>,
+) -> Receiver<io::Result<Chunk>> {
+    let (tx, rx) = channel(QUEUE_LEN);
+    spawn(async move {
+        let mut f = match source.open().await {
+            Ok(f) => f,
+            Err(e) => {
+                let _ = tx.send(Err(e)).await;
+                return;
+            }
+        };
+        for (offset, len) in chunks {
+            let reservation = memory.reserve(len).await;
+            let mut data = vec![0; len as usize];
+            let mut res = f.read_at(offset, &mut data).await;
+            if let (Ok(()), Some(key)) = (&res, &key) {
+                let (key, at) = (key.clone(), f.start() + offset);
+                (res, data) = match spawn_blocking(move || {
+                    key.apply(at, &mut data);
+                    data
+                })
+                .await
+                {
+                    Ok(data) => (Ok(()), data),
+                    Err(e) => (Err(io::Error::other(e)), Vec::new()),
+                };
+            }
+            let chunk = res.map(|_| Chunk {
+                offset,
+                data: data.into(),
+                _reservation: reservation,
+            });
+            let failed = chunk.is_err();
+            if tx.send(chunk).await.is_err() || failed {
+                return;
+            }
+        }
+    });
+    rx
+}
+
+/// Passes chunks through while hashing them, and fails at the end if they didn't hash to
+/// `expected`. The chunks have to cover the whole file, in order.
+pub fn verify(
+    mut chunks: Receiver<io::Result<Chunk>>,
+    expected: String,
+) -> Receiver<io::Result<Chunk>> {
+    let (tx, rx) = channel(QUEUE_LEN);
+    spawn(async move {
+        let mut hasher = StreamHasher::default();
+        while let Some(chunk) = chunks.recv().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    let _ = tx.send(Err(e)).await;
+                    return;
+                }
+            };
+            let data = chunk.data.clone();
+            hasher = match spawn_blocking(move || {
+                hasher.update(&data);
+                hasher
+            })
+            .await
+            {
+                Ok(hasher) => hasher,
+                Err(e) => {
+                    let _ = tx.send(Err(io::Error::other(e))).await;
+                    return;
+                }
+            };
+            if tx.send(Ok(chunk)).await.is_err() {
+                return;
+            }
+        }
+        let hash = hasher.finish();
+        if hash != expected {
+            let e = io::Error::other(format!(
+                "the file changed while it was being uploaded: it now has SHA-256 {hash}, not {expected}"
+            ));
+            let _ = tx.send(Err(e)).await;
+        }
+    });
+    rx
+}
@@ -0,0 +1,90 @@
+use std::{
+    fs,
+    io::{self, BufRead, BufReader, Read},
+    path::Path,
+};
+
+use clap::ValueEnum;
+use common::warc::validate_warc_zst;
+use flate2::bufread::GzDecoder;
+
+/// Checks that can be run on a file before uploading it, so obviously broken files are caught
+/// here instead of being rejected by the server's verify stage.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Validator {
+    /// A gzipped WARC, with one gzip member per record.
+    WarcGzip,
+    /// A zstd-compressed WARC, with one frame per record and optionally a dictionary.
+    WarcZst,
+}
+
+impl Validator {
+    /// Validates the file. This reads the whole thing, so run it in a blocking task.
+    pub fn validate(self, path: &Path) -> io::Result<()> {
+        let reader = BufReader::new(fs::File::open(path)?);
+        match self {
+            Self::WarcGzip => validate_warc_gzip(reader).map(|_| ()),
+            Self::WarcZst => validate_warc_zst(reader).map(|_| ()),
+        }
+    }
+}
+
+/// Makes sure every gzip member decompresses cleanly and contains a WARC record.
+/// Returns the number of members.
+fn validate_warc_gzip<R: BufRead>(mut reader: R) -> io::Result<u64> {
+    let mut members = 0;
+    while !reader.fill_buf()?.is_empty() {
+        // The bufread decoder stops at the end of the member, leaving the rest in the reader.
+        let mut decoder = GzDecoder::new(&mut reader);
+        let mut magic = [0; 5];
+        decoder.read_exact(&mut magic).map_err(|e| {
+            io::Error::new(e.kind(), format!("gzip member {members}: {e}"))
+        })?;
+        if &magic != b"WARC/" {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("gzip member {members} does not start with a WARC record"),
+            ));
+        }
+        // This also checks the CRC.
+        io::copy(&mut decoder, &mut io::sink()).map_err(|e| {
+            io::Error::new(e.kind(), format!("gzip member {members}: {e}"))
+        })?;
+        members += 1;
+    }
+    if members == 0 {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "file is empty"));
+    }
+    Ok(members)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::validate_warc_gzip;
+
+    fn member(data: &[u8]) -> Vec<u8> {
+        let mut e = GzEncoder::new(Vec::new(), Compression::fast());
+        e.write_all(data).unwrap();
+        e.finish().unwrap()
+    }
+
+    #[test]
+    fn test_warc_gzip() {
+        let mut good = member(b"WARC/1.1\r\nWARC-Type: warcinfo\r\n\r\n");
+        good.extend(member(b"WARC/1.1\r\nWARC-Type: response\r\n\r\n"));
+        assert_eq!(validate_warc_gzip(&good[..]).unwrap(), 2);
+
+        // Truncated
+        validate_warc_gzip(&good[..good.len() - 3]).unwrap_err();
+        // Not a WARC
+        validate_warc_gzip(&member(b"<html></html>")[..]).unwrap_err();
+        // Not gzip
+        validate_warc_gzip(&b"WARC/1.1\r\n"[..]).unwrap_err();
+        // Empty
+        validate_warc_gzip(&b""[..]).unwrap_err();
+    }
+}
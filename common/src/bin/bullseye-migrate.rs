@@ -0,0 +1,104 @@
+//! Brings the database up to date without starting a server or a worker: applies the migrations
+//! in `common::db::migrations` that haven't been yet, converts rows that older builds wrote in
+//! older schemas, and upgrades rows to the current row format. With `--dry-run`, it only says
+//! what it would do. The database is found the same way as for the server, from
+//! `RETHINKDB_HOST` and so on.
+//!
+//! Build it with `cargo build --features db --bin bullseye-migrate` in the common directory.
+
+use std::{
+    error::Error,
+    io::{self, Write},
+    process::ExitCode,
+    time::Instant,
+};
+
+use common::db::{
+    leases::Lease,
+    migrations::{self, MIGRATIONS},
+    DatabaseHandle, UploadRow,
+};
+
+const USAGE: &str = "usage: bullseye-migrate [--dry-run]";
+
+/// Does the work, holding `lock` unless it's a dry run.
+async fn migrate(conn: &DatabaseHandle, lock: Option<&Lease>) -> Result<(), Box<dyn Error>> {
+    let dry_run = lock.is_none();
+    let current = migrations::current_version(conn).await?;
+    let pending = migrations::pending(conn).await?;
+    println!(
+        "The database is at version {current}, and this build knows {}.",
+        MIGRATIONS.len()
+    );
+    for &version in &pending {
+        let description = MIGRATIONS[version as usize - 1];
+        let Some(lock) = lock else {
+            println!("Would apply migration {version}: {description}");
+            continue;
+        };
+        print!("Applying migration {version}: {description}... ");
+        io::stdout().flush()?;
+        let start = Instant::now();
+        migrations::apply_one(conn, lock, version).await?;
+        println!("done in {:.1?}", start.elapsed());
+    }
+    if !migrations::has_table(conn, "uploads").await? {
+        // Only possible with --dry-run. There aren't any rows yet.
+        return Ok(());
+    }
+
+    // Migration 14 converts these too, but old builds might have written more since.
+    let legacy = migrations::convert_legacy_rows(conn, dry_run).await?;
+    let old = match dry_run {
+        true => UploadRow::count_old_rows(conn).await?,
+        false => UploadRow::upgrade_old_rows(conn).await?,
+    };
+    let (converted, upgraded) = match dry_run {
+        true => ("Would convert", "Would upgrade"),
+        false => ("Converted", "Upgraded"),
+    };
+    println!("{converted} {} rows from older schemas.", legacy.converted);
+    println!("{upgraded} {old} rows to the current row format.");
+    for id in &legacy.unknown {
+        println!("warning: upload {id} has a status this build doesn't know; fix it by hand");
+    }
+    Ok(())
+}
+
+async fn run(dry_run: bool) -> Result<(), Box<dyn Error>> {
+    let conn = DatabaseHandle::new()?;
+    if dry_run {
+        return migrate(&conn, None).await;
+    }
+    // So a server or a worker starting now doesn't migrate at the same time.
+    let lock = migrations::lock(&conn).await?;
+    let result = migrate(&conn, Some(&lock)).await;
+    lock.release(&conn).await?;
+    result
+}
+
+fn main() -> ExitCode {
+    let dry_run = match std::env::args().nth(1).as_deref() {
+        None => false,
+        Some("-n" | "--dry-run") => true,
+        Some("-h" | "--help") => {
+            println!("{USAGE}");
+            return ExitCode::SUCCESS;
+        }
+        Some(_) => {
+            eprintln!("{USAGE}");
+            return ExitCode::from(2);
+        }
+    };
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .expect("failed to start the runtime");
+    match runtime.block_on(run(dry_run)) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,22 @@
+//! Prints the JSON Schemas of the wire payloads (see `common::schema`) as one JSON object, keyed
+//! by payload name. Given a directory, writes each one to `<name>.json` in it instead.
+//!
+//! Build it with `cargo run --features schema --bin bullseye-schema` in the common directory.
+
+use std::{fs, io, path::PathBuf};
+
+fn main() -> io::Result<()> {
+    let schemas = common::schema::schemas();
+    let Some(dir) = std::env::args_os().nth(1).map(PathBuf::from) else {
+        serde_json::to_writer_pretty(io::stdout().lock(), &schemas)?;
+        println!();
+        return Ok(());
+    };
+    fs::create_dir_all(&dir)?;
+    for (name, schema) in schemas {
+        let mut json = serde_json::to_vec_pretty(&schema)?;
+        json.push(b'\n');
+        fs::write(dir.join(format!("{name}.json")), json)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,117 @@
+//! Copies data into files inside the kernel with `copy_file_range` where it can, instead of reading
+//! it into a buffer and writing it out again. On filesystems with reflinks, like XFS and Btrfs, the
+//! kernel shares the blocks instead of copying them wherever they line up, so packing a
+//! multi-gigabyte WARC can cost next to nothing. Where that isn't supported (between filesystems
+//! on older kernels, on filesystems that don't implement it, or anywhere but Linux and FreeBSD),
+//! this falls back to a buffered copy.
+
+use std::{
+    fs::File,
+    io::{self, Cursor, Read, Seek, SeekFrom},
+};
+
+use crate::platform::{copy_range, write_all_at};
+
+/// The most copied by one `copy_file_range` call.
+const MAX_RANGE: u64 = 1024 * 1024 * 1024;
+const BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Data that can be copied into a file. Data in a file is copied inside the kernel if possible.
+pub trait Source: Read + Seek {
+    /// The file the data is in, if it's in one.
+    fn file(&self) -> Option<&File> {
+        None
+    }
+}
+
+impl Source for File {
+    fn file(&self) -> Option<&File> {
+        Some(self)
+    }
+}
+
+impl<T: AsRef<[u8]>> Source for Cursor<T> {}
+
+/// Copies up to `len` bytes from the source's position to `offset` in `dest`, and returns how many
+/// were copied, which is fewer only if the source ran out. The source's position moves past them;
+/// `dest`'s isn't used (see `platform::write_all_at`), so it can't have been opened for appending.
+pub fn copy_to<S: Source + ?Sized>(
+    src: &mut S,
+    dest: &File,
+    offset: u64,
+    len: u64,
+) -> io::Result<u64> {
+    let start = src.stream_position()?;
+    let mut copied = 0;
+    if let Some(file) = src.file() {
+        let (mut off_in, mut off_out) = (start, offset);
+        while copied < len {
+            let want = (len - copied).min(MAX_RANGE) as usize;
+            match copy_range(file, &mut off_in, dest, &mut off_out, want) {
+                Ok(0) => {
+                    src.seek(SeekFrom::Start(start + copied))?;
+                    return Ok(copied);
+                }
+                Ok(n) => copied += n as u64,
+                // Not supported here, so the rest goes through a buffer.
+                Err(e) if e.kind() == io::ErrorKind::Unsupported => break,
+                Err(e) => return Err(e),
+            }
+        }
+        src.seek(SeekFrom::Start(start + copied))?;
+    }
+    let mut buf = vec![0; BUFFER_SIZE];
+    while copied < len {
+        let want = (len - copied).min(buf.len() as u64) as usize;
+        let n = match src.read(&mut buf[..want]) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        write_all_at(dest, &buf[..n], offset + copied)?;
+        copied += n as u64;
+    }
+    Ok(copied)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::{self, File},
+        io::{Cursor, Seek, SeekFrom},
+    };
+
+    use super::copy_to;
+
+    #[test]
+    fn test_copy_to() {
+        let dir = std::env::temp_dir().join(format!("bullseye-copy-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let data: Vec<u8> = (0..3 * 1024 * 1024).map(|i| (i % 251) as u8).collect();
+        fs::write(dir.join("src"), &data).unwrap();
+        fs::write(dir.join("dest"), b"head").unwrap();
+        let dest = File::options()
+            .read(true)
+            .write(true)
+            .open(dir.join("dest"))
+            .unwrap();
+
+        // From a file, starting partway through, up to the end.
+        let mut src = File::open(dir.join("src")).unwrap();
+        src.seek(SeekFrom::Start(10)).unwrap();
+        assert_eq!(copy_to(&mut src, &dest, 4, u64::MAX).unwrap(), data.len() as u64 - 10);
+        assert_eq!(src.stream_position().unwrap(), data.len() as u64);
+        // From memory, only some of it.
+        let mut src = Cursor::new(b"tail and more");
+        let end = dest.metadata().unwrap().len();
+        assert_eq!(copy_to(&mut src, &dest, end, 4).unwrap(), 4);
+        assert_eq!(src.position(), 4);
+
+        let written = fs::read(dir.join("dest")).unwrap();
+        assert_eq!(&written[..4], b"head");
+        assert_eq!(&written[4..written.len() - 4], &data[10..]);
+        assert_eq!(&written[written.len() - 4..], b"tail");
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
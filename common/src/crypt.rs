@@ -0,0 +1,635 @@
+//! Encryption of uploads' data at rest, for ingest servers on machines that can't be trusted with
+//! it. When the server has a master key, every new upload gets a random data key, which goes on
+//! its row wrapped (encrypted with AES-256-GCM) by the master key. Without the master key, the
+//! rows and the data files are no use to anybody.
+//!
+//! Chunks are written wherever they go in the file, in whatever order they come in, so the data is
+//! encrypted with AES-256-CTR, which can start anywhere. That doesn't stop somebody who can write
+//! to the data directory from changing the data, but the SHA-256 the client gave for it is checked
+//! as usual, which catches that. An upload's data uses nonce 0, so no part of it may be encrypted
+//! twice with different contents: the server refuses chunks that overlap what it has already
+//! received (`Rejection::AlreadyReceived`). A chunk that failed partway can still be sent again,
+//! since a retry has the same data, which encrypts to the same bytes. A derived file is written in one
+//! go, so it gets a random nonce, which goes in front of it (see `DERIVED_HEADER`): that way, a
+//! derived file that's written again, by another stage, doesn't reuse a keystream either.
+//!
+//! Separately, the client can encrypt files before it sends them (see `ClientKey`), so that the
+//! server never sees what's in them. To the server, the ciphertext is the upload: its hash is the
+//! ciphertext's, and the parameters the client used go in the upload's metadata, so that whoever
+//! has the passphrase or key file can decrypt it later.
+
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::PathBuf,
+};
+
+use aes::{
+    cipher::{KeyIvInit, StreamCipher, StreamCipherSeek},
+    Aes256,
+};
+use aes_gcm::{
+    aead::{Aead, Payload},
+    Aes256Gcm, KeyInit, Nonce,
+};
+use argon2::{Algorithm, Argon2, Params, Version};
+use base16ct::lower::encode_string;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::copy::Source;
+
+/// Names the file the master key is in, as 64 hex digits.
+pub const MASTER_KEY_VAR: &str = "BULLSEYE_MASTER_KEY_FILE";
+
+/// The only cipher the client encrypts uploads with.
+pub const CLIENT_CIPHER: &str = "aes-256-ctr";
+
+/// How many bytes come before an encrypted derived file's data: its nonce.
+pub const DERIVED_HEADER: u64 = 8;
+
+type Ctr = ctr::Ctr64BE<Aes256>;
+
+/// An upload's data key, encrypted with the master key, in hex. The upload's ID is authenticated
+/// with it, so it can't be moved to another row.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct WrappedKey {
+    pub nonce: String,
+    pub key: String,
+}
+
+/// The key that wraps every upload's data key.
+pub struct MasterKey(Aes256Gcm);
+
+impl fmt::Debug for MasterKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("MasterKey(..)")
+    }
+}
+
+impl MasterKey {
+    /// Reads the master key from the file named by `BULLSEYE_MASTER_KEY_FILE`, if it's set.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        let Some(path) = std::env::var_os(MASTER_KEY_VAR).filter(|p| !p.is_empty()) else {
+            return Ok(None);
+        };
+        let hex = fs::read_to_string(&path)
+            .map_err(|e| io::Error::new(e.kind(), format!("{MASTER_KEY_VAR}: {e}")))?;
+        Self::from_hex(hex.trim()).map(Some)
+    }
+
+    pub fn from_hex(hex: &str) -> io::Result<Self> {
+        match base16ct::mixed::decode_vec(hex) {
+            Ok(key) if key.len() == 32 => Ok(Self(Aes256Gcm::new_from_slice(&key).unwrap())),
+            _ => Err(io::Error::other("the master key must be 32 bytes, in hex")),
+        }
+    }
+
+    /// Makes a data key for a new upload, and wraps it for the upload's row.
+    pub fn new_key(&self, id: &str) -> io::Result<(DataKey, WrappedKey)> {
+        let mut key = [0; 32];
+        let mut nonce = [0; 12];
+        getrandom::getrandom(&mut key).map_err(io::Error::other)?;
+        getrandom::getrandom(&mut nonce).map_err(io::Error::other)?;
+        let payload = Payload {
+            msg: &key,
+            aad: id.as_bytes(),
+        };
+        let sealed = self
+            .0
+            .encrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| io::Error::other("failed to wrap the data key"))?;
+        let wrapped = WrappedKey {
+            nonce: encode_string(&nonce),
+            key: encode_string(&sealed),
+        };
+        Ok((DataKey(key), wrapped))
+    }
+
+    /// Gets an upload's data key back. This fails if it was wrapped by another master key, or for
+    /// another upload.
+    pub fn unwrap(&self, id: &str, wrapped: &WrappedKey) -> io::Result<DataKey> {
+        let bad = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("the data key of {id} can't be unwrapped with this master key"),
+            )
+        };
+        let nonce = base16ct::mixed::decode_vec(&wrapped.nonce).map_err(|_| bad())?;
+        let sealed = base16ct::mixed::decode_vec(&wrapped.key).map_err(|_| bad())?;
+        if nonce.len() != 12 {
+            return Err(bad());
+        }
+        let payload = Payload {
+            msg: &sealed,
+            aad: id.as_bytes(),
+        };
+        let key = self
+            .0
+            .decrypt(Nonce::from_slice(&nonce), payload)
+            .map_err(|_| bad())?;
+        Ok(DataKey(key.try_into().map_err(|_| bad())?))
+    }
+}
+
+/// An upload's data key.
+#[derive(Clone)]
+pub struct DataKey([u8; 32]);
+
+impl fmt::Debug for DataKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("DataKey(..)")
+    }
+}
+
+impl DataKey {
+    /// The key for the upload's own data.
+    pub fn data(&self) -> FileKey {
+        self.with_nonce(0)
+    }
+
+    fn with_nonce(&self, nonce: u64) -> FileKey {
+        FileKey { key: self.0, nonce }
+    }
+}
+
+/// The key for one of an upload's files.
+#[derive(Clone)]
+pub struct FileKey {
+    key: [u8; 32],
+    nonce: u64,
+}
+
+impl fmt::Debug for FileKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "FileKey(.., {})", self.nonce)
+    }
+}
+
+impl FileKey {
+    /// Encrypts `buf`, which goes at `offset` in the file, or decrypts it, which is the same thing.
+    pub fn apply(&self, offset: u64, buf: &mut [u8]) {
+        let mut iv = [0; 16];
+        iv[..8].copy_from_slice(&self.nonce.to_be_bytes());
+        let mut cipher = Ctr::new(&self.key.into(), &iv.into());
+        cipher.seek(offset);
+        cipher.apply_keystream(buf);
+    }
+}
+
+/// How the client encrypted an upload before sending it. This goes in the upload's metadata.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ClientEncryption {
+    /// Always `aes-256-ctr`.
+    pub cipher: String,
+    /// The first half of the counter block, in hex.
+    pub nonce: String,
+    /// Where the key came from.
+    pub key: KeySource,
+    /// A hash of the key, in hex, to tell whether a passphrase or key file is the right one.
+    pub check: String,
+}
+
+/// Where the key a client encrypted an upload with came from.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum KeySource {
+    /// Argon2id, with these parameters, of a passphrase.
+    Passphrase {
+        /// In hex.
+        salt: String,
+        /// In KiB.
+        memory: u32,
+        iterations: u32,
+        parallelism: u32,
+    },
+    /// 32 bytes in a file, as 64 hex digits.
+    Keyfile,
+}
+
+/// The key a client encrypts uploads with.
+pub struct ClientKey {
+    key: [u8; 32],
+    source: KeySource,
+}
+
+impl fmt::Debug for ClientKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ClientKey(.., {:?})", self.source)
+    }
+}
+
+impl ClientKey {
+    /// Makes the key for a project's uploads from its passphrase. The salt comes from the
+    /// project's name, so that everybody with the passphrase gets the same key.
+    pub fn from_passphrase(passphrase: &str, project: &str) -> io::Result<Self> {
+        let salt = Sha256::new()
+            .chain_update(b"bullseye passphrase salt\0")
+            .chain_update(project)
+            .finalize();
+        let source = KeySource::Passphrase {
+            salt: encode_string(&salt[..16]),
+            memory: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        };
+        Self::derive(passphrase, source)
+    }
+
+    /// Makes a key again from a passphrase, with the parameters it was made with.
+    pub fn derive(passphrase: &str, source: KeySource) -> io::Result<Self> {
+        let KeySource::Passphrase {
+            salt,
+            memory,
+            iterations,
+            parallelism,
+        } = &source
+        else {
+            return Err(io::Error::other("the key didn't come from a passphrase"));
+        };
+        let salt = base16ct::mixed::decode_vec(salt)
+            .map_err(|_| io::Error::other("the passphrase's salt isn't hex"))?;
+        let params =
+            Params::new(*memory, *iterations, *parallelism, Some(32)).map_err(io::Error::other)?;
+        let mut key = [0; 32];
+        Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+            .hash_password_into(passphrase.as_bytes(), &salt, &mut key)
+            .map_err(io::Error::other)?;
+        Ok(Self { key, source })
+    }
+
+    /// Reads a key file's contents: 64 hex digits.
+    pub fn from_hex(hex: &str) -> io::Result<Self> {
+        match base16ct::mixed::decode_vec(hex.trim()) {
+            Ok(key) if key.len() == 32 => Ok(Self {
+                key: key.try_into().unwrap(),
+                source: KeySource::Keyfile,
+            }),
+            _ => Err(io::Error::other("the key file must hold 32 bytes, in hex")),
+        }
+    }
+
+    fn check(&self) -> String {
+        let check = Sha256::new()
+            .chain_update(b"bullseye key check\0")
+            .chain_update(self.key)
+            .finalize();
+        encode_string(&check[..8])
+    }
+
+    /// Gets the parameters to encrypt a file with, from the SHA-256 of its plaintext. The nonce
+    /// comes from the key and the hash, so the same file always encrypts to the same ciphertext:
+    /// an interrupted upload can be resumed, and the server can tell it already has a file. That
+    /// also means that somebody who can see the uploads can tell when two are the same file.
+    pub fn encryption(&self, hash: &str) -> ClientEncryption {
+        let nonce = Sha256::new()
+            .chain_update(b"bullseye nonce\0")
+            .chain_update(self.key)
+            .chain_update(hash)
+            .finalize();
+        ClientEncryption {
+            cipher: CLIENT_CIPHER.to_string(),
+            nonce: encode_string(&nonce[..8]),
+            key: self.source.clone(),
+            check: self.check(),
+        }
+    }
+
+    /// Gets the key to encrypt or decrypt a file with, checking that it's this key.
+    pub fn file_key(&self, encryption: &ClientEncryption) -> io::Result<FileKey> {
+        if encryption.cipher != CLIENT_CIPHER {
+            return Err(io::Error::other(format!(
+                "unknown cipher {}",
+                encryption.cipher
+            )));
+        }
+        if encryption.check != self.check() {
+            return Err(io::Error::other(
+                "the file wasn't encrypted with this passphrase or key file",
+            ));
+        }
+        let nonce = base16ct::mixed::decode_vec(&encryption.nonce)
+            .ok()
+            .and_then(|n| <[u8; 8]>::try_from(n).ok())
+            .ok_or_else(|| io::Error::other("the nonce must be 8 bytes, in hex"))?;
+        Ok(FileKey {
+            key: self.key,
+            nonce: u64::from_be_bytes(nonce),
+        })
+    }
+}
+
+/// Reads a data file, decrypting it if there's a key. Seeking works like it does on the file,
+/// except that offsets don't count the header of an encrypted derived file.
+pub struct Decrypting<R> {
+    inner: R,
+    key: Option<FileKey>,
+    pos: u64,
+    /// Where the data starts in `inner`.
+    base: u64,
+}
+
+impl<R: Seek> Decrypting<R> {
+    pub fn new(inner: R, key: Option<FileKey>) -> io::Result<Self> {
+        Self::with_base(inner, key, 0)
+    }
+
+    fn with_base(mut inner: R, key: Option<FileKey>, base: u64) -> io::Result<Self> {
+        let pos = inner.seek(SeekFrom::Start(base))? - base;
+        Ok(Self {
+            inner,
+            key,
+            pos,
+            base,
+        })
+    }
+
+    pub fn get_ref(&self) -> &R {
+        &self.inner
+    }
+}
+
+impl<R: Read> Read for Decrypting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        if let Some(key) = &self.key {
+            key.apply(self.pos, &mut buf[..n]);
+        }
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: Seek> Seek for Decrypting<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        let pos = match pos {
+            SeekFrom::Start(n) => SeekFrom::Start(n + self.base),
+            pos => pos,
+        };
+        let pos = self.inner.seek(pos)?;
+        if pos < self.base {
+            self.inner.seek(SeekFrom::Start(self.base))?;
+            self.pos = 0;
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "can't seek to before the start of the data",
+            ));
+        }
+        self.pos = pos - self.base;
+        Ok(self.pos)
+    }
+}
+
+/// Only data that isn't encrypted can be copied inside the kernel.
+impl Source for Decrypting<File> {
+    fn file(&self) -> Option<&File> {
+        match self.key {
+            Some(_) => None,
+            None => Some(&self.inner),
+        }
+    }
+}
+
+/// Writes a data file from the start, encrypting it if there's a key.
+pub struct Encrypting<W> {
+    inner: W,
+    key: Option<FileKey>,
+    pos: u64,
+    buf: Vec<u8>,
+}
+
+impl<W> Encrypting<W> {
+    pub fn new(inner: W, key: Option<FileKey>) -> Self {
+        Self {
+            inner,
+            key,
+            pos: 0,
+            buf: Vec::new(),
+        }
+    }
+
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: Write> Write for Encrypting<W> {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        let Some(key) = &self.key else {
+            let n = self.inner.write(data)?;
+            self.pos += n as u64;
+            return Ok(n);
+        };
+        self.buf.clear();
+        self.buf.extend_from_slice(data);
+        key.apply(self.pos, &mut self.buf);
+        self.inner.write_all(&self.buf)?;
+        self.pos += data.len() as u64;
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// One of an upload's files, and the key its upload's files are encrypted with, if they are.
+#[derive(Clone, Debug)]
+pub struct DataFile {
+    pub path: PathBuf,
+    key: Option<DataKey>,
+    /// Whether it's the derived file, rather than the upload's own data.
+    derived: bool,
+}
+
+impl DataFile {
+    pub fn new(path: impl Into<PathBuf>, key: Option<DataKey>, derived: bool) -> Self {
+        Self {
+            path: path.into(),
+            key,
+            derived,
+        }
+    }
+
+    /// A file that isn't encrypted.
+    pub fn plain(path: impl Into<PathBuf>) -> Self {
+        Self::new(path, None, false)
+    }
+
+    pub fn is_encrypted(&self) -> bool {
+        self.key.is_some()
+    }
+
+    /// Opens the file for reading, decrypting it if it's encrypted.
+    pub fn open(&self) -> io::Result<Decrypting<File>> {
+        let mut file = File::open(&self.path)?;
+        match &self.key {
+            None => Decrypting::new(file, None),
+            Some(key) if !self.derived => Decrypting::new(file, Some(key.data())),
+            Some(key) => {
+                let mut nonce = [0; DERIVED_HEADER as usize];
+                file.read_exact(&mut nonce)?;
+                let key = key.with_nonce(u64::from_be_bytes(nonce));
+                Decrypting::with_base(file, Some(key), DERIVED_HEADER)
+            }
+        }
+    }
+
+    /// Starts the upload's derived file on `inner`, so that it's encrypted like the upload is.
+    pub fn derived_writer<W: Write>(&self, mut inner: W) -> io::Result<Encrypting<W>> {
+        let Some(key) = &self.key else {
+            return Ok(Encrypting::new(inner, None));
+        };
+        let mut nonce = [0; DERIVED_HEADER as usize];
+        getrandom::getrandom(&mut nonce).map_err(io::Error::other)?;
+        inner.write_all(&nonce)?;
+        Ok(Encrypting::new(
+            inner,
+            Some(key.with_nonce(u64::from_be_bytes(nonce))),
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs::File,
+        io::{Cursor, Read, Seek, SeekFrom, Write},
+    };
+
+    use super::{
+        ClientKey, DataFile, Decrypting, Encrypting, KeySource, MasterKey, DERIVED_HEADER,
+    };
+
+    #[test]
+    fn test_wrap() {
+        let master = MasterKey::from_hex(&"ab".repeat(32)).unwrap();
+        let (key, wrapped) = master.new_key("upload").unwrap();
+        assert_eq!(master.unwrap("upload", &wrapped).unwrap().0, key.0);
+        // Another upload's row, or another master key.
+        master.unwrap("other", &wrapped).unwrap_err();
+        let other = MasterKey::from_hex(&"cd".repeat(32)).unwrap();
+        other.unwrap("upload", &wrapped).unwrap_err();
+        // Each upload gets its own key.
+        assert_ne!(master.new_key("upload").unwrap().0 .0, key.0);
+
+        MasterKey::from_hex("abcd").unwrap_err();
+        MasterKey::from_hex(&"zz".repeat(32)).unwrap_err();
+    }
+
+    #[test]
+    fn test_random_access() {
+        let master = MasterKey::from_hex(&"ab".repeat(32)).unwrap();
+        let (key, _) = master.new_key("upload").unwrap();
+        let data: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+
+        // Written in pieces, out of order, at offsets that don't line up with the cipher's blocks.
+        let mut file = vec![0; data.len()];
+        for (start, end) in [(7000, 10_000), (0, 13), (13, 7000)] {
+            let mut piece = data[start..end].to_vec();
+            key.data().apply(start as u64, &mut piece);
+            file[start..end].copy_from_slice(&piece);
+        }
+        assert_ne!(file, data);
+
+        let mut reader = Decrypting::new(Cursor::new(&file), Some(key.data())).unwrap();
+        let mut all = Vec::new();
+        reader.read_to_end(&mut all).unwrap();
+        assert_eq!(all, data);
+        reader.seek(SeekFrom::Start(4097)).unwrap();
+        let mut part = [0; 100];
+        reader.read_exact(&mut part).unwrap();
+        assert_eq!(part, data[4097..4197]);
+
+        let mut writer = Encrypting::new(Vec::new(), Some(key.data()));
+        for piece in data.chunks(333) {
+            writer.write_all(piece).unwrap();
+        }
+        assert_eq!(writer.into_inner(), file);
+    }
+
+    #[test]
+    fn test_client_key() {
+        let key = ClientKey::from_passphrase("hunter2", "project").unwrap();
+        let encryption = key.encryption("abc");
+        // Everybody with the passphrase gets the same key, and the same file the same nonce.
+        let again = ClientKey::derive("hunter2", encryption.key.clone()).unwrap();
+        assert_eq!(again.encryption("abc"), encryption);
+        assert_ne!(key.encryption("abd").nonce, encryption.nonce);
+        let file_key = again.file_key(&encryption).unwrap();
+
+        let data = b"some sensitive data".to_vec();
+        let mut buf = data.clone();
+        file_key.apply(0, &mut buf);
+        assert_ne!(buf, data);
+        key.file_key(&encryption).unwrap().apply(0, &mut buf);
+        assert_eq!(buf, data);
+
+        // Another passphrase, or another project's key.
+        let wrong = ClientKey::derive("hunter3", encryption.key.clone()).unwrap();
+        wrong.file_key(&encryption).unwrap_err();
+        let other = ClientKey::from_passphrase("hunter2", "other").unwrap();
+        other.file_key(&encryption).unwrap_err();
+
+        let keyfile = ClientKey::from_hex(&format!("{}\n", "ab".repeat(32))).unwrap();
+        let encryption = keyfile.encryption("abc");
+        assert_eq!(encryption.key, KeySource::Keyfile);
+        keyfile.file_key(&encryption).unwrap();
+        ClientKey::derive("hunter2", KeySource::Keyfile).unwrap_err();
+        ClientKey::from_hex("abcd").unwrap_err();
+    }
+
+    #[test]
+    fn test_derived() {
+        let master = MasterKey::from_hex(&"ab".repeat(32)).unwrap();
+        let (key, _) = master.new_key("upload").unwrap();
+        let data: Vec<u8> = (0..10_000).map(|i| (i % 251) as u8).collect();
+        let path =
+            std::env::temp_dir().join(format!("bullseye-crypt-{}.derived", std::process::id()));
+        let derived = DataFile::new(&path, Some(key.clone()), true);
+
+        // Written twice, like when a later stage derives another file: the keystream differs.
+        let mut written = Vec::new();
+        for _ in 0..2 {
+            let mut writer = derived
+                .derived_writer(File::create(&path).unwrap())
+                .unwrap();
+            for piece in data.chunks(333) {
+                writer.write_all(piece).unwrap();
+            }
+            drop(writer);
+            written.push(std::fs::read(&path).unwrap());
+        }
+        assert_ne!(written[0], written[1]);
+        assert_eq!(written[1].len(), data.len() + DERIVED_HEADER as usize);
+
+        let mut reader = derived.open().unwrap();
+        let mut all = Vec::new();
+        reader.read_to_end(&mut all).unwrap();
+        assert_eq!(all, data);
+        reader.seek(SeekFrom::Start(4097)).unwrap();
+        let mut part = [0; 100];
+        reader.read_exact(&mut part).unwrap();
+        assert_eq!(part, data[4097..4197]);
+        assert_eq!(
+            reader.seek(SeekFrom::End(-3)).unwrap(),
+            data.len() as u64 - 3
+        );
+        reader.seek(SeekFrom::Current(-20_000)).unwrap_err();
+
+        // The upload's own data isn't read as if it had a header.
+        std::fs::write(&path, &data).unwrap();
+        let mut all = Vec::new();
+        DataFile::new(&path, None, true)
+            .open()
+            .unwrap()
+            .read_to_end(&mut all)
+            .unwrap();
+        assert_eq!(all, data);
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -1,21 +1,61 @@
-use std::fmt;
+use std::{fmt, io, path::Path, time::SystemTime};
 
 use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::{
+    crypt::{ClientEncryption, DataFile, DataKey, MasterKey, WrappedKey},
+    payloads::Rejection,
+    signing::nonce_hash,
+};
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct Metadata {
     pub uploader: String,
+    /// The names of the items in the upload. The server can look uploads up by these.
     pub items: Vec<String>,
+    /// Set if the client encrypted the data before sending it.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<ClientEncryption>,
 }
 
-#[derive(Serialize, Deserialize, Clone, Debug)]
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct File {
     pub hash: String,
     pub name: String,
     pub size: u64,
 }
 
+/// One of the files in a bundle upload. A bundle's data is its members' data one after another,
+/// so this member is at `offset..offset + file.size`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct BundleMember {
+    #[serde(flatten)]
+    pub file: File,
+    pub offset: u64,
+}
+
+/// One piece of a file that the client split into several uploads, because it was too big for
+/// one. This piece is at `offset..offset + file.size` in the whole file.
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Shard {
+    /// Shared by every shard of the file, and nothing else.
+    pub group: String,
+    /// Which shard this is, from 0.
+    pub index: u64,
+    /// How many shards the file was split into.
+    pub count: u64,
+    pub offset: u64,
+    /// The whole file.
+    pub whole: File,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub enum UploadError {
     /// The checksum did not match. The client should try uploading again.
     #[serde(rename = "FAILED_CHECKSUM")]
@@ -30,6 +70,7 @@ pub enum UploadError {
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(rename_all = "UPPERCASE")]
 pub enum Status {
     /// The file is currently being uploaded. The file has been fully allocated but its
@@ -44,12 +85,74 @@ pub enum Status {
     /// The file has been safely readied for uploading. The client's job is done.
     Finished,
     /// The upload was abandoned by the client and the file has been removed.
+    /// New uploads are soft-deleted instead; this is only found on old rows.
     Abandoned,
+    /// The upload was abandoned or failed, and is waiting to be purged. Its data is kept until
+    /// `delete_after`, so it can still be recovered.
+    Deleted,
+    /// Processing failed too many times. The item is parked until an operator requeues or
+    /// discards it.
+    #[serde(rename = "DEAD_LETTER")]
+    DeadLetter,
     /// Something went wrong with the upload.
     #[serde(untagged)]
     Error(UploadError),
 }
 
+impl Status {
+    /// Every status, as it's written in rows.
+    pub const NAMES: &[&str] = &[
+        "UPLOADING",
+        "VERIFYING",
+        "DERIVING",
+        "PACKING",
+        "FINISHED",
+        "ABANDONED",
+        "DELETED",
+        "DEAD_LETTER",
+        "FAILED_CHECKSUM",
+        "FAILED_VERIFY",
+        "FAILED_OTHER",
+    ];
+
+    /// Reads a status the way older builds wrote it: in another case, with dashes or spaces
+    /// instead of underscores, as a bare `FAILED` or `ERROR`, or with failures wrapped in an
+    /// object like `{"ERROR": "FAILED_CHECKSUM"}`. Returns None if it isn't one of those.
+    pub fn from_legacy(status: &Value) -> Option<Self> {
+        match status {
+            Value::String(s) => {
+                let name = s.trim().to_uppercase().replace(['-', ' '], "_");
+                match name.as_str() {
+                    "FAILED" | "ERROR" => Some(Status::Error(UploadError::Other)),
+                    _ => serde_json::from_value(Value::String(name)).ok(),
+                }
+            }
+            Value::Object(fields) if fields.len() == 1 => {
+                Self::from_legacy(fields.values().next().unwrap())
+            }
+            _ => None,
+        }
+    }
+
+    /// Whether the upload is done with, one way or another.
+    pub fn is_terminal(&self) -> bool {
+        matches!(
+            self,
+            Status::Finished
+                | Status::Abandoned
+                | Status::Deleted
+                | Status::DeadLetter
+                | Status::Error(_)
+        )
+    }
+
+    /// Whether the upload failed: something went wrong with it, or it couldn't be processed.
+    /// Abandoned and deleted uploads don't count, since that's usually what the client wanted.
+    pub fn is_failure(&self) -> bool {
+        matches!(self, Status::DeadLetter | Status::Error(_))
+    }
+}
+
 impl fmt::Display for Status {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
@@ -60,7 +163,356 @@ impl fmt::Display for Status {
     }
 }
 
+/// How far along a processor is with the current stage.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Progress {
+    /// Usually bytes, but processors can use any unit.
+    pub done: u64,
+    pub total: u64,
+}
+
+impl Progress {
+    /// Gets the progress as a percentage.
+    pub fn percent(&self) -> f64 {
+        if self.total == 0 {
+            return 100.0;
+        }
+        (self.done as f64 / self.total as f64 * 100.0).min(100.0)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "lowercase")]
+pub enum MegawarcLocation {
+    Warc,
+    #[serde(rename = "warc.zst")]
+    WarcZst,
+    Tar,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct MegawarcTarget {
+    pub container: MegawarcLocation,
+    /// For tar members, this includes the header and the padding.
+    pub offset: u64,
+    pub size: u64,
+}
+
+/// Where an item was packed.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Packed {
+    /// The megawarc's name, without the extensions.
+    pub megawarc: String,
+    pub target: MegawarcTarget,
+}
+
+/// What anyone can find out about an upload by looking for an item in it: enough to tell which
+/// upload it is and where it was packed, but nothing about who uploaded it or how.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ItemUpload {
+    pub id: String,
+    pub project: String,
+    pub pipeline: String,
+    pub status: Status,
+    pub file: File,
+    pub items: Vec<String>,
+    #[serde(default)]
+    pub packed: Option<Packed>,
+    /// Whether it was found in the archive.
+    #[serde(default)]
+    pub archived: bool,
+}
+
+/// A digest of an upload's data that the server computed itself, rather than taking the client's
+/// word for it.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Digest {
+    /// Like `sha256`.
+    pub algorithm: String,
+    /// In lowercase hex.
+    pub value: String,
+    /// When the data was verified against it, in seconds since the epoch.
+    pub verified_at: u64,
+}
+
+impl Digest {
+    /// A SHA-256 digest that was just verified.
+    pub fn sha256(value: impl Into<String>) -> Self {
+        Self {
+            algorithm: "sha256".to_string(),
+            value: value.into(),
+            verified_at: UploadRow::now(),
+        }
+    }
+}
+
+/// A request that changed something, as recorded in the audit trail.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct AuditEntry {
+    /// When the request was answered, in seconds since the epoch.
+    pub time: u64,
+    /// The client's address.
+    pub remote: Option<String>,
+    /// Whether the request carried the admin token.
+    pub admin: bool,
+    pub method: String,
+    /// The route, like `/upload/{uuid}/data`.
+    pub endpoint: String,
+    /// The uploads the request was about.
+    pub uploads: Vec<String>,
+    /// The HTTP status of the response.
+    pub status: u16,
+}
+
+/// One chunk the server received.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ChunkSample {
+    /// When the chunk finished, in seconds since the epoch.
+    pub time: u64,
+    pub bytes: u64,
+    /// How long it took to receive and write, in milliseconds.
+    pub millis: u64,
+}
+
+/// How the upload's data came in, for diagnosing slow uploads.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct TransferStats {
+    /// How many chunks were received, including retries.
+    #[serde(default)]
+    pub chunks: u64,
+    /// How many of them were retries, as the client reported.
+    #[serde(default)]
+    pub retried_chunks: u64,
+    /// How many bytes were received, including retries.
+    #[serde(default)]
+    pub bytes: u64,
+    /// How long was spent receiving and writing chunks, in milliseconds.
+    #[serde(default)]
+    pub busy_millis: u64,
+    /// When the first chunk came in, in seconds since the epoch.
+    #[serde(default)]
+    pub first_chunk: Option<u64>,
+    /// When the last chunk finished, in seconds since the epoch.
+    #[serde(default)]
+    pub last_chunk: Option<u64>,
+    /// The latest chunks, oldest first. Only the last `MAX_CHUNK_SAMPLES` are kept.
+    #[serde(default)]
+    pub samples: Vec<ChunkSample>,
+    /// How long it took from starting the upload to finishing it, in seconds.
+    #[serde(default)]
+    pub wall_time: Option<u64>,
+}
+
+/// How many chunks TransferStats keeps samples of.
+pub const MAX_CHUNK_SAMPLES: usize = 500;
+
+impl TransferStats {
+    /// The average rate chunks were received at while they were being sent, in bytes per second.
+    pub fn bytes_per_second(&self) -> Option<f64> {
+        (self.busy_millis > 0).then(|| self.bytes as f64 * 1000.0 / self.busy_millis as f64)
+    }
+}
+
+/// An upload that hasn't received any data for a while. See `Project::stall_timeout`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Stall {
+    /// When data last came in, or when the upload was started if none has, in seconds since the
+    /// epoch.
+    pub since: u64,
+    /// When the upload will be abandoned unless more data comes in, in seconds since the epoch.
+    pub abandon_at: u64,
+}
+
+impl Stall {
+    /// Whether an upload that last received data at `since` counts as stalled at `now`, given
+    /// its project's stall timeout. Uploads are warned about halfway to being abandoned.
+    pub fn check(since: u64, timeout: u64, now: u64) -> Option<Self> {
+        (now.saturating_sub(since) >= timeout / 2).then_some(Self {
+            since,
+            abandon_at: since.saturating_add(timeout),
+        })
+    }
+}
+
+/// Sorts byte ranges, given as `(start, end)` pairs, and joins the ones that overlap or touch.
+/// Empty ranges are dropped.
+pub fn merge_ranges(mut ranges: Vec<(u64, u64)>) -> Vec<(u64, u64)> {
+    ranges.retain(|(start, end)| start < end);
+    ranges.sort_unstable();
+    let mut merged: Vec<(u64, u64)> = Vec::with_capacity(ranges.len());
+    for (start, end) in ranges {
+        match merged.last_mut() {
+            Some(last) if start <= last.1 => last.1 = last.1.max(end),
+            _ => merged.push((start, end)),
+        }
+    }
+    merged
+}
+
+/// Gets the parts of `0..size` that aren't in `ranges`, which must already be merged.
+pub fn missing_ranges(ranges: &[(u64, u64)], size: u64) -> Vec<(u64, u64)> {
+    let mut missing = Vec::new();
+    let mut pos = 0;
+    for &(start, end) in ranges {
+        if start > pos {
+            missing.push((pos, start.min(size)));
+        }
+        pos = pos.max(end);
+        if pos >= size {
+            break;
+        }
+    }
+    if pos < size {
+        missing.push((pos, size));
+    }
+    missing.retain(|(start, end)| start < end);
+    missing
+}
+
+/// An uploader who isn't allowed to upload anything.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Ban {
+    #[serde(rename = "id")]
+    pub uploader: String,
+    /// Shown to the uploader.
+    pub reason: String,
+    /// When they were banned, in seconds since the epoch.
+    pub time: u64,
+}
+
+/// How much one uploader (`Metadata.uploader`) has uploaded.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct UploaderStats {
+    #[serde(rename = "id")]
+    pub uploader: String,
+    /// The total size of the uploads they finished.
+    pub bytes: u64,
+    /// How many uploads they finished.
+    pub items: u64,
+}
+
+/// How many items of a project and pipeline are in one status.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct QueueDepth {
+    pub project: String,
+    pub pipeline: String,
+    pub status: Status,
+    pub items: u64,
+    /// How long, in seconds, it's been since anything happened to the item that's been waiting
+    /// longest.
+    pub oldest_age: u64,
+}
+
+impl QueueDepth {
+    /// Counts items by project, pipeline and status, given `(project, pipeline, status,
+    /// last_activity)` for each. The result is sorted by project, then pipeline, then status.
+    pub fn tally(
+        items: impl IntoIterator<Item = (String, String, Status, u64)>,
+        now: u64,
+    ) -> Vec<Self> {
+        let mut depths: Vec<Self> = Vec::new();
+        for (project, pipeline, status, last_activity) in items {
+            let age = now.saturating_sub(last_activity);
+            match depths
+                .iter_mut()
+                .find(|d| d.project == project && d.pipeline == pipeline && d.status == status)
+            {
+                Some(d) => {
+                    d.items += 1;
+                    d.oldest_age = d.oldest_age.max(age);
+                }
+                None => depths.push(Self {
+                    project,
+                    pipeline,
+                    status,
+                    items: 1,
+                    oldest_age: age,
+                }),
+            }
+        }
+        depths.sort_by(|a, b| {
+            (&a.project, &a.pipeline, a.status.to_string())
+                .cmp(&(&b.project, &b.pipeline, b.status.to_string()))
+        });
+        depths
+    }
+}
+
+/// A piece of a file, as cut up by the dedup stage.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Chunk {
+    pub hash: String,
+    pub size: u64,
+}
+
+/// How much of an upload was already in earlier uploads to the same project, as worked out by the
+/// dedup stage.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Dedup {
+    pub chunks: u64,
+    /// How many of the chunks an earlier upload had.
+    pub duplicate_chunks: u64,
+    pub bytes: u64,
+    pub duplicate_bytes: u64,
+    /// `duplicate_bytes / bytes`: 0 if nothing was seen before, 1 if everything was.
+    pub ratio: f64,
+}
+
+impl Dedup {
+    /// Adds up the chunks, given which hashes earlier uploads had.
+    pub fn new(chunks: &[Chunk], seen: impl Fn(&str) -> bool) -> Self {
+        let mut dedup = Self {
+            chunks: chunks.len() as u64,
+            duplicate_chunks: 0,
+            bytes: 0,
+            duplicate_bytes: 0,
+            ratio: 0.0,
+        };
+        for chunk in chunks {
+            dedup.bytes += chunk.size;
+            if seen(&chunk.hash) {
+                dedup.duplicate_chunks += 1;
+                dedup.duplicate_bytes += chunk.size;
+            }
+        }
+        if dedup.bytes > 0 {
+            dedup.ratio = dedup.duplicate_bytes as f64 / dedup.bytes as f64;
+        }
+        dedup
+    }
+}
+
+/// The version of the row format this build writes, recorded on rows as `schema_version`. Bump it
+/// when a change to UploadRow means rows written by older builds have to be upgraded, and add a
+/// step to `UploadRow::upgrade` and `UploadRow::upgrade_old_rows`.
+///
+/// - 0: rows from before the version was recorded, which might not say when they were created.
+/// - 1: every row says when it was created.
+pub const ROW_SCHEMA_VERSION: u32 = 1;
+
+/// An upload, as stored in the database.
+///
+/// Rows are read leniently, so servers and workers of different versions can share a table while
+/// they're upgraded one at a time: unknown fields (from newer builds) are ignored, and missing ones
+/// (from older builds) get defaults. Writes only touch the fields they change, so fields this
+/// build doesn't know about are kept.
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct UploadRow {
     /** The primary key of the upload */
     pub(crate) id: String,
@@ -72,6 +524,9 @@ pub struct UploadRow {
     pub(crate) file: File,
     /** The last time the server received data from the client; can be used to expire uploads */
     pub(crate) last_activity: u64,
+    /// When the upload was started, in seconds since the epoch.
+    #[serde(default)]
+    pub(crate) created: u64,
 
     pub(crate) pipeline: String,
     pub(crate) project: String,
@@ -81,11 +536,687 @@ pub struct UploadRow {
     pub(crate) processing: bool,
 
     pub(crate) metadata: Metadata,
+
+    /// The SHA-256 hash of the file as computed by the server, once it has been verified.
+    #[serde(default)]
+    pub(crate) server_hash: Option<String>,
+    /// The server's own digest of the upload's data, once the checksum stage has found that it
+    /// matches the client's hash.
+    #[serde(default)]
+    pub(crate) digest: Option<Digest>,
+
+    /// Set if the project requires strict offsets: chunks must start at a multiple of this.
+    #[serde(default)]
+    pub(crate) chunk_size: Option<u64>,
+    /// The end of the furthest chunk that has been written.
+    #[serde(default)]
+    pub(crate) high_water_mark: u64,
+
+    /// Set once the retention task has deleted the item's data.
+    #[serde(default)]
+    pub(crate) files_removed: bool,
+
+    /// When a soft-deleted item's data will be purged, in seconds since the epoch.
+    #[serde(default)]
+    pub(crate) delete_after: Option<u64>,
+    /// The status the item was in before it was soft-deleted.
+    #[serde(default)]
+    pub(crate) deleted_from: Option<Status>,
+
+    /// When the scrubber last re-hashed the file, in seconds since the epoch.
+    #[serde(default)]
+    pub(crate) scrubbed_at: Option<u64>,
+
+    /// Progress reported by the processor working on the current stage. Reset whenever the
+    /// status changes.
+    #[serde(default)]
+    pub(crate) progress: Option<Progress>,
+
+    /// How many times processing the current stage has failed.
+    #[serde(default)]
+    pub(crate) attempts: u32,
+    /// Why the last attempt failed.
+    #[serde(default)]
+    pub(crate) last_error: Option<String>,
+    /// Why each attempt at the current stage failed, oldest first.
+    #[serde(default)]
+    pub(crate) errors: Vec<String>,
+    /// The status a dead-lettered item was in, which it goes back to when it's requeued.
+    #[serde(default)]
+    pub(crate) dead_from: Option<Status>,
+    /// The item won't be checked out again before this time, in seconds since the epoch.
+    #[serde(default)]
+    pub(crate) retry_after: Option<u64>,
+    /// Where the item ended up, once it's been packed.
+    #[serde(default)]
+    pub(crate) packed: Option<Packed>,
+    /// The URL of the server instance that owns the upload, if several share the database. Only
+    /// that instance writes to the file.
+    #[serde(default)]
+    pub(crate) node: Option<String>,
+    /// How the data came in.
+    #[serde(default)]
+    pub(crate) transfer: TransferStats,
+    /// Set while the upload has gone a while without receiving any data. Cleared when a chunk
+    /// comes in.
+    #[serde(default)]
+    pub(crate) stalled: Option<Stall>,
+    /// The version of the row format the row was written with. See ROW_SCHEMA_VERSION.
+    #[serde(default)]
+    pub(crate) schema_version: u32,
+    /// Set by an operator to keep the upload from being reaped, expired or purged.
+    #[serde(default)]
+    pub(crate) pinned: bool,
+    /// The parts of the file that have been written, as `(start, end)` pairs in the order the
+    /// chunks came in. Retried and overlapping chunks aren't merged here; see `received_ranges`.
+    #[serde(default)]
+    pub(crate) received: Vec<(u64, u64)>,
+    /// The files in the upload, if it's a bundle of several. Empty for a single file.
+    #[serde(default)]
+    pub(crate) members: Vec<BundleMember>,
+    /// The file a deriving stage made from the upload, like a recompressed copy, which later
+    /// stages use instead. It's kept next to the upload, as `derived_name(id)`.
+    #[serde(default)]
+    pub(crate) derived: Option<File>,
+    /// How much of the upload earlier uploads already had, once the dedup stage has run.
+    #[serde(default)]
+    pub(crate) dedup: Option<Dedup>,
+    /// Set on rows that have been moved to the archive table.
+    #[serde(default)]
+    pub(crate) archived: bool,
+    /// Set if the upload is one piece of a bigger file.
+    #[serde(default)]
+    pub(crate) shard: Option<Shard>,
+    /// The key the upload's files are encrypted with, wrapped by the server's master key. None if
+    /// they aren't encrypted. See `crypt`.
+    #[serde(default)]
+    pub(crate) encryption: Option<WrappedKey>,
+    /// Set once the upload has been added to its uploader's totals, so it isn't counted again if
+    /// an operator moves it out of FINISHED and back.
+    #[serde(default)]
+    pub(crate) counted: bool,
+    /// The hash of the nonce the client started the upload with, if it sent one. See
+    /// `signing::nonce_hash`.
+    #[serde(default)]
+    pub(crate) nonce_hash: Option<String>,
+}
+
+/// The name of an upload's derived file, in its directory.
+pub fn derived_name(id: &str) -> String {
+    format!("{id}.derived")
+}
+
+/// Builds the row for a new upload, in the Uploading status. Get one with `UploadRow::builder`,
+/// and save the row with `UploadRow::insert`.
+#[derive(Clone, Debug)]
+pub struct UploadRowBuilder {
+    id: String,
+    file: File,
+    project: String,
+    pipeline: String,
+    metadata: Metadata,
+    dir: String,
+    chunk_size: Option<u64>,
+    node: Option<String>,
+    created: Option<u64>,
+    members: Vec<File>,
+    shard: Option<Shard>,
+    encryption: Option<WrappedKey>,
+    nonce_hash: Option<String>,
+}
+
+impl UploadRowBuilder {
+    /// Sets the directory the file is in. By default it's the data directory itself.
+    pub fn dir(mut self, dir: impl Into<String>) -> Self {
+        self.dir = dir.into();
+        self
+    }
+
+    /// Makes the upload use strict offsets, with chunks of this size.
+    pub fn chunk_size(mut self, chunk_size: Option<u64>) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Makes the upload owned by a server instance, identified by its URL.
+    pub fn node(mut self, node: Option<String>) -> Self {
+        self.node = node;
+        self
+    }
+
+    /// Sets when the upload was started, in seconds since the epoch. By default it's now.
+    pub fn created(mut self, created: u64) -> Self {
+        self.created = Some(created);
+        self
+    }
+
+    /// Makes the upload a bundle of these files, in this order. The upload's file then has to
+    /// describe all of them together: its size is theirs added up.
+    pub fn members(mut self, members: Vec<File>) -> Self {
+        self.members = members;
+        self
+    }
+
+    /// Makes the upload one piece of a bigger file.
+    pub fn shard(mut self, shard: Option<Shard>) -> Self {
+        self.shard = shard;
+        self
+    }
+
+    /// Makes the upload's files encrypted with this data key. See `MasterKey::new_key`.
+    pub fn encryption(mut self, encryption: Option<WrappedKey>) -> Self {
+        self.encryption = encryption;
+        self
+    }
+
+    /// Records the hash of the nonce the client started the upload with. See `signing`.
+    pub fn nonce_hash(mut self, nonce_hash: Option<String>) -> Self {
+        self.nonce_hash = nonce_hash;
+        self
+    }
+
+    /// Checks the row makes sense and builds it.
+    pub fn build(self) -> Result<UploadRow, String> {
+        if self.id.is_empty() {
+            return Err("the upload needs an ID".to_string());
+        }
+        if self.project.is_empty() || self.pipeline.is_empty() {
+            return Err("the upload needs a project and a pipeline".to_string());
+        }
+        if self.chunk_size == Some(0) {
+            return Err("the chunk size can't be 0".to_string());
+        }
+        if let Some(shard) = &self.shard {
+            if shard.group.is_empty() {
+                return Err("the shard needs a group".to_string());
+            }
+            if shard.index >= shard.count {
+                return Err(format!(
+                    "shard {} of a file in {} shards",
+                    shard.index, shard.count
+                ));
+            }
+            if shard.offset.saturating_add(self.file.size) > shard.whole.size {
+                return Err("the shard goes past the end of the whole file".to_string());
+            }
+            if !self.members.is_empty() {
+                return Err("a shard can't be a bundle".to_string());
+            }
+        }
+        let mut offset = 0;
+        let mut members = Vec::with_capacity(self.members.len());
+        for file in self.members {
+            if file.name.is_empty() {
+                return Err("every file in the bundle needs a name".to_string());
+            }
+            if members.iter().any(|m: &BundleMember| m.file.name == file.name) {
+                return Err(format!(
+                    "the bundle has more than one file called {}",
+                    file.name
+                ));
+            }
+            let size = file.size;
+            members.push(BundleMember { file, offset });
+            offset = offset
+                .checked_add(size)
+                .ok_or("the files in the bundle add up to more bytes than fit in 64 bits")?;
+        }
+        if !members.is_empty() && offset != self.file.size {
+            return Err(format!(
+                "the files in the bundle add up to {offset} bytes, but the upload is {}",
+                self.file.size
+            ));
+        }
+        let created = self.created.unwrap_or_else(UploadRow::now);
+        Ok(UploadRow {
+            id: self.id,
+            dir: self.dir,
+            status: Status::Uploading,
+            file: self.file,
+            last_activity: created,
+            created,
+            pipeline: self.pipeline,
+            project: self.project,
+            processing: false,
+            metadata: self.metadata,
+            server_hash: None,
+            digest: None,
+            chunk_size: self.chunk_size,
+            high_water_mark: 0,
+            files_removed: false,
+            delete_after: None,
+            deleted_from: None,
+            scrubbed_at: None,
+            progress: None,
+            attempts: 0,
+            last_error: None,
+            errors: Vec::new(),
+            dead_from: None,
+            retry_after: None,
+            packed: None,
+            node: self.node,
+            transfer: TransferStats::default(),
+            stalled: None,
+            schema_version: ROW_SCHEMA_VERSION,
+            pinned: false,
+            received: Vec::new(),
+            members,
+            derived: None,
+            dedup: None,
+            archived: false,
+            shard: self.shard,
+            encryption: self.encryption,
+            counted: false,
+            nonce_hash: self.nonce_hash,
+        })
+    }
+}
+
+impl UploadRow {
+    /// Starts building the row for a new upload.
+    pub fn builder(
+        id: impl Into<String>,
+        file: File,
+        project: impl Into<String>,
+        pipeline: impl Into<String>,
+        metadata: Metadata,
+    ) -> UploadRowBuilder {
+        UploadRowBuilder {
+            id: id.into(),
+            file,
+            project: project.into(),
+            pipeline: pipeline.into(),
+            metadata,
+            dir: String::new(),
+            chunk_size: None,
+            node: None,
+            created: None,
+            members: Vec::new(),
+            shard: None,
+            encryption: None,
+            nonce_hash: None,
+        }
+    }
+
+    /// The current time, in seconds since the epoch.
+    pub(crate) fn now() -> u64 {
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs()
+    }
+
+    /// Brings a row written by an older build up to date. Rows from newer builds are left as they
+    /// are.
+    pub fn upgrade(&mut self) {
+        if self.schema_version < 1 && self.created == 0 {
+            // Near enough, like migration 9.
+            self.created = self.last_activity;
+        }
+        self.schema_version = self.schema_version.max(ROW_SCHEMA_VERSION);
+    }
+
+    /// Works out how to bring a row written in an older schema up to date, for
+    /// `bullseye-migrate`, and returns the fields to change. Statuses that can't be made sense of
+    /// (see `Status::from_legacy`) are left alone. The row's `writing` field, which is what
+    /// `processing` used to be called, should be removed too.
+    pub fn legacy_changes(row: &Value) -> Map<String, Value> {
+        let mut changes = Map::new();
+        if let (Some(writing), None) = (row.get("writing"), row.get("processing")) {
+            changes.insert(
+                "processing".to_string(),
+                Value::Bool(writing.as_bool().unwrap_or(false)),
+            );
+        }
+        if let Some(status) = row.get("status") {
+            if serde_json::from_value::<Status>(status.clone()).is_err() {
+                if let Some(status) = Status::from_legacy(status) {
+                    changes.insert("status".to_string(), serde_json::to_value(status).unwrap());
+                }
+            }
+        }
+        changes
+    }
+
+    /// Gets the unique ID of the item.
+    pub fn id(&self) -> &String {
+        &self.id
+    }
+
+    /// Gets the file size.
+    pub fn size(&self) -> u64 {
+        self.file.size
+    }
+
+    /// Gets the upload's metadata.
+    pub fn metadata(&self) -> &Metadata {
+        &self.metadata
+    }
+
+    /// Gets the current status.
+    pub fn status(&self) -> &Status {
+        &self.status
+    }
+
+    /// Gets the file, as the client described it.
+    pub fn file(&self) -> &File {
+        &self.file
+    }
+
+    /// Gets the files in the upload, if it's a bundle. A single file has none.
+    pub fn members(&self) -> &[BundleMember] {
+        &self.members
+    }
+
+    /// Gets which piece of a bigger file the upload is, if it's one.
+    pub fn shard(&self) -> Option<&Shard> {
+        self.shard.as_ref()
+    }
+
+    /// Gets how much of the upload earlier uploads already had, if the dedup stage has run.
+    pub fn dedup(&self) -> Option<&Dedup> {
+        self.dedup.as_ref()
+    }
+
+    /// Whether the upload is done with for good, so it can be moved to the archive table.
+    pub fn is_archivable(&self) -> bool {
+        let done = match self.status {
+            Status::Finished | Status::Abandoned => true,
+            Status::Deleted => self.files_removed,
+            _ => false,
+        };
+        done && !self.pinned
+    }
+
+    /// Whether the row was read from the archive table.
+    pub fn archived(&self) -> bool {
+        self.archived
+    }
+
+    /// Gets the version of the row format the row was written with.
+    pub fn schema_version(&self) -> u32 {
+        self.schema_version
+    }
+
+    /// Whether the upload uses strict offsets.
+    pub fn strict_offsets(&self) -> bool {
+        self.chunk_size.is_some()
+    }
+
+    /// In strict mode, checks that a chunk may be written at `offset`.
+    ///
+    /// The offset has to be aligned to the chunk size, and can be at most one chunk behind the
+    /// high-water mark, so a client can retry a chunk whose acknowledgement it never received.
+    pub fn check_offset(&self, offset: u64) -> Result<(), Rejection> {
+        let Some(chunk_size) = self.chunk_size else {
+            return Ok(());
+        };
+        if chunk_size == 0 || !offset.is_multiple_of(chunk_size) {
+            return Err(Rejection::MisalignedOffset { chunk_size });
+        }
+        if offset < self.high_water_mark.saturating_sub(chunk_size) {
+            return Err(Rejection::OffsetRegressed {
+                high_water_mark: self.high_water_mark,
+            });
+        }
+        Ok(())
+    }
+
+    /// Gets the hash computed by the server, if it has been verified yet.
+    pub fn server_hash(&self) -> Option<&str> {
+        self.server_hash.as_deref()
+    }
+
+    /// Gets the digest the checksum stage verified the data against, if it has run.
+    pub fn digest(&self) -> Option<&Digest> {
+        self.digest.as_ref()
+    }
+
+    /// Gets the progress of the current stage, if the processor has reported any.
+    pub fn progress(&self) -> Option<Progress> {
+        self.progress
+    }
+
+    /// Gets how many times processing the current stage has failed.
+    pub fn attempts(&self) -> u32 {
+        self.attempts
+    }
+
+    /// Gets why the last attempt at processing the current stage failed.
+    pub fn last_error(&self) -> Option<&str> {
+        self.last_error.as_deref()
+    }
+
+    /// Gets why each attempt at processing the current stage failed, oldest first.
+    pub fn errors(&self) -> &[String] {
+        &self.errors
+    }
+
+    /// Gets where the item was packed, if it has been.
+    pub fn packed(&self) -> Option<&Packed> {
+        self.packed.as_ref()
+    }
+
+    /// Gets the URL of the server instance that owns the upload, if there is one.
+    pub fn node(&self) -> Option<&str> {
+        self.node.as_deref()
+    }
+
+    /// Gets when the upload was started, in seconds since the epoch. 0 on rows from before this
+    /// was recorded.
+    pub fn created(&self) -> u64 {
+        self.created
+    }
+
+    /// Gets the last time the server received data for the upload or a processor touched it, in
+    /// seconds since the epoch.
+    pub fn last_activity(&self) -> u64 {
+        self.last_activity
+    }
+
+    /// Gets the last time data was received for the upload, or when it was started if none has
+    /// been, in seconds since the epoch. Unlike `last_activity`, chunks that fail don't count.
+    pub fn last_received(&self) -> u64 {
+        self.transfer.last_chunk.unwrap_or(self.created)
+    }
+
+    /// Gets the stall warning, if the upload has gone a while without receiving any data.
+    pub fn stalled(&self) -> Option<Stall> {
+        self.stalled
+    }
+
+    /// Whether a processor has the upload checked out. This stays set if the processor dies,
+    /// until someone else reclaims it.
+    pub fn processing(&self) -> bool {
+        self.processing
+    }
+
+    /// Gets the chunk size, if the upload uses strict offsets.
+    pub fn chunk_size(&self) -> Option<u64> {
+        self.chunk_size
+    }
+
+    /// Gets the end of the furthest chunk that has been written.
+    pub fn high_water_mark(&self) -> u64 {
+        self.high_water_mark
+    }
+
+    /// Gets when a soft-deleted upload's data will be purged, in seconds since the epoch.
+    pub fn delete_after(&self) -> Option<u64> {
+        self.delete_after
+    }
+
+    /// Gets the status a soft-deleted upload was in before it was deleted.
+    pub fn deleted_from(&self) -> Option<&Status> {
+        self.deleted_from.as_ref()
+    }
+
+    /// Gets when the scrubber last re-hashed the file, in seconds since the epoch.
+    pub fn scrubbed_at(&self) -> Option<u64> {
+        self.scrubbed_at
+    }
+
+    /// Gets the status a dead-lettered upload goes back to when it's requeued.
+    pub fn dead_from(&self) -> Option<&Status> {
+        self.dead_from.as_ref()
+    }
+
+    /// Gets the time before which the upload won't be checked out again, if it's waiting for a
+    /// retry.
+    pub fn retry_after(&self) -> Option<u64> {
+        self.retry_after
+    }
+
+    /// Whether an operator has pinned the upload, which keeps it from being reaped, expired or
+    /// purged.
+    pub fn pinned(&self) -> bool {
+        self.pinned
+    }
+
+    /// Gets the parts of the file that have been written, sorted and merged.
+    pub fn received_ranges(&self) -> Vec<(u64, u64)> {
+        merge_ranges(self.received.clone())
+    }
+
+    /// Gets the parts of the file that haven't been written yet.
+    pub fn missing_ranges(&self) -> Vec<(u64, u64)> {
+        missing_ranges(&self.received_ranges(), self.file.size)
+    }
+
+    /// Gets the statistics about how the data came in.
+    pub fn transfer(&self) -> &TransferStats {
+        &self.transfer
+    }
+
+    /// Gets the hash the file's contents should have: the derived file's if there is one, then
+    /// the server's if it has one, otherwise the one the client sent.
+    pub fn expected_hash(&self) -> &str {
+        match &self.derived {
+            Some(derived) => &derived.hash,
+            None => self.server_hash.as_deref().unwrap_or(&self.file.hash),
+        }
+    }
+
+    /// Gets the file a deriving stage made from the upload, if one did.
+    pub fn derived(&self) -> Option<&File> {
+        self.derived.as_ref()
+    }
+
+    /// Gets the file later stages should work on: the derived file if there is one, otherwise
+    /// the upload as the client described it.
+    pub fn current_file(&self) -> &File {
+        self.derived.as_ref().unwrap_or(&self.file)
+    }
+
+    /// Gets the name of the current file's data in the upload's directory.
+    pub fn data_name(&self) -> String {
+        match self.derived {
+            Some(_) => derived_name(&self.id),
+            None => self.id.clone(),
+        }
+    }
+
+    /// Checks whether `nonce` is the one the client started the upload with. Uploads started
+    /// without one never match.
+    pub fn nonce_matches(&self, nonce: Option<&str>) -> bool {
+        match (&self.nonce_hash, nonce) {
+            (Some(hash), Some(nonce)) => *hash == nonce_hash(nonce),
+            _ => false,
+        }
+    }
+
+    /// Checks whether the upload's files are encrypted.
+    pub fn is_encrypted(&self) -> bool {
+        self.encryption.is_some()
+    }
+
+    /// Gets the key the upload's files are encrypted with, if they are. That needs the master key.
+    pub fn data_key(&self, master: Option<&MasterKey>) -> io::Result<Option<DataKey>> {
+        match (&self.encryption, master) {
+            (None, _) => Ok(None),
+            (Some(wrapped), Some(master)) => master.unwrap(&self.id, wrapped).map(Some),
+            (Some(_), None) => Err(io::Error::other(format!(
+                "{} is encrypted, and there's no master key to decrypt it with",
+                self.id
+            ))),
+        }
+    }
+
+    /// Gets the current file's data (see `data_name`), for reading with `DataFile::open`.
+    pub fn data_file(&self, master: Option<&MasterKey>) -> io::Result<DataFile> {
+        let path = Path::new(&self.dir).join(self.data_name());
+        Ok(DataFile::new(path, self.data_key(master)?, self.derived.is_some()))
+    }
+
+    /// Gets the upload's own data, even if a file was derived from it.
+    pub fn original_file(&self, master: Option<&MasterKey>) -> io::Result<DataFile> {
+        let path = Path::new(&self.dir).join(&self.id);
+        Ok(DataFile::new(path, self.data_key(master)?, false))
+    }
+}
+
+#[cfg(test)]
+impl UploadRow {
+    /// An empty row in the Uploading status, for tests.
+    pub(crate) fn blank() -> Self {
+        UploadRow {
+            id: String::new(),
+            dir: String::new(),
+            status: Status::Uploading,
+            file: File {
+                hash: String::new(),
+                name: String::new(),
+                size: 100,
+            },
+            last_activity: 0,
+            created: 0,
+            pipeline: String::new(),
+            project: String::new(),
+            processing: false,
+            metadata: Metadata {
+                uploader: String::new(),
+                items: Vec::new(),
+                encryption: None,
+            },
+            server_hash: None,
+            digest: None,
+            chunk_size: None,
+            high_water_mark: 0,
+            files_removed: false,
+            delete_after: None,
+            deleted_from: None,
+            scrubbed_at: None,
+            progress: None,
+            attempts: 0,
+            last_error: None,
+            errors: Vec::new(),
+            dead_from: None,
+            retry_after: None,
+            packed: None,
+            node: None,
+            transfer: TransferStats::default(),
+            stalled: None,
+            schema_version: ROW_SCHEMA_VERSION,
+            pinned: false,
+            received: Vec::new(),
+            members: Vec::new(),
+            derived: None,
+            dedup: None,
+            archived: false,
+            shard: None,
+            encryption: None,
+            counted: false,
+            nonce_hash: None,
+        }
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Status, UploadError};
+    use serde_json::{json, Value};
+
+    use super::{Chunk, Dedup, File, QueueDepth, Metadata, Shard, Stall, Status, TransferStats, UploadError, UploadRow, ROW_SCHEMA_VERSION};
+    use crate::payloads::Rejection;
 
     #[test]
     fn status_serialization() {
@@ -93,6 +1224,8 @@ mod tests {
             (Status::Verifying, "VERIFYING"),
             (Status::Uploading, "UPLOADING"),
             (Status::Error(UploadError::Verify), "FAILED_VERIFY"),
+            (Status::Deleted, "DELETED"),
+            (Status::DeadLetter, "DEAD_LETTER"),
         ];
         for (src, expected) in tests {
             assert_eq!(
@@ -106,4 +1239,323 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn legacy_statuses() {
+        for name in Status::NAMES {
+            let status: Status = serde_json::from_value(json!(name)).unwrap();
+            assert_eq!(status.to_string(), *name);
+        }
+        let tests = [
+            (json!("verifying"), Some(Status::Verifying)),
+            (json!("Dead-Letter"), Some(Status::DeadLetter)),
+            (json!("failed"), Some(Status::Error(UploadError::Other))),
+            (json!({"ERROR": "FAILED_CHECKSUM"}), Some(Status::Error(UploadError::Checksum))),
+            (json!("LOST"), None),
+            (json!(3), None),
+        ];
+        for (value, expected) in tests {
+            assert_eq!(Status::from_legacy(&value), expected, "{value}");
+        }
+    }
+
+    #[test]
+    fn legacy_rows() {
+        let row = json!({"status": "uploading", "writing": true});
+        assert_eq!(
+            Value::Object(UploadRow::legacy_changes(&row)),
+            json!({"status": "UPLOADING", "processing": true})
+        );
+        // Rows that already have `processing` keep it, and unknown statuses are left alone.
+        let row = json!({"status": "LOST", "writing": true, "processing": false});
+        assert!(UploadRow::legacy_changes(&row).is_empty());
+        let row = serde_json::to_value(UploadRow::blank()).unwrap();
+        assert!(UploadRow::legacy_changes(&row).is_empty());
+    }
+
+    #[test]
+    fn status_helpers() {
+        let tests = [
+            (Status::Uploading, false, false),
+            (Status::Verifying, false, false),
+            (Status::Packing, false, false),
+            (Status::Finished, true, false),
+            (Status::Abandoned, true, false),
+            (Status::Deleted, true, false),
+            (Status::DeadLetter, true, true),
+            (Status::Error(UploadError::Checksum), true, true),
+        ];
+        for (status, terminal, failure) in tests {
+            assert_eq!(status.is_terminal(), terminal, "{status}");
+            assert_eq!(status.is_failure(), failure, "{status}");
+        }
+    }
+
+    #[test]
+    fn builder() {
+        let file = File {
+            hash: "abc".to_string(),
+            name: "a.warc.gz".to_string(),
+            size: 100,
+        };
+        let metadata = Metadata {
+            uploader: "someone".to_string(),
+            items: Vec::new(),
+            encryption: None,
+        };
+        let row = UploadRow::builder("id", file.clone(), "project", "default", metadata.clone())
+            .dir("project")
+            .chunk_size(Some(10))
+            .created(1000)
+            .build()
+            .unwrap();
+        assert_eq!(row.status, Status::Uploading);
+        assert_eq!(row.dir, "project");
+        assert_eq!(row.created(), 1000);
+        assert_eq!(row.last_activity(), 1000);
+        assert!(row.strict_offsets());
+        assert!(!row.processing());
+
+        let builder = UploadRow::builder("id", file.clone(), "project", "default", metadata.clone());
+        builder.clone().chunk_size(Some(0)).build().unwrap_err();
+        UploadRow::builder("", file.clone(), "project", "default", metadata.clone())
+            .build()
+            .unwrap_err();
+        UploadRow::builder("id", file, "project", "", metadata)
+            .build()
+            .unwrap_err();
+        assert!(builder.build().unwrap().created() > 0);
+    }
+
+    #[test]
+    fn bundle() {
+        let file = |name: &str, size| File {
+            hash: "abc".to_string(),
+            name: name.to_string(),
+            size,
+        };
+        let metadata = Metadata {
+            uploader: "someone".to_string(),
+            items: Vec::new(),
+            encryption: None,
+        };
+        let builder =
+            UploadRow::builder("id", file("a.bundle", 100), "project", "default", metadata);
+        let row = builder
+            .clone()
+            .members(vec![file("a.warc.gz", 90), file("a.json", 10)])
+            .build()
+            .unwrap();
+        let offsets: Vec<_> = row
+            .members()
+            .iter()
+            .map(|m| (m.file.name.as_str(), m.offset))
+            .collect();
+        assert_eq!(offsets, [("a.warc.gz", 0), ("a.json", 90)]);
+        assert!(builder.clone().build().unwrap().members().is_empty());
+
+        builder
+            .clone()
+            .members(vec![file("a.warc.gz", 90), file("a.json", 20)])
+            .build()
+            .unwrap_err();
+        builder
+            .clone()
+            .members(vec![file("a.json", 50), file("a.json", 50)])
+            .build()
+            .unwrap_err();
+        builder
+            .clone()
+            .members(vec![file("", 100)])
+            .build()
+            .unwrap_err();
+        // Would wrap around to 100.
+        builder
+            .members(vec![file("a.warc.gz", u64::MAX), file("a.json", 101)])
+            .build()
+            .unwrap_err();
+    }
+
+    #[test]
+    fn shard() {
+        let file = |size| File {
+            hash: "abc".to_string(),
+            name: "a.warc.gz".to_string(),
+            size,
+        };
+        let metadata = Metadata {
+            uploader: "someone".to_string(),
+            items: Vec::new(),
+            encryption: None,
+        };
+        let builder = UploadRow::builder("id", file(40), "project", "default", metadata);
+        let shard = Shard {
+            group: "g".to_string(),
+            index: 2,
+            count: 3,
+            offset: 60,
+            whole: file(100),
+        };
+        let row = builder.clone().shard(Some(shard.clone())).build().unwrap();
+        assert_eq!(row.shard(), Some(&shard));
+        assert_eq!(builder.clone().build().unwrap().shard(), None);
+
+        let bad = [
+            Shard { index: 3, ..shard.clone() },
+            Shard { offset: 61, ..shard.clone() },
+            Shard { group: String::new(), ..shard.clone() },
+        ];
+        for bad in bad {
+            builder.clone().shard(Some(bad)).build().unwrap_err();
+        }
+        builder
+            .shard(Some(shard))
+            .members(vec![file(40)])
+            .build()
+            .unwrap_err();
+    }
+
+    #[test]
+    fn row_compatibility() {
+        let mut row = serde_json::to_value(UploadRow::blank()).unwrap();
+        let fields = row.as_object_mut().unwrap();
+        // Written by an older build...
+        fields.remove("schema_version");
+        fields.remove("created");
+        fields.remove("transfer");
+        fields.remove("digest");
+        fields.insert("last_activity".to_string(), 1234.into());
+        // ...or a newer one.
+        fields.insert("from_the_future".to_string(), true.into());
+        let mut row: UploadRow = serde_json::from_value(row).unwrap();
+        assert_eq!(row.schema_version(), 0);
+        row.upgrade();
+        assert_eq!(row.schema_version(), ROW_SCHEMA_VERSION);
+        assert_eq!(row.created(), 1234);
+        assert_eq!(row.digest(), None);
+
+        // Rows from newer builds keep their version.
+        row.schema_version = ROW_SCHEMA_VERSION + 1;
+        row.upgrade();
+        assert_eq!(row.schema_version(), ROW_SCHEMA_VERSION + 1);
+    }
+
+    #[test]
+    fn transfer_stats() {
+        // Rows from before transfer statistics were recorded.
+        let stats: TransferStats = serde_json::from_str("{}").unwrap();
+        assert_eq!(stats, TransferStats::default());
+        assert_eq!(stats.bytes_per_second(), None);
+        let stats = TransferStats {
+            bytes: 3_000_000,
+            busy_millis: 1500,
+            ..Default::default()
+        };
+        assert_eq!(stats.bytes_per_second(), Some(2_000_000.0));
+    }
+
+    #[test]
+    fn stalls() {
+        let mut row = UploadRow::blank();
+        row.created = 1000;
+        assert_eq!(row.last_received(), 1000);
+        row.transfer.last_chunk = Some(1500);
+        assert_eq!(row.last_received(), 1500);
+        assert_eq!(Stall::check(1500, 600, 1799), None);
+        assert_eq!(
+            Stall::check(1500, 600, 1800),
+            Some(Stall { since: 1500, abandon_at: 2100 })
+        );
+    }
+
+    #[test]
+    fn strict_offsets() {
+        let mut row = UploadRow::blank();
+        row.check_offset(3).unwrap();
+        row.chunk_size = Some(10);
+        row.check_offset(0).unwrap();
+        assert_eq!(
+            row.check_offset(3),
+            Err(Rejection::MisalignedOffset { chunk_size: 10 })
+        );
+        row.high_water_mark = 30;
+        row.check_offset(20).unwrap();
+        row.check_offset(30).unwrap();
+        assert_eq!(
+            row.check_offset(10),
+            Err(Rejection::OffsetRegressed {
+                high_water_mark: 30
+            })
+        );
+    }
+    #[test]
+    fn received_ranges() {
+        let mut row = UploadRow::blank();
+        row.file.size = 100;
+        assert_eq!(row.missing_ranges(), vec![(0, 100)]);
+        // Out of order, retried, and touching.
+        row.received = vec![(40, 60), (0, 10), (40, 60), (10, 20), (50, 70), (90, 90)];
+        assert_eq!(row.received_ranges(), vec![(0, 20), (40, 70)]);
+        assert_eq!(row.missing_ranges(), vec![(20, 40), (70, 100)]);
+        row.received.push((70, 100));
+        assert_eq!(row.missing_ranges(), vec![(20, 40)]);
+        row.received.push((15, 45));
+        assert_eq!(row.received_ranges(), vec![(0, 100)]);
+        assert!(row.missing_ranges().is_empty());
+    }
+    #[test]
+    fn dedup() {
+        let chunk = |hash: &str, size| Chunk {
+            hash: hash.to_string(),
+            size,
+        };
+        let chunks = [chunk("a", 30), chunk("b", 10), chunk("a", 30), chunk("c", 30)];
+        let dedup = Dedup::new(&chunks, |h| h == "a");
+        assert_eq!((dedup.chunks, dedup.duplicate_chunks), (4, 2));
+        assert_eq!((dedup.bytes, dedup.duplicate_bytes), (100, 60));
+        assert_eq!(dedup.ratio, 0.6);
+        assert_eq!(Dedup::new(&[], |_| true).ratio, 0.0);
+    }
+    #[test]
+    fn archivable() {
+        let mut row = UploadRow::blank();
+        row.status = Status::Finished;
+        assert!(row.is_archivable());
+        row.pinned = true;
+        assert!(!row.is_archivable());
+        row.pinned = false;
+        row.status = Status::Deleted;
+        assert!(!row.is_archivable());
+        row.files_removed = true;
+        assert!(row.is_archivable());
+        row.status = Status::Error(UploadError::Verify);
+        assert!(!row.is_archivable());
+    }
+    #[test]
+    fn queue_depths() {
+        let item = |project: &str, status, last_activity| {
+            (project.to_string(), "p".to_string(), status, last_activity)
+        };
+        let depths = QueueDepth::tally(
+            [
+                item("b", Status::Verifying, 90),
+                item("a", Status::Packing, 50),
+                item("b", Status::Verifying, 40),
+                item("a", Status::Deriving, 95),
+            ],
+            100,
+        );
+        let summary: Vec<_> = depths
+            .iter()
+            .map(|d| (d.project.as_str(), d.status.to_string(), d.items, d.oldest_age))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                ("a", "DERIVING".to_string(), 1, 5),
+                ("a", "PACKING".to_string(), 1, 50),
+                ("b", "VERIFYING".to_string(), 2, 60),
+            ]
+        );
+    }
 }
@@ -1,16 +1,50 @@
 use async_stream::stream;
+use deadpool::managed::{Hook, HookError, Metrics, Object, Pool, Timeouts};
 use fix_hidden_lifetime_bug::fix_hidden_lifetime_bug;
 use futures::{Stream, TryStreamExt};
 use serde::{Deserialize, Serialize};
-use std::{error::Error, fmt, time::SystemTime};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
 use unreql::{
-    cmd::options::{ChangesOptions, UpdateOptions},
+    cmd::{
+        connect::Options,
+        options::{BetweenOptions, ChangesOptions, ReadMode, TableOptions, UpdateOptions},
+    },
     r, rjson, func,
     types::{Change, WriteStatus},
+    Command, Session,
 };
-use unreql_deadpool::{IntoPoolWrapper, PoolWrapper};
+use unreql_deadpool::{IntoPoolWrapper, PoolWrapper, SessionManager};
 
 pub use crate::data::*;
+use crate::{payloads::{FirehoseEvent, UploadEvent}, pipeline::Pipeline};
+
+pub mod archive;
+pub mod audit;
+pub mod bans;
+pub mod chunks;
+pub mod leases;
+pub mod migrations;
+pub mod uploaders;
+
+/// How many times in a row a changefeed is reopened before giving up. The delay doubles every
+/// time, starting at a second.
+const CHANGEFEED_MAX_TRIES: u32 = 7;
+/// Sessions that have been idle for this long are checked before they're used.
+const HEALTH_CHECK_IDLE: Duration = Duration::from_secs(10);
+/// How often `DatabaseHandle::keep_healthy` checks the idle sessions.
+const HEALTH_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// How many uploads `UploadRow::requeue_matching` requeues in one query. RethinkDB won't return
+/// more than 100,000 changes at once.
+const REQUEUE_BATCH: usize = 10_000;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
 pub enum DbError {
@@ -33,39 +67,45 @@ impl fmt::Display for DbError {
 
 impl Error for DbError {}
 
-impl UploadRow {
-    fn now() -> u64 {
-        SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs()
+/// Picks the fields of an ItemUpload out of a row, so nothing else leaves the database.
+fn item_fields() -> Command {
+    func!(|row| {
+        rjson!({
+            "id": row.clone().g("id"),
+            "project": row.clone().g("project"),
+            "pipeline": row.clone().g("pipeline"),
+            "status": row.clone().g("status"),
+            "file": row.clone().g("file"),
+            "items": row.clone().g("metadata").g("items"),
+            "packed": row.clone().g("packed").default(rjson!(null)),
+            "archived": row.g("archived").default(false),
+        })
+    })
+}
+
+/// Turns an unreql error into a DbError, logging it.
+fn log_error(e: unreql::Error) -> DbError {
+    println!("warning: Unknown database error occured, see: {e:?}");
+    DbError::Other
+}
+
+/// Turns the result of a single-row write into a DbError if it didn't go through.
+fn check_write<T>(s: unreql::Result<WriteStatus<T>>) -> Result<WriteStatus<T>, DbError> {
+    match s {
+        Ok(ws) if ws.errors > 0 => Err(DbError::WriteFailed),
+        Ok(ws) if ws.skipped > 0 => Err(DbError::NotFound),
+        Ok(ws) => Ok(ws),
+        Err(_) => Err(DbError::WriteFailed),
     }
+}
 
-    /// Creates a new database entry.
-    pub async fn new(
-        conn: &DatabaseHandle,
-        dir: String,
-        id: String,
-        file: File,
-        pipeline: String,
-        project: String,
-        metadata: Metadata,
-    ) -> Result<Self, DbError> {
-        let s = Self {
-            id,
-            dir,
-            file,
-            pipeline,
-            project,
-            status: Status::Uploading,
-            last_activity: Self::now(),
-            processing: false,
-            metadata,
-        };
+impl UploadRow {
+    /// Creates the upload's database entry. Build the row with `UploadRow::builder`.
+    pub async fn insert(&self, conn: &DatabaseHandle) -> Result<(), DbError> {
         let result: Result<WriteStatus, _> = r
             .db("atuploads")
             .table("uploads")
-            .insert(s.clone())
+            .insert(self.clone())
             .exec(&conn.pool)
             .await;
         match result {
@@ -73,13 +113,70 @@ impl UploadRow {
                 if a.inserted != 1 {
                     Err(DbError::WriteFailed)
                 } else {
-                    Ok(s)
+                    Ok(())
                 }
             }
             Err(_) => Err(DbError::Other),
         }
     }
 
+    /// Removes the upload's database entry outright. This is only for backing out an upload that
+    /// was just created; anything a client might have seen is soft-deleted instead.
+    pub async fn remove(&self, conn: &DatabaseHandle) -> Result<(), DbError> {
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table("uploads")
+            .get(self.id.clone())
+            .delete(())
+            .exec(&conn.pool)
+            .await;
+        check_write(s).map(|_| ())
+    }
+
+    /// Upgrades the rows written by older builds in the database, like `upgrade` does in memory.
+    /// Returns how many there were. During a rolling upgrade, older builds keep writing rows in
+    /// the old format, so this has to be run again once they're all gone.
+    pub async fn upgrade_old_rows(conn: &DatabaseHandle) -> Result<u64, DbError> {
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table("uploads")
+            .filter(func!(|row| {
+                row.g("schema_version").default(0).lt(ROW_SCHEMA_VERSION)
+            }))
+            .update(func!(|row| {
+                rjson!({
+                    "created": row.clone().g("created").default(row.g("last_activity")),
+                    "schema_version": ROW_SCHEMA_VERSION,
+                })
+            }))
+            .exec(&conn.pool)
+            .await;
+        match s {
+            Ok(ws) if ws.errors > 0 => Err(DbError::WriteFailed),
+            Ok(ws) => Ok(ws.replaced as u64),
+            Err(e) => {
+                println!("warning: Unknown database error occured, see: {e:?}");
+                Err(DbError::Other)
+            }
+        }
+    }
+
+    /// Counts the rows `upgrade_old_rows` would upgrade.
+    pub async fn count_old_rows(conn: &DatabaseHandle) -> Result<u64, DbError> {
+        r.db("atuploads")
+            .table("uploads")
+            .filter(func!(|row| {
+                row.g("schema_version").default(0).lt(ROW_SCHEMA_VERSION)
+            }))
+            .count(())
+            .exec(&conn.pool)
+            .await
+            .map_err(|e| {
+                println!("warning: Unknown database error occured, see: {e:?}");
+                DbError::Other
+            })
+    }
+
     /// Gets the directory containing the upload.
     pub fn dir(&self) -> &String {
         &self.dir
@@ -96,7 +193,11 @@ impl UploadRow {
         if let Ok(mut v) = result {
             match v.len() {
                 0 => Err(DbError::NotFound),
-                1 => Ok(v.remove(0)),
+                1 => {
+                    let mut row = v.remove(0);
+                    row.upgrade();
+                    Ok(row)
+                }
                 _ => unreachable!(),
             }
         } else {
@@ -115,11 +216,14 @@ impl UploadRow {
     /// true that have been claimed for more than 60 seconds. It is up to you to make sure nobody
     /// else is modifying the file. If processing is set to false, check_out will only return items
     /// with `processing` set to false.
+    ///
+    /// Items waiting for a retry (see schedule_retry) are skipped until it's their time.
     pub async fn check_out(conn: &DatabaseHandle, project: String, pipeline: String, status: Status, processing: bool) -> Result<Option<Self>, DbError> {
         let activity_grace = match processing {
             true => Self::now() - 60,
             false => u64::MAX,
         };
+        let now = Self::now();
         let s: unreql::Result<WriteStatus<Self>> = r
             .db("atuploads")
             .table("uploads")
@@ -128,6 +232,10 @@ impl UploadRow {
             .filter(func!(|row| {
                 row.g("last_activity").lt(activity_grace)
             }))
+            // null sorts before numbers, so items that aren't waiting for a retry are included.
+            .filter(func!(|row| {
+                row.g("retry_after").lt(now)
+            }))
             .sample(1)
             .update(r.with_opt(
                 r.branch(
@@ -153,7 +261,10 @@ impl UploadRow {
                 } else if ws.replaced > 0 {
                     let mut changes = ws.changes.unwrap();
                     assert_eq!(changes.len(), 1);
-                    let v = changes.remove(0).new_val;
+                    let mut v: Option<Self> = changes.remove(0).new_val;
+                    if let Some(row) = &mut v {
+                        row.upgrade();
+                    }
                     Ok(v)
                 } else {
                     Ok(None)
@@ -163,32 +274,356 @@ impl UploadRow {
         }
     }
 
-    /// Gets the unique ID of the item.
-    pub fn id(&self) -> &String {
-        &self.id
+    /// Moves the upload out of Uploading into the first stage of its pipeline.
+    ///
+    /// This is idempotent: if the upload has already been finished, the row is refreshed and
+    /// `Ok(false)` is returned. The update is conditional on the status still being Uploading, so
+    /// if two calls race, only one of them gets `Ok(true)`.
+    pub async fn finish(&mut self, conn: &DatabaseHandle, pipeline: &Pipeline) -> Result<bool, DbError> {
+        if pipeline.stages.contains(&self.status) {
+            return Ok(false);
+        }
+        if self.status != Status::Uploading {
+            return Err(DbError::WrongStatus);
+        }
+        let next = pipeline.next(&self.status).ok_or(DbError::WrongStatus)?;
+        let uploading = Status::Uploading;
+        let wall_time = Self::now().saturating_sub(self.created);
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table("uploads")
+            .get(self.id.clone())
+            .update(r.branch(
+                r.row().g("status").eq(rjson!(uploading)),
+                rjson!({
+                    "status": next.clone(),
+                    "transfer": {
+                        "wall_time": wall_time,
+                    },
+                }),
+                rjson!({}),
+            ))
+            .exec(&conn.pool)
+            .await;
+        match s {
+            unreql::Result::Ok(ws) => {
+                if ws.errors > 0 {
+                    Err(DbError::WriteFailed)
+                } else if ws.skipped > 0 {
+                    Err(DbError::NotFound)
+                } else if ws.replaced > 0 {
+                    self.status = next;
+                    self.transfer.wall_time = Some(wall_time);
+                    if self.status == Status::Finished {
+                        self.count_for_uploader(conn).await;
+                    }
+                    Ok(true)
+                } else {
+                    // Somebody else changed the status first. Find out what it is now.
+                    *self = Self::from_database(conn, self.id.clone()).await?;
+                    if pipeline.stages.contains(&self.status) {
+                        Ok(false)
+                    } else {
+                        Err(DbError::WrongStatus)
+                    }
+                }
+            }
+            unreql::Result::Err(_) => Err(DbError::WriteFailed),
+        }
+    }
+
+    /// Soft-deletes an upload that is still in progress. See soft_delete.
+    pub async fn abandon(&mut self, conn: &DatabaseHandle, grace: u64) -> Result<(), DbError> {
+        if self.status != Status::Uploading {
+            return Err(DbError::WrongStatus);
+        }
+        self.soft_delete(conn, grace).await
+    }
+
+    /// Moves the item to the Deleted status. Its data is kept for `grace` seconds, after which
+    /// the purge job removes it. Until then, it can be brought back with restore.
+    pub async fn soft_delete(&mut self, conn: &DatabaseHandle, grace: u64) -> Result<(), DbError> {
+        if self.status == Status::Deleted {
+            return Err(DbError::WrongStatus);
+        }
+        let delete_after = Self::now() + grace;
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table("uploads")
+            .get(self.id.clone())
+            .update(rjson!({
+                "status": Status::Deleted,
+                "deleted_from": self.status.clone(),
+                "delete_after": delete_after,
+                "processing": false,
+            }))
+            .exec(&conn.pool)
+            .await;
+        check_write(s)?;
+        self.deleted_from = Some(std::mem::replace(&mut self.status, Status::Deleted));
+        self.delete_after = Some(delete_after);
+        self.processing = false;
+        Ok(())
     }
 
-    /// Gets the file size.
-    pub fn size(&self) -> u64 {
-        self.file.size
+    /// Undoes a soft delete, as long as the data hasn't been purged yet.
+    pub async fn restore(&mut self, conn: &DatabaseHandle) -> Result<(), DbError> {
+        let Some(previous) = self.deleted_from.clone() else {
+            return Err(DbError::WrongStatus);
+        };
+        if self.status != Status::Deleted || self.files_removed {
+            return Err(DbError::WrongStatus);
+        }
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table("uploads")
+            .get(self.id.clone())
+            .update(rjson!({
+                "status": previous.clone(),
+                "deleted_from": None::<Status>,
+                "delete_after": None::<u64>,
+                "last_activity": Self::now(),
+            }))
+            .exec(&conn.pool)
+            .await;
+        check_write(s)?;
+        self.status = previous;
+        self.deleted_from = None;
+        self.delete_after = None;
+        Ok(())
     }
 
-    /// Gets the current status.
-    pub fn status(&self) -> &Status {
-        &self.status
+    /// Lists soft-deleted items whose grace period is over. Pinned items are left out.
+    pub async fn list_purgeable(conn: &DatabaseHandle) -> Result<Vec<Self>, DbError> {
+        let now = Self::now();
+        let result: unreql::Result<Vec<Self>> = r
+            .db("atuploads")
+            .table("uploads")
+            .get_all(r.with_opt(rjson!(Status::Deleted), r.index("status")))
+            // null sorts before numbers, so make sure there's actually a deadline.
+            .filter(func!(|row| {
+                row.g("delete_after").gt(0)
+            }))
+            .filter(func!(|row| {
+                row.g("delete_after").lt(now)
+            }))
+            .filter(func!(|row| {
+                row.g("pinned").default(false).eq(false)
+            }))
+            .exec_to_vec(&conn.pool)
+            .await;
+        result.map_err(|e| {
+            println!("warning: Unknown database error occured, see: {e:?}");
+            DbError::Other
+        })
     }
 
-    /// Convenience wrapper around change_status to set the status to Verifying.
-    pub async fn finish(&mut self, conn: &DatabaseHandle) -> Result<(), DbError> {
-        if self.status != Status::Uploading {
+    /// Raises the high-water mark to `end` if it's lower.
+    pub async fn acknowledge(&mut self, conn: &DatabaseHandle, end: u64) -> Result<(), DbError> {
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table("uploads")
+            .get(self.id.clone())
+            .update(r.branch(
+                r.row().g("high_water_mark").lt(end),
+                rjson!({
+                    "high_water_mark": end
+                }),
+                rjson!({}),
+            ))
+            .exec(&conn.pool)
+            .await;
+        match s {
+            unreql::Result::Ok(ws) => {
+                if ws.errors > 0 {
+                    Err(DbError::WriteFailed)
+                } else if ws.skipped > 0 {
+                    Err(DbError::NotFound)
+                } else {
+                    self.high_water_mark = self.high_water_mark.max(end);
+                    Ok(())
+                }
+            }
+            unreql::Result::Err(_) => Err(DbError::WriteFailed),
+        }
+    }
+
+    /// Records that `start..end` of the file has been written, so a client that comes back to the
+    /// upload knows what it still has to send.
+    pub async fn record_received(&mut self, conn: &DatabaseHandle, start: u64, end: u64) -> Result<(), DbError> {
+        // Appended in the database, so chunks written at the same time are all kept.
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table("uploads")
+            .get(self.id.clone())
+            .update(func!(|row| {
+                rjson!({
+                    "received": row.g("received").default(rjson!([])).append(rjson!([start, end]))
+                })
+            }))
+            .exec(&conn.pool)
+            .await;
+        check_write(s)?;
+        self.received.push((start, end));
+        Ok(())
+    }
+
+    /// Lists a project's items in `status` whose last activity was before `cutoff` (in seconds
+    /// since the epoch). Pinned items are left out, so the reaper and the retention task don't
+    /// touch them.
+    pub async fn list_stale(
+        conn: &DatabaseHandle,
+        project: String,
+        status: Status,
+        cutoff: u64,
+    ) -> Result<Vec<Self>, DbError> {
+        let result: unreql::Result<Vec<Self>> = r
+            .db("atuploads")
+            .table("uploads")
+            .get_all(r.with_opt(rjson!(status), r.index("status")))
+            .filter(rjson!({ "project": project }))
+            .filter(func!(|row| {
+                row.g("last_activity").lt(cutoff)
+            }))
+            .filter(func!(|row| {
+                row.g("pinned").default(false).eq(false)
+            }))
+            .exec_to_vec(&conn.pool)
+            .await;
+        result.map_err(|e| {
+            println!("warning: Unknown database error occured, see: {e:?}");
+            DbError::Other
+        })
+    }
+
+    /// Lists the uploads that are still uploading but haven't received any data since their
+    /// project's cutoff in `cutoffs` (in seconds since the epoch), however recently their client
+    /// sent a request. See `last_received`. Projects without a cutoff, and pinned uploads, are
+    /// left out. This is one query however many projects there are.
+    pub async fn list_stalled(conn: &DatabaseHandle, cutoffs: BTreeMap<String, u64>) -> Result<Vec<Self>, DbError> {
+        let result: unreql::Result<Vec<Self>> = r
+            .db("atuploads")
+            .table("uploads")
+            .get_all(r.with_opt(rjson!(Status::Uploading), r.index("status")))
+            .filter(func!(|row| {
+                row.clone()
+                    .g("transfer")
+                    .g("last_chunk")
+                    .default(row.clone().g("created"))
+                    .lt(r.expr(rjson!(cutoffs.clone())).g(row.g("project")).default(0))
+            }))
+            .filter(func!(|row| {
+                row.g("pinned").default(false).eq(false)
+            }))
+            .exec_to_vec(&conn.pool)
+            .await;
+        result.map_err(|e| {
+            println!("warning: Unknown database error occured, see: {e:?}");
+            DbError::Other
+        })
+    }
+
+    /// Gets the name of the project the item belongs to.
+    pub fn project(&self) -> &String {
+        &self.project
+    }
+
+    /// Whether the item's data has been cleaned up by the retention task.
+    pub fn files_removed(&self) -> bool {
+        self.files_removed
+    }
+
+    /// Records that the item's data has been cleaned up.
+    pub async fn mark_files_removed(&mut self, conn: &DatabaseHandle) -> Result<(), DbError> {
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table("uploads")
+            .get(self.id.clone())
+            .update(rjson!({
+                "files_removed": true,
+                "delete_after": None::<u64>,
+            }))
+            .exec(&conn.pool)
+            .await;
+        check_write(s)?;
+        self.files_removed = true;
+        self.delete_after = None;
+        Ok(())
+    }
+
+    /// Lists finished items whose data is still on disk and that haven't been scrubbed since
+    /// `cutoff` (in seconds since the epoch).
+    pub async fn list_unscrubbed(conn: &DatabaseHandle, cutoff: u64) -> Result<Vec<Self>, DbError> {
+        let result: unreql::Result<Vec<Self>> = r
+            .db("atuploads")
+            .table("uploads")
+            .get_all(r.with_opt(rjson!(Status::Finished), r.index("status")))
+            .filter(rjson!({
+                "files_removed": false,
+            }))
+            // null sorts before numbers, so items that were never scrubbed are included.
+            .filter(func!(|row| {
+                row.g("scrubbed_at").lt(cutoff)
+            }))
+            .exec_to_vec(&conn.pool)
+            .await;
+        result.map_err(|e| {
+            println!("warning: Unknown database error occured, see: {e:?}");
+            DbError::Other
+        })
+    }
+
+    /// Records that the scrubber has checked the file.
+    pub async fn mark_scrubbed(&mut self, conn: &DatabaseHandle) -> Result<(), DbError> {
+        let now = Self::now();
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table("uploads")
+            .get(self.id.clone())
+            .update(rjson!({
+                "scrubbed_at": now,
+            }))
+            .exec(&conn.pool)
+            .await;
+        check_write(s)?;
+        self.scrubbed_at = Some(now);
+        Ok(())
+    }
+
+    /// Marks a finished item as corrupted. This bypasses the pipeline, since Finished is
+    /// otherwise terminal.
+    pub async fn flag_corrupt(&mut self, conn: &DatabaseHandle) -> Result<(), DbError> {
+        let finished = Status::Finished;
+        let failed = Status::Error(UploadError::Checksum);
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table("uploads")
+            .get(self.id.clone())
+            .update(r.branch(
+                r.row().g("status").eq(rjson!(finished)),
+                rjson!({
+                    "status": failed.clone(),
+                    "scrubbed_at": Self::now(),
+                }),
+                rjson!({}),
+            ))
+            .exec(&conn.pool)
+            .await;
+        if check_write(s)?.replaced == 0 {
             return Err(DbError::WrongStatus);
         }
+        self.status = failed;
+        Ok(())
+    }
+
+    /// Records the hash the server computed for the file, so clients can confirm it end-to-end.
+    pub async fn set_server_hash(&mut self, conn: &DatabaseHandle, hash: String) -> Result<(), DbError> {
         let s: unreql::Result<WriteStatus> = r
             .db("atuploads")
             .table("uploads")
             .get(self.id.clone())
             .update(rjson!({
-                "status": Status::Verifying
+                "server_hash": hash.clone()
             }))
             .exec(&conn.pool)
             .await;
@@ -199,7 +634,7 @@ impl UploadRow {
                 } else if ws.skipped > 0 {
                     Err(DbError::NotFound)
                 } else {
-                    self.status = Status::Verifying;
+                    self.server_hash = Some(hash);
                     Ok(())
                 }
             }
@@ -207,6 +642,74 @@ impl UploadRow {
         }
     }
 
+    /// Adds a chunk to the upload's transfer statistics. `retry` is whether the client said it
+    /// had sent the chunk before.
+    pub async fn record_chunk(&mut self, conn: &DatabaseHandle, bytes: u64, elapsed: Duration, retry: bool) -> Result<(), DbError> {
+        let now = Self::now();
+        let sample = ChunkSample {
+            time: now,
+            bytes,
+            millis: elapsed.as_millis() as u64,
+        };
+        let millis = sample.millis;
+        let retried = u64::from(retry);
+        // Done in the database, so chunks written at the same time all get counted.
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table("uploads")
+            .get(self.id.clone())
+            .update(func!(|row| {
+                let transfer = row.g("transfer");
+                rjson!({
+                    "transfer": {
+                        "chunks": transfer.clone().g("chunks").default(0).add(1),
+                        "retried_chunks": transfer.clone().g("retried_chunks").default(0).add(retried),
+                        "bytes": transfer.clone().g("bytes").default(0).add(bytes),
+                        "busy_millis": transfer.clone().g("busy_millis").default(0).add(millis),
+                        "first_chunk": transfer.clone().g("first_chunk").default(now),
+                        "last_chunk": now,
+                        "samples": transfer
+                            .g("samples")
+                            .default(rjson!([]))
+                            .append(sample.clone())
+                            .slice(-(MAX_CHUNK_SAMPLES as i64)),
+                    },
+                    "stalled": None::<Stall>,
+                })
+            }))
+            .exec(&conn.pool)
+            .await;
+        check_write(s)?;
+        let transfer = &mut self.transfer;
+        transfer.chunks += 1;
+        transfer.retried_chunks += retried;
+        transfer.bytes += bytes;
+        transfer.busy_millis += millis;
+        transfer.first_chunk.get_or_insert(now);
+        transfer.last_chunk = Some(now);
+        transfer.samples.push(sample);
+        let excess = transfer.samples.len().saturating_sub(MAX_CHUNK_SAMPLES);
+        transfer.samples.drain(..excess);
+        self.stalled = None;
+        Ok(())
+    }
+
+    /// Warns the upload's subscribers that it hasn't received any data for a while.
+    pub async fn mark_stalled(&mut self, conn: &DatabaseHandle, stall: Stall) -> Result<(), DbError> {
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table("uploads")
+            .get(self.id.clone())
+            .update(rjson!({
+                "stalled": stall,
+            }))
+            .exec(&conn.pool)
+            .await;
+        check_write(s)?;
+        self.stalled = Some(stall);
+        Ok(())
+    }
+
     /// Sets the last_activity to now.
     pub async fn enter(&mut self, conn: &DatabaseHandle) -> Result<(), DbError> {
         let now = Self::now();
@@ -234,23 +737,55 @@ impl UploadRow {
         }
     }
 
-    pub fn file(&self) -> &File {
-        &self.file
+    /// Like change_status, but first checks that the pipeline allows the transition.
+    pub async fn transition(
+        &mut self,
+        conn: &DatabaseHandle,
+        pipeline: &Pipeline,
+        new_status: Status,
+    ) -> Result<(), DbError> {
+        if !pipeline.allows(&self.status, &new_status) {
+            return Err(DbError::WrongStatus);
+        }
+        self.change_status(conn, new_status).await
+    }
+
+    /// Moves the item to the next stage of its pipeline and sets processing to false.
+    pub async fn advance(&mut self, conn: &DatabaseHandle, pipeline: &Pipeline) -> Result<(), DbError> {
+        let next = pipeline.next(&self.status).ok_or(DbError::WrongStatus)?;
+        self.change_status(conn, next).await
+    }
+
+    /// Gets the name of the pipeline the item is on.
+    pub fn pipeline(&self) -> &String {
+        &self.pipeline
     }
 
     /// Changes the status of the item to new_status and sets processing to false.
+    /// This doesn't check whether the transition makes sense; see transition for that.
     pub async fn change_status(
         &mut self,
         conn: &DatabaseHandle,
         new_status: Status,
     ) -> Result<(), DbError> {
+        // A finished upload has been counted for its uploader, even if it's from before `counted`
+        // was recorded, so it isn't counted again if it's moved back.
+        let leaving_finished = self.status == Status::Finished && new_status != Status::Finished;
         let s: unreql::Result<WriteStatus> = r
             .db("atuploads")
             .table("uploads")
             .get(self.id.clone())
-            .update(rjson!({
-                "status": new_status.clone(),
-                "processing": false,
+            .update(func!(|row| {
+                rjson!({
+                    "status": new_status.clone(),
+                    "processing": false,
+                    "progress": None::<Progress>,
+                    "attempts": 0,
+                    "last_error": None::<String>,
+                    "errors": Vec::<String>::new(),
+                    "retry_after": None::<u64>,
+                    "counted": row.g("counted").default(false).or(leaving_finished),
+                })
             }))
             .exec(&conn.pool)
             .await;
@@ -261,7 +796,18 @@ impl UploadRow {
                 } else if ws.skipped > 0 {
                     Err(DbError::NotFound)
                 } else {
+                    self.counted |= leaving_finished;
+                    if new_status == Status::Finished && self.status != Status::Finished {
+                        self.count_for_uploader(conn).await;
+                    }
                     self.status = new_status;
+                    self.processing = false;
+                    self.progress = None;
+                    self.attempts = 0;
+                    self.last_error = None;
+                    self.errors.clear();
+                    self.retry_after = None;
+                    self.record_manifest().await;
                     Ok(())
                 }
             }
@@ -269,30 +815,809 @@ impl UploadRow {
         }
     }
 
-    /// Streams status changes.
-    #[fix_hidden_lifetime_bug] // what the fuck
-    pub fn stream_status_changes(&mut self, conn: &DatabaseHandle) -> impl Stream<Item = Status> {
-        let opts = ChangesOptions::new()
-            .include_initial(true)
-            .include_states(false);
-
-        let mut q = r
+    /// Adds the upload to its uploader's totals, once it has got all the way through its
+    /// pipeline. Uploads that fail on the way aren't counted, and each upload is only counted
+    /// once, however many times it's finished.
+    async fn count_for_uploader(&mut self, conn: &DatabaseHandle) {
+        // Claimed in the database, so only one of several racing finishes counts it.
+        let s: unreql::Result<WriteStatus> = r
             .db("atuploads")
             .table("uploads")
             .get(self.id.clone())
-            .changes(opts)
-            .run::<_, Change>(&conn.pool);
+            .update(r.branch(
+                r.row().g("counted").default(false),
+                rjson!({}),
+                rjson!({ "counted": true }),
+            ))
+            .exec(&conn.pool)
+            .await;
+        // The upload is done either way, so don't fail it over the leaderboard.
+        match check_write(s) {
+            Ok(ws) if ws.replaced > 0 => self.counted = true,
+            Ok(_) => {
+                self.counted = true;
+                return;
+            }
+            Err(e) => {
+                println!("warning: failed to count upload {} for its uploader: {e}", self.id);
+                return;
+            }
+        }
+        let uploader = self.metadata.uploader.clone();
+        if let Err(e) = UploaderStats::count(conn, uploader, self.file.size).await {
+            println!("warning: failed to count upload {} for its uploader: {e}", self.id);
+        }
+    }
 
-        stream! {
-            while let Ok(Some(changed)) = q.try_next().await {
-                if let Some(new_val) = changed.new_val {
-                    let res: Result<Self, _> = serde_json::from_value(new_val);
-                    if let Ok(status) = res {
-                        self.status = status.status;
-                        yield self.status.clone();
-                    } /* else {
-                        dbg!(&res);
-                    } */
+    /// Updates the upload's sidecar manifest. Failures are only logged, since the database is
+    /// still the source of truth.
+    pub async fn record_manifest(&self) {
+        self.record_manifest_note(None).await
+    }
+
+    /// Like record_manifest_note, for several rows at once. They're written one after another in
+    /// the same blocking task.
+    pub async fn record_manifest_notes(rows: Vec<Self>, note: Option<String>) {
+        let failed = tokio::task::spawn_blocking(move || {
+            rows.iter()
+                .filter_map(|row| crate::manifest::record_note(row, note.clone()).err().map(|e| (row.id.clone(), e)))
+                .collect::<Vec<_>>()
+        })
+        .await;
+        match failed {
+            Ok(failed) => {
+                for (id, e) in failed {
+                    println!("warning: Failed to write manifest for {id}: {e}");
+                }
+            }
+            Err(e) => println!("warning: Failed to write manifests: {e}"),
+        }
+    }
+
+    /// Like record_manifest, but attaches a note to the history entry.
+    pub async fn record_manifest_note(&self, note: Option<String>) {
+        let row = self.clone();
+        match tokio::task::spawn_blocking(move || crate::manifest::record_note(&row, note)).await {
+            Ok(Ok(())) => {}
+            Ok(Err(e)) => println!("warning: Failed to write manifest for {}: {e}", self.id),
+            Err(e) => println!("warning: Failed to write manifest for {}: {e}", self.id),
+        }
+    }
+
+    /// Records a failed attempt at processing the item and releases it, so that it can be checked
+    /// out again after `delay` seconds.
+    pub async fn schedule_retry(&mut self, conn: &DatabaseHandle, error: String, delay: u64) -> Result<(), DbError> {
+        let retry_after = Self::now() + delay;
+        let attempts = self.attempts + 1;
+        let mut errors = self.errors.clone();
+        errors.push(error.clone());
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table("uploads")
+            .get(self.id.clone())
+            .update(rjson!({
+                "attempts": attempts,
+                "last_error": error.clone(),
+                "errors": errors.clone(),
+                "retry_after": retry_after,
+                "processing": false,
+                "progress": None::<Progress>,
+            }))
+            .exec(&conn.pool)
+            .await;
+        check_write(s)?;
+        self.attempts = attempts;
+        self.last_error = Some(error);
+        self.errors = errors;
+        self.retry_after = Some(retry_after);
+        self.processing = false;
+        self.progress = None;
+        Ok(())
+    }
+
+    /// Parks an item whose processing has failed too many times, keeping its error chain.
+    pub async fn dead_letter(&mut self, conn: &DatabaseHandle, error: String) -> Result<(), DbError> {
+        if self.status.is_terminal() {
+            return Err(DbError::WrongStatus);
+        }
+        let attempts = self.attempts + 1;
+        let mut errors = self.errors.clone();
+        errors.push(error.clone());
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table("uploads")
+            .get(self.id.clone())
+            .update(rjson!({
+                "status": Status::DeadLetter,
+                "dead_from": self.status.clone(),
+                "attempts": attempts,
+                "last_error": error.clone(),
+                "errors": errors.clone(),
+                "retry_after": None::<u64>,
+                "processing": false,
+                "progress": None::<Progress>,
+            }))
+            .exec(&conn.pool)
+            .await;
+        check_write(s)?;
+        self.dead_from = Some(std::mem::replace(&mut self.status, Status::DeadLetter));
+        self.attempts = attempts;
+        self.last_error = Some(error);
+        self.errors = errors;
+        self.retry_after = None;
+        self.processing = false;
+        self.progress = None;
+        Ok(())
+    }
+
+    /// Sends a dead-lettered item back to the status it failed in, with a clean slate.
+    pub async fn requeue(&mut self, conn: &DatabaseHandle) -> Result<(), DbError> {
+        let Some(previous) = self.dead_from.clone() else {
+            return Err(DbError::WrongStatus);
+        };
+        if self.status != Status::DeadLetter {
+            return Err(DbError::WrongStatus);
+        }
+        let dead_letter = Status::DeadLetter;
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table("uploads")
+            .get(self.id.clone())
+            .update(r.branch(
+                r.row().g("status").eq(rjson!(dead_letter)),
+                rjson!({
+                    "status": previous.clone(),
+                    "dead_from": None::<Status>,
+                    "attempts": 0,
+                    "last_error": None::<String>,
+                    "errors": Vec::<String>::new(),
+                    "last_activity": Self::now(),
+                }),
+                rjson!({}),
+            ))
+            .exec(&conn.pool)
+            .await;
+        if check_write(s)?.replaced == 0 {
+            return Err(DbError::WrongStatus);
+        }
+        self.status = previous;
+        self.dead_from = None;
+        self.attempts = 0;
+        self.last_error = None;
+        self.errors.clear();
+        Ok(())
+    }
+
+    /// Lists dead-lettered items, optionally only those of one project.
+    pub async fn list_dead_letters(conn: &DatabaseHandle, project: Option<String>) -> Result<Vec<Self>, DbError> {
+        let result: unreql::Result<Vec<Self>> = match project {
+            Some(project) => {
+                conn.table_for_reads("uploads")
+                    .get_all(r.with_opt(rjson!(Status::DeadLetter), r.index("status")))
+                    .filter(rjson!({
+                        "project": project,
+                    }))
+                    .exec_to_vec(&conn.reads)
+                    .await
+            }
+            None => {
+                conn.table_for_reads("uploads")
+                    .get_all(r.with_opt(rjson!(Status::DeadLetter), r.index("status")))
+                    .exec_to_vec(&conn.reads)
+                    .await
+            }
+        };
+        result.map_err(|e| {
+            println!("warning: Unknown database error occured, see: {e:?}");
+            DbError::Other
+        })
+    }
+
+    /// Requeues every upload with this status whose last activity was before `cutoff`, optionally
+    /// only those of one project or pipeline. They go to `target`, or back to where they were
+    /// dead-lettered from if it's None; dead-lettered uploads that don't say where that was are
+    /// left alone, like pinned uploads and ones whose files are gone. Returns the requeued rows.
+    ///
+    /// They're requeued `REQUEUE_BATCH` at a time, since RethinkDB won't return more changes than
+    /// that from one query. Each row is only changed if it still has the status, so rows that
+    /// something else moved on in the meantime aren't counted.
+    pub async fn requeue_matching(
+        conn: &DatabaseHandle,
+        status: Status,
+        project: Option<String>,
+        pipeline: Option<String>,
+        cutoff: u64,
+        target: Option<Status>,
+    ) -> Result<Vec<Self>, DbError> {
+        let mut requeued = Vec::new();
+        loop {
+            let mut query = r
+                .db("atuploads")
+                .table("uploads")
+                .get_all(r.with_opt(rjson!(status.clone()), r.index("status")))
+                .filter(func!(|row| {
+                    row.g("last_activity").lt(cutoff)
+                }))
+                .filter(func!(|row| {
+                    row.g("pinned").default(false).eq(false)
+                }))
+                .filter(func!(|row| {
+                    row.g("files_removed").default(false).eq(false)
+                }));
+            if let Some(project) = project.clone() {
+                query = query.filter(rjson!({ "project": project }));
+            }
+            if let Some(pipeline) = pipeline.clone() {
+                query = query.filter(rjson!({ "pipeline": pipeline }));
+            }
+            let new_status = match target.clone() {
+                Some(target) => r.expr(rjson!(target)),
+                None => {
+                    query = query.filter(func!(|row| {
+                        row.g("dead_from").default(rjson!(null)).ne(rjson!(null))
+                    }));
+                    r.row().g("dead_from").default(rjson!(null))
+                }
+            };
+            let s: unreql::Result<WriteStatus<Self>> = query
+                .limit(REQUEUE_BATCH)
+                .update(r.with_opt(
+                    r.branch(
+                        r.row().g("status").eq(rjson!(status.clone())),
+                        rjson!({
+                            "status": new_status,
+                            "dead_from": None::<Status>,
+                            "processing": false,
+                            "progress": None::<Progress>,
+                            "attempts": 0,
+                            "last_error": None::<String>,
+                            "errors": Vec::<String>::new(),
+                            "retry_after": None::<u64>,
+                            "last_activity": Self::now(),
+                        }),
+                        rjson!({}),
+                    ),
+                    UpdateOptions {
+                        return_changes: Some(true.into()),
+                        ..Default::default()
+                    },
+                ))
+                .exec(&conn.pool)
+                .await;
+            let ws = check_write(s)?;
+            let batch: Vec<Self> = ws
+                .changes
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|change| change.new_val)
+                .map(|mut row| {
+                    row.upgrade();
+                    row
+                })
+                .collect();
+            // Requeued rows don't match any more, so the next batch is new ones. Stop once one
+            // comes up short, or nothing in it could be changed.
+            let done = batch.is_empty() || (ws.replaced as usize) < REQUEUE_BATCH;
+            requeued.extend(batch);
+            if done {
+                return Ok(requeued);
+            }
+        }
+    }
+
+    /// Lists the pipelines of the uploads with this status, optionally only in one project.
+    pub async fn pipelines_with_status(
+        conn: &DatabaseHandle,
+        status: Status,
+        project: Option<String>,
+    ) -> Result<Vec<String>, DbError> {
+        let mut query = conn
+            .table_for_reads("uploads")
+            .get_all(r.with_opt(rjson!(status), r.index("status")));
+        if let Some(project) = project {
+            query = query.filter(rjson!({ "project": project }));
+        }
+        query
+            .g("pipeline")
+            .distinct(())
+            .exec_to_vec(&conn.reads)
+            .await
+            .map_err(log_error)
+    }
+
+    /// Records the processor's progress, which also counts as activity.
+    pub async fn set_progress(&mut self, conn: &DatabaseHandle, progress: Progress) -> Result<(), DbError> {
+        let now = Self::now();
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table("uploads")
+            .get(self.id.clone())
+            .update(rjson!({
+                "progress": progress,
+                "last_activity": now,
+            }))
+            .exec(&conn.pool)
+            .await;
+        check_write(s)?;
+        self.progress = Some(progress);
+        self.last_activity = now;
+        Ok(())
+    }
+
+    /// Records where the item was packed.
+    pub async fn set_packed(&mut self, conn: &DatabaseHandle, packed: Packed) -> Result<(), DbError> {
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table("uploads")
+            .get(self.id.clone())
+            .update(rjson!({
+                "packed": packed.clone(),
+            }))
+            .exec(&conn.pool)
+            .await;
+        check_write(s)?;
+        self.packed = Some(packed);
+        Ok(())
+    }
+
+    /// Records the file a deriving stage made from the upload.
+    pub async fn set_derived(&mut self, conn: &DatabaseHandle, derived: File) -> Result<(), DbError> {
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table("uploads")
+            .get(self.id.clone())
+            .update(rjson!({
+                "derived": derived.clone(),
+            }))
+            .exec(&conn.pool)
+            .await;
+        check_write(s)?;
+        self.derived = Some(derived);
+        Ok(())
+    }
+
+    /// Records the digest the checksum stage verified the upload's data against.
+    pub async fn set_digest(&mut self, conn: &DatabaseHandle, digest: Digest) -> Result<(), DbError> {
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table("uploads")
+            .get(self.id.clone())
+            .update(rjson!({
+                "digest": digest.clone(),
+            }))
+            .exec(&conn.pool)
+            .await;
+        check_write(s)?;
+        self.digest = Some(digest);
+        Ok(())
+    }
+
+    /// Records the files found in the upload, replacing any the client listed.
+    pub async fn set_members(&mut self, conn: &DatabaseHandle, members: Vec<BundleMember>) -> Result<(), DbError> {
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table("uploads")
+            .get(self.id.clone())
+            .update(rjson!({
+                "members": members.clone(),
+            }))
+            .exec(&conn.pool)
+            .await;
+        check_write(s)?;
+        self.members = members;
+        Ok(())
+    }
+
+    /// Pins or unpins the upload. Pinned uploads are never reaped, expired or purged, so their
+    /// data is kept for as long as someone needs to look at it.
+    pub async fn set_pinned(&mut self, conn: &DatabaseHandle, pinned: bool) -> Result<(), DbError> {
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table("uploads")
+            .get(self.id.clone())
+            .update(rjson!({
+                "pinned": pinned,
+            }))
+            .exec(&conn.pool)
+            .await;
+        check_write(s)?;
+        self.pinned = pinned;
+        Ok(())
+    }
+
+    /// Lists the pinned uploads.
+    pub async fn list_pinned(conn: &DatabaseHandle) -> Result<Vec<Self>, DbError> {
+        let result: unreql::Result<Vec<Self>> = conn
+            .table_for_reads("uploads")
+            .filter(rjson!({
+                "pinned": true,
+            }))
+            .exec_to_vec(&conn.reads)
+            .await;
+        result.map_err(|e| {
+            println!("warning: Unknown database error occured, see: {e:?}");
+            DbError::Other
+        })
+    }
+
+    /// Replaces the upload's metadata.
+    pub async fn set_metadata(&mut self, conn: &DatabaseHandle, metadata: Metadata) -> Result<(), DbError> {
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table("uploads")
+            .get(self.id.clone())
+            .update(rjson!({
+                "metadata": metadata.clone(),
+            }))
+            .exec(&conn.pool)
+            .await;
+        check_write(s)?;
+        self.metadata = metadata;
+        Ok(())
+    }
+
+    /// Moves the upload to another project and pipeline. Its file stays where it is. This isn't
+    /// allowed once the upload is done with, since that would change history.
+    pub async fn set_project(&mut self, conn: &DatabaseHandle, project: String, pipeline: String) -> Result<(), DbError> {
+        if self.status.is_terminal() {
+            return Err(DbError::WrongStatus);
+        }
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table("uploads")
+            .get(self.id.clone())
+            .update(rjson!({
+                "project": project.clone(),
+                "pipeline": pipeline.clone(),
+            }))
+            .exec(&conn.pool)
+            .await;
+        check_write(s)?;
+        self.project = project;
+        self.pipeline = pipeline;
+        Ok(())
+    }
+
+    /// Records activity on the upload. A processor that has it checked out should do this every
+    /// so often, so nobody reclaims it as stale.
+    pub async fn touch(&mut self, conn: &DatabaseHandle) -> Result<(), DbError> {
+        let now = Self::now();
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table("uploads")
+            .get(self.id.clone())
+            .update(rjson!({
+                "last_activity": now,
+            }))
+            .exec(&conn.pool)
+            .await;
+        check_write(s)?;
+        self.last_activity = now;
+        Ok(())
+    }
+
+    /// Gives a checked out upload back without counting a failed attempt, so it can be checked
+    /// out again after `delay` seconds. For processors waiting on something else to happen.
+    pub async fn postpone(&mut self, conn: &DatabaseHandle, delay: u64) -> Result<(), DbError> {
+        let retry_after = Self::now() + delay;
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table("uploads")
+            .get(self.id.clone())
+            .update(rjson!({
+                "retry_after": retry_after,
+                "processing": false,
+                "progress": None::<Progress>,
+            }))
+            .exec(&conn.pool)
+            .await;
+        check_write(s)?;
+        self.retry_after = Some(retry_after);
+        self.processing = false;
+        self.progress = None;
+        Ok(())
+    }
+
+    /// Gives a checked out upload back without changing its status, so it can be checked out
+    /// again straight away.
+    pub async fn release(&mut self, conn: &DatabaseHandle) -> Result<(), DbError> {
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table("uploads")
+            .get(self.id.clone())
+            .update(rjson!({
+                "processing": false,
+            }))
+            .exec(&conn.pool)
+            .await;
+        check_write(s)?;
+        self.processing = false;
+        Ok(())
+    }
+
+    /// Gets every shard of a file, by the group they share. See `Shard`.
+    pub async fn list_shard_group(conn: &DatabaseHandle, group: String) -> Result<Vec<Self>, DbError> {
+        let result: unreql::Result<Vec<Self>> = conn
+            .table_for_reads("uploads")
+            .filter(rjson!({
+                "shard": {"group": group},
+            }))
+            .exec_to_vec(&conn.reads)
+            .await;
+        result.map_err(|e| {
+            println!("warning: Unknown database error occured, see: {e:?}");
+            DbError::Other
+        })
+    }
+
+    /// Finds the uploads that contain an item, with only the fields in ItemUpload.
+    pub async fn find_by_item(conn: &DatabaseHandle, item: String) -> Result<Vec<ItemUpload>, DbError> {
+        let result: unreql::Result<Vec<ItemUpload>> = conn
+            .table_for_reads("uploads")
+            .get_all(r.with_opt(item, r.index("items")))
+            .map(item_fields())
+            .exec_to_vec(&conn.reads)
+            .await;
+        result.map_err(|e| {
+            println!("warning: Unknown database error occured, see: {e:?}");
+            DbError::Other
+        })
+    }
+
+    /// Finds the uploads in a project that have a file with this hash and size, and haven't
+    /// failed or been abandoned. Uploads still in progress don't count either.
+    pub async fn find_by_hash(conn: &DatabaseHandle, project: String, hash: String, size: u64) -> Result<Vec<Self>, DbError> {
+        let good = [Status::Verifying, Status::Deriving, Status::Packing, Status::Finished];
+        let result: unreql::Result<Vec<Self>> = conn
+            .table_for_reads("uploads")
+            .get_all(r.with_opt(rjson!([project, hash]), r.index("hash")))
+            .filter(func!(|row| {
+                row.clone().g("file").g("size").eq(size)
+                    .and(r.expr(rjson!(good.clone())).contains(row.g("status")))
+            }))
+            .exec_to_vec(&conn.reads)
+            .await;
+        result.map_err(|e| {
+            println!("warning: Unknown database error occured, see: {e:?}");
+            DbError::Other
+        })
+    }
+
+    /// Finds an upload of the same file to a project that's still in progress, if there is one.
+    /// This reads from the primary, since an upload that was just started has to be found.
+    pub async fn find_active_by_hash(conn: &DatabaseHandle, project: String, hash: String, size: u64) -> Result<Option<Self>, DbError> {
+        let active = [Status::Uploading, Status::Verifying, Status::Deriving, Status::Packing];
+        let result: unreql::Result<Vec<Self>> = r
+            .db("atuploads")
+            .table("uploads")
+            .get_all(r.with_opt(rjson!([project, hash]), r.index("hash")))
+            .filter(func!(|row| {
+                row.clone().g("file").g("size").eq(size)
+                    .and(r.expr(rjson!(active.clone())).contains(row.g("status")))
+            }))
+            .limit(1)
+            .exec_to_vec(&conn.pool)
+            .await;
+        match result {
+            Ok(mut rows) => Ok(rows.pop()),
+            Err(e) => {
+                println!("warning: Unknown database error occured, see: {e:?}");
+                Err(DbError::Other)
+            }
+        }
+    }
+
+    /// Counts an uploader's uploads that are still in progress.
+    pub async fn count_active(conn: &DatabaseHandle, uploader: String) -> Result<u64, DbError> {
+        let uploading = Status::Uploading;
+        r.db("atuploads")
+            .table("uploads")
+            .get_all(r.with_opt(rjson!([uploader, uploading]), r.index("uploader_status")))
+            .count(())
+            .exec(&conn.pool)
+            .await
+            .map_err(|e| {
+                println!("warning: Unknown database error occured, see: {e:?}");
+                DbError::Other
+            })
+    }
+
+    /// Counts the items in each project, pipeline and status that isn't terminal, and dead-lettered
+    /// ones, to show where items are piling up.
+    pub async fn queue_depths(conn: &DatabaseHandle) -> Result<Vec<QueueDepth>, DbError> {
+        #[derive(Deserialize)]
+        struct Queued {
+            project: String,
+            pipeline: String,
+            status: Status,
+            last_activity: u64,
+        }
+        let statuses = [Status::Uploading, Status::Verifying, Status::Deriving, Status::Packing, Status::DeadLetter];
+        let mut items = Vec::new();
+        for status in statuses {
+            let rows: Vec<Queued> = conn
+                .table_for_reads("uploads")
+                .get_all(r.with_opt(rjson!(status), r.index("status")))
+                .pluck(r.args(rjson!(["project", "pipeline", "status", "last_activity"])))
+                .exec_to_vec(&conn.reads)
+                .await
+                .map_err(|e| {
+                    println!("warning: Unknown database error occured, see: {e:?}");
+                    DbError::Other
+                })?;
+            items.extend(rows.into_iter().map(|q| (q.project, q.pipeline, q.status, q.last_activity)));
+        }
+        Ok(QueueDepth::tally(items, Self::now()))
+    }
+
+    /// Adds up the sizes of the uploads an uploader has started since `since`.
+    pub async fn bytes_started_since(conn: &DatabaseHandle, uploader: String, since: u64) -> Result<u64, DbError> {
+        let bytes: unreql::Result<f64> = r
+            .db("atuploads")
+            .table("uploads")
+            .between(
+                rjson!([uploader.clone(), since]),
+                rjson!([uploader, r.maxval()]),
+                BetweenOptions::new().index("uploader_created".to_string()),
+            )
+            .g("file")
+            .g("size")
+            .sum(())
+            .exec(&conn.pool)
+            .await;
+        bytes.map(|b| b as u64).map_err(|e| {
+            println!("warning: Unknown database error occured, see: {e:?}");
+            DbError::Other
+        })
+    }
+
+    /// Streams status changes, progress updates and stall warnings. The current status is always
+    /// sent first.
+    ///
+    /// If the changefeed drops, it is reopened with backoff, starting again from the current row;
+    /// statuses the row went through in the meantime are skipped. If it can't be reopened, an
+    /// UploadEvent::Error is sent and the stream ends.
+    ///
+    /// If `include_row` is set, every status change is followed by the whole row.
+    #[fix_hidden_lifetime_bug] // what the fuck
+    pub fn stream_events(&mut self, conn: &DatabaseHandle, include_row: bool) -> impl Stream<Item = UploadEvent> {
+        let id = self.id.clone();
+        let changefeed = move || {
+            let opts = ChangesOptions::new()
+                .include_initial(true)
+                .include_states(false);
+            r.db("atuploads")
+                .table("uploads")
+                .get(id.clone())
+                .changes(opts)
+                .run::<_, Change>(&conn.pool)
+        };
+
+        stream! {
+            // What has been sent so far.
+            let mut status = None;
+            let mut progress = None;
+            let mut stalled = None;
+            let mut q = changefeed();
+            // Failures since the last change that came through.
+            let mut tries = 0;
+            loop {
+                let changed = match q.try_next().await {
+                    Ok(Some(changed)) => changed,
+                    failed => {
+                        let error = match failed {
+                            Err(e) => format!("{e:?}"),
+                            _ => "changefeed ended".to_string(),
+                        };
+                        if tries >= CHANGEFEED_MAX_TRIES {
+                            println!("warning: giving up on the changefeed for {}: {error}", self.id);
+                            yield UploadEvent::Error(error);
+                            break;
+                        }
+                        let to_sleep = 1 << tries;
+                        println!("warning: changefeed for {} failed, reopening in {to_sleep}s: {error}", self.id);
+                        tokio::time::sleep(Duration::from_secs(to_sleep)).await;
+                        tries += 1;
+                        q = changefeed();
+                        continue;
+                    }
+                };
+                tries = 0;
+                if let Some(new_val) = changed.new_val {
+                    let res: Result<Self, _> = serde_json::from_value(new_val);
+                    if let Ok(row) = res {
+                        self.status = row.status.clone();
+                        self.progress = row.progress;
+                        self.stalled = row.stalled;
+                        if status.as_ref() != Some(&self.status) {
+                            status = Some(self.status.clone());
+                            progress = None;
+                            yield UploadEvent::StatusChange(self.status.clone());
+                            if include_row {
+                                yield UploadEvent::Row(Box::new(row));
+                            }
+                        }
+                        if self.progress.is_some() && self.progress != progress {
+                            progress = self.progress;
+                            yield UploadEvent::Progress(progress.unwrap());
+                        }
+                        if self.stalled != stalled {
+                            stalled = self.stalled;
+                            if let Some(stall) = stalled {
+                                yield UploadEvent::Stalled(stall);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Streams the status changes of every upload, or of those in one project and pipeline if
+    /// they're given. New uploads count as a change; nothing is sent for the uploads that already
+    /// exist.
+    ///
+    /// If the changefeed drops, it is reopened with backoff; changes made in the meantime are
+    /// missed. If it can't be reopened, a FirehoseEvent::Error is sent and the stream ends.
+    pub fn stream_all_events(
+        conn: &DatabaseHandle,
+        project: Option<String>,
+        pipeline: Option<String>,
+    ) -> impl Stream<Item = FirehoseEvent> + '_ {
+        let mut filter = serde_json::Map::new();
+        if let Some(project) = project {
+            filter.insert("project".to_string(), project.into());
+        }
+        if let Some(pipeline) = pipeline {
+            filter.insert("pipeline".to_string(), pipeline.into());
+        }
+        let changefeed = move || {
+            let opts = ChangesOptions::new().include_states(false);
+            r.db("atuploads")
+                .table("uploads")
+                .filter(rjson!(filter.clone()))
+                .changes(opts)
+                .run::<_, Change>(&conn.pool)
+        };
+
+        stream! {
+            let mut q = changefeed();
+            // Failures since the last change that came through.
+            let mut tries = 0;
+            loop {
+                let changed = match q.try_next().await {
+                    Ok(Some(changed)) => changed,
+                    failed => {
+                        let error = match failed {
+                            Err(e) => format!("{e:?}"),
+                            _ => "changefeed ended".to_string(),
+                        };
+                        if tries >= CHANGEFEED_MAX_TRIES {
+                            println!("warning: giving up on the firehose changefeed: {error}");
+                            yield FirehoseEvent::Error(error);
+                            break;
+                        }
+                        let to_sleep = 1 << tries;
+                        println!("warning: firehose changefeed failed, reopening in {to_sleep}s: {error}");
+                        tokio::time::sleep(Duration::from_secs(to_sleep)).await;
+                        tries += 1;
+                        q = changefeed();
+                        continue;
+                    }
+                };
+                tries = 0;
+                // Purged rows have no new value, and aren't a status change.
+                let Some(Ok(row)) = changed.new_val.map(serde_json::from_value::<Self>) else {
+                    continue;
+                };
+                let old_status = changed.old_val.and_then(|v| serde_json::from_value::<Self>(v).ok()).map(|old| old.status);
+                if old_status.as_ref() != Some(&row.status) {
+                    yield FirehoseEvent::StatusChange {
+                        id: row.id,
+                        project: row.project,
+                        pipeline: row.pipeline,
+                        status: row.status,
+                    };
                 }
             }
         }
@@ -302,6 +1627,111 @@ impl UploadRow {
 /// A connection pool for the database.
 pub struct DatabaseHandle {
     pub(crate) pool: PoolWrapper,
+    /// For queries that list or search items, which can be sent to a replica. This is the same
+    /// pool as `pool` unless RETHINKDB_READ_HOST is set.
+    pub(crate) reads: PoolWrapper,
+    /// Whether queries on `reads` can be answered with out-of-date data.
+    outdated_reads: bool,
+    primary: CheckedPool,
+    replica: Option<CheckedPool>,
+}
+
+/// Statistics about a connection pool.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PoolStats {
+    pub max_size: usize,
+    /// Open sessions, whether they're in use or not.
+    pub size: usize,
+    /// Open sessions that aren't in use.
+    pub available: usize,
+    /// Tasks waiting for a session.
+    pub waiting: usize,
+    /// How many health checks have been run.
+    pub health_checks: u64,
+    /// How many sessions failed a health check and were thrown away.
+    pub discarded: u64,
+}
+
+#[derive(Default)]
+struct HealthCounters {
+    checks: AtomicU64,
+    discarded: AtomicU64,
+}
+
+impl HealthCounters {
+    /// Runs a trivial query to make sure the session still works.
+    async fn check(&self, session: &Session) -> unreql::Result<()> {
+        self.checks.fetch_add(1, Ordering::Relaxed);
+        let result: unreql::Result<u8> = r.expr(1).exec(session).await;
+        if let Err(e) = &result {
+            self.discarded.fetch_add(1, Ordering::Relaxed);
+            println!("warning: discarding broken database session, see: {e:?}");
+        }
+        result.map(|_| ())
+    }
+}
+
+/// A pool whose sessions are health-checked.
+struct CheckedPool {
+    raw: Pool<SessionManager>,
+    health: Arc<HealthCounters>,
+}
+
+impl CheckedPool {
+    fn new(cfg: Options) -> Result<Self, String> {
+        let health = Arc::new(HealthCounters::default());
+        let h = health.clone();
+        let raw = Pool::builder(SessionManager::new(cfg))
+            .max_size(4)
+            // Sessions that have been sitting around might have been dropped by the server.
+            .pre_recycle(Hook::async_fn(move |session: &mut Session, metrics: &Metrics| {
+                let h = h.clone();
+                Box::pin(async move {
+                    if metrics.last_used() < HEALTH_CHECK_IDLE {
+                        return Ok(());
+                    }
+                    h.check(session)
+                        .await
+                        .map_err(|e| HookError::Message(format!("{e:?}")))
+                })
+            }))
+            .build()
+            .map_err(|e| e.to_string())?;
+        Ok(Self { raw, health })
+    }
+
+    fn stats(&self) -> PoolStats {
+        let status = self.raw.status();
+        PoolStats {
+            max_size: status.max_size,
+            size: status.size,
+            available: status.available,
+            waiting: status.waiting,
+            health_checks: self.health.checks.load(Ordering::Relaxed),
+            discarded: self.health.discarded.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Checks every idle session, throwing away the broken ones.
+    async fn check_idle(&self) {
+        // Take them all at once, so none of them is checked twice.
+        let no_wait = Timeouts {
+            wait: Some(Duration::ZERO),
+            ..Default::default()
+        };
+        let mut sessions = Vec::new();
+        for _ in 0..self.raw.status().available {
+            match self.raw.timeout_get(&no_wait).await {
+                Ok(session) => sessions.push(session),
+                Err(_) => break,
+            }
+        }
+        for session in sessions {
+            if self.health.check(&session).await.is_err() {
+                let _ = Object::take(session);
+            }
+        }
+    }
 }
 
 macro_rules! cfg_from_env {
@@ -314,22 +1744,79 @@ macro_rules! cfg_from_env {
 
 impl DatabaseHandle {
     /// Creates a new connection pool.
+    ///
+    /// If RETHINKDB_READ_HOST is set, listings and searches go to that server instead. Unless
+    /// RETHINKDB_READ_MODE is `outdated`, RethinkDB still answers them from the primary replica
+    /// of the table, so set both to take load off the primary.
     pub fn new() -> Result<Self, String> {
-        let mut cfg = unreql::cmd::connect::Options::default();
+        let mut cfg = Options::default();
         cfg_from_env!(cfg, "RETHINKDB_HOST", host);
-        // todo: parse string for RETHINKDB_PORT
-        // cfg_from_env!(cfg, "RETHINKDB_PORT", port);
+        if let Ok(port) = std::env::var("RETHINKDB_PORT") {
+            cfg = cfg.port(port.parse().map_err(|e| format!("RETHINKDB_PORT: {e}"))?);
+        }
         cfg_from_env!(cfg, "RETHINKDB_USER", user);
         cfg_from_env!(cfg, "RETHINKDB_PASSWORD", password);
-        let manager = unreql_deadpool::SessionManager::new(cfg);
-        let pool = deadpool::managed::Pool::builder(manager)
-            .max_size(4)
-            .build();
-        match pool {
-            Ok(pool) => Ok(Self {
-                pool: pool.wrapper(),
-            }),
-            Err(e) => Err(e.to_string()),
+        let outdated_reads = match std::env::var("RETHINKDB_READ_MODE").as_deref() {
+            Ok("outdated") => true,
+            Ok("single") | Err(_) => false,
+            Ok(other) => return Err(format!("RETHINKDB_READ_MODE: unknown read mode {other}")),
+        };
+        let replica = match std::env::var("RETHINKDB_READ_HOST") {
+            Ok(host) => Some(CheckedPool::new(cfg.clone().host(host))?),
+            Err(_) => None,
+        };
+        let primary = CheckedPool::new(cfg)?;
+        Ok(Self {
+            pool: primary.raw.clone().wrapper(),
+            reads: replica.as_ref().unwrap_or(&primary).raw.clone().wrapper(),
+            outdated_reads,
+            primary,
+            replica,
+        })
+    }
+
+    /// Starts a query on a table for listing or searching, which might be answered by a replica.
+    /// Run it on `reads`.
+    pub(crate) fn table_for_reads(&self, table: &'static str) -> Command {
+        let opts = TableOptions::new().read_mode(match self.outdated_reads {
+            true => ReadMode::Outdated,
+            false => ReadMode::Single,
+        });
+        r.db("atuploads").table(r.with_opt(table, opts))
+    }
+
+    /// Gets statistics about the pool.
+    pub fn stats(&self) -> PoolStats {
+        self.primary.stats()
+    }
+
+    /// Gets statistics about the pool for RETHINKDB_READ_HOST, if it's set.
+    pub fn read_stats(&self) -> Option<PoolStats> {
+        self.replica.as_ref().map(CheckedPool::stats)
+    }
+
+    /// Runs a trivial query, to see whether the database is answering.
+    pub async fn ping(&self) -> Result<(), DbError> {
+        let result: unreql::Result<u8> = r.expr(1).exec(&self.pool).await;
+        result.map(|_| ()).map_err(|e| {
+            println!("warning: Unknown database error occured, see: {e:?}");
+            DbError::Other
+        })
+    }
+
+    /// Checks the idle sessions every so often, forever, and throws away the broken ones.
+    ///
+    /// Sessions are also checked when they're taken out of the pool after sitting idle for a
+    /// while, but busy ones never are, so this is needed to catch those.
+    pub async fn keep_healthy(&self) {
+        let mut timer = tokio::time::interval(HEALTH_CHECK_INTERVAL);
+        timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            timer.tick().await;
+            self.primary.check_idle().await;
+            if let Some(replica) = &self.replica {
+                replica.check_idle().await;
+            }
         }
     }
 }
@@ -0,0 +1,144 @@
+//! The archive: uploads that were done with a while ago, moved out of the uploads table to keep it
+//! and its indexes small. Rows are moved whole, with their history and where they were packed,
+//! and marked `archived`. Nothing changes them once they're there.
+//!
+//! Uploads that something might still happen to stay in the uploads table: failed and
+//! dead-lettered ones (the retention task and operators deal with those), deleted ones whose data
+//! hasn't been purged yet, and pinned ones.
+
+use unreql::{func, r, rjson, types::WriteStatus};
+
+use super::{check_write, item_fields, log_error, DatabaseHandle, DbError, ItemUpload, Status, UploadRow};
+
+pub(crate) const ARCHIVE_TABLE: &str = "uploads_archive";
+/// How many rows are moved in one go.
+const BATCH: usize = 1000;
+
+impl UploadRow {
+    /// Moves a project's uploads that are done with, and whose last activity was before `cutoff`
+    /// (in seconds since the epoch), to the archive. Returns how many were moved.
+    ///
+    /// Each row is copied before it's deleted, so if this is interrupted a row can be in both
+    /// tables for a while; the copy in the uploads table wins, and the move is tried again next
+    /// time.
+    pub async fn archive_old(
+        conn: &DatabaseHandle,
+        project: String,
+        cutoff: u64,
+    ) -> Result<u64, DbError> {
+        let mut moved = 0;
+        for status in [Status::Finished, Status::Abandoned, Status::Deleted] {
+            // The same as is_archivable, so a batch isn't taken up by rows that can't be moved.
+            let files_removed = status != Status::Deleted;
+            let rows: Vec<Self> = r
+                .db("atuploads")
+                .table("uploads")
+                .get_all(r.with_opt(rjson!(status.clone()), r.index("status")))
+                .filter(rjson!({ "project": project.clone() }))
+                .filter(func!(|row| {
+                    row.clone()
+                        .g("last_activity")
+                        .lt(cutoff)
+                        .and(row.clone().g("pinned").default(false).not())
+                        .and(row.g("files_removed").default(false).or(files_removed))
+                }))
+                .limit(BATCH)
+                .exec_to_vec(&conn.pool)
+                .await
+                .map_err(log_error)?;
+            for mut row in rows.into_iter().filter(|row| row.is_archivable()) {
+                let (id, last_activity) = (row.id.clone(), row.last_activity);
+                row.archived = true;
+                let s: unreql::Result<WriteStatus> = r
+                    .db("atuploads")
+                    .table(ARCHIVE_TABLE)
+                    .get(id.clone())
+                    .replace(row)
+                    .exec(&conn.pool)
+                    .await;
+                check_write(s)?;
+                // Only if nothing touched it in the meantime.
+                let s: unreql::Result<WriteStatus> = r
+                    .db("atuploads")
+                    .table("uploads")
+                    .get_all(id)
+                    .filter(rjson!({
+                        "status": status.clone(),
+                        "last_activity": last_activity,
+                    }))
+                    .delete(())
+                    .exec(&conn.pool)
+                    .await;
+                moved += check_write(s)?.deleted as u64;
+            }
+        }
+        Ok(moved)
+    }
+
+    /// Gets an upload from the archive.
+    pub async fn from_archive(conn: &DatabaseHandle, uuid: String) -> Result<Self, DbError> {
+        let row: Option<Self> = conn
+            .table_for_reads(ARCHIVE_TABLE)
+            .get(uuid)
+            .exec(&conn.reads)
+            .await
+            .map_err(log_error)?;
+        let mut row = row.ok_or(DbError::NotFound)?;
+        row.upgrade();
+        Ok(row)
+    }
+
+    /// Gets an upload from the uploads table, or from the archive if it isn't there.
+    pub async fn from_either(conn: &DatabaseHandle, uuid: String) -> Result<Self, DbError> {
+        match Self::from_database(conn, uuid.clone()).await {
+            Err(DbError::NotFound) => Self::from_archive(conn, uuid).await,
+            result => result,
+        }
+    }
+
+    /// Finds the archived uploads that contain an item, like `find_by_item`.
+    pub async fn find_archived_by_item(
+        conn: &DatabaseHandle,
+        item: String,
+    ) -> Result<Vec<ItemUpload>, DbError> {
+        conn.table_for_reads(ARCHIVE_TABLE)
+            .get_all(r.with_opt(item, r.index("items")))
+            .map(item_fields())
+            .exec_to_vec(&conn.reads)
+            .await
+            .map_err(log_error)
+    }
+
+    /// Finds the archived uploads in a project that have a file with this hash and size. Only
+    /// finished uploads count, like in `find_by_hash`.
+    pub async fn find_archived_by_hash(
+        conn: &DatabaseHandle,
+        project: String,
+        hash: String,
+        size: u64,
+    ) -> Result<Vec<Self>, DbError> {
+        conn.table_for_reads(ARCHIVE_TABLE)
+            .get_all(r.with_opt(rjson!([project, hash]), r.index("hash")))
+            .filter(func!(|row| {
+                row.clone()
+                    .g("file")
+                    .g("size")
+                    .eq(size)
+                    .and(row.g("status").eq(rjson!(Status::Finished)))
+            }))
+            .exec_to_vec(&conn.reads)
+            .await
+            .map_err(log_error)
+    }
+}
+
+/// Adds archived rows to rows from the uploads table, leaving out any that are in both, going by
+/// the IDs `id` gets.
+pub fn merge_archived<T>(mut rows: Vec<T>, archived: Vec<T>, id: impl Fn(&T) -> &str) -> Vec<T> {
+    for row in archived {
+        if !rows.iter().any(|existing| id(existing) == id(&row)) {
+            rows.push(row);
+        }
+    }
+    rows
+}
@@ -0,0 +1,49 @@
+//! The audit trail. Entries are only ever added to the audit table, never changed or removed.
+
+use unreql::{r, types::WriteStatus};
+
+use super::{check_write, AuditEntry, DatabaseHandle, DbError};
+
+pub(crate) const AUDIT_TABLE: &str = "audit";
+
+impl AuditEntry {
+    /// Adds the entry to the audit trail.
+    pub async fn record(&self, conn: &DatabaseHandle) -> Result<(), DbError> {
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table(AUDIT_TABLE)
+            .insert(self.clone())
+            .exec(&conn.pool)
+            .await;
+        check_write(s).map(|_| ())
+    }
+
+    /// Lists the latest `limit` entries, optionally only those about one upload.
+    pub async fn list(
+        conn: &DatabaseHandle,
+        upload: Option<String>,
+        limit: usize,
+    ) -> Result<Vec<Self>, DbError> {
+        let result: unreql::Result<Vec<Self>> = match upload {
+            Some(upload) => {
+                conn.table_for_reads(AUDIT_TABLE)
+                    .get_all(r.with_opt(upload, r.index("uploads")))
+                    .order_by(r.desc("time"))
+                    .limit(limit)
+                    .exec_to_vec(&conn.reads)
+                    .await
+            }
+            None => {
+                conn.table_for_reads(AUDIT_TABLE)
+                    .order_by(r.index(r.desc("time")))
+                    .limit(limit)
+                    .exec_to_vec(&conn.reads)
+                    .await
+            }
+        };
+        result.map_err(|e| {
+            println!("warning: Unknown database error occured, see: {e:?}");
+            DbError::Other
+        })
+    }
+}
@@ -0,0 +1,54 @@
+//! Uploaders an operator has banned. A banned uploader can't start uploads or send chunks.
+
+use unreql::{r, types::WriteStatus};
+
+use super::{check_write, log_error, Ban, DatabaseHandle, DbError};
+
+pub(crate) const BANS_TABLE: &str = "bans";
+
+impl Ban {
+    /// Bans the uploader, or changes the reason if they're already banned.
+    pub async fn save(&self, conn: &DatabaseHandle) -> Result<(), DbError> {
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table(BANS_TABLE)
+            .get(self.uploader.clone())
+            .replace(self.clone())
+            .exec(&conn.pool)
+            .await;
+        check_write(s).map(|_| ())
+    }
+
+    /// Lifts an uploader's ban. Returns NotFound if they weren't banned.
+    pub async fn lift(conn: &DatabaseHandle, uploader: String) -> Result<(), DbError> {
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table(BANS_TABLE)
+            .get(uploader)
+            .delete(())
+            .exec(&conn.pool)
+            .await;
+        // Deleting a document that isn't there counts as skipped.
+        check_write(s).map(|_| ())
+    }
+
+    /// Gets an uploader's ban, if they're banned. This reads from the primary, so bans take
+    /// effect straight away.
+    pub async fn get(conn: &DatabaseHandle, uploader: String) -> Result<Option<Self>, DbError> {
+        r.db("atuploads")
+            .table(BANS_TABLE)
+            .get(uploader)
+            .exec(&conn.pool)
+            .await
+            .map_err(log_error)
+    }
+
+    /// Lists every ban, most recent first.
+    pub async fn list(conn: &DatabaseHandle) -> Result<Vec<Self>, DbError> {
+        conn.table_for_reads(BANS_TABLE)
+            .order_by(r.desc("time"))
+            .exec_to_vec(&conn.reads)
+            .await
+            .map_err(log_error)
+    }
+}
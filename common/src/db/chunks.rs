@@ -0,0 +1,146 @@
+//! The chunk index: the hash of every chunk the dedup stage has seen in each project, and the
+//! upload it was first seen in. Chunks stay in it after their uploads are deleted, until they
+//! haven't been seen for the project's `chunk_retention`.
+
+use std::{
+    collections::{HashMap, HashSet},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+use unreql::{
+    cmd::options::{BetweenOptions, ReturnChanges, UpdateOptions},
+    r, rjson,
+    types::WriteStatus,
+};
+
+use super::{check_write, log_error, Chunk, DatabaseHandle, DbError, Dedup, UploadRow};
+
+pub(crate) const CHUNKS_TABLE: &str = "chunks";
+/// How many chunks are looked up or added in one query.
+const BATCH: usize = 1000;
+
+/// A chunk in the index. Its ID is `[project, hash]`.
+#[derive(Serialize, Deserialize)]
+struct ChunkRow {
+    id: (String, String),
+    /// The upload it was first seen in.
+    upload: String,
+    size: u64,
+    /// When it was last seen in an upload, in seconds since the epoch.
+    #[serde(default)]
+    last_seen: u64,
+}
+
+/// Removes the chunks that haven't been seen in any of a project's uploads since `before`.
+/// Returns how many were removed.
+pub async fn prune(conn: &DatabaseHandle, project: String, before: u64) -> Result<u64, DbError> {
+    let s: WriteStatus = r
+        .db("atuploads")
+        .table(CHUNKS_TABLE)
+        .between(
+            rjson!([project.clone(), r.minval()]),
+            rjson!([project, before]),
+            BetweenOptions::new().index("project_last_seen".to_string()),
+        )
+        .delete(())
+        .exec(&conn.pool)
+        .await
+        .map_err(log_error)?;
+    Ok(s.deleted as u64)
+}
+
+impl UploadRow {
+    /// Looks the upload's chunks up in the index, adds the ones it didn't have, and records how
+    /// much of the upload was already there. Chunks first seen in this upload, like on a retry,
+    /// don't count.
+    pub async fn record_dedup(
+        &mut self,
+        conn: &DatabaseHandle,
+        chunks: &[Chunk],
+    ) -> Result<Dedup, DbError> {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let mut sizes = HashMap::new();
+        let hashes: Vec<&str> = chunks
+            .iter()
+            .filter(|c| sizes.insert(c.hash.as_str(), c.size).is_none())
+            .map(|c| c.hash.as_str())
+            .collect();
+        let mut seen = HashSet::new();
+        for batch in hashes.chunks(BATCH) {
+            let keys: Vec<_> = batch
+                .iter()
+                .map(|h| rjson!([self.project.clone(), h.to_string()]))
+                .collect();
+            // Marks the ones that are there as seen, and gets them.
+            let found: WriteStatus<ChunkRow> = r
+                .db("atuploads")
+                .table(CHUNKS_TABLE)
+                .get_all(r.args(keys))
+                .update(r.with_opt(
+                    rjson!({"last_seen": now}),
+                    // Including the ones that were already seen this second.
+                    UpdateOptions {
+                        return_changes: Some(ReturnChanges::Always),
+                        ..Default::default()
+                    },
+                ))
+                .exec(&conn.pool)
+                .await
+                .map_err(log_error)?;
+            let found = found
+                .changes
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|c| c.new_val);
+            let found: HashSet<String> = found
+                .into_iter()
+                .map(|c| {
+                    if c.upload != self.id {
+                        seen.insert(c.id.1.clone());
+                    }
+                    c.id.1
+                })
+                .collect();
+            let new: Vec<ChunkRow> = batch
+                .iter()
+                .filter(|h| !found.contains(**h))
+                .map(|h| ChunkRow {
+                    id: (self.project.clone(), h.to_string()),
+                    upload: self.id.clone(),
+                    size: sizes[*h],
+                    last_seen: now,
+                })
+                .collect();
+            if new.is_empty() {
+                continue;
+            }
+            // Another worker might add some of the same chunks first. Those inserts fail, which
+            // is fine: the chunk is in the index either way.
+            let _: WriteStatus = r
+                .db("atuploads")
+                .table(CHUNKS_TABLE)
+                .insert(new)
+                .exec(&conn.pool)
+                .await
+                .map_err(log_error)?;
+        }
+
+        let dedup = Dedup::new(chunks, |h| seen.contains(h));
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table("uploads")
+            .get(self.id.clone())
+            .update(rjson!({
+                "dedup": dedup.clone(),
+            }))
+            .exec(&conn.pool)
+            .await;
+        check_write(s)?;
+        self.dedup = Some(dedup.clone());
+        Ok(dedup)
+    }
+}
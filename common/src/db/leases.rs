@@ -0,0 +1,106 @@
+//! Leases, so that when several servers share the database, only one of them runs each singleton
+//! task, like the reaper or the scrubber.
+//!
+//! A lease is a document in the leases table. Whoever wrote it holds it until it expires, and
+//! renews it every time it runs the task, so if the holder goes away another instance takes over
+//! once the lease runs out.
+
+use std::{process, time::Duration};
+
+use serde::Deserialize;
+use serde_json::Value;
+use unreql::{func, r, rjson, types::WriteStatus};
+
+use super::{check_write, log_error, DatabaseHandle, DbError, UploadRow};
+
+pub(crate) const LEASES_TABLE: &str = "leases";
+
+/// A lease on a singleton task.
+pub struct Lease {
+    /// The table the lease is kept in.
+    table: &'static str,
+    name: String,
+    /// Who we are. This is unique to the process.
+    holder: String,
+    /// How long the lease lasts without being renewed, in seconds.
+    ttl: u64,
+}
+
+#[derive(Deserialize)]
+struct LeaseRow {
+    holder: String,
+}
+
+impl Lease {
+    /// Creates a lease on the task called `name`, which lasts for `ttl` without being renewed.
+    /// This should be a good deal longer than the time between renewals.
+    pub fn new(name: &str, ttl: Duration) -> Self {
+        Self::in_table(LEASES_TABLE, name, ttl)
+    }
+
+    /// Like `new`, but the lease is kept in another table, for before the leases table exists.
+    pub(crate) fn in_table(table: &'static str, name: &str, ttl: Duration) -> Self {
+        let started = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        Self {
+            table,
+            name: name.to_string(),
+            holder: format!("{}-{started}", process::id()),
+            ttl: ttl.as_secs(),
+        }
+    }
+
+    /// Takes the lease if nobody holds it, or renews it if we do. Returns whether we hold it.
+    pub async fn acquire(&self, conn: &DatabaseHandle) -> Result<bool, DbError> {
+        let now = UploadRow::now();
+        let holder = self.holder.clone();
+        let ours = rjson!({
+            "id": self.name.clone(),
+            "holder": self.holder.clone(),
+            "expires": now + self.ttl,
+        });
+        // This is atomic, so only one instance can win.
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table(self.table)
+            .get(self.name.clone())
+            .replace(func!(|row| {
+                r.branch(
+                    row.clone()
+                        .eq(Value::Null)
+                        .or(row.clone().g("expires").lt(now))
+                        .or(row.clone().g("holder").eq(holder.clone())),
+                    ours.clone(),
+                    row,
+                )
+            }))
+            .exec(&conn.pool)
+            .await;
+        check_write(s)?;
+        let current: Option<LeaseRow> = r
+            .db("atuploads")
+            .table(self.table)
+            .get(self.name.clone())
+            .exec(&conn.pool)
+            .await
+            .map_err(log_error)?;
+        Ok(current.is_some_and(|l| l.holder == self.holder))
+    }
+
+    /// Gives up the lease if we hold it, so another instance can take over right away.
+    pub async fn release(&self, conn: &DatabaseHandle) -> Result<(), DbError> {
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table(self.table)
+            .get_all(self.name.clone())
+            .filter(rjson!({
+                "holder": self.holder.clone(),
+            }))
+            .delete(())
+            .exec(&conn.pool)
+            .await;
+        check_write(s).map(|_| ())
+    }
+}
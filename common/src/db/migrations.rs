@@ -0,0 +1,555 @@
+//! Versioned changes to the database: tables, indexes, and fields added to rows.
+//!
+//! Migrations are applied in order, and the last one that was applied is recorded in the `meta`
+//! table, so each one only runs once. They can be run more than once safely anyway, in case
+//! something was interrupted. To change the schema, add a migration to the end of MIGRATIONS and
+//! a case for it in `apply`; never change one that has already been released.
+
+use std::time::{Duration, SystemTime};
+
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use unreql::{cmd::options::IndexCreateOptions, func, r, rjson, types::WriteStatus};
+
+use super::{
+    archive::ARCHIVE_TABLE,
+    audit::AUDIT_TABLE,
+    bans::BANS_TABLE,
+    check_write,
+    chunks::CHUNKS_TABLE,
+    leases::{Lease, LEASES_TABLE},
+    uploaders::{self, UPLOADERS_TABLE},
+    DatabaseHandle, DbError, Status, TransferStats, UploadRow,
+};
+
+const DB: &str = "atuploads";
+const META_TABLE: &str = "meta";
+/// The ID of the document in the meta table that holds the schema version.
+const SCHEMA_KEY: &str = "schema";
+/// The ID of the lease in the meta table that whoever is migrating holds. See `lock`.
+const LOCK_KEY: &str = "migrating";
+/// How long the lock lasts without being renewed. It's renewed before each migration, so this
+/// only has to be longer than the slowest one.
+const LOCK_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// Descriptions of the migrations. Migration `n` is at index `n - 1`.
+pub const MIGRATIONS: &[&str] = &[
+    "create the uploads and meta tables, and the nf_status index",
+    "add the status index",
+    "fill in defaults for fields added to uploads since they were created",
+    "add the items index",
+    "create the leases table",
+    "create the audit table and its indexes",
+    "create the uploaders table and its bytes index, and fill it in from existing uploads",
+    "add the hash index",
+    "fill in when uploads were created, and add the uploader_status and uploader_created indexes",
+    "create the bans table",
+    "record the row format version on uploads",
+    "create the chunks table",
+    "create the uploads_archive table and its items and hash indexes",
+    "convert rows from older schemas, and fill in defaults for fields added to uploads since migration 3",
+    "record when chunks were last seen, and add the project_last_seen index",
+];
+
+#[derive(Serialize, Deserialize)]
+struct SchemaVersion {
+    id: String,
+    version: u32,
+}
+
+/// What `convert_legacy_rows` found.
+#[derive(Debug, Default)]
+pub struct LegacyRows {
+    /// How many rows were converted, or would have been.
+    pub converted: u64,
+    /// The IDs of rows whose status couldn't be made sense of. They have to be fixed by hand.
+    pub unknown: Vec<String>,
+}
+
+/// Turns an unreql error into a DbError, logging it.
+fn log_error(e: unreql::Error) -> DbError {
+    println!("warning: database error while migrating, see: {e:?}");
+    DbError::Other
+}
+
+async fn has_db(conn: &DatabaseHandle) -> Result<bool, DbError> {
+    let dbs: Vec<String> = r.db_list().exec(&conn.pool).await.map_err(log_error)?;
+    Ok(dbs.iter().any(|d| d == DB))
+}
+
+// Another process might create the database or a table between checking for it and creating
+// it, so creating it can fail because it's already there.
+
+async fn ensure_db(conn: &DatabaseHandle) -> Result<(), DbError> {
+    if has_db(conn).await? {
+        return Ok(());
+    }
+    let created: unreql::Result<Value> = r.db_create(DB).exec(&conn.pool).await;
+    match created {
+        Err(e) if !has_db(conn).await? => Err(log_error(e)),
+        _ => Ok(()),
+    }
+}
+
+async fn ensure_table(conn: &DatabaseHandle, table: &'static str) -> Result<(), DbError> {
+    if has_table(conn, table).await? {
+        return Ok(());
+    }
+    let created: unreql::Result<Value> = r.db(DB).table_create(table).exec(&conn.pool).await;
+    match created {
+        Err(e) if !has_table(conn, table).await? => Err(log_error(e)),
+        _ => Ok(()),
+    }
+}
+
+async fn has_index(
+    conn: &DatabaseHandle,
+    table: &'static str,
+    index: &str,
+) -> Result<bool, DbError> {
+    let indexes: Vec<String> = r
+        .db(DB)
+        .table(table)
+        .index_list()
+        .exec(&conn.pool)
+        .await
+        .map_err(log_error)?;
+    Ok(indexes.iter().any(|i| i == index))
+}
+
+/// Waits for new indexes to be ready, so queries don't fail right after migrating.
+async fn wait_for_indexes(conn: &DatabaseHandle, table: &'static str) -> Result<(), DbError> {
+    let _: Value = r
+        .db(DB)
+        .table(table)
+        .index_wait(())
+        .exec(&conn.pool)
+        .await
+        .map_err(log_error)?;
+    Ok(())
+}
+
+/// Applies one migration.
+async fn apply(conn: &DatabaseHandle, version: u32) -> Result<(), DbError> {
+    match version {
+        1 => {
+            ensure_db(conn).await?;
+            ensure_table(conn, "uploads").await?;
+            ensure_table(conn, META_TABLE).await?;
+            if !has_index(conn, "uploads", "nf_status").await? {
+                // [project: String, pipeline: String, status: Status, processing: bool]
+                let _: Value = r
+                    .db(DB)
+                    .table("uploads")
+                    .index_create((
+                        "nf_status",
+                        func!(|row| {
+                            rjson!([
+                                row.clone().g("project"),
+                                row.clone().g("pipeline"),
+                                row.clone().g("status"),
+                                row.g("processing"),
+                            ])
+                        }),
+                    ))
+                    .exec(&conn.pool)
+                    .await
+                    .map_err(log_error)?;
+            }
+            wait_for_indexes(conn, "uploads").await
+        }
+        2 => {
+            if !has_index(conn, "uploads", "status").await? {
+                let _: Value = r
+                    .db(DB)
+                    .table("uploads")
+                    .index_create("status")
+                    .exec(&conn.pool)
+                    .await
+                    .map_err(log_error)?;
+            }
+            wait_for_indexes(conn, "uploads").await
+        }
+        3 => {
+            // Queries filter on these, and missing fields don't match any filter. Fields that are
+            // already there are kept as they are.
+            let s: unreql::Result<WriteStatus> = r
+                .db(DB)
+                .table("uploads")
+                .update(func!(|row| {
+                    rjson!({
+                        "server_hash": row.clone().g("server_hash").default(Value::Null),
+                        "chunk_size": row.clone().g("chunk_size").default(Value::Null),
+                        "high_water_mark": row.clone().g("high_water_mark").default(0),
+                        "files_removed": row.clone().g("files_removed").default(false),
+                        "delete_after": row.clone().g("delete_after").default(Value::Null),
+                        "deleted_from": row.clone().g("deleted_from").default(Value::Null),
+                        "scrubbed_at": row.clone().g("scrubbed_at").default(Value::Null),
+                        "progress": row.clone().g("progress").default(Value::Null),
+                        "attempts": row.clone().g("attempts").default(0),
+                        "last_error": row.clone().g("last_error").default(Value::Null),
+                        "errors": row.clone().g("errors").default(rjson!([])),
+                        "dead_from": row.clone().g("dead_from").default(Value::Null),
+                        "retry_after": row.g("retry_after").default(Value::Null),
+                    })
+                }))
+                .exec(&conn.pool)
+                .await;
+            check_write(s).map(|_| ())
+        }
+        4 => {
+            if !has_index(conn, "uploads", "items").await? {
+                // One entry per item in the upload.
+                let _: Value = r
+                    .db(DB)
+                    .table("uploads")
+                    .index_create(r.with_opt(
+                        ("items", func!(|row| row.g("metadata").g("items"))),
+                        IndexCreateOptions {
+                            multi: Some(true),
+                            ..Default::default()
+                        },
+                    ))
+                    .exec(&conn.pool)
+                    .await
+                    .map_err(log_error)?;
+            }
+            wait_for_indexes(conn, "uploads").await
+        }
+        5 => ensure_table(conn, LEASES_TABLE).await,
+        6 => {
+            ensure_table(conn, AUDIT_TABLE).await?;
+            for (index, multi) in [("time", false), ("uploads", true)] {
+                if has_index(conn, AUDIT_TABLE, index).await? {
+                    continue;
+                }
+                let _: Value = r
+                    .db(DB)
+                    .table(AUDIT_TABLE)
+                    .index_create(r.with_opt(
+                        index,
+                        IndexCreateOptions {
+                            multi: Some(multi),
+                            ..Default::default()
+                        },
+                    ))
+                    .exec(&conn.pool)
+                    .await
+                    .map_err(log_error)?;
+            }
+            wait_for_indexes(conn, AUDIT_TABLE).await
+        }
+        7 => {
+            ensure_table(conn, UPLOADERS_TABLE).await?;
+            if !has_index(conn, UPLOADERS_TABLE, "bytes").await? {
+                let _: Value = r
+                    .db(DB)
+                    .table(UPLOADERS_TABLE)
+                    .index_create("bytes")
+                    .exec(&conn.pool)
+                    .await
+                    .map_err(log_error)?;
+            }
+            wait_for_indexes(conn, UPLOADERS_TABLE).await?;
+            uploaders::backfill(conn).await
+        }
+        8 => {
+            if !has_index(conn, "uploads", "hash").await? {
+                // [project: String, hash: String]
+                let _: Value = r
+                    .db(DB)
+                    .table("uploads")
+                    .index_create((
+                        "hash",
+                        func!(|row| {
+                            rjson!([row.clone().g("project"), row.g("file").g("hash")])
+                        }),
+                    ))
+                    .exec(&conn.pool)
+                    .await
+                    .map_err(log_error)?;
+            }
+            wait_for_indexes(conn, "uploads").await
+        }
+        9 => {
+            // Near enough for old rows.
+            let s: unreql::Result<WriteStatus> = r
+                .db(DB)
+                .table("uploads")
+                .update(func!(|row| {
+                    rjson!({
+                        "created": row.clone().g("created").default(row.g("last_activity")),
+                    })
+                }))
+                .exec(&conn.pool)
+                .await;
+            check_write(s)?;
+            for (index, field) in [
+                ("uploader_status", "status"),
+                ("uploader_created", "created"),
+            ] {
+                if has_index(conn, "uploads", index).await? {
+                    continue;
+                }
+                // [uploader: String, field]
+                let _: Value = r
+                    .db(DB)
+                    .table("uploads")
+                    .index_create((
+                        index,
+                        func!(|row| {
+                            rjson!([row.clone().g("metadata").g("uploader"), row.g(field)])
+                        }),
+                    ))
+                    .exec(&conn.pool)
+                    .await
+                    .map_err(log_error)?;
+            }
+            wait_for_indexes(conn, "uploads").await
+        }
+        10 => ensure_table(conn, BANS_TABLE).await,
+        11 => UploadRow::upgrade_old_rows(conn).await.map(|_| ()),
+        12 => ensure_table(conn, CHUNKS_TABLE).await,
+        13 => {
+            ensure_table(conn, ARCHIVE_TABLE).await?;
+            if !has_index(conn, ARCHIVE_TABLE, "items").await? {
+                // The same as the uploads table's.
+                let _: Value = r
+                    .db(DB)
+                    .table(ARCHIVE_TABLE)
+                    .index_create(r.with_opt(
+                        ("items", func!(|row| row.g("metadata").g("items"))),
+                        IndexCreateOptions {
+                            multi: Some(true),
+                            ..Default::default()
+                        },
+                    ))
+                    .exec(&conn.pool)
+                    .await
+                    .map_err(log_error)?;
+            }
+            if !has_index(conn, ARCHIVE_TABLE, "hash").await? {
+                // [project: String, hash: String]
+                let _: Value = r
+                    .db(DB)
+                    .table(ARCHIVE_TABLE)
+                    .index_create((
+                        "hash",
+                        func!(|row| {
+                            rjson!([row.clone().g("project"), row.g("file").g("hash")])
+                        }),
+                    ))
+                    .exec(&conn.pool)
+                    .await
+                    .map_err(log_error)?;
+            }
+            wait_for_indexes(conn, ARCHIVE_TABLE).await
+        }
+        14 => {
+            convert_legacy_rows(conn, false).await?;
+            // Like migration 3. `processing` is in the nf_status index, so rows without it are
+            // never checked out.
+            let s: unreql::Result<WriteStatus> = r
+                .db(DB)
+                .table("uploads")
+                .update(func!(|row| {
+                    rjson!({
+                        "processing": row.clone().g("processing").default(false),
+                        "pinned": row.clone().g("pinned").default(false),
+                        "archived": row.clone().g("archived").default(false),
+                        "members": row.clone().g("members").default(rjson!([])),
+                        "received": row.clone().g("received").default(rjson!([])),
+                        "transfer": row.g("transfer").default(rjson!(TransferStats::default())),
+                    })
+                }))
+                .exec(&conn.pool)
+                .await;
+            check_write(s).map(|_| ())
+        }
+        15 => {
+            // Chunks from before this count as seen now, so they aren't all pruned at once.
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap()
+                .as_secs();
+            let _: WriteStatus = r
+                .db(DB)
+                .table(CHUNKS_TABLE)
+                .filter(func!(|row| row.has_fields("last_seen").not()))
+                .update(rjson!({"last_seen": now}))
+                .exec(&conn.pool)
+                .await
+                .map_err(log_error)?;
+            if !has_index(conn, CHUNKS_TABLE, "project_last_seen").await? {
+                // [project: String, last_seen: u64]
+                let _: Value = r
+                    .db(DB)
+                    .table(CHUNKS_TABLE)
+                    .index_create((
+                        "project_last_seen",
+                        func!(|row| rjson!([row.clone().g("id").nth(0), row.g("last_seen")])),
+                    ))
+                    .exec(&conn.pool)
+                    .await
+                    .map_err(log_error)?;
+            }
+            wait_for_indexes(conn, CHUNKS_TABLE).await
+        }
+        _ => unreachable!("no migration {version}"),
+    }
+}
+
+/// Whether the table has been created yet.
+pub async fn has_table(conn: &DatabaseHandle, table: &str) -> Result<bool, DbError> {
+    if !has_db(conn).await? {
+        return Ok(false);
+    }
+    let tables: Vec<String> = r
+        .db(DB)
+        .table_list()
+        .exec(&conn.pool)
+        .await
+        .map_err(log_error)?;
+    Ok(tables.iter().any(|t| t == table))
+}
+
+/// Gets the version of the schema, which is the number of migrations that have been applied.
+pub async fn current_version(conn: &DatabaseHandle) -> Result<u32, DbError> {
+    if !has_table(conn, META_TABLE).await? {
+        return Ok(0);
+    }
+    let version: Option<SchemaVersion> = r
+        .db(DB)
+        .table(META_TABLE)
+        .get(SCHEMA_KEY)
+        .exec(&conn.pool)
+        .await
+        .map_err(log_error)?;
+    Ok(version.map_or(0, |v| v.version))
+}
+
+async fn set_version(conn: &DatabaseHandle, version: u32) -> Result<(), DbError> {
+    let s: unreql::Result<WriteStatus> = r
+        .db(DB)
+        .table(META_TABLE)
+        .get(SCHEMA_KEY)
+        .replace(rjson!({
+            "id": SCHEMA_KEY,
+            "version": version,
+        }))
+        .exec(&conn.pool)
+        .await;
+    check_write(s).map(|_| ())
+}
+
+/// Gets the migrations that haven't been applied yet, in order.
+///
+/// Fails if the database is newer than this build knows about, since it might not understand
+/// the rows.
+pub async fn pending(conn: &DatabaseHandle) -> Result<Vec<u32>, DbError> {
+    let current = current_version(conn).await?;
+    let latest = MIGRATIONS.len() as u32;
+    if current > latest {
+        println!("warning: database schema is at version {current}, but the latest this build knows is {latest}");
+        return Err(DbError::Other);
+    }
+    Ok((current + 1..=latest).collect())
+}
+
+/// Takes the lock that stops two processes, like a server and a worker starting together, from
+/// migrating at once, waiting for whoever has it to finish. The lock is a lease in the meta
+/// table, which is created for it if it isn't there yet. Give it back with `Lease::release`.
+pub async fn lock(conn: &DatabaseHandle) -> Result<Lease, DbError> {
+    ensure_db(conn).await?;
+    ensure_table(conn, META_TABLE).await?;
+    let lock = Lease::in_table(META_TABLE, LOCK_KEY, LOCK_TTL);
+    let mut waited = false;
+    while !lock.acquire(conn).await? {
+        if !waited {
+            println!("waiting for another process to finish migrating the database");
+            waited = true;
+        }
+        tokio::time::sleep(Duration::from_secs(1)).await;
+    }
+    Ok(lock)
+}
+
+/// Applies one migration and records that it was. They have to be applied in order, while
+/// holding `lock`, which is renewed first.
+pub async fn apply_one(conn: &DatabaseHandle, lock: &Lease, version: u32) -> Result<(), DbError> {
+    if !lock.acquire(conn).await? {
+        println!("warning: lost the migration lock before migration {version}");
+        return Err(DbError::Other);
+    }
+    apply(conn, version).await?;
+    set_version(conn, version).await
+}
+
+/// Applies any migrations that haven't been yet. Returns the versions that were applied.
+pub async fn migrate(conn: &DatabaseHandle) -> Result<Vec<u32>, DbError> {
+    // Don't bother with the lock if there's nothing to do, which is almost always.
+    if pending(conn).await?.is_empty() {
+        return Ok(Vec::new());
+    }
+    let lock = lock(conn).await?;
+    let result = async {
+        // Whoever had the lock before might have applied them already.
+        let pending = pending(conn).await?;
+        for &version in &pending {
+            apply_one(conn, &lock, version).await?;
+        }
+        Ok(pending)
+    }
+    .await;
+    lock.release(conn).await?;
+    result
+}
+
+/// Converts rows written in older schemas, which might have `writing` instead of `processing`
+/// or statuses in an old format (see `UploadRow::legacy_changes`). With `dry_run`, only finds
+/// them. Migration 14 does this once, but `bullseye-migrate` can do it again for rows that old
+/// builds wrote since.
+pub async fn convert_legacy_rows(
+    conn: &DatabaseHandle,
+    dry_run: bool,
+) -> Result<LegacyRows, DbError> {
+    let rows: Vec<Value> = r
+        .db(DB)
+        .table("uploads")
+        .filter(func!(|row| {
+            let known = r
+                .expr(rjson!(Status::NAMES))
+                .contains(row.clone().g("status"));
+            row.has_fields("writing").or(known.not())
+        }))
+        .exec_to_vec(&conn.pool)
+        .await
+        .map_err(log_error)?;
+    let mut found = LegacyRows::default();
+    for row in rows {
+        let id = row["id"].as_str().unwrap_or_default().to_string();
+        let changes = UploadRow::legacy_changes(&row);
+        let status = changes.get("status").or(row.get("status")).cloned();
+        if status.is_none_or(|s| serde_json::from_value::<Status>(s).is_err()) {
+            found.unknown.push(id.clone());
+        }
+        if changes.is_empty() && row.get("writing").is_none() {
+            continue;
+        }
+        found.converted += 1;
+        if dry_run {
+            continue;
+        }
+        let s: unreql::Result<WriteStatus> = r
+            .db(DB)
+            .table("uploads")
+            .get(id)
+            .replace(func!(|row| row
+                .without("writing")
+                .merge(rjson!(changes.clone()))))
+            .exec(&conn.pool)
+            .await;
+        check_write(s)?;
+    }
+    Ok(found)
+}
@@ -0,0 +1,122 @@
+//! Running totals of what each uploader has uploaded, for the leaderboard. They're kept in their
+//! own table, so they don't go down when old uploads are purged.
+
+use std::collections::HashMap;
+
+use serde::Deserialize;
+use serde_json::Value;
+use unreql::{func, r, rjson, types::WriteStatus};
+
+use super::{check_write, log_error, DatabaseHandle, DbError, Status, UploaderStats};
+
+pub(crate) const UPLOADERS_TABLE: &str = "uploaders";
+
+impl UploaderStats {
+    /// Adds a finished upload of `bytes` bytes to an uploader's totals.
+    pub async fn count(conn: &DatabaseHandle, uploader: String, bytes: u64) -> Result<(), DbError> {
+        let first = rjson!({
+            "id": uploader.clone(),
+            "bytes": bytes,
+            "items": 1,
+        });
+        // This is atomic, so concurrent finishes don't lose counts.
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table(UPLOADERS_TABLE)
+            .get(uploader)
+            .replace(func!(|row| {
+                r.branch(
+                    row.clone().eq(Value::Null),
+                    first.clone(),
+                    rjson!({
+                        "id": row.clone().g("id"),
+                        "bytes": row.clone().g("bytes").add(bytes),
+                        "items": row.g("items").add(1),
+                    }),
+                )
+            }))
+            .exec(&conn.pool)
+            .await;
+        check_write(s).map(|_| ())
+    }
+
+    /// Lists the `limit` uploaders who have uploaded the most bytes, most first.
+    pub async fn leaderboard(conn: &DatabaseHandle, limit: usize) -> Result<Vec<Self>, DbError> {
+        conn.table_for_reads(UPLOADERS_TABLE)
+            .order_by(r.index(r.desc("bytes")))
+            .limit(limit)
+            .exec_to_vec(&conn.reads)
+            .await
+            .map_err(log_error)
+    }
+
+    /// Gets one uploader's totals, if they have finished anything.
+    pub async fn get(conn: &DatabaseHandle, uploader: String) -> Result<Option<Self>, DbError> {
+        conn.table_for_reads(UPLOADERS_TABLE)
+            .get(uploader)
+            .exec(&conn.reads)
+            .await
+            .map_err(log_error)
+    }
+}
+
+#[derive(Deserialize)]
+struct CountedUploader {
+    uploader: String,
+}
+
+#[derive(Deserialize)]
+struct CountedFile {
+    size: u64,
+}
+
+/// The parts of an upload that the totals are made of.
+#[derive(Deserialize)]
+struct Counted {
+    metadata: CountedUploader,
+    file: CountedFile,
+}
+
+/// Works out the totals from the uploads that are still in the database, and overwrites the
+/// stored ones with them. Used when the table is created.
+pub(crate) async fn backfill(conn: &DatabaseHandle) -> Result<(), DbError> {
+    let finished = Status::Finished;
+    // Everything that got all the way through its pipeline, like UploadRow::change_status counts,
+    // including ones that have been deleted since.
+    let rows: Vec<Counted> = r
+        .db("atuploads")
+        .table("uploads")
+        .filter(func!(|row| {
+            row.clone().g("status").eq(rjson!(finished.clone())).or(row
+                .g("deleted_from")
+                .default(Value::Null)
+                .eq(rjson!(finished)))
+        }))
+        .exec_to_vec(&conn.pool)
+        .await
+        .map_err(log_error)?;
+    let mut totals: HashMap<String, UploaderStats> = HashMap::new();
+    for row in rows {
+        let uploader = row.metadata.uploader;
+        let stats = totals
+            .entry(uploader.clone())
+            .or_insert_with(|| UploaderStats {
+                uploader,
+                bytes: 0,
+                items: 0,
+            });
+        stats.bytes += row.file.size;
+        stats.items += 1;
+    }
+    for stats in totals.into_values() {
+        let s: unreql::Result<WriteStatus> = r
+            .db("atuploads")
+            .table(UPLOADERS_TABLE)
+            .get(stats.uploader.clone())
+            .replace(stats)
+            .exec(&conn.pool)
+            .await;
+        check_write(s)?;
+    }
+    Ok(())
+}
@@ -0,0 +1,256 @@
+//! Stable codes for the errors the server sends. Every error response has one next to its
+//! `ErrorablePayload`, along with a link to where it's explained, so a line from a client's log is
+//! enough to tell what went wrong. Codes are never renamed or reused, but new ones can be added,
+//! so clients shouldn't refuse ones they don't know.
+
+use std::fmt;
+
+use serde::{Deserialize, Serialize};
+
+#[cfg(feature = "db")]
+use crate::db::DbError;
+use crate::payloads::{ErrorablePayload, Rejection};
+
+/// Where the codes are explained. Each one has a heading of its own there.
+pub const DOCS_URL: &str = "https://github.com/TheTechRobo/bullseye/blob/main/docs/errors.md";
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    NotFound,
+    Invalid,
+    Conflict,
+    WrongStatus,
+    Unauthorized,
+    Unavailable,
+    Internal,
+    Io,
+    Database,
+    WriteFailed,
+    UnknownProject,
+    UnknownPipeline,
+    FileTooLarge,
+    FileTypeNotAllowed,
+    EncryptionNotAllowed,
+    ChunkSizeRequired,
+    MisalignedOffset,
+    OffsetRegressed,
+    AlreadyReceived,
+    BadSignature,
+    TooManyActiveUploads,
+    DailyQuotaExceeded,
+    Banned,
+    DuplicateUpload,
+}
+
+impl ErrorCode {
+    pub const ALL: [ErrorCode; 24] = [
+        Self::NotFound,
+        Self::Invalid,
+        Self::Conflict,
+        Self::WrongStatus,
+        Self::Unauthorized,
+        Self::Unavailable,
+        Self::Internal,
+        Self::Io,
+        Self::Database,
+        Self::WriteFailed,
+        Self::UnknownProject,
+        Self::UnknownPipeline,
+        Self::FileTooLarge,
+        Self::FileTypeNotAllowed,
+        Self::EncryptionNotAllowed,
+        Self::ChunkSizeRequired,
+        Self::MisalignedOffset,
+        Self::OffsetRegressed,
+        Self::AlreadyReceived,
+        Self::BadSignature,
+        Self::TooManyActiveUploads,
+        Self::DailyQuotaExceeded,
+        Self::Banned,
+        Self::DuplicateUpload,
+    ];
+
+    /// The code as it's sent.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::NotFound => "not_found",
+            Self::Invalid => "invalid",
+            Self::Conflict => "conflict",
+            Self::WrongStatus => "wrong_status",
+            Self::Unauthorized => "unauthorized",
+            Self::Unavailable => "unavailable",
+            Self::Internal => "internal",
+            Self::Io => "io",
+            Self::Database => "database",
+            Self::WriteFailed => "write_failed",
+            Self::UnknownProject => "unknown_project",
+            Self::UnknownPipeline => "unknown_pipeline",
+            Self::FileTooLarge => "file_too_large",
+            Self::FileTypeNotAllowed => "file_type_not_allowed",
+            Self::EncryptionNotAllowed => "encryption_not_allowed",
+            Self::ChunkSizeRequired => "chunk_size_required",
+            Self::MisalignedOffset => "misaligned_offset",
+            Self::OffsetRegressed => "offset_regressed",
+            Self::AlreadyReceived => "already_received",
+            Self::BadSignature => "bad_signature",
+            Self::TooManyActiveUploads => "too_many_active_uploads",
+            Self::DailyQuotaExceeded => "daily_quota_exceeded",
+            Self::Banned => "banned",
+            Self::DuplicateUpload => "duplicate_upload",
+        }
+    }
+
+    /// Where this code is explained.
+    pub fn docs(self) -> String {
+        format!("{DOCS_URL}#{self}")
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl From<&Rejection> for ErrorCode {
+    fn from(rejection: &Rejection) -> Self {
+        match rejection {
+            Rejection::UnknownProject => Self::UnknownProject,
+            Rejection::UnknownPipeline => Self::UnknownPipeline,
+            Rejection::FileTooLarge { .. } => Self::FileTooLarge,
+            Rejection::FileTypeNotAllowed { .. } => Self::FileTypeNotAllowed,
+            Rejection::EncryptionNotAllowed => Self::EncryptionNotAllowed,
+            Rejection::ChunkSizeRequired => Self::ChunkSizeRequired,
+            Rejection::MisalignedOffset { .. } => Self::MisalignedOffset,
+            Rejection::OffsetRegressed { .. } => Self::OffsetRegressed,
+            Rejection::AlreadyReceived => Self::AlreadyReceived,
+            Rejection::BadSignature => Self::BadSignature,
+            Rejection::TooManyActiveUploads { .. } => Self::TooManyActiveUploads,
+            Rejection::DailyQuotaExceeded { .. } => Self::DailyQuotaExceeded,
+            Rejection::Banned { .. } => Self::Banned,
+            Rejection::DuplicateUpload { .. } => Self::DuplicateUpload,
+        }
+    }
+}
+
+#[cfg(feature = "db")]
+impl From<&DbError> for ErrorCode {
+    fn from(e: &DbError) -> Self {
+        match e {
+            DbError::NotFound => Self::NotFound,
+            DbError::WriteFailed => Self::WriteFailed,
+            DbError::WrongStatus => Self::WrongStatus,
+            DbError::Other => Self::Database,
+        }
+    }
+}
+
+/// The body of an error response: the payload, the way clients have always read it, with the
+/// error's code and link alongside.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ErrorResponse {
+    #[serde(flatten)]
+    pub payload: ErrorablePayload<()>,
+    pub code: ErrorCode,
+    pub docs: String,
+}
+
+impl ErrorResponse {
+    pub fn new(payload: ErrorablePayload<()>, code: ErrorCode) -> Self {
+        Self {
+            payload,
+            code,
+            docs: code.docs(),
+        }
+    }
+}
+
+/// The code and link from an error response, as a client reads them. Servers from before the
+/// codes don't send them, and codes are kept as strings so ones added since still get logged.
+#[derive(Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ErrorReference {
+    #[serde(default)]
+    pub code: Option<String>,
+    #[serde(default)]
+    pub docs: Option<String>,
+}
+
+impl ErrorReference {
+    /// Reads them from a response body, if they're there.
+    pub fn from_body(body: &str) -> Self {
+        serde_json::from_str(body).unwrap_or_default()
+    }
+}
+
+/// Writes nothing if there's no code, so it can go straight after a message.
+impl fmt::Display for ErrorReference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match (&self.code, &self.docs) {
+            (Some(code), Some(docs)) => write!(f, " (error {code}, see {docs})"),
+            (Some(code), None) => write!(f, " (error {code})"),
+            (None, _) => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{ErrorCode, ErrorReference, ErrorResponse, DOCS_URL};
+    use crate::payloads::{ErrorablePayload, Rejection};
+
+    /// Makes sure the codes are sent the way they're documented, and that every one is.
+    #[test]
+    fn test_codes() {
+        let docs = include_str!("../../docs/errors.md");
+        for code in ErrorCode::ALL {
+            assert_eq!(
+                serde_json::to_value(code).unwrap(),
+                code.as_str(),
+                "{code:?}"
+            );
+            assert!(
+                docs.contains(&format!("\n### {code}\n")),
+                "{code} isn't in docs/errors.md"
+            );
+        }
+        assert_eq!(
+            ErrorCode::BadSignature.docs(),
+            format!("{DOCS_URL}#bad_signature")
+        );
+        let rejection = Rejection::FileTooLarge { max_size: 1 };
+        assert_eq!(ErrorCode::from(&rejection), ErrorCode::FileTooLarge);
+    }
+
+    /// Ensures that clients that don't know about codes can still read error responses, and that
+    /// ones that do can read codes they don't know.
+    #[test]
+    fn test_error_response() {
+        let response = ErrorResponse::new(
+            ErrorablePayload::Rejected(Rejection::BadSignature),
+            ErrorCode::BadSignature,
+        );
+        let body = serde_json::to_string(&response).unwrap();
+        assert!(matches!(
+            serde_json::from_str(&body).unwrap(),
+            ErrorablePayload::<String>::Rejected(Rejection::BadSignature)
+        ));
+
+        let reference = ErrorReference::from_body(&body);
+        assert_eq!(reference.code.as_deref(), Some("bad_signature"));
+        assert_eq!(
+            reference.to_string(),
+            format!(" (error bad_signature, see {DOCS_URL}#bad_signature)")
+        );
+
+        let newer = r#"{"status": "err", "payload": "?", "code": "something_new"}"#;
+        assert_eq!(
+            ErrorReference::from_body(newer).to_string(),
+            " (error something_new)"
+        );
+        let older = r#"{"status": "not_found"}"#;
+        assert_eq!(ErrorReference::from_body(older), ErrorReference::default());
+    }
+}
@@ -1,21 +1,482 @@
+//! Helpers for packing uploads into megawarcs, in the format used by ArchiveTeam's megawarc tool.
+//!
+//! A megawarc is three files:
+//! - `NAME.megawarc.warc.gz`: every gzipped WARC, concatenated
+//! - `NAME.megawarc.warc.zst`: every zstd-compressed WARC, concatenated. Since a `.warc.zst` can
+//!   only have one dictionary, at the start, only WARCs with the same dictionary as the first one
+//!   go here, without their dictionary frame. The rest go in the tar.
+//! - `NAME.megawarc.tar`: everything that isn't a WARC
+//! - `NAME.megawarc.json`: an index with one JSON line per member, saying where it ended up
+
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
 use serde::{Deserialize, Serialize};
 
-use crate::db::UploadRow;
+pub use crate::data::{MegawarcLocation, MegawarcTarget};
+use crate::{
+    copy::{copy_to, Source},
+    db::UploadRow,
+    platform::wait_for_lock,
+    warc::{is_warc_zst, read_dictionary_frame, write_dictionary_frame},
+};
 
-#[derive(Serialize, Deserialize)]
-pub enum MegawarcLocation {
-    Warc,
-}
+/// Tar files are made of blocks of this size.
+const BLOCK_SIZE: u64 = 512;
+/// Tar files end with two empty blocks.
+const TRAILER_SIZE: u64 = 2 * BLOCK_SIZE;
 
-#[derive(Serialize, Deserialize)]
-pub struct MegawarcTarget {
-    pub container: MegawarcLocation,
-    pub offset: u64,
+/// The tar header fields of a member, as megawarc records them.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct MegawarcHeaderFields {
+    pub name: String,
     pub size: u64,
+    pub mtime: u64,
+    pub mode: u32,
 }
 
 #[derive(Serialize, Deserialize)]
 pub struct MegawarcMetadata {
     pub target: MegawarcTarget,
+    pub header_fields: MegawarcHeaderFields,
     pub upload_details: Option<UploadRow>,
 }
+
+/// A megawarc that uploads can be appended to.
+#[derive(Clone, Debug)]
+pub struct Megawarc {
+    prefix: PathBuf,
+}
+
+impl Megawarc {
+    /// The megawarc called `name` in `dir`. Its files are created when the first member is added.
+    pub fn new(dir: &Path, name: &str) -> Self {
+        Self {
+            prefix: dir.join(format!("{name}.megawarc")),
+        }
+    }
+
+    fn path(&self, extension: &str) -> PathBuf {
+        let mut path = self.prefix.clone().into_os_string();
+        path.push(extension);
+        path.into()
+    }
+
+    pub fn warc_path(&self) -> PathBuf {
+        self.path(".warc.gz")
+    }
+
+    pub fn warc_zst_path(&self) -> PathBuf {
+        self.path(".warc.zst")
+    }
+
+    pub fn tar_path(&self) -> PathBuf {
+        self.path(".tar")
+    }
+
+    pub fn index_path(&self) -> PathBuf {
+        self.path(".json")
+    }
+
+    /// Gets the container a file goes in, going by its name.
+    pub fn container(name: &str) -> MegawarcLocation {
+        if name.ends_with(".warc.gz") {
+            MegawarcLocation::Warc
+        } else if is_warc_zst(name) {
+            MegawarcLocation::WarcZst
+        } else {
+            MegawarcLocation::Tar
+        }
+    }
+
+    /// Appends an upload's data to the right container and adds it to the index, and returns where
+    /// it ended up. This blocks, including while somebody else is appending to the megawarc.
+    ///
+    /// The member is only there once it's in the index, which is written last. Both are synced
+    /// to disk before this returns. If anything goes wrong, or an earlier append was interrupted,
+    /// whatever was written to the containers past the last indexed member is cut off.
+    pub fn append<R: Source>(&self, row: &UploadRow, data: R) -> io::Result<MegawarcTarget> {
+        let mut index = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(self.index_path())?;
+        // This is released when the index is closed.
+        wait_for_lock(&index, true)?;
+        self.repair(&mut index)?;
+        match self.append_locked(&mut index, row, data) {
+            Ok(target) => Ok(target),
+            Err(e) => {
+                self.repair(&mut index)?;
+                Err(e)
+            }
+        }
+    }
+
+    fn append_locked<R: Source>(
+        &self,
+        index: &mut File,
+        row: &UploadRow,
+        mut data: R,
+    ) -> io::Result<MegawarcTarget> {
+        let name = row.current_file().name.clone();
+        let mtime = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(io::Error::other)?
+            .as_secs();
+        let mut header_fields = MegawarcHeaderFields {
+            name,
+            size: row.current_file().size,
+            mtime,
+            mode: 0o644,
+        };
+        // A WARC the client encrypted isn't one any more.
+        let container = match row.metadata().encryption {
+            Some(_) => MegawarcLocation::Tar,
+            None => Self::container(&header_fields.name),
+        };
+        let target = match container {
+            MegawarcLocation::Warc => {
+                // Not for appending, so the data can be copied inside the kernel. The index's lock
+                // keeps anyone else from writing to it.
+                let warc = OpenOptions::new()
+                    .write(true)
+                    .create(true)
+                    .truncate(false)
+                    .open(self.warc_path())?;
+                let offset = warc.metadata()?.len();
+                let size = copy_to(&mut data, &warc, offset, u64::MAX)?;
+                warc.sync_data()?;
+                header_fields.size = size;
+                MegawarcTarget {
+                    container: MegawarcLocation::Warc,
+                    offset,
+                    size,
+                }
+            }
+            MegawarcLocation::WarcZst => {
+                let start = data.stream_position()?;
+                match append_warc_zst(&self.warc_zst_path(), &mut data)? {
+                    Some(target) => target,
+                    None => {
+                        data.seek(SeekFrom::Start(start))?;
+                        append_tar(&self.tar_path(), &mut header_fields, &mut data)?
+                    }
+                }
+            }
+            MegawarcLocation::Tar => append_tar(&self.tar_path(), &mut header_fields, &mut data)?,
+        };
+
+        let mut line = serde_json::to_vec(&MegawarcMetadata {
+            target: target.clone(),
+            header_fields,
+            upload_details: Some(row.clone()),
+        })?;
+        line.push(b'\n');
+        index.write_all(&line)?;
+        index.sync_data()?;
+        Ok(target)
+    }
+
+    /// Cuts off anything past the last indexed member, including a partly written index line.
+    fn repair(&self, index: &mut File) -> io::Result<()> {
+        let mut data = Vec::new();
+        index.seek(SeekFrom::Start(0))?;
+        index.read_to_end(&mut data)?;
+        let valid = data.iter().rposition(|b| *b == b'\n').map_or(0, |i| i + 1);
+        if valid < data.len() {
+            index.set_len(valid as u64)?;
+        }
+        let mut ends = HashMap::new();
+        for line in data[..valid]
+            .split(|b| *b == b'\n')
+            .filter(|l| !l.is_empty())
+        {
+            let target = serde_json::from_slice::<MegawarcMetadata>(line)?.target;
+            let end = ends.entry(target.container).or_insert(0);
+            *end = (*end).max(target.offset + target.size);
+        }
+        for (container, path) in [
+            (MegawarcLocation::Warc, self.warc_path()),
+            (MegawarcLocation::WarcZst, self.warc_zst_path()),
+            (MegawarcLocation::Tar, self.tar_path()),
+        ] {
+            let end = ends.get(&container).copied().unwrap_or(0);
+            let expected = match container {
+                MegawarcLocation::Tar if end > 0 => end + TRAILER_SIZE,
+                _ => end,
+            };
+            let mut file = match OpenOptions::new().read(true).write(true).open(&path) {
+                Ok(f) => f,
+                Err(e) if e.kind() == io::ErrorKind::NotFound && end == 0 => continue,
+                Err(e) => return Err(e),
+            };
+            let len = file.metadata()?.len();
+            // An interrupted append to the tar might have only overwritten the trailer.
+            let intact = len == expected
+                && (container != MegawarcLocation::Tar || end == 0 || has_trailer(&mut file, len)?);
+            if !intact {
+                // The first call clears out any garbage where the tar trailer goes.
+                file.set_len(end)?;
+                file.set_len(expected)?;
+                file.sync_data()?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Adds the records of a `.warc.zst` to the end of the container. Returns None if its dictionary
+/// doesn't match the container's, in which case nothing is written.
+fn append_warc_zst<R: Source>(path: &Path, data: &mut R) -> io::Result<Option<MegawarcTarget>> {
+    let dictionary = read_dictionary_frame(data)?;
+    let mut warc = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    if warc.metadata()?.len() == 0 {
+        if let Some(dictionary) = &dictionary {
+            write_dictionary_frame(&mut warc, dictionary)?;
+        }
+    } else if read_dictionary_frame(&mut warc)? != dictionary {
+        return Ok(None);
+    }
+    let offset = warc.seek(SeekFrom::End(0))?;
+    let size = copy_to(data, &warc, offset, u64::MAX)?;
+    warc.sync_data()?;
+    Ok(Some(MegawarcTarget {
+        container: MegawarcLocation::WarcZst,
+        offset,
+        size,
+    }))
+}
+
+/// Adds a member to the end of a tar file, moving the trailer after it.
+fn append_tar<R: Source>(
+    path: &Path,
+    fields: &mut MegawarcHeaderFields,
+    data: &mut R,
+) -> io::Result<MegawarcTarget> {
+    let mut tar = OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(path)?;
+    let len = tar.metadata()?.len();
+    let offset = match len {
+        0 => 0,
+        len if len < TRAILER_SIZE || len % BLOCK_SIZE != 0 => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} is not a tar file", path.display()),
+            ))
+        }
+        len => {
+            if !has_trailer(&mut tar, len)? {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "tar file doesn't end with a trailer",
+                ));
+            }
+            len - TRAILER_SIZE
+        }
+    };
+    tar.seek(SeekFrom::Start(offset))?;
+
+    let mut header = tar::Header::new_gnu();
+    header.set_path(&fields.name)?;
+    header.set_size(fields.size);
+    header.set_mtime(fields.mtime);
+    header.set_mode(fields.mode);
+    header.set_entry_type(tar::EntryType::Regular);
+    header.set_cksum();
+    tar.write_all(header.as_bytes())?;
+    let size = copy_to(data, &tar, offset + BLOCK_SIZE, fields.size)?;
+    if size != fields.size {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            format!("expected {} bytes of data, got {size}", fields.size),
+        ));
+    }
+    tar.seek(SeekFrom::Start(offset + BLOCK_SIZE + size))?;
+    let padding = (BLOCK_SIZE - size % BLOCK_SIZE) % BLOCK_SIZE;
+    tar.write_all(&vec![0; (padding + TRAILER_SIZE) as usize])?;
+    tar.sync_data()?;
+    Ok(MegawarcTarget {
+        container: MegawarcLocation::Tar,
+        offset,
+        size: BLOCK_SIZE + size + padding,
+    })
+}
+
+/// Checks that the tar file ends with the trailer, so we don't overwrite a member.
+fn has_trailer(tar: &mut File, len: u64) -> io::Result<bool> {
+    let mut trailer = [0; TRAILER_SIZE as usize];
+    tar.seek(SeekFrom::Start(len - TRAILER_SIZE))?;
+    tar.read_exact(&mut trailer)?;
+    Ok(trailer.iter().all(|b| *b == 0))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::{BufRead, Cursor, Write},
+        path::PathBuf,
+    };
+
+    use super::{Megawarc, MegawarcLocation, MegawarcMetadata};
+    use crate::{crypt::ClientKey, data::UploadRow, warc::write_dictionary_frame};
+
+    fn row(name: &str, size: u64) -> UploadRow {
+        let mut row = UploadRow::blank();
+        row.file.name = name.to_string();
+        row.file.size = size;
+        row
+    }
+
+    #[test]
+    fn test_append() {
+        let dir = std::env::temp_dir().join(format!("bullseye-megawarc-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let megawarc = Megawarc::new(&dir, "test");
+
+        let warc = megawarc
+            .append(&row("a.warc.gz", 4), Cursor::new(b"WARC"))
+            .unwrap();
+        let text = megawarc
+            .append(&row("a.txt", 5), Cursor::new(b"hello"))
+            .unwrap();
+        let warc2 = megawarc
+            .append(&row("b.warc.gz", 3), Cursor::new(b"GZ!"))
+            .unwrap();
+        let text2 = megawarc
+            .append(&row("b.txt", 3), Cursor::new(b"bye"))
+            .unwrap();
+        assert_eq!(warc.container, MegawarcLocation::Warc);
+        assert_eq!((warc.offset, warc.size), (0, 4));
+        assert_eq!((warc2.offset, warc2.size), (4, 3));
+        assert_eq!(text.container, MegawarcLocation::Tar);
+        assert_eq!((text.offset, text.size), (0, 1024));
+        assert_eq!((text2.offset, text2.size), (1024, 1024));
+
+        assert_eq!(fs::read(megawarc.warc_path()).unwrap(), b"WARCGZ!");
+        let mut archive = tar::Archive::new(fs::File::open(megawarc.tar_path()).unwrap());
+        let names: Vec<String> = archive
+            .entries()
+            .unwrap()
+            .map(|e| e.unwrap().path().unwrap().display().to_string())
+            .collect();
+        assert_eq!(names, ["a.txt", "b.txt"]);
+
+        let index = fs::read(megawarc.index_path()).unwrap();
+        let lines: Vec<MegawarcMetadata> = index
+            .lines()
+            .map(|l| serde_json::from_str(&l.unwrap()).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 4);
+        assert_eq!(lines[1].target, text);
+        assert_eq!(lines[1].header_fields.name, "a.txt");
+        assert!(index.starts_with(br#"{"target":{"container":"warc","offset":0,"size":4}"#));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_append_encrypted() {
+        let dir = std::env::temp_dir().join(format!(
+            "bullseye-megawarc-encrypted-{}",
+            std::process::id()
+        ));
+        fs::create_dir_all(&dir).unwrap();
+        let megawarc = Megawarc::new(&dir, "test");
+        let mut encrypted = row("a.warc.gz", 4);
+        let key = ClientKey::from_hex(&"ab".repeat(32)).unwrap();
+        encrypted.metadata.encryption = Some(key.encryption("abc"));
+        let target = megawarc.append(&encrypted, Cursor::new(b"WARC")).unwrap();
+        assert_eq!(target.container, MegawarcLocation::Tar);
+        assert!(!megawarc.warc_path().exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_repair() {
+        let dir =
+            std::env::temp_dir().join(format!("bullseye-megawarc-repair-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let megawarc = Megawarc::new(&dir, "test");
+        megawarc
+            .append(&row("a.warc.gz", 4), Cursor::new(b"WARC"))
+            .unwrap();
+        megawarc
+            .append(&row("a.txt", 5), Cursor::new(b"hello"))
+            .unwrap();
+        let tar = fs::read(megawarc.tar_path()).unwrap();
+
+        // Pretend an append was interrupted partway through each file.
+        let garbage = |path: PathBuf| {
+            let mut f = fs::OpenOptions::new().append(true).open(path).unwrap();
+            f.write_all(b"garbage").unwrap();
+        };
+        garbage(megawarc.warc_path());
+        garbage(megawarc.tar_path());
+        garbage(megawarc.index_path());
+
+        // The file is shorter than it claims to be, so this fails, and is cut off again.
+        megawarc
+            .append(&row("b.txt", 10), Cursor::new(b"short"))
+            .unwrap_err();
+        assert_eq!(fs::read(megawarc.warc_path()).unwrap(), b"WARC");
+        assert_eq!(fs::read(megawarc.tar_path()).unwrap(), tar);
+        let index = fs::read(megawarc.index_path()).unwrap();
+        assert_eq!(index.lines().count(), 2);
+        assert!(index.ends_with(b"\n"));
+
+        let warc = megawarc
+            .append(&row("b.warc.gz", 3), Cursor::new(b"GZ!"))
+            .unwrap();
+        assert_eq!((warc.offset, warc.size), (4, 3));
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_append_zst() {
+        let dir =
+            std::env::temp_dir().join(format!("bullseye-megawarc-zst-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let megawarc = Megawarc::new(&dir, "test");
+        let file = |dictionary: &[u8], records: &[u8]| {
+            let mut rv = Vec::new();
+            write_dictionary_frame(&mut rv, dictionary).unwrap();
+            rv.extend(records);
+            Cursor::new(rv)
+        };
+
+        let a = megawarc
+            .append(&row("a.warc.zst", 14), file(b"dict", b"aa"))
+            .unwrap();
+        let b = megawarc
+            .append(&row("b.warc.zst", 15), file(b"dict", b"bbb"))
+            .unwrap();
+        // A different dictionary can't go in the same file.
+        let c = megawarc
+            .append(&row("c.warc.zst", 16), file(b"other", b"ccc"))
+            .unwrap();
+        assert_eq!(a.container, MegawarcLocation::WarcZst);
+        assert_eq!((a.offset, a.size), (12, 2));
+        assert_eq!((b.offset, b.size), (14, 3));
+        assert_eq!(c.container, MegawarcLocation::Tar);
+        assert_eq!(
+            fs::read(megawarc.warc_zst_path()).unwrap(),
+            file(b"dict", b"aabbb").into_inner()
+        );
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,15 +1,24 @@
-use std::{io, os::fd::RawFd};
+use std::io;
 
 use base16ct::lower::encode_string;
-// See the acquire_lock function for rationale.
-#[allow(deprecated)]
-use nix::{errno::Errno, fcntl::flock};
 use sha2::{Digest, Sha256};
 
+pub mod copy;
+pub mod crypt;
 pub mod data;
 #[cfg(feature = "db")]
 pub mod db;
+pub mod errors;
+pub mod manifest;
+pub mod merkle;
 pub mod payloads;
+pub mod pipeline;
+pub mod platform;
+pub mod registry;
+#[cfg(feature = "schema")]
+pub mod schema;
+pub mod signing;
+pub mod warc;
 #[cfg(feature = "db")]
 pub mod helpers;
 
@@ -20,32 +29,24 @@ pub fn hash_file<T: io::Read>(mut file: T) -> io::Result<String> {
     Ok(encode_string(&rv))
 }
 
-pub fn acquire_lock(fd: RawFd, exclusive: bool) -> io::Result<()> {
-    let arg = match exclusive {
-        true => nix::fcntl::FlockArg::LockExclusiveNonblock,
-        false => nix::fcntl::FlockArg::LockSharedNonblock,
-    };
-    // We can't use the Flock struct because it requires an owned std::File or OwnedFd. I'm not
-    // sure why it's so insistent on consuming the file. How does it expect you to *use* the file?
-    // We could theoretically duplicate the file handle, but why would we when there's a
-    // perfectly good deprecated function here?
-    #[allow(deprecated)]
-    let res = flock(fd, arg);
-    match res {
-        Ok(()) => Ok(()),
-        Err(e) => {
-            if e == Errno::EWOULDBLOCK { // The lock isn't available yet. Let the client retry.
-                Err(io::Error::other("file is locked"))
-            } else {
-                Err(io::Error::other(e))
-            }
-        }
+/// Hashes data that comes in pieces, giving the same result as `hash_file` on all of it.
+#[derive(Default)]
+pub struct StreamHasher(Sha256);
+
+impl StreamHasher {
+    pub fn update(&mut self, data: &[u8]) {
+        self.0.update(data);
+    }
+
+    pub fn finish(self) -> String {
+        let rv: [u8; 32] = self.0.finalize().into();
+        encode_string(&rv)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::hash_file;
+    use crate::{hash_file, StreamHasher};
 
     #[test]
     fn test_sha256() {
@@ -56,5 +57,14 @@ mod tests {
             hash_file(b).unwrap(),
         )
     }
+
+    #[test]
+    fn stream_hasher() {
+        let b = "This is a STRING!\n".as_bytes();
+        let mut hasher = StreamHasher::default();
+        hasher.update(&b[..5]);
+        hasher.update(&b[5..]);
+        assert_eq!(hasher.finish(), hash_file(b).unwrap());
+    }
 }
 
@@ -0,0 +1,108 @@
+//! Sidecar manifests stored next to each upload.
+//!
+//! The manifest contains a snapshot of the upload's row and the statuses it has been through, so
+//! the data directory can still be made sense of if the database is lost.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::data::{Status, UploadRow};
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct StatusChange {
+    pub status: Status,
+    /// Seconds since the epoch.
+    pub at: u64,
+    /// Anything a processor had to say about the change, like a command's stderr.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub note: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Manifest {
+    pub row: UploadRow,
+    pub history: Vec<StatusChange>,
+}
+
+/// Gets the path of the manifest for an upload.
+pub fn manifest_path(dir: &Path, id: &str) -> PathBuf {
+    dir.join(format!("{id}.json"))
+}
+
+/// Reads the manifest for an upload.
+pub fn read(dir: &Path, id: &str) -> io::Result<Manifest> {
+    let data = fs::read(manifest_path(dir, id))?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+/// Writes the current state of the row to its manifest, adding its status to the history if it
+/// changed. This does blocking I/O.
+pub fn record(row: &UploadRow) -> io::Result<()> {
+    record_note(row, None)
+}
+
+/// Like record, but always adds a history entry carrying the note.
+pub fn record_note(row: &UploadRow, note: Option<String>) -> io::Result<()> {
+    let dir = Path::new(&row.dir);
+    let mut history = match read(dir, &row.id) {
+        Ok(m) => m.history,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Vec::new(),
+        Err(e) => return Err(e),
+    };
+    if note.is_some() || history.last().map(|c| &c.status) != Some(&row.status) {
+        let at = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        history.push(StatusChange {
+            status: row.status.clone(),
+            at,
+            note,
+        });
+    }
+    let manifest = Manifest {
+        row: row.clone(),
+        history,
+    };
+    let path = manifest_path(dir, &row.id);
+    let mut tmp = path.clone().into_os_string();
+    tmp.push(".tmp");
+    fs::write(&tmp, serde_json::to_vec_pretty(&manifest)?)?;
+    fs::rename(tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::data::{Status, UploadRow};
+
+    #[test]
+    fn test_history() {
+        let dir = std::env::temp_dir().join(format!("bullseye-manifest-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let mut row = UploadRow::blank();
+        row.id = "manifest-test".to_string();
+        row.dir = dir.to_str().unwrap().to_string();
+        super::record(&row).unwrap();
+        // Recording the same status again doesn't add to the history.
+        super::record(&row).unwrap();
+        row.status = Status::Verifying;
+        super::record(&row).unwrap();
+        let m = super::read(&dir, "manifest-test").unwrap();
+        let statuses: Vec<Status> = m.history.into_iter().map(|c| c.status).collect();
+        assert_eq!(statuses, [Status::Uploading, Status::Verifying]);
+        assert_eq!(m.row.status, Status::Verifying);
+        // Notes are always recorded.
+        super::record_note(&row, Some("hello".to_string())).unwrap();
+        let m = super::read(&dir, "manifest-test").unwrap();
+        assert_eq!(m.history.len(), 3);
+        assert_eq!(m.history[2].note.as_deref(), Some("hello"));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
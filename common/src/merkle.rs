@@ -0,0 +1,240 @@
+//! Merkle trees of uploaded files, kept next to the data as `<id>.merkle.json`.
+//!
+//! A file is split into leaves of `LEAF_SIZE` bytes (the last one can be shorter) and the SHA-256
+//! of each leaf is recorded, along with the root of a binary tree over them. Stages that read the
+//! file later can use the leaves to check or re-send part of it, or to find out which part of a
+//! damaged file is wrong. Inner nodes are the SHA-256 of a 0x01 byte followed by their children's
+//! digests; a node without a sibling is carried up unchanged.
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use base16ct::lower::encode_string;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::StreamHasher;
+
+/// How many bytes of the file each leaf covers.
+pub const LEAF_SIZE: u64 = 16 * 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct MerkleTree {
+    pub leaf_size: u64,
+    /// The size of the whole file.
+    pub size: u64,
+    /// The SHA-256 of each leaf, in order.
+    pub leaves: Vec<String>,
+    pub root: String,
+}
+
+impl MerkleTree {
+    fn new(leaf_size: u64, size: u64, leaves: Vec<[u8; 32]>) -> Self {
+        let root = root(leaves.clone());
+        Self {
+            leaf_size,
+            size,
+            leaves: leaves.iter().map(|l| encode_string(l)).collect(),
+            root: encode_string(&root),
+        }
+    }
+
+    /// The byte range of the file that leaf `i` covers.
+    pub fn leaf_range(&self, i: usize) -> (u64, u64) {
+        let start = i as u64 * self.leaf_size;
+        (start, (start + self.leaf_size).min(self.size))
+    }
+
+    /// The leaves that differ from another tree of the same file. Returns None if the trees
+    /// weren't built the same way, so they can't be compared.
+    pub fn diff(&self, other: &Self) -> Option<Vec<usize>> {
+        if self.leaf_size != other.leaf_size || self.size != other.size {
+            return None;
+        }
+        Some(
+            self.leaves
+                .iter()
+                .zip(&other.leaves)
+                .enumerate()
+                .filter(|(_, (a, b))| a != b)
+                .map(|(i, _)| i)
+                .collect(),
+        )
+    }
+}
+
+fn root(mut level: Vec<[u8; 32]>) -> [u8; 32] {
+    if level.is_empty() {
+        return Sha256::digest([]).into();
+    }
+    while level.len() > 1 {
+        level = level
+            .chunks(2)
+            .map(|pair| match pair {
+                [left, right] => {
+                    let mut hasher = Sha256::new();
+                    hasher.update([1]);
+                    hasher.update(left);
+                    hasher.update(right);
+                    hasher.finalize().into()
+                }
+                [only] => *only,
+                _ => unreachable!(),
+            })
+            .collect();
+    }
+    level[0]
+}
+
+/// Hashes a file as a whole and leaf by leaf at the same time. The data has to be given in order.
+pub struct Hasher {
+    whole: StreamHasher,
+    leaf: Sha256,
+    leaf_size: u64,
+    /// How much of the current leaf has been hashed.
+    in_leaf: u64,
+    size: u64,
+    leaves: Vec<[u8; 32]>,
+}
+
+impl Default for Hasher {
+    fn default() -> Self {
+        Self::new(LEAF_SIZE)
+    }
+}
+
+impl Hasher {
+    pub fn new(leaf_size: u64) -> Self {
+        Self {
+            whole: StreamHasher::default(),
+            leaf: Sha256::new(),
+            leaf_size,
+            in_leaf: 0,
+            size: 0,
+            leaves: Vec::new(),
+        }
+    }
+
+    pub fn update(&mut self, mut data: &[u8]) {
+        self.whole.update(data);
+        self.size += data.len() as u64;
+        while !data.is_empty() {
+            let n = ((self.leaf_size - self.in_leaf) as usize).min(data.len());
+            self.leaf.update(&data[..n]);
+            self.in_leaf += n as u64;
+            data = &data[n..];
+            if self.in_leaf == self.leaf_size {
+                self.leaves
+                    .push(std::mem::take(&mut self.leaf).finalize().into());
+                self.in_leaf = 0;
+            }
+        }
+    }
+
+    /// Returns the SHA-256 of all the data, the same as `hash_file` would give, and its tree.
+    pub fn finish(mut self) -> (String, MerkleTree) {
+        if self.in_leaf > 0 {
+            self.leaves.push(self.leaf.finalize().into());
+        }
+        (
+            self.whole.finish(),
+            MerkleTree::new(self.leaf_size, self.size, self.leaves),
+        )
+    }
+}
+
+impl io::Write for Hasher {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Reads everything from `file`, returning its SHA-256 and its tree.
+pub fn hash_file<T: io::Read>(mut file: T) -> io::Result<(String, MerkleTree)> {
+    let mut hasher = Hasher::default();
+    io::copy(&mut file, &mut hasher)?;
+    Ok(hasher.finish())
+}
+
+pub fn tree_path(dir: impl AsRef<Path>, id: &str) -> PathBuf {
+    dir.as_ref().join(format!("{id}.merkle.json"))
+}
+
+/// Reads the tree stored for an upload. This does blocking I/O.
+pub fn read(dir: impl AsRef<Path>, id: &str) -> io::Result<MerkleTree> {
+    let data = fs::read(tree_path(dir, id))?;
+    Ok(serde_json::from_slice(&data)?)
+}
+
+/// Stores an upload's tree, replacing any old one. This does blocking I/O.
+pub fn write(dir: impl AsRef<Path>, id: &str, tree: &MerkleTree) -> io::Result<()> {
+    let path = tree_path(dir, id);
+    let mut tmp = path.clone().into_os_string();
+    tmp.push(".tmp");
+    fs::write(&tmp, serde_json::to_vec(tree)?)?;
+    fs::rename(tmp, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use sha2::{Digest, Sha256};
+
+    use super::{Hasher, MerkleTree};
+
+    fn tree(data: &[u8], leaf_size: u64) -> (String, MerkleTree) {
+        let mut hasher = Hasher::new(leaf_size);
+        // In uneven pieces, so some cross leaf boundaries.
+        for piece in data.chunks(3) {
+            hasher.update(piece);
+        }
+        hasher.finish()
+    }
+
+    #[test]
+    fn leaves() {
+        let data = b"0123456789";
+        let (hash, tree) = tree(data, 4);
+        assert_eq!(hash, crate::hash_file(&data[..]).unwrap());
+        let expected =
+            ["0123", "4567", "89"].map(|leaf| crate::hash_file(leaf.as_bytes()).unwrap());
+        assert_eq!(tree.leaves, expected);
+        assert_eq!(tree.leaf_range(2), (8, 10));
+
+        let node = |left: &[u8], right: &[u8]| -> Vec<u8> {
+            Sha256::new()
+                .chain_update([1])
+                .chain_update(left)
+                .chain_update(right)
+                .finalize()
+                .to_vec()
+        };
+        let leaf = |s: &str| Sha256::digest(s).to_vec();
+        let root = node(&node(&leaf("0123"), &leaf("4567")), &leaf("89"));
+        assert_eq!(tree.root, base16ct::lower::encode_string(&root));
+
+        // A file that fits in one leaf has that leaf as its root.
+        let (hash, tree) = self::tree(b"0123", 4);
+        assert_eq!(tree.root, hash);
+        let (hash, tree) = self::tree(b"", 4);
+        assert!(tree.leaves.is_empty());
+        assert_eq!(tree.root, hash);
+    }
+
+    #[test]
+    fn diff() {
+        let (_, good) = tree(b"0123456789", 4);
+        let (_, bad) = tree(b"0123450789", 4);
+        assert_eq!(good.diff(&bad), Some(vec![1]));
+        assert_ne!(good.root, bad.root);
+        let (_, other) = tree(b"0123456789", 2);
+        assert_eq!(good.diff(&other), None);
+    }
+}
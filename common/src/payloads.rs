@@ -1,17 +1,125 @@
-use crate::data::{File, Metadata, Status, UploadRow};
+use crate::data::{AuditEntry, Ban, File, ItemUpload, Metadata, Progress, QueueDepth, Shard, Stall, Status, UploadRow, UploaderStats};
 #[cfg(feature = "db")]
 use crate::db::DbError;
+use crate::{merkle::MerkleTree, pipeline::Pipeline, registry::Project};
 use serde::{Deserialize, Serialize};
+use std::{collections::BTreeMap, fmt};
 
 // Response payloads
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(tag = "status", content = "payload")]
 #[serde(rename_all = "snake_case")]
 pub enum ErrorablePayload<T> {
     Ok(T),
     NotFound,
     Err(String),
+    /// The request was understood but isn't allowed. Retrying it won't help.
+    Rejected(Rejection),
+    /// The server can't handle the request right now, but should be able to after waiting this
+    /// long.
+    Unavailable {
+        retry_after_secs: u64,
+        /// Why, for people reading logs.
+        #[serde(default)]
+        reason: String,
+    },
+}
+
+/// Why a request was rejected.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(tag = "reason")]
+#[serde(rename_all = "snake_case")]
+pub enum Rejection {
+    UnknownProject,
+    UnknownPipeline,
+    /// The project doesn't accept files this big.
+    FileTooLarge { max_size: u64 },
+    /// The project doesn't accept files with this name or type.
+    FileTypeNotAllowed { extensions: Vec<String>, mime_types: Vec<String> },
+    /// The client encrypted the file, but the project doesn't take encrypted uploads.
+    EncryptionNotAllowed,
+    /// The project uses strict offsets, so the client has to say what chunk size it will use.
+    ChunkSizeRequired,
+    /// The chunk doesn't start at a multiple of the chunk size.
+    MisalignedOffset { chunk_size: u64 },
+    /// The chunk starts before data that has already been acknowledged.
+    OffsetRegressed { high_water_mark: u64 },
+    /// The upload is encrypted at rest, and part of the chunk has already been received. Writing
+    /// it again could reuse the keystream. See `crypt`.
+    AlreadyReceived,
+    /// The request's signature is missing, wrong or expired. See `signing`.
+    BadSignature,
+    /// The uploader already has as many uploads in progress as they're allowed.
+    TooManyActiveUploads { max: u64 },
+    /// The uploader has started uploads totalling `used` bytes in the last 24 hours, and this one
+    /// would take them over their limit.
+    DailyQuotaExceeded { max_bytes: u64, used: u64 },
+    /// An operator has banned the uploader, and says why.
+    Banned { message: String },
+    /// The same file is already being uploaded to the project. The client can carry on with that
+    /// upload instead of starting another one.
+    DuplicateUpload {
+        id: String,
+        status: Status,
+        /// How much has been uploaded, if the upload uses strict offsets.
+        high_water_mark: u64,
+        /// How far the processor has got, if the upload is past Uploading.
+        progress: Option<Progress>,
+    },
+}
+
+impl Rejection {
+    /// Whether this is an uploader limit, which lifts by itself as other uploads finish or the day
+    /// goes on. The server says when to try again in `Retry-After`.
+    pub fn is_temporary(&self) -> bool {
+        matches!(
+            self,
+            Self::TooManyActiveUploads { .. } | Self::DailyQuotaExceeded { .. }
+        )
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownProject => write!(f, "unknown project"),
+            Self::UnknownPipeline => write!(f, "unknown pipeline"),
+            Self::FileTooLarge { max_size } => {
+                write!(f, "file is larger than the maximum of {max_size} bytes")
+            }
+            Self::FileTypeNotAllowed {
+                extensions,
+                mime_types,
+            } => write!(
+                f,
+                "file type not allowed (extensions: {extensions:?}, MIME types: {mime_types:?})"
+            ),
+            Self::EncryptionNotAllowed => write!(f, "the project doesn't accept encrypted uploads"),
+            Self::ChunkSizeRequired => write!(f, "a chunk size is required"),
+            Self::MisalignedOffset { chunk_size } => {
+                write!(f, "offset is not a multiple of the chunk size {chunk_size}")
+            }
+            Self::OffsetRegressed { high_water_mark } => {
+                write!(f, "offset is before the acknowledged high-water mark {high_water_mark}")
+            }
+            Self::AlreadyReceived => write!(f, "part of the chunk has already been received"),
+            Self::BadSignature => write!(f, "the request's signature is missing, wrong or expired"),
+            Self::TooManyActiveUploads { max } => {
+                write!(f, "too many uploads in progress (the limit is {max})")
+            }
+            Self::DailyQuotaExceeded { max_bytes, used } => write!(
+                f,
+                "daily quota exceeded ({used} of {max_bytes} bytes used in the last 24 hours)"
+            ),
+            Self::Banned { message } => write!(f, "the uploader is banned: {message}"),
+            Self::DuplicateUpload { id, status, .. } => {
+                write!(f, "this file is already being uploaded as {id} ({status})")
+            }
+        }
+    }
 }
 
 #[cfg(feature = "db")]
@@ -29,28 +137,306 @@ impl<T> From<DbError> for ErrorablePayload<T> {
 pub type SingleUploadResponse = UploadRow;
 
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct UploadInformation {
     pub id: String,
     pub base_url: String,
+    /// If set, every chunk must start at a multiple of this and chunks must not go backwards.
+    #[serde(default)]
+    pub chunk_size: Option<u64>,
+    /// If set, chunk and finish requests have to be signed with this. See `signing`.
+    #[serde(default)]
+    pub secret: Option<String>,
 }
 
 pub type NewUploadResponse = UploadInformation;
 
+pub type DeadLettersResponse = Vec<UploadRow>;
+
+pub type PinsResponse = Vec<UploadRow>;
+
+/// The uploads containing an item. Packed ones say where they are in which megawarc.
+pub type ItemSearchResponse = Vec<ItemUpload>;
+
+/// Whether the project already has a file. Which uploads have it isn't said, since anyone can ask.
+/// See `GET /hash/{sha256}`.
+pub type HashLookupResponse = bool;
+
+/// Which parts of an upload's file the server has, so a client can send only the rest.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ReceivedRangesResponse {
+    pub size: u64,
+    /// The parts that have been written, as sorted `(start, end)` pairs.
+    pub received: Vec<(u64, u64)>,
+    /// The parts that haven't.
+    pub missing: Vec<(u64, u64)>,
+}
+
+pub type MerkleTreeResponse = MerkleTree;
+
+/// Audit entries, newest first.
+pub type AuditResponse = Vec<AuditEntry>;
+
+/// Banned uploaders, most recently banned first.
+pub type BansResponse = Vec<Ban>;
+
+/// Uploaders by how many bytes they've uploaded, most first.
+pub type LeaderboardResponse = Vec<UploaderStats>;
+
+/// How many items are in each status that isn't terminal, by project and pipeline.
+pub type QueuesResponse = Vec<QueueDepth>;
+
+/// The projects the server accepts uploads for.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ProjectsResponse {
+    /// If this is set, no projects are configured and any project name is accepted.
+    pub any_project: bool,
+    pub projects: BTreeMap<String, Project>,
+}
+
+/// The pipelines a project may use.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct PipelinesResponse {
+    /// If this is set, no pipelines are configured and any pipeline name is accepted, with the
+    /// default stages.
+    pub any_pipeline: bool,
+    pub pipelines: BTreeMap<String, Pipeline>,
+}
+
+/// How many bytes the benchmark sink received.
+pub type BenchmarkSinkResponse = u64;
+
+/// Whether the server is in maintenance mode. See `POST /admin/maintenance`.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct MaintenanceStatus {
+    /// New uploads are turned away, but existing ones can still be finished.
+    pub draining: bool,
+    /// How long clients are told to wait before trying to start an upload again.
+    pub retry_after_secs: u64,
+}
+
+/// See `GET /health`.
+pub type HealthResponse = MaintenanceStatus;
+
+/// The result of an action on several items.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct BulkActionResponse {
+    pub succeeded: Vec<String>,
+    /// Why the action failed, by item ID.
+    pub failed: BTreeMap<String, String>,
+}
+
+/// See `POST /admin/requeue`.
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct RequeueResponse {
+    /// How many uploads were requeued.
+    pub requeued: u64,
+    /// Pipelines that had uploads with the status, but that aren't in the registry or have no
+    /// stages to send them back to, so their uploads were left alone.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub skipped_pipelines: Vec<String>,
+}
+
 // Request payloads
 
+/// What the benchmark sink does with the chunks it gets.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "snake_case")]
+pub enum SinkMode {
+    /// Throw them away, to measure the network.
+    #[default]
+    Discard,
+    /// Write them to a scratch file in the data directory, to measure the disk too.
+    Write,
+    /// Write them and sync after every piece, like real uploads.
+    Fsync,
+}
+
+impl SinkMode {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::Discard => "discard",
+            Self::Write => "write",
+            Self::Fsync => "fsync",
+        }
+    }
+}
+
+impl std::str::FromStr for SinkMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "discard" => Ok(Self::Discard),
+            "write" => Ok(Self::Write),
+            "fsync" => Ok(Self::Fsync),
+            _ => Err(format!("unknown sink mode {s} (expected discard, write or fsync)")),
+        }
+    }
+}
+
+/// Turns maintenance mode on or off.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct MaintenancePayload {
+    pub draining: bool,
+    /// How long clients are told to wait. 60 seconds by default.
+    #[serde(default)]
+    pub retry_after_secs: Option<u64>,
+}
+
+/// Bans an uploader.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct BanPayload {
+    pub uploader: String,
+    pub reason: String,
+}
+
+/// Sets an upload's status by hand.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct StatusChangePayload {
+    pub status: Status,
+    /// Why, for the upload's history. It can't be empty.
+    pub reason: String,
+    /// Allow changes the upload's pipeline doesn't, like going back a stage.
+    #[serde(default)]
+    pub force: bool,
+}
+
+/// Which failed uploads to requeue. Only failed and dead-lettered statuses can be requeued.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct RequeuePayload {
+    pub status: Status,
+    /// Only uploads whose last activity was at least this many seconds ago.
+    #[serde(default)]
+    pub older_than: u64,
+    #[serde(default)]
+    pub project: Option<String>,
+    #[serde(default)]
+    pub pipeline: Option<String>,
+}
+
+/// The items an admin action applies to.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct BulkActionPayload {
+    pub ids: Vec<String>,
+}
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct UploadInitialisationPayload {
     pub file: File,
     pub project: String,
     pub pipeline: String,
     pub metadata: Metadata,
+    /// The chunk size the client intends to use. Required by projects with strict offsets.
+    #[serde(default)]
+    pub chunk_size: Option<u64>,
+    /// Set to upload several files as one bundle, which goes through the pipeline as one upload.
+    /// The data is sent as the files one after another, in this order, and `file` describes all
+    /// of it: its size is the files' added up, and its hash is that of their data together.
+    #[serde(default)]
+    pub members: Vec<File>,
+    /// Set if this is one piece of a file too big to upload whole. The pieces are uploaded
+    /// separately, and put back together once they've all been verified.
+    #[serde(default)]
+    pub shard: Option<Shard>,
+    /// A random string the client makes up for the upload, and sends again if it has to retry
+    /// the request. If the upload was created but the answer got lost, the retry gets it back
+    /// instead of being turned away as a duplicate. See `signing::new_nonce`.
+    #[serde(default)]
+    pub nonce: Option<String>,
 }
 
 pub type UploadChunkResponse = ();
 
+/// The status of the upload after finishing it. If the upload had already been finished, this
+/// is whatever status it's in now.
+pub type FinishResponse = Status;
+
 #[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 #[serde(tag = "type", content = "payload")]
 #[serde(rename_all = "snake_case")]
 pub enum UploadEvent {
     StatusChange(Status),
+    /// The whole row as of the status change just before it. Only sent when asked for with
+    /// `?include=row`.
+    Row(Box<UploadRow>),
+    /// Progress of the processor working on the current status.
+    Progress(Progress),
+    /// The upload hasn't received any data for a while, and will be abandoned unless some comes
+    /// in. Only sent while it's uploading.
+    Stalled(Stall),
+    /// The server lost track of the upload's changes and couldn't recover. No more events are sent
+    /// on this stream, but subscribing again might work.
+    Error(String),
+}
+
+/// An event on the firehose, `GET /events`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(tag = "type", content = "payload")]
+#[serde(rename_all = "snake_case")]
+pub enum FirehoseEvent {
+    /// An upload was created or changed status.
+    StatusChange { id: String, project: String, pipeline: String, status: Status },
+    /// The server lost track of the changes and couldn't recover. No more events are sent on this
+    /// stream, but subscribing again might work.
+    Error(String),
+}
+
+impl UploadEvent {
+    /// What kind of event this is, for filtering. Errors have no kind: they end the stream, so
+    /// they're always sent.
+    pub fn kind(&self) -> Option<EventKind> {
+        match self {
+            Self::StatusChange(_) => Some(EventKind::Status),
+            Self::Row(_) => Some(EventKind::Row),
+            Self::Progress(_) => Some(EventKind::Progress),
+            Self::Stalled(_) => Some(EventKind::Stalled),
+            Self::Error(_) => None,
+        }
+    }
+}
+
+/// The kinds of event a subscriber can ask for with `?events=`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EventKind {
+    Status,
+    Row,
+    Progress,
+    Stalled,
+}
+
+impl EventKind {
+    /// Parses a comma-separated list of kinds, like `status,progress`.
+    pub fn parse_list(s: &str) -> Result<Vec<Self>, String> {
+        s.split(',').filter(|k| !k.is_empty()).map(str::parse).collect()
+    }
+}
+
+impl std::str::FromStr for EventKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "status" => Ok(Self::Status),
+            "row" => Ok(Self::Row),
+            "progress" => Ok(Self::Progress),
+            "stalled" => Ok(Self::Stalled),
+            _ => Err(format!("unknown event type {s} (expected status, row, progress or stalled)")),
+        }
+    }
 }
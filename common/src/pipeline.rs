@@ -0,0 +1,100 @@
+use serde::{Deserialize, Serialize};
+
+use crate::data::Status;
+
+/// The sequence of statuses an upload goes through after it has been uploaded.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Pipeline {
+    /// The stages after `Uploading`, in order. The last one is always `Finished`.
+    pub stages: Vec<Status>,
+}
+
+impl Default for Pipeline {
+    fn default() -> Self {
+        Self {
+            stages: vec![Status::Verifying, Status::Packing, Status::Finished],
+        }
+    }
+}
+
+impl Pipeline {
+    /// Makes sure the stage list makes sense.
+    pub fn validate(&self) -> Result<(), String> {
+        if self.stages.last() != Some(&Status::Finished) {
+            return Err("the last stage must be FINISHED".to_string());
+        }
+        for (i, stage) in self.stages.iter().enumerate() {
+            if stage == &Status::Uploading || (stage.is_terminal() && stage != &Status::Finished) {
+                return Err(format!("{stage} can't be a pipeline stage"));
+            }
+            if self.stages[..i].contains(stage) {
+                return Err(format!("{stage} appears more than once"));
+            }
+        }
+        Ok(())
+    }
+
+    /// Gets the status that follows `current`, or None if `current` is terminal or isn't part
+    /// of this pipeline.
+    pub fn next(&self, current: &Status) -> Option<Status> {
+        if current == &Status::Uploading {
+            return self.stages.first().cloned();
+        }
+        let i = self.stages.iter().position(|s| s == current)?;
+        self.stages.get(i + 1).cloned()
+    }
+
+    /// Checks whether an upload on this pipeline may move from `from` to `to`.
+    pub fn allows(&self, from: &Status, to: &Status) -> bool {
+        if from.is_terminal() {
+            return false;
+        }
+        match to {
+            Status::Error(_) | Status::DeadLetter => true,
+            Status::Abandoned | Status::Deleted => from == &Status::Uploading,
+            _ => self.next(from).as_ref() == Some(to),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Pipeline;
+    use crate::data::{Status, UploadError};
+
+    #[test]
+    fn test_transitions() {
+        let p = Pipeline {
+            stages: vec![Status::Verifying, Status::Deriving, Status::Finished],
+        };
+        p.validate().unwrap();
+        assert_eq!(p.next(&Status::Uploading), Some(Status::Verifying));
+        assert_eq!(p.next(&Status::Deriving), Some(Status::Finished));
+        assert_eq!(p.next(&Status::Finished), None);
+        assert_eq!(p.next(&Status::Packing), None);
+
+        assert!(p.allows(&Status::Verifying, &Status::Deriving));
+        assert!(!p.allows(&Status::Verifying, &Status::Finished));
+        assert!(p.allows(&Status::Deriving, &Status::Error(UploadError::Verify)));
+        assert!(p.allows(&Status::Uploading, &Status::Abandoned));
+        assert!(!p.allows(&Status::Verifying, &Status::Abandoned));
+        assert!(!p.allows(&Status::Finished, &Status::Error(UploadError::Other)));
+        assert!(p.allows(&Status::Deriving, &Status::DeadLetter));
+        assert!(!p.allows(&Status::DeadLetter, &Status::Deriving));
+    }
+
+    #[test]
+    fn test_validation() {
+        Pipeline::default().validate().unwrap();
+        let bad = [
+            vec![],
+            vec![Status::Verifying],
+            vec![Status::Uploading, Status::Finished],
+            vec![Status::Verifying, Status::Verifying, Status::Finished],
+        ];
+        for stages in bad {
+            Pipeline { stages }.validate().unwrap_err();
+        }
+    }
+}
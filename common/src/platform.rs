@@ -0,0 +1,242 @@
+//! The file operations that depend on the OS. Bullseye is meant to run on Linux, but everything
+//! also builds on macOS and Windows, for development and small deployments. Where something isn't
+//! available there, it fails with `io::ErrorKind::Unsupported` so callers can fall back to
+//! something slower, or do without.
+
+use std::{
+    fs::{File, Metadata, TryLockError},
+    io,
+    path::Path,
+};
+
+fn unsupported(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Unsupported,
+        format!("{what} isn't supported here"),
+    )
+}
+
+/// Locks the whole file, shared or exclusively, without waiting. The lock is released when the
+/// file is closed.
+///
+/// These are advisory locks: flock on Unix, so a lock can't be taken twice through the same file
+/// description, and LockFileEx on Windows, where they stop other processes reading and writing
+/// the file too.
+pub fn acquire_lock(file: &File, exclusive: bool) -> io::Result<()> {
+    let res = match exclusive {
+        true => file.try_lock(),
+        false => file.try_lock_shared(),
+    };
+    match res {
+        Ok(()) => Ok(()),
+        // The lock isn't available yet. Let the client retry.
+        Err(TryLockError::WouldBlock) => Err(io::Error::new(io::ErrorKind::WouldBlock, "file is locked")),
+        Err(TryLockError::Error(e)) => Err(e),
+    }
+}
+
+/// Like acquire_lock, but waits for the lock if somebody else has it.
+pub fn wait_for_lock(file: &File, exclusive: bool) -> io::Result<()> {
+    match exclusive {
+        true => file.lock(),
+        false => file.lock_shared(),
+    }
+}
+
+/// Makes the file at least `len` bytes long, reserving the space on disk with `posix_fallocate`.
+/// Fails with `Unsupported` if the filesystem can't do that, like ZFS, and on macOS and Windows.
+pub fn allocate(file: &File, len: u64) -> io::Result<()> {
+    let len: i64 = len
+        .try_into()
+        .map_err(|_| io::Error::other("File too large"))?;
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    {
+        use nix::{errno::Errno, fcntl::posix_fallocate};
+        use std::os::fd::AsRawFd;
+
+        match posix_fallocate(file.as_raw_fd(), 0, len) {
+            Ok(()) => Ok(()),
+            Err(Errno::EOPNOTSUPP) => Err(unsupported("posix_fallocate")),
+            Err(e) => Err(e.into()),
+        }
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+    {
+        let _ = (file, len);
+        Err(unsupported("posix_fallocate"))
+    }
+}
+
+/// Frees the space under `offset..offset + len` without changing the file's size, with
+/// `fallocate(FALLOC_FL_PUNCH_HOLE)`. That part of the file reads as zeroes afterwards. Fails with
+/// `Unsupported` if the filesystem can't do that, and anywhere but Linux.
+pub fn punch_hole(file: &File, offset: u64, len: u64) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use nix::{
+            errno::Errno,
+            fcntl::{fallocate, FallocateFlags},
+        };
+        use std::os::fd::AsRawFd;
+
+        let too_large = |_| io::Error::other("File too large");
+        let offset = offset.try_into().map_err(too_large)?;
+        let len = len.try_into().map_err(too_large)?;
+        let mode = FallocateFlags::FALLOC_FL_PUNCH_HOLE | FallocateFlags::FALLOC_FL_KEEP_SIZE;
+        match fallocate(file.as_raw_fd(), mode, offset, len) {
+            Ok(()) => Ok(()),
+            Err(Errno::EOPNOTSUPP) => Err(unsupported("punching holes")),
+            Err(e) => Err(e.into()),
+        }
+    }
+    #[cfg(not(target_os = "linux"))]
+    {
+        let _ = (file, offset, len);
+        Err(unsupported("punching holes"))
+    }
+}
+
+/// How much space a file takes up on disk. This is less than its size if it has holes in it, and
+/// can be more because of the filesystem's block size. Elsewhere than Unix, it's just the size.
+pub fn allocated_bytes(meta: &Metadata) -> u64 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+
+        // st_blocks is always in units of 512 bytes.
+        meta.blocks() * 512
+    }
+    #[cfg(not(unix))]
+    {
+        meta.len()
+    }
+}
+
+/// What's left on a filesystem, for unprivileged users.
+#[derive(Clone, Copy, Debug)]
+pub struct DiskSpace {
+    pub free_bytes: u64,
+    pub free_inodes: u64,
+}
+
+/// Gets what's left on the filesystem `path` is on, with `statvfs`. Fails with `Unsupported` on
+/// Windows.
+// statvfs's fields are 32 bits on some platforms.
+#[allow(clippy::useless_conversion)]
+pub fn disk_space(path: &Path) -> io::Result<DiskSpace> {
+    #[cfg(unix)]
+    {
+        let stats = nix::sys::statvfs::statvfs(path)?;
+        Ok(DiskSpace {
+            free_bytes: u64::from(stats.fragment_size()) * u64::from(stats.blocks_available()),
+            free_inodes: u64::from(stats.files_available()),
+        })
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = path;
+        Err(unsupported("statvfs"))
+    }
+}
+
+/// Copies up to `len` bytes from `off_in` in `src` to `off_out` in `dest` inside the kernel, with
+/// `copy_file_range`, and moves the offsets past them. Returns how many were copied, which is 0
+/// at the end of `src`. Fails with `Unsupported` if it can't be done between these files, and
+/// anywhere but Linux and FreeBSD.
+pub fn copy_range(
+    src: &File,
+    off_in: &mut u64,
+    dest: &File,
+    off_out: &mut u64,
+    len: usize,
+) -> io::Result<usize> {
+    #[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+    {
+        use nix::{errno::Errno, fcntl::copy_file_range};
+
+        let (mut i, mut o) = (*off_in as i64, *off_out as i64);
+        let n = loop {
+            match copy_file_range(src, Some(&mut i), dest, Some(&mut o), len) {
+                Ok(n) => break n,
+                Err(Errno::EINTR) => continue,
+                Err(Errno::ENOSYS | Errno::EXDEV | Errno::EINVAL | Errno::EOPNOTSUPP) => {
+                    return Err(unsupported("copy_file_range"))
+                }
+                Err(e) => return Err(e.into()),
+            }
+        };
+        (*off_in, *off_out) = (i as u64, o as u64);
+        Ok(n)
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+    {
+        let _ = (src, off_in, dest, off_out, len);
+        Err(unsupported("copy_file_range"))
+    }
+}
+
+/// Writes all of `buf` at `offset` in the file. On Unix, the file's position doesn't change; on
+/// Windows, it ends up after what was written.
+pub fn write_all_at(file: &File, buf: &[u8], offset: u64) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::FileExt;
+
+        file.write_all_at(buf, offset)
+    }
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::FileExt;
+
+        let (mut buf, mut offset) = (buf, offset);
+        while !buf.is_empty() {
+            match file.seek_write(buf, offset) {
+                Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+                Ok(n) => {
+                    buf = &buf[n..];
+                    offset += n as u64;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io};
+
+    use super::{acquire_lock, allocate, allocated_bytes, disk_space, punch_hole};
+
+    #[test]
+    fn test_platform() {
+        let path = std::env::temp_dir().join(format!("bullseye-platform-{}", std::process::id()));
+        let file = fs::File::create(&path).unwrap();
+        let other = fs::File::open(&path).unwrap();
+        acquire_lock(&file, false).unwrap();
+        acquire_lock(&other, false).unwrap();
+        drop(other);
+        let other = fs::File::open(&path).unwrap();
+        assert_eq!(acquire_lock(&other, true).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+        drop(file);
+        acquire_lock(&other, true).unwrap();
+
+        // Whatever the platform, this either works or says it can't.
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        match allocate(&file, 4096) {
+            Ok(()) => assert!(file.metadata().unwrap().len() >= 4096),
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::Unsupported),
+        }
+        match punch_hole(&file, 0, 4096) {
+            Ok(()) => assert!(allocated_bytes(&file.metadata().unwrap()) < 4096),
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::Unsupported),
+        }
+        match disk_space(&path) {
+            Ok(space) => assert!(space.free_bytes > 0),
+            Err(e) => assert_eq!(e.kind(), io::ErrorKind::Unsupported),
+        }
+        fs::remove_file(path).unwrap();
+    }
+}
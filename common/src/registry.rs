@@ -0,0 +1,390 @@
+//! The registry of projects and pipelines (and their settings) the server knows about.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::Path,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{data::File, payloads::Rejection, pipeline::Pipeline};
+
+/// The environment variable containing the path of the registry file.
+pub const REGISTRY_ENV: &str = "BULLSEYE_REGISTRY";
+/// Where the registry is read from if the environment variable isn't set.
+pub const DEFAULT_REGISTRY_PATH: &str = "registry.json";
+
+/// Settings for a single project.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Project {
+    /// The largest file the project accepts, in bytes.
+    #[serde(default)]
+    pub max_file_size: Option<u64>,
+    /// Filename extensions the project accepts, e.g. `.warc.gz`. If both this and `mime_types`
+    /// are empty, any file is accepted.
+    #[serde(default)]
+    pub extensions: Vec<String>,
+    /// MIME types the project accepts, guessed from the filename.
+    #[serde(default)]
+    pub mime_types: Vec<String>,
+    /// The pipelines the project may use. If empty, any pipeline may be used.
+    #[serde(default)]
+    pub pipelines: Vec<String>,
+    /// Require chunks to be aligned to the chunk size the client picked, and not to go
+    /// backwards. Catches buggy clients early.
+    #[serde(default)]
+    pub strict_offsets: bool,
+    /// How long, in seconds, an upload may sit in UPLOADING without receiving data before it is
+    /// abandoned. If unset, idle uploads are kept forever.
+    #[serde(default)]
+    pub idle_timeout: Option<u64>,
+    /// How long, in seconds, an upload may go without receiving any data before it is
+    /// abandoned, even if its client keeps making requests, like chunks that never finish. Its
+    /// subscribers get a `stalled` event halfway there. If unset, only `idle_timeout` applies.
+    #[serde(default)]
+    pub stall_timeout: Option<u64>,
+    /// How long, in seconds, failed uploads are kept before they are soft-deleted. If unset,
+    /// they are kept forever.
+    #[serde(default)]
+    pub retention: Option<u64>,
+    /// How long, in seconds, soft-deleted uploads are kept before their data is purged.
+    /// Defaults to a day.
+    #[serde(default)]
+    pub delete_grace: Option<u64>,
+    /// How long, in seconds, uploads that are done with (finished, abandoned, or deleted and
+    /// purged) are kept in the uploads table before they're moved to the archive. If unset,
+    /// they're never moved.
+    #[serde(default)]
+    pub archive_after: Option<u64>,
+    /// How long, in seconds, a chunk the dedup stage has seen is kept in the chunk index after it
+    /// was last seen in one of the project's uploads. Defaults to 90 days.
+    #[serde(default)]
+    pub chunk_retention: Option<u64>,
+    /// Accept uploads the client encrypted (`Metadata.encryption`). Stages that need to look
+    /// inside the file, like the WARC checks, fail those uploads, so only set this for projects
+    /// whose pipelines don't have them.
+    #[serde(default)]
+    pub client_encryption: bool,
+}
+
+/// Limits on what a single uploader (`Metadata.uploader`) can do, so one misconfigured client
+/// can't take over the server.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct UploaderLimits {
+    /// How many uploads they can have in progress at once.
+    #[serde(default)]
+    pub max_active_uploads: Option<u64>,
+    /// How many bytes of uploads they can start in any 24 hours. Uploads count as soon as they're
+    /// started, whether or not they're finished.
+    #[serde(default)]
+    pub max_bytes_per_day: Option<u64>,
+}
+
+impl UploaderLimits {
+    /// Overrides the limits that are set in `other`.
+    fn merge(&self, other: &Self) -> Self {
+        Self {
+            max_active_uploads: other.max_active_uploads.or(self.max_active_uploads),
+            max_bytes_per_day: other.max_bytes_per_day.or(self.max_bytes_per_day),
+        }
+    }
+}
+
+/// The default for Project::delete_grace.
+pub const DEFAULT_DELETE_GRACE: u64 = 24 * 60 * 60;
+/// The default for Project::chunk_retention.
+pub const DEFAULT_CHUNK_RETENTION: u64 = 90 * 24 * 60 * 60;
+
+impl Project {
+    /// Gets how long soft-deleted uploads are kept.
+    pub fn delete_grace(&self) -> u64 {
+        self.delete_grace.unwrap_or(DEFAULT_DELETE_GRACE)
+    }
+
+    /// Gets how long chunks are kept in the chunk index.
+    pub fn chunk_retention(&self) -> u64 {
+        self.chunk_retention.unwrap_or(DEFAULT_CHUNK_RETENTION)
+    }
+
+    /// Checks a file against the project's policy.
+    pub fn check_file(&self, file: &File) -> Result<(), Rejection> {
+        self.check_size(file)?;
+        self.check_type(file)
+    }
+
+    fn check_size(&self, file: &File) -> Result<(), Rejection> {
+        if let Some(max_size) = self.max_file_size {
+            if file.size > max_size {
+                return Err(Rejection::FileTooLarge { max_size });
+            }
+        }
+        Ok(())
+    }
+
+    fn check_type(&self, file: &File) -> Result<(), Rejection> {
+        if self.extensions.is_empty() && self.mime_types.is_empty() {
+            return Ok(());
+        }
+        let name = file.name.to_lowercase();
+        let extension_ok = self
+            .extensions
+            .iter()
+            .any(|e| name.ends_with(&e.to_lowercase()));
+        let mime_ok = mime_guess::from_path(&name)
+            .iter()
+            .any(|m| self.mime_types.iter().any(|t| t.eq_ignore_ascii_case(m.essence_str())));
+        if extension_ok || mime_ok {
+            Ok(())
+        } else {
+            Err(Rejection::FileTypeNotAllowed {
+                extensions: self.extensions.clone(),
+                mime_types: self.mime_types.clone(),
+            })
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct Registry {
+    /// If this is empty, every project is accepted with no restrictions.
+    #[serde(default)]
+    pub projects: HashMap<String, Project>,
+    /// If this is empty, every pipeline is accepted and uses the default stages.
+    #[serde(default)]
+    pub pipelines: HashMap<String, Pipeline>,
+    /// The limits on every uploader.
+    #[serde(default)]
+    pub uploader_limits: UploaderLimits,
+    /// Limits for particular uploaders, which replace the ones in `uploader_limits`.
+    #[serde(default)]
+    pub uploaders: HashMap<String, UploaderLimits>,
+}
+
+impl Registry {
+    /// Reads the registry from a JSON file and validates it.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let data = fs::read(path)?;
+        let registry: Self = serde_json::from_slice(&data)?;
+        registry.validate().map_err(io::Error::other)?;
+        Ok(registry)
+    }
+
+    /// Reads the registry from the path in `BULLSEYE_REGISTRY`, or `registry.json`.
+    /// If the default file doesn't exist, an empty registry is returned.
+    pub fn from_env() -> io::Result<Self> {
+        match std::env::var(REGISTRY_ENV) {
+            Ok(path) => Self::load(Path::new(&path)),
+            Err(_) => match Self::load(Path::new(DEFAULT_REGISTRY_PATH)) {
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+                res => res,
+            },
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, project) in &self.projects {
+            for pipeline in &project.pipelines {
+                if self.pipeline(pipeline).is_none() {
+                    return Err(format!("project {name}: unknown pipeline {pipeline}"));
+                }
+            }
+        }
+        for (name, pipeline) in &self.pipelines {
+            pipeline
+                .validate()
+                .map_err(|e| format!("pipeline {name}: {e}"))?;
+        }
+        Ok(())
+    }
+
+    /// Gets a project by name. Returns None if projects are configured but this isn't one of
+    /// them.
+    pub fn project(&self, name: &str) -> Option<Project> {
+        if self.projects.is_empty() {
+            return Some(Project::default());
+        }
+        self.projects.get(name).cloned()
+    }
+
+    /// Gets how long soft-deleted uploads of a project are kept.
+    pub fn delete_grace(&self, project: &str) -> u64 {
+        self.project(project).map_or(DEFAULT_DELETE_GRACE, |p| p.delete_grace())
+    }
+
+    /// Checks whether a new upload is acceptable, and returns the pipeline it will go through.
+    /// For a bundle, `members` are the files in it: the size limit applies to the whole bundle,
+    /// and the allowed types to each member rather than to the bundle's own name.
+    pub fn check_upload(
+        &self,
+        project: &str,
+        pipeline: &str,
+        file: &File,
+        members: &[File],
+        encrypted: bool,
+    ) -> Result<Pipeline, Rejection> {
+        let project = self.project(project).ok_or(Rejection::UnknownProject)?;
+        if encrypted && !project.client_encryption {
+            return Err(Rejection::EncryptionNotAllowed);
+        }
+        if !project.pipelines.is_empty() && !project.pipelines.iter().any(|p| p == pipeline) {
+            return Err(Rejection::UnknownPipeline);
+        }
+        let pipeline = self.pipeline(pipeline).ok_or(Rejection::UnknownPipeline)?;
+        if members.is_empty() {
+            project.check_file(file)?;
+        } else {
+            project.check_size(file)?;
+            members.iter().try_for_each(|m| project.check_type(m))?;
+        }
+        Ok(pipeline)
+    }
+
+    /// Lists the pipelines a project may use, or None if it isn't a project. An empty list means
+    /// any pipeline may be used, with the default stages.
+    pub fn project_pipelines(&self, name: &str) -> Option<BTreeMap<String, Pipeline>> {
+        let project = self.project(name)?;
+        Some(
+            self.pipelines
+                .iter()
+                .filter(|(p, _)| project.pipelines.is_empty() || project.pipelines.contains(p))
+                .map(|(p, pipeline)| (p.clone(), pipeline.clone()))
+                .collect(),
+        )
+    }
+
+    /// Gets the limits on an uploader.
+    pub fn uploader_limits(&self, uploader: &str) -> UploaderLimits {
+        match self.uploaders.get(uploader) {
+            Some(limits) => self.uploader_limits.merge(limits),
+            None => self.uploader_limits.clone(),
+        }
+    }
+
+    /// Gets a pipeline by name. Returns None if pipelines are configured but this isn't one of
+    /// them.
+    pub fn pipeline(&self, name: &str) -> Option<Pipeline> {
+        if self.pipelines.is_empty() {
+            return Some(Pipeline::default());
+        }
+        self.pipelines.get(name).cloned()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Registry, UploaderLimits};
+    use crate::{data::{File, Status}, payloads::Rejection, pipeline::Pipeline};
+
+    #[test]
+    fn test_pipeline_lookup() {
+        let r = Registry::default();
+        assert_eq!(r.pipeline("anything"), Some(Pipeline::default()));
+
+        let r: Registry = serde_json::from_str(
+            r#"{"pipelines": {"warc": {"stages": ["VERIFYING", "FINISHED"]}}}"#,
+        )
+        .unwrap();
+        r.validate().unwrap();
+        assert_eq!(
+            r.pipeline("warc").unwrap().stages,
+            [Status::Verifying, Status::Finished]
+        );
+        assert_eq!(r.pipeline("anything"), None);
+    }
+
+    #[test]
+    fn test_file_policy() {
+        let r: Registry = serde_json::from_str(
+            r#"{"projects": {"urls": {"max_file_size": 100, "extensions": [".warc.gz", ".warc.zst"]}}}"#,
+        )
+        .unwrap();
+        let file = |name: &str, size| File {
+            hash: String::new(),
+            name: name.to_string(),
+            size,
+        };
+        r.check_upload("urls", "any", &file("a.warc.gz", 100), &[], false).unwrap();
+        r.check_upload("urls", "any", &file("A.WARC.ZST", 1), &[], false).unwrap();
+        assert_eq!(
+            r.check_upload("urls", "any", &file("a.warc.gz", 101), &[], false).unwrap_err(),
+            Rejection::FileTooLarge { max_size: 100 }
+        );
+        assert!(matches!(
+            r.check_upload("urls", "any", &file("a.txt", 1), &[], false).unwrap_err(),
+            Rejection::FileTypeNotAllowed { .. }
+        ));
+        assert_eq!(
+            r.check_upload("other", "any", &file("a.warc.gz", 1), &[], false).unwrap_err(),
+            Rejection::UnknownProject
+        );
+
+        let r: Registry = serde_json::from_str(r#"{"projects": {"p": {"mime_types": ["text/plain"]}}}"#).unwrap();
+        r.check_upload("p", "any", &file("notes.txt", 1), &[], false).unwrap();
+        r.check_upload("p", "any", &file("notes.json", 1), &[], false).unwrap_err();
+
+        // A bundle's members have to be allowed types, but its own name doesn't matter.
+        r.check_upload("p", "any", &file("a.bundle", 2), &[file("a.txt", 1), file("b.txt", 1)], false).unwrap();
+        r.check_upload("p", "any", &file("a.bundle", 2), &[file("a.txt", 1), file("b.json", 1)], false).unwrap_err();
+
+        // Encrypted uploads have to be allowed by the project.
+        assert_eq!(
+            r.check_upload("p", "any", &file("notes.txt", 1), &[], true).unwrap_err(),
+            Rejection::EncryptionNotAllowed
+        );
+        let r: Registry = serde_json::from_str(r#"{"projects": {"p": {"client_encryption": true}}}"#).unwrap();
+        r.check_upload("p", "any", &file("notes.txt", 1), &[], true).unwrap();
+    }
+
+    #[test]
+    fn test_project_pipelines() {
+        let r = Registry::default();
+        assert_eq!(r.project_pipelines("anything").unwrap().len(), 0);
+
+        let r: Registry = serde_json::from_str(
+            r#"{
+                "projects": {"urls": {"pipelines": ["warc"]}, "misc": {}},
+                "pipelines": {
+                    "warc": {"stages": ["VERIFYING", "FINISHED"]},
+                    "plain": {"stages": ["FINISHED"]}
+                }
+            }"#,
+        )
+        .unwrap();
+        r.validate().unwrap();
+        let urls = r.project_pipelines("urls").unwrap();
+        assert_eq!(urls.keys().collect::<Vec<_>>(), ["warc"]);
+        let misc = r.project_pipelines("misc").unwrap();
+        assert_eq!(misc.keys().collect::<Vec<_>>(), ["plain", "warc"]);
+        assert_eq!(r.project_pipelines("other"), None);
+    }
+
+    #[test]
+    fn test_uploader_limits() {
+        let r: Registry = serde_json::from_str(
+            r#"{
+                "uploader_limits": {"max_active_uploads": 4, "max_bytes_per_day": 1000},
+                "uploaders": {"big": {"max_bytes_per_day": 5000}}
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(
+            r.uploader_limits("someone"),
+            UploaderLimits {
+                max_active_uploads: Some(4),
+                max_bytes_per_day: Some(1000),
+            }
+        );
+        assert_eq!(
+            r.uploader_limits("big"),
+            UploaderLimits {
+                max_active_uploads: Some(4),
+                max_bytes_per_day: Some(5000),
+            }
+        );
+        assert_eq!(Registry::default().uploader_limits("someone"), UploaderLimits::default());
+    }
+}
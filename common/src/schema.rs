@@ -0,0 +1,96 @@
+//! JSON Schemas for what goes over the wire, so clients that aren't written in Rust can check
+//! their payloads against the ones the server uses. `bullseye-schema` prints them.
+//!
+//! Responses are wrapped in `ErrorablePayload`, like the server sends them. Errors also have the
+//! fields in `ErrorResponse`. Endpoints that stream events send one `UploadEvent` or
+//! `FirehoseEvent` per line.
+
+use std::collections::BTreeMap;
+
+use schemars::{schema_for, JsonSchema, Schema};
+
+use crate::{errors::ErrorResponse, payloads::*, registry::Registry};
+
+fn response<T: JsonSchema>() -> Schema {
+    schema_for!(ErrorablePayload<T>)
+}
+
+/// Every schema, by the name of the payload type.
+pub fn schemas() -> BTreeMap<&'static str, Schema> {
+    BTreeMap::from([
+        // Requests
+        (
+            "UploadInitialisationPayload",
+            schema_for!(UploadInitialisationPayload),
+        ),
+        ("MaintenancePayload", schema_for!(MaintenancePayload)),
+        ("BanPayload", schema_for!(BanPayload)),
+        ("StatusChangePayload", schema_for!(StatusChangePayload)),
+        ("RequeuePayload", schema_for!(RequeuePayload)),
+        ("BulkActionPayload", schema_for!(BulkActionPayload)),
+        // Responses
+        ("NewUploadResponse", response::<NewUploadResponse>()),
+        ("SingleUploadResponse", response::<SingleUploadResponse>()),
+        (
+            "ReceivedRangesResponse",
+            response::<ReceivedRangesResponse>(),
+        ),
+        ("MerkleTreeResponse", response::<MerkleTreeResponse>()),
+        ("UploadChunkResponse", response::<UploadChunkResponse>()),
+        ("FinishResponse", response::<FinishResponse>()),
+        ("ItemSearchResponse", response::<ItemSearchResponse>()),
+        ("HashLookupResponse", response::<HashLookupResponse>()),
+        ("ProjectsResponse", response::<ProjectsResponse>()),
+        ("PipelinesResponse", response::<PipelinesResponse>()),
+        ("HealthResponse", response::<HealthResponse>()),
+        ("LeaderboardResponse", response::<LeaderboardResponse>()),
+        ("QueuesResponse", response::<QueuesResponse>()),
+        ("DeadLettersResponse", response::<DeadLettersResponse>()),
+        ("PinsResponse", response::<PinsResponse>()),
+        ("AuditResponse", response::<AuditResponse>()),
+        ("BansResponse", response::<BansResponse>()),
+        ("BulkActionResponse", response::<BulkActionResponse>()),
+        ("RequeueResponse", response::<RequeueResponse>()),
+        ("BenchmarkSinkResponse", response::<BenchmarkSinkResponse>()),
+        ("ErrorResponse", schema_for!(ErrorResponse)),
+        // Events
+        ("UploadEvent", schema_for!(UploadEvent)),
+        ("FirehoseEvent", schema_for!(FirehoseEvent)),
+        // The server's project registry, from BULLSEYE_REGISTRY.
+        ("Registry", schema_for!(Registry)),
+    ])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::schemas;
+
+    /// Makes sure the schemas describe what's actually sent.
+    #[test]
+    fn test_schemas() {
+        let schemas = schemas();
+        let init = schemas["UploadInitialisationPayload"].as_value();
+        let mut required: Vec<_> = init["required"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|v| v.as_str().unwrap())
+            .collect();
+        required.sort_unstable();
+        assert_eq!(required, ["file", "metadata", "pipeline", "project"]);
+
+        // Statuses are upper case, and errors are untagged.
+        let status = serde_json::to_string(&schemas["FinishResponse"]).unwrap();
+        assert!(status.contains("\"UPLOADING\""));
+        assert!(status.contains("\"DEAD_LETTER\""));
+        assert!(status.contains("\"FAILED_CHECKSUM\""));
+
+        let new_upload = schemas["NewUploadResponse"].as_value();
+        let variants = new_upload["oneOf"].as_array().unwrap();
+        let tags: Vec<_> = variants
+            .iter()
+            .filter_map(|v| v["properties"]["status"]["const"].as_str())
+            .collect();
+        assert_eq!(tags, ["ok", "not_found", "err", "rejected", "unavailable"]);
+    }
+}
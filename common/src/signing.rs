@@ -0,0 +1,149 @@
+//! Signed requests. When the server has a signing key, every upload gets a secret derived from it,
+//! which is handed to the client when the upload is created. Chunk, finish and abandon requests
+//! then have to carry an HMAC made with that secret, so knowing an upload's ID isn't enough to
+//! write to it or throw it away.
+//!
+//! The signature goes in the `signature` query parameter, with the time it stops being valid (in
+//! seconds since the epoch) in `expires`. It covers the action (`data`, `finish` or `abandon`),
+//! the upload ID, the offset and length of the chunk (both 0 for `finish` and `abandon`), and
+//! `expires`.
+//!
+//! A client that didn't get the answer to its `POST /upload` can send it again and get the upload,
+//! secret and all, but only if the request has the same `nonce` as the first one. Only a hash of
+//! the nonce is kept on the row, so reading the row doesn't give it away.
+
+use std::io;
+
+use base16ct::lower::{decode_vec, encode_string};
+use hmac::{Hmac, Mac};
+use sha2::{Digest, Sha256};
+use subtle::ConstantTimeEq;
+
+/// HMAC-SHA256 over `message`, ready to be finalized or verified.
+fn hmac_sha256(key: &[u8], message: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).expect("HMAC takes keys of any length");
+    mac.update(message);
+    mac
+}
+
+/// Derives the secret for an upload from the server's signing key.
+pub fn upload_secret(key: &[u8], id: &str) -> String {
+    encode_string(&hmac_sha256(key, id.as_bytes()).finalize().into_bytes())
+}
+
+/// Compares in constant time, so secrets like the admin token can't be guessed byte by byte.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.ct_eq(b).into()
+}
+
+/// Makes up a nonce for a new upload. See `UploadInitialisationPayload::nonce`.
+pub fn new_nonce() -> io::Result<String> {
+    let mut nonce = [0; 32];
+    getrandom::getrandom(&mut nonce).map_err(io::Error::other)?;
+    Ok(encode_string(&nonce))
+}
+
+/// Hashes a nonce for storing on the upload's row.
+pub fn nonce_hash(nonce: &str) -> String {
+    encode_string(&Sha256::digest(nonce.as_bytes()))
+}
+
+/// What a signature covers.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SignedRequest<'a> {
+    /// `data`, `finish` or `abandon`.
+    pub action: &'a str,
+    pub id: &'a str,
+    pub offset: u64,
+    pub length: u64,
+    /// In seconds since the epoch.
+    pub expires: u64,
+}
+
+impl SignedRequest<'_> {
+    fn mac(&self, secret: &str) -> Hmac<Sha256> {
+        let message = format!(
+            "{}\n{}\n{}\n{}\n{}",
+            self.action, self.id, self.offset, self.length, self.expires
+        );
+        hmac_sha256(secret.as_bytes(), message.as_bytes())
+    }
+
+    /// Signs the request with an upload's secret.
+    pub fn sign(&self, secret: &str) -> String {
+        encode_string(&self.mac(secret).finalize().into_bytes())
+    }
+
+    /// Checks a signature. `now` is in seconds since the epoch.
+    pub fn verify(&self, secret: &str, signature: &str, now: u64) -> bool {
+        // The comparison is in constant time, so the signature can't be guessed byte by byte.
+        let same = decode_vec(signature.as_bytes()).is_ok_and(|s| self.mac(secret).verify_slice(&s).is_ok());
+        same && now <= self.expires
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{constant_time_eq, new_nonce, nonce_hash, upload_secret, SignedRequest};
+
+    #[test]
+    fn test_secret() {
+        // RFC 4231, test cases 2 and 6
+        assert_eq!(
+            upload_secret(b"Jefe", "what do ya want for nothing?"),
+            "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+        assert_eq!(
+            upload_secret(&[0xaa; 131], "Test Using Larger Than Block-Size Key - Hash Key First"),
+            "60e431591ee0b67f0d8a26aacbf5b77f8e0bc6213728c5140546040f0ee37f54"
+        );
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret2"));
+    }
+
+    #[test]
+    fn test_verify() {
+        let secret = upload_secret(b"key", "upload");
+        let request = SignedRequest {
+            action: "data",
+            id: "upload",
+            offset: 0,
+            length: 100,
+            expires: 1000,
+        };
+        let signature = request.sign(&secret);
+        assert!(request.verify(&secret, &signature, 999));
+        // Expired
+        assert!(!request.verify(&secret, &signature, 1001));
+        // Different chunk, action or upload
+        let other = SignedRequest {
+            length: 101,
+            ..request
+        };
+        assert!(!other.verify(&secret, &signature, 999));
+        let other = SignedRequest {
+            action: "finish",
+            ..request
+        };
+        assert!(!other.verify(&secret, &signature, 999));
+        let other = SignedRequest {
+            id: "other",
+            ..request
+        };
+        assert!(!other.verify(&upload_secret(b"key", "other"), &signature, 999));
+    }
+
+    #[test]
+    fn test_nonce() {
+        let nonce = new_nonce().unwrap();
+        assert_eq!(nonce.len(), 64);
+        assert_ne!(nonce, new_nonce().unwrap());
+        assert_ne!(nonce_hash(&nonce), nonce);
+        assert_eq!(nonce_hash(&nonce), nonce_hash(&nonce));
+    }
+}
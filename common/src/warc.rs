@@ -0,0 +1,165 @@
+//! Helpers for zstd-compressed WARCs (`.warc.zst`).
+//!
+//! Each record is its own zstd frame. The file may start with a skippable frame holding the
+//! dictionary the records were compressed with, which may itself be zstd-compressed.
+
+use std::io::{self, BufRead, Read, Seek, SeekFrom, Write};
+
+/// The magic number of a zstd frame.
+const ZSTD_MAGIC: u32 = 0xFD2FB528;
+/// The magic number of the skippable frame holding the dictionary.
+const DICTIONARY_FRAME_MAGIC: u32 = 0x184D2A5D;
+
+/// Whether a file name is that of a zstd-compressed WARC.
+pub fn is_warc_zst(name: &str) -> bool {
+    name.ends_with(".warc.zst")
+}
+
+/// Reads the dictionary frame at the start of the file, if there is one, and returns its contents
+/// as stored. Afterwards, the reader is positioned at the first record.
+pub fn read_dictionary_frame<R: Read + Seek>(reader: &mut R) -> io::Result<Option<Vec<u8>>> {
+    let start = reader.stream_position()?;
+    let mut header = [0; 8];
+    if let Err(e) = reader.read_exact(&mut header) {
+        reader.seek(SeekFrom::Start(start))?;
+        return match e.kind() {
+            io::ErrorKind::UnexpectedEof => Ok(None),
+            _ => Err(e),
+        };
+    }
+    let magic = u32::from_le_bytes(header[..4].try_into().unwrap());
+    if magic != DICTIONARY_FRAME_MAGIC {
+        reader.seek(SeekFrom::Start(start))?;
+        return Ok(None);
+    }
+    let len = u32::from_le_bytes(header[4..].try_into().unwrap());
+    let mut dictionary = Vec::new();
+    reader
+        .by_ref()
+        .take(len.into())
+        .read_to_end(&mut dictionary)?;
+    if dictionary.len() != len as usize {
+        return Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "dictionary frame is truncated",
+        ));
+    }
+    Ok(Some(dictionary))
+}
+
+/// Writes a dictionary frame with the given contents.
+pub fn write_dictionary_frame<W: Write>(writer: &mut W, contents: &[u8]) -> io::Result<()> {
+    let len = u32::try_from(contents.len())
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, "dictionary is too big"))?;
+    writer.write_all(&DICTIONARY_FRAME_MAGIC.to_le_bytes())?;
+    writer.write_all(&len.to_le_bytes())?;
+    writer.write_all(contents)
+}
+
+/// Gets the dictionary out of the contents of a dictionary frame, decompressing it if needed.
+pub fn decode_dictionary(contents: &[u8]) -> io::Result<Vec<u8>> {
+    if contents.starts_with(&ZSTD_MAGIC.to_le_bytes()) {
+        zstd::decode_all(contents)
+    } else {
+        Ok(contents.to_vec())
+    }
+}
+
+/// Makes sure every frame decompresses cleanly and contains a WARC record.
+/// Returns the number of records.
+pub fn validate_warc_zst<R: BufRead + Seek>(mut reader: R) -> io::Result<u64> {
+    let dictionary = match read_dictionary_frame(&mut reader)? {
+        Some(d) => decode_dictionary(&d)
+            .map_err(|e| io::Error::new(e.kind(), format!("bad dictionary: {e}")))?,
+        None => Vec::new(),
+    };
+    let mut records = 0;
+    while !reader.fill_buf()?.is_empty() {
+        let err = |e: io::Error| io::Error::new(e.kind(), format!("zstd frame {records}: {e}"));
+        let decoder = match dictionary.is_empty() {
+            true => zstd::Decoder::with_buffer(&mut reader),
+            false => zstd::Decoder::with_dictionary(&mut reader, &dictionary),
+        };
+        // Stop at the end of the frame, leaving the rest in the reader.
+        let mut decoder = decoder.map_err(err)?.single_frame();
+        let mut magic = [0; 5];
+        decoder.read_exact(&mut magic).map_err(err)?;
+        if &magic != b"WARC/" {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("zstd frame {records} does not start with a WARC record"),
+            ));
+        }
+        io::copy(&mut decoder, &mut io::sink()).map_err(err)?;
+        records += 1;
+    }
+    if records == 0 {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "file has no records",
+        ));
+    }
+    Ok(records)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::{read_dictionary_frame, validate_warc_zst, DICTIONARY_FRAME_MAGIC};
+
+    fn frame(data: &[u8], dictionary: &[u8]) -> Vec<u8> {
+        match dictionary.is_empty() {
+            true => zstd::bulk::compress(data, 3).unwrap(),
+            false => zstd::bulk::Compressor::with_dictionary(3, dictionary)
+                .unwrap()
+                .compress(data)
+                .unwrap(),
+        }
+    }
+
+    fn dictionary_frame(contents: &[u8]) -> Vec<u8> {
+        let mut rv = DICTIONARY_FRAME_MAGIC.to_le_bytes().to_vec();
+        rv.extend((contents.len() as u32).to_le_bytes());
+        rv.extend(contents);
+        rv
+    }
+
+    #[test]
+    fn test_warc_zst() {
+        let mut good = frame(b"WARC/1.1\r\nWARC-Type: warcinfo\r\n\r\n", b"");
+        good.extend(frame(b"WARC/1.1\r\nWARC-Type: response\r\n\r\n", b""));
+        assert_eq!(validate_warc_zst(Cursor::new(&good)).unwrap(), 2);
+        // Truncated
+        validate_warc_zst(Cursor::new(&good[..good.len() - 3])).unwrap_err();
+        // Not a WARC
+        validate_warc_zst(Cursor::new(frame(b"<html></html>", b""))).unwrap_err();
+        // Empty
+        validate_warc_zst(Cursor::new(b"")).unwrap_err();
+    }
+
+    #[test]
+    fn test_dictionary() {
+        // A raw content dictionary; zstd accepts anything as one.
+        let dictionary = b"WARC/1.1\r\nWARC-Type: response\r\nContent-Length: ".repeat(4);
+        for stored in [
+            dictionary.clone(),
+            zstd::encode_all(&dictionary[..], 3).unwrap(),
+        ] {
+            let mut file = dictionary_frame(&stored);
+            file.extend(frame(
+                b"WARC/1.1\r\nWARC-Type: response\r\n\r\n",
+                &dictionary,
+            ));
+            assert_eq!(validate_warc_zst(Cursor::new(&file)).unwrap(), 1);
+
+            let mut reader = Cursor::new(&file);
+            let frame_len = dictionary_frame(&stored).len() as u64;
+            assert_eq!(read_dictionary_frame(&mut reader).unwrap(), Some(stored));
+            assert_eq!(reader.position(), frame_len);
+        }
+        // Without the dictionary, the record can't be decompressed.
+        let record = frame(b"WARC/1.1\r\n\r\n", &dictionary);
+        validate_warc_zst(Cursor::new(record)).unwrap_err();
+    }
+}
@@ -0,0 +1,247 @@
+//! Allow and deny lists of client addresses, for each group of routes: the admin routes
+//! (`/admin/...`, `/items/...`, `/metrics` and `/events`) and everything else.
+//!
+//! Each list is a comma-separated list of CIDR blocks or single addresses, read from the
+//! environment: `BULLSEYE_ADMIN_ALLOW`, `BULLSEYE_ADMIN_DENY`, `BULLSEYE_PUBLIC_ALLOW` and
+//! `BULLSEYE_PUBLIC_DENY`. A client is let in if it matches no deny rule and, if there are any
+//! allow rules, at least one of them.
+//!
+//! Behind a reverse proxy, put its address in `BULLSEYE_TRUSTED_PROXIES`. Then the client's
+//! address is taken from `X-Forwarded-For`, skipping any trusted proxies at the end of it.
+//! Proxies on the Unix socket are always trusted.
+
+use std::{io, net::IpAddr, str::FromStr};
+
+use actix_web::dev::ServiceRequest;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl FromStr for Cidr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix) = match s.split_once('/') {
+            Some((addr, prefix)) => (addr, Some(prefix)),
+            None => (s, None),
+        };
+        let addr: IpAddr = addr.parse().map_err(|e| format!("{s}: {e}"))?;
+        let max = if addr.is_ipv4() { 32 } else { 128 };
+        let prefix = match prefix {
+            Some(p) => p.parse().map_err(|e| format!("{s}: {e}"))?,
+            None => max,
+        };
+        if prefix > max {
+            return Err(format!("{s}: prefix is longer than the address"));
+        }
+        Ok(Self { addr, prefix })
+    }
+}
+
+impl Cidr {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        // Compare IPv4 clients that show up as IPv4-mapped IPv6 addresses as IPv4.
+        let ip = match ip {
+            IpAddr::V6(v6) => v6.to_ipv4_mapped().map_or(ip, IpAddr::V4),
+            ip => ip,
+        };
+        let (net, ip, bits) = match (self.addr, ip) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => (u32::from(net).into(), u32::from(ip).into(), 32),
+            (IpAddr::V6(net), IpAddr::V6(ip)) => (u128::from(net), u128::from(ip), 128),
+            _ => return false,
+        };
+        let mask = u128::MAX
+            .checked_shl(bits - u32::from(self.prefix))
+            .unwrap_or(0);
+        net & mask == ip & mask
+    }
+}
+
+/// Parses a comma-separated list of CIDR blocks.
+fn parse_list(s: &str) -> Result<Vec<Cidr>, String> {
+    s.split(',')
+        .map(str::trim)
+        .filter(|c| !c.is_empty())
+        .map(Cidr::from_str)
+        .collect()
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Rules {
+    pub allow: Vec<Cidr>,
+    pub deny: Vec<Cidr>,
+}
+
+impl Rules {
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|c| c.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|c| c.contains(ip)))
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct AccessConfig {
+    pub admin: Rules,
+    pub public: Rules,
+    pub trusted_proxies: Vec<Cidr>,
+}
+
+impl AccessConfig {
+    pub fn from_env() -> io::Result<Self> {
+        fn var(name: &str) -> io::Result<Vec<Cidr>> {
+            match std::env::var(name) {
+                Ok(v) => parse_list(&v).map_err(|e| io::Error::other(format!("{name}: {e}"))),
+                Err(_) => Ok(Vec::new()),
+            }
+        }
+        Ok(Self {
+            admin: Rules {
+                allow: var("BULLSEYE_ADMIN_ALLOW")?,
+                deny: var("BULLSEYE_ADMIN_DENY")?,
+            },
+            public: Rules {
+                allow: var("BULLSEYE_PUBLIC_ALLOW")?,
+                deny: var("BULLSEYE_PUBLIC_DENY")?,
+            },
+            trusted_proxies: var("BULLSEYE_TRUSTED_PROXIES")?,
+        })
+    }
+
+    /// Works out the client's address from the peer's address and `X-Forwarded-For`.
+    fn client_ip(&self, peer: IpAddr, forwarded_for: Option<&str>) -> IpAddr {
+        let trusted = |ip: IpAddr| self.trusted_proxies.iter().any(|c| c.contains(ip));
+        if !trusted(peer) {
+            return peer;
+        }
+        let mut client = peer;
+        // Proxies add the address they got the request from to the end.
+        for hop in forwarded_for.unwrap_or("").rsplit(',') {
+            let Ok(hop) = hop.trim().parse::<IpAddr>() else {
+                break;
+            };
+            client = hop;
+            if !trusted(hop) {
+                break;
+            }
+        }
+        client
+    }
+
+    /// Works out the client's address for a request over the Unix socket. Whatever connected to
+    /// it is on this machine, so it's trusted like a proxy. If it didn't say who it's forwarding
+    /// for, the request is its own.
+    fn socket_client_ip(&self, forwarded_for: Option<&str>) -> Option<IpAddr> {
+        let forwarded_for = forwarded_for?;
+        let (rest, last) = forwarded_for
+            .rsplit_once(',')
+            .unwrap_or(("", forwarded_for));
+        let last = last.trim().parse().ok()?;
+        Some(self.client_ip(last, Some(rest)))
+    }
+
+    /// Decides whether to let a request in. Returns the client's address either way, if it's
+    /// known.
+    pub fn check(&self, req: &ServiceRequest) -> (bool, Option<IpAddr>) {
+        let forwarded_for = req
+            .headers()
+            .get("X-Forwarded-For")
+            .and_then(|v| v.to_str().ok());
+        let ip = match req.peer_addr() {
+            Some(peer) => self.client_ip(peer.ip(), forwarded_for),
+            None => match self.socket_client_ip(forwarded_for) {
+                Some(ip) => ip,
+                // Straight from this machine.
+                None => return (true, None),
+            },
+        };
+        let path = req.path();
+        let admin = ["/admin/", "/items/"].iter().any(|p| path.starts_with(p));
+        let rules = if admin || path == "/metrics" || path == "/events" {
+            &self.admin
+        } else {
+            &self.public
+        };
+        (rules.permits(ip), Some(ip))
+    }
+}
+
+/// The client's address, as worked out by `AccessConfig::check`. It's put in the request's
+/// extensions.
+#[derive(Clone, Copy, Debug)]
+pub struct ClientIp(pub IpAddr);
+
+#[cfg(test)]
+mod tests {
+    use std::net::IpAddr;
+
+    use super::{parse_list, AccessConfig, Cidr, Rules};
+
+    fn ip(s: &str) -> IpAddr {
+        s.parse().unwrap()
+    }
+
+    #[test]
+    fn test_cidr() {
+        let net: Cidr = "10.1.0.0/16".parse().unwrap();
+        assert!(net.contains(ip("10.1.2.3")));
+        assert!(!net.contains(ip("10.2.0.1")));
+        assert!(net.contains(ip("::ffff:10.1.2.3")));
+        assert!(!net.contains(ip("fd00::1")));
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains(ip("192.0.2.1")));
+        let v6: Cidr = "fd00::/8".parse().unwrap();
+        assert!(v6.contains(ip("fd12::1")));
+        let single: Cidr = "192.0.2.1".parse().unwrap();
+        assert!(single.contains(ip("192.0.2.1")));
+        assert!(!single.contains(ip("192.0.2.2")));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("example.com".parse::<Cidr>().is_err());
+    }
+
+    #[test]
+    fn test_rules() {
+        let rules = Rules {
+            allow: parse_list("10.0.0.0/8, 192.0.2.1").unwrap(),
+            deny: parse_list("10.6.6.0/24").unwrap(),
+        };
+        assert!(rules.permits(ip("10.1.1.1")));
+        assert!(rules.permits(ip("192.0.2.1")));
+        assert!(!rules.permits(ip("10.6.6.6")));
+        assert!(!rules.permits(ip("198.51.100.1")));
+        assert!(Rules::default().permits(ip("198.51.100.1")));
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let config = AccessConfig {
+            trusted_proxies: parse_list("127.0.0.1, 10.0.0.0/8").unwrap(),
+            ..Default::default()
+        };
+        // Untrusted peers can't pretend to be somebody else.
+        assert_eq!(
+            config.client_ip(ip("198.51.100.1"), Some("10.0.0.1")),
+            ip("198.51.100.1")
+        );
+        assert_eq!(
+            config.client_ip(ip("127.0.0.1"), Some("203.0.113.9, 198.51.100.1, 10.0.0.2")),
+            ip("198.51.100.1")
+        );
+        assert_eq!(config.client_ip(ip("127.0.0.1"), None), ip("127.0.0.1"));
+        assert_eq!(
+            config.client_ip(ip("127.0.0.1"), Some("garbage")),
+            ip("127.0.0.1")
+        );
+        assert_eq!(
+            config.socket_client_ip(Some("203.0.113.9, 198.51.100.1")),
+            Some(ip("198.51.100.1"))
+        );
+        assert_eq!(
+            config.socket_client_ip(Some("198.51.100.1, 10.0.0.2")),
+            Some(ip("198.51.100.1"))
+        );
+        assert_eq!(config.socket_client_ip(None), None);
+    }
+}
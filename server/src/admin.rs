@@ -0,0 +1,449 @@
+//! Endpoints for operators. These are only enabled if `BULLSEYE_ADMIN_TOKEN` is set, and require
+//! it as a bearer token.
+
+use std::time::SystemTime;
+
+use actix_web::{
+    delete, get,
+    http::header::AUTHORIZATION,
+    post,
+    web::{self, Bytes, Json},
+    HttpMessage, HttpRequest, HttpResponse,
+};
+use async_stream::stream;
+use common::db::{AuditEntry, Ban, DbError, Status, UploadRow};
+use common::signing::constant_time_eq;
+use futures::{pin_mut, StreamExt};
+use serde::Deserialize;
+
+use crate::{
+    audit::AuditUploads,
+    compress,
+    error::{ApiError, ApiResult},
+    payloads::*,
+    SharedCtx,
+};
+
+const ADMIN_TOKEN_ENV: &str = "BULLSEYE_ADMIN_TOKEN";
+
+/// Reads the admin token from the environment.
+pub fn token_from_env() -> Option<String> {
+    std::env::var(ADMIN_TOKEN_ENV)
+        .ok()
+        .filter(|t| !t.is_empty())
+}
+
+/// Whether the request carries the admin token.
+pub fn is_admin(req: &HttpRequest, conn: &SharedCtx) -> bool {
+    let Some(token) = &conn.admin_token else {
+        return false;
+    };
+    let given = req
+        .headers()
+        .get(AUTHORIZATION)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|h| h.strip_prefix("Bearer "));
+    given.is_some_and(|given| constant_time_eq(given.as_bytes(), token.as_bytes()))
+}
+
+/// Returns an error unless the request carries the admin token. If there's no token, the admin
+/// endpoints aren't there at all.
+pub fn check_auth(req: &HttpRequest, conn: &SharedCtx) -> Result<(), ApiError> {
+    if conn.admin_token.is_none() {
+        return Err(ApiError::NotFound);
+    }
+    match is_admin(req, conn) {
+        true => Ok(()),
+        false => Err(ApiError::Unauthorized),
+    }
+}
+
+#[derive(Deserialize)]
+struct DeadLettersQuery {
+    project: Option<String>,
+}
+
+#[get("/admin/dead_letters")]
+async fn dead_letters(
+    conn: web::Data<SharedCtx>,
+    req: HttpRequest,
+    query: web::Query<DeadLettersQuery>,
+) -> ApiResult {
+    check_auth(&req, &conn)?;
+    let rows: DeadLettersResponse =
+        UploadRow::list_dead_letters(&conn.pool, query.project.clone()).await?;
+    Ok(ErrorablePayload::Ok(rows).to_response(HttpResponse::Ok()))
+}
+
+#[post("/admin/dead_letters/requeue")]
+async fn requeue_dead_letters(
+    conn: web::Data<SharedCtx>,
+    req: HttpRequest,
+    payload: Json<BulkActionPayload>,
+) -> ApiResult {
+    check_auth(&req, &conn)?;
+    req.extensions_mut()
+        .insert(AuditUploads(payload.ids.clone()));
+    let mut result = BulkActionResponse::default();
+    for id in &payload.ids {
+        let res = match UploadRow::from_database(&conn.pool, id.clone()).await {
+            Ok(mut row) => match row.requeue(&conn.pool).await {
+                Ok(()) => {
+                    row.record_manifest_note(Some("requeued by an operator".to_string()))
+                        .await;
+                    Ok(())
+                }
+                Err(e) => Err(e),
+            },
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok(()) => result.succeeded.push(id.clone()),
+            Err(e) => {
+                result.failed.insert(id.clone(), e.to_string());
+            }
+        }
+    }
+    Ok(ErrorablePayload::Ok(result).to_response(HttpResponse::Ok()))
+}
+
+#[post("/admin/dead_letters/discard")]
+async fn discard_dead_letters(
+    conn: web::Data<SharedCtx>,
+    req: HttpRequest,
+    payload: Json<BulkActionPayload>,
+) -> ApiResult {
+    check_auth(&req, &conn)?;
+    req.extensions_mut()
+        .insert(AuditUploads(payload.ids.clone()));
+    let mut result = BulkActionResponse::default();
+    for id in &payload.ids {
+        let res = match UploadRow::from_database(&conn.pool, id.clone()).await {
+            Ok(row) if row.status() != &Status::DeadLetter => Err(DbError::WrongStatus),
+            Ok(mut row) => {
+                let grace = conn.registry.get().delete_grace(row.project());
+                match row.soft_delete(&conn.pool, grace).await {
+                    Ok(()) => {
+                        row.record_manifest_note(Some("discarded by an operator".to_string()))
+                            .await;
+                        Ok(())
+                    }
+                    Err(e) => Err(e),
+                }
+            }
+            Err(e) => Err(e),
+        };
+        match res {
+            Ok(()) => result.succeeded.push(id.clone()),
+            Err(e) => {
+                result.failed.insert(id.clone(), e.to_string());
+            }
+        }
+    }
+    Ok(ErrorablePayload::Ok(result).to_response(HttpResponse::Ok()))
+}
+
+/// The most audit entries returned at once.
+const MAX_AUDIT_ENTRIES: usize = 1000;
+
+#[derive(Deserialize)]
+struct AuditQuery {
+    upload: Option<String>,
+    limit: Option<usize>,
+}
+
+#[get("/admin/audit")]
+async fn audit_trail(
+    conn: web::Data<SharedCtx>,
+    req: HttpRequest,
+    query: web::Query<AuditQuery>,
+) -> ApiResult {
+    check_auth(&req, &conn)?;
+    let limit = query.limit.unwrap_or(100).min(MAX_AUDIT_ENTRIES);
+    let entries: AuditResponse = AuditEntry::list(&conn.pool, query.upload.clone(), limit).await?;
+    Ok(ErrorablePayload::Ok(entries).to_response(HttpResponse::Ok()))
+}
+
+#[get("/admin/bans")]
+async fn bans(conn: web::Data<SharedCtx>, req: HttpRequest) -> ApiResult {
+    check_auth(&req, &conn)?;
+    let bans: BansResponse = Ban::list(&conn.pool).await?;
+    Ok(ErrorablePayload::Ok(bans).to_response(HttpResponse::Ok()))
+}
+
+#[post("/admin/bans")]
+async fn ban(conn: web::Data<SharedCtx>, req: HttpRequest, payload: Json<BanPayload>) -> ApiResult {
+    check_auth(&req, &conn)?;
+    let ban = Ban {
+        uploader: payload.uploader.clone(),
+        reason: payload.reason.clone(),
+        time: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+    };
+    ban.save(&conn.pool).await?;
+    conn.bans.invalidate();
+    Ok(ErrorablePayload::Ok(ban).to_response(HttpResponse::Ok()))
+}
+
+#[delete("/admin/bans/{uploader}")]
+async fn lift_ban(
+    conn: web::Data<SharedCtx>,
+    req: HttpRequest,
+    path: web::Path<String>,
+) -> ApiResult {
+    check_auth(&req, &conn)?;
+    Ban::lift(&conn.pool, path.into_inner()).await?;
+    conn.bans.invalidate();
+    Ok(ErrorablePayload::Ok(()).to_response(HttpResponse::Ok()))
+}
+
+#[get("/admin/pins")]
+async fn pins(conn: web::Data<SharedCtx>, req: HttpRequest) -> ApiResult {
+    check_auth(&req, &conn)?;
+    let rows: PinsResponse = UploadRow::list_pinned(&conn.pool).await?;
+    Ok(ErrorablePayload::Ok(rows).to_response(HttpResponse::Ok()))
+}
+
+/// Pins or unpins an upload.
+async fn set_pinned(conn: &SharedCtx, req: &HttpRequest, id: String, pinned: bool) -> ApiResult {
+    check_auth(req, conn)?;
+    req.extensions_mut().insert(AuditUploads(vec![id.clone()]));
+    let mut row = UploadRow::from_database(&conn.pool, id).await?;
+    row.set_pinned(&conn.pool, pinned).await?;
+    let note = if pinned { "pinned" } else { "unpinned" };
+    row.record_manifest_note(Some(format!("{note} by an operator")))
+        .await;
+    Ok(ErrorablePayload::Ok(()).to_response(HttpResponse::Ok()))
+}
+
+#[post("/admin/pins/{uuid}")]
+async fn pin(conn: web::Data<SharedCtx>, req: HttpRequest, path: web::Path<String>) -> ApiResult {
+    set_pinned(&conn, &req, path.into_inner(), true).await
+}
+
+#[delete("/admin/pins/{uuid}")]
+async fn unpin(conn: web::Data<SharedCtx>, req: HttpRequest, path: web::Path<String>) -> ApiResult {
+    set_pinned(&conn, &req, path.into_inner(), false).await
+}
+
+/// Sets an upload's status, as long as its pipeline allows the change or `force` is set. The
+/// reason goes in the upload's history.
+#[post("/admin/upload/{uuid}/status")]
+async fn set_status(
+    conn: web::Data<SharedCtx>,
+    req: HttpRequest,
+    path: web::Path<String>,
+    payload: Json<StatusChangePayload>,
+) -> ApiResult {
+    check_auth(&req, &conn)?;
+    let id = path.into_inner();
+    req.extensions_mut().insert(AuditUploads(vec![id.clone()]));
+    if payload.reason.trim().is_empty() {
+        return Err(ApiError::Invalid("A reason is required".to_string()));
+    }
+    let mut row = UploadRow::from_database(&conn.pool, id).await?;
+    let old = row.status().clone();
+    let result = match (payload.force, conn.registry.get().pipeline(row.pipeline())) {
+        (true, _) => row.change_status(&conn.pool, payload.status.clone()).await,
+        (false, Some(pipeline)) => {
+            row.transition(&conn.pool, &pipeline, payload.status.clone())
+                .await
+        }
+        (false, None) => Err(DbError::WrongStatus),
+    };
+    match result {
+        Ok(()) => {}
+        Err(DbError::WrongStatus) => {
+            return Err(ApiError::Conflict(format!(
+                "The pipeline doesn't allow going from {old} to {}; set force to do it anyway",
+                payload.status
+            )))
+        }
+        Err(e) => return Err(e.into()),
+    }
+    let forced = if payload.force { ", forced" } else { "" };
+    let note = format!(
+        "status changed from {old} to {} by an operator{forced}: {}",
+        payload.status, payload.reason
+    );
+    log::info!("{}: {note}", row.id());
+    row.record_manifest_note(Some(note)).await;
+    Ok(ErrorablePayload::<SingleUploadResponse>::Ok(row).to_response(HttpResponse::Ok()))
+}
+
+/// Requeues every failed or dead-lettered upload with a status, optionally only those that have
+/// been sitting there for a while or that are in one project or pipeline. Failed uploads go back
+/// to the first stage of their pipeline, and dead-lettered ones to where they were
+/// dead-lettered from.
+#[post("/admin/requeue")]
+async fn requeue(
+    conn: web::Data<SharedCtx>,
+    req: HttpRequest,
+    payload: Json<RequeuePayload>,
+) -> ApiResult {
+    check_auth(&req, &conn)?;
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    let cutoff = now.saturating_sub(payload.older_than);
+    let mut skipped_pipelines = Vec::new();
+    let rows = match &payload.status {
+        Status::DeadLetter => {
+            UploadRow::requeue_matching(
+                &conn.pool,
+                payload.status.clone(),
+                payload.project.clone(),
+                payload.pipeline.clone(),
+                cutoff,
+                None,
+            )
+            .await?
+        }
+        Status::Error(_) => {
+            let registry = conn.registry.get();
+            // The first stage depends on the pipeline, so each one is done separately.
+            let names = match &payload.pipeline {
+                Some(name) => vec![name.clone()],
+                None => {
+                    UploadRow::pipelines_with_status(
+                        &conn.pool,
+                        payload.status.clone(),
+                        payload.project.clone(),
+                    )
+                    .await?
+                }
+            };
+            let mut rows = Vec::new();
+            for name in names {
+                let first = registry
+                    .pipeline(&name)
+                    .and_then(|pipeline| pipeline.next(&Status::Uploading));
+                let Some(first) = first else {
+                    if payload.pipeline.is_some() {
+                        return Err(ApiError::Invalid(format!(
+                            "Unknown pipeline {name}, or it has no stages"
+                        )));
+                    }
+                    log::warn!("not requeueing uploads in pipeline {name}, which isn't in the registry or has no stages");
+                    skipped_pipelines.push(name);
+                    continue;
+                };
+                rows.extend(
+                    UploadRow::requeue_matching(
+                        &conn.pool,
+                        payload.status.clone(),
+                        payload.project.clone(),
+                        Some(name),
+                        cutoff,
+                        Some(first),
+                    )
+                    .await?,
+                );
+            }
+            rows
+        }
+        status => {
+            return Err(ApiError::Invalid(format!(
+                "Only failed and dead-lettered uploads can be requeued, not {status}"
+            )))
+        }
+    };
+    req.extensions_mut()
+        .insert(AuditUploads(rows.iter().map(|r| r.id().clone()).collect()));
+    log::info!(
+        "requeued {} uploads with status {}",
+        rows.len(),
+        payload.status
+    );
+    let requeued = rows.len() as u64;
+    let note = format!("requeued from {} by an operator", payload.status);
+    UploadRow::record_manifest_notes(rows, Some(note)).await;
+    Ok(ErrorablePayload::Ok(RequeueResponse {
+        requeued,
+        skipped_pipelines,
+    })
+    .to_response(HttpResponse::Ok()))
+}
+
+/// Reads the registry again, like SIGHUP. If it's invalid, the old one is kept and the error is
+/// returned.
+#[post("/admin/reload")]
+async fn reload(conn: web::Data<SharedCtx>, req: HttpRequest) -> ApiResult {
+    check_auth(&req, &conn)?;
+    conn.registry
+        .reload()
+        .map_err(|e| ApiError::Internal(format!("failed to reload the registry: {e}")))?;
+    Ok(ErrorablePayload::Ok(()).to_response(HttpResponse::Ok()))
+}
+
+/// Turns maintenance mode on or off. See `maintenance`.
+#[post("/admin/maintenance")]
+async fn maintenance(
+    conn: web::Data<SharedCtx>,
+    req: HttpRequest,
+    payload: Json<MaintenancePayload>,
+) -> ApiResult {
+    check_auth(&req, &conn)?;
+    let status = conn.maintenance.set(&payload);
+    log::info!(
+        "maintenance mode is {}",
+        if status.draining { "on" } else { "off" }
+    );
+    Ok(ErrorablePayload::Ok(status).to_response(HttpResponse::Ok()))
+}
+
+#[derive(Deserialize)]
+struct FirehoseQuery {
+    project: Option<String>,
+    pipeline: Option<String>,
+}
+
+/// Streams the status changes of every upload as JSON lines, for dashboards and trackers that
+/// mirror the server's state.
+#[get("/events")]
+async fn firehose(
+    conn: web::Data<SharedCtx>,
+    req: HttpRequest,
+    query: web::Query<FirehoseQuery>,
+) -> ApiResult {
+    check_auth(&req, &conn)?;
+    let FirehoseQuery { project, pipeline } = query.into_inner();
+    let conn = conn.into_inner();
+    Ok(compress::streaming(
+        &req,
+        stream! {
+            let iter = UploadRow::stream_all_events(&conn.pool, project, pipeline);
+            pin_mut!(iter);
+            while let Some(event) = iter.next().await {
+                if let Ok(mut serialized) = serde_json::to_vec(&event) {
+                    serialized.push(b'\n');
+                    yield Ok(Bytes::from(serialized));
+                } else {
+                    yield Err("JSON serialize error\n");
+                }
+            }
+        },
+    ))
+}
+
+/// Registers the admin endpoints.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(dead_letters)
+        .service(requeue_dead_letters)
+        .service(discard_dead_letters)
+        .service(audit_trail)
+        .service(bans)
+        .service(ban)
+        .service(lift_ban)
+        .service(pins)
+        .service(pin)
+        .service(unpin)
+        .service(set_status)
+        .service(requeue)
+        .service(reload)
+        .service(maintenance)
+        .service(firehose);
+}
@@ -0,0 +1,68 @@
+//! Records every request that changes something in the audit trail: who made it, what it was
+//! about, and how it went. Chunks aren't recorded, since they're most of the requests and
+//! recording each one would put a database write in the way of every chunk; starting, finishing
+//! and abandoning the upload they're part of are.
+
+use std::time::SystemTime;
+
+use actix_web::{dev::ServiceResponse, http::Method, web, HttpMessage};
+use common::data::AuditEntry;
+use log::warn;
+
+use crate::{access::ClientIp, admin, SharedCtx};
+
+/// Endpoints that change things but aren't recorded, as their route patterns.
+const UNAUDITED: &[&str] = &["/upload/{uuid}/data"];
+
+/// The uploads a request was about, for requests that don't have one in their path. Handlers put
+/// this in the request's extensions.
+pub struct AuditUploads(pub Vec<String>);
+
+/// Records a request if it changes anything. Failing to record it doesn't fail the request.
+pub async fn record<B>(res: &ServiceResponse<B>) {
+    let req = res.request();
+    if matches!(*req.method(), Method::GET | Method::HEAD | Method::OPTIONS) {
+        return;
+    }
+    if req
+        .match_pattern()
+        .is_some_and(|pattern| UNAUDITED.contains(&pattern.as_str()))
+    {
+        return;
+    }
+    let Some(conn) = req.app_data::<web::Data<SharedCtx>>() else {
+        return;
+    };
+    let uploads = match req.extensions().get::<AuditUploads>() {
+        Some(AuditUploads(uploads)) => uploads.clone(),
+        None => req
+            .match_info()
+            .get("uuid")
+            .map(str::to_string)
+            .into_iter()
+            .collect(),
+    };
+    let entry = AuditEntry {
+        time: SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs(),
+        remote: req
+            .extensions()
+            .get::<ClientIp>()
+            .map(|ClientIp(ip)| ip.to_string()),
+        admin: admin::is_admin(req, conn),
+        method: req.method().to_string(),
+        endpoint: req
+            .match_pattern()
+            .unwrap_or_else(|| req.path().to_string()),
+        uploads,
+        status: res.status().as_u16(),
+    };
+    if let Err(e) = entry.record(&conn.pool).await {
+        warn!(
+            "failed to record {} {} in the audit trail: {e}",
+            entry.method, entry.endpoint
+        );
+    }
+}
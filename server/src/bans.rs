@@ -0,0 +1,82 @@
+//! Turning away banned uploaders. New uploads check the database, so a ban takes effect for them
+//! straight away; chunks check a copy of the bans that's refreshed every `BULLSEYE_BAN_CACHE`
+//! seconds (30 by default; 0 turns the cache off), so that sending one doesn't mean a query.
+
+use std::{
+    collections::HashMap,
+    io,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use common::db::{Ban, DatabaseHandle, DbError};
+use common::payloads::Rejection;
+
+use crate::error::ApiError;
+
+pub struct BanCache {
+    ttl: Duration,
+    cached: Mutex<Option<(Instant, HashMap<String, Ban>)>>,
+}
+
+impl BanCache {
+    pub fn from_env() -> io::Result<Self> {
+        let ttl = match std::env::var("BULLSEYE_BAN_CACHE") {
+            Ok(v) => v
+                .parse()
+                .map_err(|e| io::Error::other(format!("BULLSEYE_BAN_CACHE: {e}")))?,
+            Err(_) => 30,
+        };
+        Ok(Self {
+            ttl: Duration::from_secs(ttl),
+            cached: Mutex::new(None),
+        })
+    }
+
+    /// Gets an uploader's ban, if they're banned, from the copy.
+    async fn get(&self, conn: &DatabaseHandle, uploader: &str) -> Result<Option<Ban>, DbError> {
+        if self.ttl.is_zero() {
+            return Ban::get(conn, uploader.to_string()).await;
+        }
+        if let Some((at, bans)) = &*self.cached.lock().unwrap() {
+            if at.elapsed() < self.ttl {
+                return Ok(bans.get(uploader).cloned());
+            }
+        }
+        // Several requests might refresh it at once when it runs out, which is fine.
+        let bans: HashMap<String, Ban> = Ban::list(conn)
+            .await?
+            .into_iter()
+            .map(|ban| (ban.uploader.clone(), ban))
+            .collect();
+        let ban = bans.get(uploader).cloned();
+        *self.cached.lock().unwrap() = Some((Instant::now(), bans));
+        Ok(ban)
+    }
+
+    /// Throws the copy away, so a ban made or lifted through this instance applies to chunks
+    /// straight away. Other instances catch up when theirs runs out.
+    pub fn invalidate(&self) {
+        *self.cached.lock().unwrap() = None;
+    }
+
+    /// Turns away an uploader that's starting an upload, if they're banned.
+    pub async fn check_new(&self, conn: &DatabaseHandle, uploader: &str) -> Result<(), ApiError> {
+        rejection(Ban::get(conn, uploader.to_string()).await?)
+    }
+
+    /// Turns away a chunk from an uploader, if they're banned.
+    pub async fn check_chunk(&self, conn: &DatabaseHandle, uploader: &str) -> Result<(), ApiError> {
+        rejection(self.get(conn, uploader).await?)
+    }
+}
+
+fn rejection(ban: Option<Ban>) -> Result<(), ApiError> {
+    match ban {
+        Some(ban) => Err(Rejection::Banned {
+            message: ban.reason,
+        }
+        .into()),
+        None => Ok(()),
+    }
+}
@@ -0,0 +1,69 @@
+//! A sink for `bullseye-client bench` to measure throughput against. It's only there if
+//! `BULLSEYE_BENCHMARK_SINK` is set, since anyone can use it to make the server do work.
+
+use std::path::Path;
+
+use actix_web::{put, web, HttpResponse};
+use futures::StreamExt;
+use serde::Deserialize;
+use tokio::{
+    fs::{remove_file, File},
+    io::AsyncWriteExt,
+};
+
+use crate::{
+    error::{ApiError, ApiResult},
+    payloads::*,
+    SharedCtx,
+};
+
+/// Whether to turn on the sink.
+pub fn enabled_from_env() -> bool {
+    std::env::var("BULLSEYE_BENCHMARK_SINK").is_ok_and(|v| !v.is_empty() && v != "0")
+}
+
+#[derive(Deserialize)]
+struct SinkQuery {
+    #[serde(default)]
+    mode: SinkMode,
+}
+
+/// Reads the body, doing what the mode says with it. Returns how many bytes there were.
+async fn sink(mut body: web::Payload, mode: SinkMode, scratch: &Path) -> Result<u64, String> {
+    let mut file = match mode {
+        SinkMode::Discard => None,
+        _ => Some(File::create(scratch).await.map_err(|e| e.to_string())?),
+    };
+    let mut received = 0;
+    while let Some(chunk) = body.next().await {
+        let chunk = chunk.map_err(|e| e.to_string())?;
+        if let Some(file) = &mut file {
+            file.write_all(&chunk).await.map_err(|e| e.to_string())?;
+            if mode == SinkMode::Fsync {
+                file.sync_all().await.map_err(|e| e.to_string())?;
+            }
+        }
+        received += chunk.len() as u64;
+    }
+    Ok(received)
+}
+
+#[put("/benchmark/sink")]
+async fn benchmark_sink(
+    conn: web::Data<SharedCtx>,
+    body: web::Payload,
+    query: web::Query<SinkQuery>,
+) -> ApiResult {
+    let scratch = conn.cwd.join(format!("benchmark-{}.tmp", uuidv7::create()));
+    let res = sink(body, query.mode, &scratch).await;
+    if query.mode != SinkMode::Discard {
+        let _ = remove_file(&scratch).await;
+    }
+    let received: BenchmarkSinkResponse = res.map_err(ApiError::Internal)?;
+    Ok(ErrorablePayload::Ok(received).to_response(HttpResponse::Created()))
+}
+
+/// Registers the sink.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(benchmark_sink);
+}
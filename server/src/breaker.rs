@@ -0,0 +1,199 @@
+//! A circuit breaker around the database. Once requests have failed with database errors
+//! `BULLSEYE_DB_BREAKER_FAILURES` times (5 by default, 0 to turn the breaker off) within
+//! `BULLSEYE_DB_BREAKER_WINDOW` seconds (10 by default), the breaker opens: requests are turned
+//! away straight away with a 503, instead of each one queueing on a pool that can't reach the
+//! database. Every `BULLSEYE_DB_BREAKER_COOLDOWN` seconds (5 by default) a trivial query checks
+//! whether the database is back, and the breaker closes as soon as it answers.
+
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use common::db::{DatabaseHandle, DbError};
+
+use crate::error::ApiError;
+
+enum State {
+    /// Requests go through. There have been `failures` database errors since `since`.
+    Closed { failures: u32, since: Instant },
+    /// Requests are turned away. The database is checked again at `until`.
+    Open { until: Instant },
+}
+
+pub struct Breaker {
+    threshold: u32,
+    window: Duration,
+    cooldown: Duration,
+    state: Mutex<State>,
+}
+
+fn from_env_or<T: std::str::FromStr>(name: &str, default: T) -> io::Result<T>
+where
+    T::Err: std::fmt::Display,
+{
+    match std::env::var(name) {
+        Ok(v) => v
+            .parse()
+            .map_err(|e| io::Error::other(format!("{name}: {e}"))),
+        Err(_) => Ok(default),
+    }
+}
+
+impl Breaker {
+    fn new(threshold: u32, window: Duration, cooldown: Duration) -> Self {
+        Self {
+            threshold,
+            window,
+            cooldown,
+            state: Mutex::new(State::Closed {
+                failures: 0,
+                since: Instant::now(),
+            }),
+        }
+    }
+
+    /// Reads `BULLSEYE_DB_BREAKER_FAILURES`, `BULLSEYE_DB_BREAKER_WINDOW` and
+    /// `BULLSEYE_DB_BREAKER_COOLDOWN`.
+    pub fn from_env() -> io::Result<Self> {
+        Ok(Self::new(
+            from_env_or("BULLSEYE_DB_BREAKER_FAILURES", 5)?,
+            Duration::from_secs(from_env_or("BULLSEYE_DB_BREAKER_WINDOW", 10)?),
+            Duration::from_secs(from_env_or("BULLSEYE_DB_BREAKER_COOLDOWN", 5)?),
+        ))
+    }
+
+    /// Gets the error to send instead of handling a request, if the breaker is open. `/metrics`
+    /// and `/health` are always let through, like with `Overload`.
+    pub fn check(&self, path: &str) -> Option<ApiError> {
+        if path == "/metrics" || path == "/health" {
+            return None;
+        }
+        let State::Open { until } = *self.state.lock().unwrap() else {
+            return None;
+        };
+        let wait = until.saturating_duration_since(Instant::now());
+        Some(ApiError::Unavailable {
+            retry_after_secs: wait.as_secs().max(1),
+            reason: "the database isn't answering".to_string(),
+        })
+    }
+
+    /// Records how a request that was let through turned out. Only database errors that aren't
+    /// about the request itself, like a lost connection, count against the database.
+    pub fn record(&self, error: Option<&actix_web::Error>) {
+        let failed = error
+            .and_then(|e| e.as_error::<ApiError>())
+            .is_some_and(|e| matches!(e, ApiError::Db(DbError::Other)));
+        if !failed || self.threshold == 0 {
+            return;
+        }
+        let mut state = self.state.lock().unwrap();
+        let State::Closed { failures, since } = &mut *state else {
+            // It's already open. This request was let through before that.
+            return;
+        };
+        let now = Instant::now();
+        if now.duration_since(*since) > self.window {
+            (*failures, *since) = (0, now);
+        }
+        *failures += 1;
+        if *failures >= self.threshold {
+            log::warn!(
+                "{failures} database errors in {:?}, turning requests away for now",
+                now.duration_since(*since)
+            );
+            *state = State::Open {
+                until: now + self.cooldown,
+            };
+        }
+    }
+
+    /// Records whether the database answered a check, closing the breaker if it did.
+    fn probed(&self, ok: bool) {
+        let mut state = self.state.lock().unwrap();
+        *state = match ok {
+            true => {
+                log::info!("the database is answering again");
+                State::Closed {
+                    failures: 0,
+                    since: Instant::now(),
+                }
+            }
+            false => State::Open {
+                until: Instant::now() + self.cooldown,
+            },
+        };
+    }
+
+    /// Checks whether the database is back whenever the breaker has been open for a cooldown,
+    /// forever.
+    pub async fn probe(self: Arc<Self>, db: Arc<DatabaseHandle>) {
+        loop {
+            let due = match *self.state.lock().unwrap() {
+                State::Open { until } => until,
+                State::Closed { .. } => Instant::now() + self.cooldown,
+            };
+            tokio::time::sleep_until(due.into()).await;
+            if !matches!(*self.state.lock().unwrap(), State::Open { .. }) {
+                continue;
+            }
+            let answered = tokio::time::timeout(self.cooldown, db.ping()).await;
+            self.probed(matches!(answered, Ok(Ok(()))));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use common::db::DbError;
+
+    use super::Breaker;
+    use crate::error::ApiError;
+
+    #[test]
+    fn test_breaker() {
+        let breaker = Breaker::new(3, Duration::from_secs(60), Duration::from_secs(30));
+        let db_error = actix_web::Error::from(ApiError::Db(DbError::Other));
+        let not_found = actix_web::Error::from(ApiError::Db(DbError::NotFound));
+        breaker.record(Some(&db_error));
+        breaker.record(Some(&not_found));
+        breaker.record(None);
+        breaker.record(Some(&db_error));
+        assert!(breaker.check("/upload").is_none());
+
+        breaker.record(Some(&db_error));
+        match breaker.check("/upload") {
+            Some(ApiError::Unavailable {
+                retry_after_secs, ..
+            }) => assert!((29..=30).contains(&retry_after_secs)),
+            other => panic!("expected a 503, not {other:?}"),
+        }
+        assert!(breaker.check("/health").is_none());
+
+        breaker.probed(false);
+        assert!(breaker.check("/upload").is_some());
+        breaker.probed(true);
+        assert!(breaker.check("/upload").is_none());
+    }
+
+    #[test]
+    fn test_window() {
+        let breaker = Breaker::new(2, Duration::ZERO, Duration::from_secs(30));
+        let db_error = actix_web::Error::from(ApiError::Db(DbError::Other));
+        breaker.record(Some(&db_error));
+        std::thread::sleep(Duration::from_millis(10));
+        // The first one was too long ago to count.
+        breaker.record(Some(&db_error));
+        assert!(breaker.check("/upload").is_none());
+
+        let off = Breaker::new(0, Duration::from_secs(60), Duration::from_secs(30));
+        for _ in 0..10 {
+            off.record(Some(&db_error));
+        }
+        assert!(off.check("/upload").is_none());
+    }
+}
@@ -0,0 +1,178 @@
+//! Compression for the event streams. Each event is flushed as soon as it's written, so subscribers
+//! still get it straight away, but it's compressed along with everything sent before it, so the
+//! repetitive JSON shrinks a lot. actix's Compress middleware would hold events back until it had
+//! a buffer's worth.
+
+use std::{
+    io::{self, Write},
+    mem,
+};
+
+use actix_web::{
+    http::header::{ACCEPT_ENCODING, CONTENT_ENCODING, VARY},
+    web::Bytes,
+    HttpRequest, HttpResponse,
+};
+use async_stream::stream;
+use flate2::{write::GzEncoder, Compression};
+use futures::{pin_mut, Stream, StreamExt};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Zstd,
+}
+
+impl Encoding {
+    /// Picks an encoding the client accepts, preferring zstd.
+    pub fn negotiate(req: &HttpRequest) -> Option<Self> {
+        let header = req.headers().get(ACCEPT_ENCODING)?.to_str().ok()?;
+        Self::parse(header)
+    }
+
+    fn parse(header: &str) -> Option<Self> {
+        let accepted: Vec<&str> = header
+            .split(',')
+            .filter_map(|coding| {
+                let mut parts = coding.split(';').map(str::trim);
+                let name = parts.next()?;
+                let refused = parts.any(|p| {
+                    p.strip_prefix("q=")
+                        .and_then(|q| q.parse::<f32>().ok())
+                        .is_some_and(|q| q == 0.0)
+                });
+                (!refused).then_some(name)
+            })
+            .collect();
+        [Self::Zstd, Self::Gzip]
+            .into_iter()
+            .find(|e| accepted.iter().any(|a| a.eq_ignore_ascii_case(e.name())))
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Zstd => "zstd",
+        }
+    }
+}
+
+/// Compresses a stream a piece at a time, flushing after each one.
+enum StreamEncoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Zstd(zstd::stream::write::Encoder<'static, Vec<u8>>),
+}
+
+impl StreamEncoder {
+    fn new(encoding: Encoding) -> io::Result<Self> {
+        Ok(match encoding {
+            Encoding::Gzip => Self::Gzip(GzEncoder::new(Vec::new(), Compression::default())),
+            Encoding::Zstd => Self::Zstd(zstd::stream::write::Encoder::new(Vec::new(), 3)?),
+        })
+    }
+
+    /// Compresses the data and returns everything that's ready to be sent, which is enough to
+    /// decompress all the data so far.
+    fn encode(&mut self, data: &[u8]) -> io::Result<Bytes> {
+        let out = match self {
+            Self::Gzip(encoder) => {
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                encoder.get_mut()
+            }
+            Self::Zstd(encoder) => {
+                encoder.write_all(data)?;
+                encoder.flush()?;
+                encoder.get_mut()
+            }
+        };
+        Ok(Bytes::from(mem::take(out)))
+    }
+
+    /// Finishes the stream, returning what's left to send.
+    fn finish(self) -> io::Result<Bytes> {
+        let out = match self {
+            Self::Gzip(encoder) => encoder.finish()?,
+            Self::Zstd(encoder) => encoder.finish()?,
+        };
+        Ok(Bytes::from(out))
+    }
+}
+
+/// Responds with a stream of events, compressed if the client accepts it.
+pub fn streaming<S>(req: &HttpRequest, events: S) -> HttpResponse
+where
+    S: Stream<Item = Result<Bytes, &'static str>> + 'static,
+{
+    let mut resp = HttpResponse::Ok();
+    resp.insert_header((VARY, "Accept-Encoding"));
+    let encoder = Encoding::negotiate(req).and_then(|e| Some((e, StreamEncoder::new(e).ok()?)));
+    let Some((encoding, mut encoder)) = encoder else {
+        return resp.streaming(events);
+    };
+    resp.insert_header((CONTENT_ENCODING, encoding.name()))
+        .streaming(stream! {
+            pin_mut!(events);
+            while let Some(event) = events.next().await {
+                yield event.and_then(|data| encoder.encode(&data).map_err(|_| "compression error\n"));
+            }
+            if let Ok(rest) = encoder.finish() {
+                yield Ok(rest);
+            }
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::{Encoding, StreamEncoder};
+
+    #[test]
+    fn test_negotiate() {
+        assert_eq!(
+            Encoding::parse("gzip, deflate, br, zstd"),
+            Some(Encoding::Zstd)
+        );
+        assert_eq!(Encoding::parse("GZIP"), Some(Encoding::Gzip));
+        assert_eq!(
+            Encoding::parse("zstd;q=0, gzip;q=0.5"),
+            Some(Encoding::Gzip)
+        );
+        assert_eq!(Encoding::parse("br, identity"), None);
+    }
+
+    /// Ensures that each event can be read as soon as it's sent.
+    #[test]
+    fn test_flush_per_event() {
+        let events: [&[u8]; 2] = [b"{\"type\":\"progress\"}\n", b"{\"type\":\"status\"}\n"];
+        for encoding in [Encoding::Gzip, Encoding::Zstd] {
+            let mut encoder = StreamEncoder::new(encoding).unwrap();
+            let mut sent = Vec::new();
+            let mut expected = Vec::new();
+            for event in events {
+                sent.extend_from_slice(&encoder.encode(event).unwrap());
+                expected.extend_from_slice(event);
+                let mut got = vec![0; expected.len()];
+                let read = match encoding {
+                    Encoding::Gzip => flate2::read::GzDecoder::new(&sent[..]).read_exact(&mut got),
+                    Encoding::Zstd => zstd::stream::read::Decoder::new(&sent[..])
+                        .unwrap()
+                        .read_exact(&mut got),
+                };
+                read.unwrap();
+                assert_eq!(got, expected, "{encoding:?}");
+            }
+            sent.extend_from_slice(&encoder.finish().unwrap());
+            let mut got = Vec::new();
+            match encoding {
+                Encoding::Gzip => flate2::read::GzDecoder::new(&sent[..]).read_to_end(&mut got),
+                Encoding::Zstd => zstd::stream::read::Decoder::new(&sent[..])
+                    .unwrap()
+                    .read_to_end(&mut got),
+            }
+            .unwrap();
+            assert_eq!(got, expected, "{encoding:?}");
+        }
+    }
+}
@@ -0,0 +1,241 @@
+//! Gives up on requests that take too long, so a handler waiting on a dead client or a wedged
+//! database doesn't hold its locks, writer slot and database session forever. The handler is
+//! dropped, which releases them, and the client gets a 503 if it's still there.
+//!
+//! Chunks are allowed much longer, as long as data keeps coming in.
+
+use std::{
+    future::Future,
+    io,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    dev::{Payload, ServiceRequest, ServiceResponse},
+    http::Method,
+    HttpMessage,
+};
+use futures::{pin_mut, StreamExt};
+
+use crate::error::ApiError;
+
+/// What clients are told to wait before trying again.
+const RETRY_AFTER_SECS: u64 = 5;
+
+/// How long requests may take. Durations of zero are no limit.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Deadlines {
+    /// For everything but chunks.
+    request: Duration,
+    /// How long a chunk can go without receiving anything. Once the chunk has all come in, this
+    /// also bounds how long writing it and updating the database can take.
+    chunk_stall: Duration,
+    /// How long a chunk can take altogether.
+    chunk: Duration,
+}
+
+fn secs_from_env_or(name: &str, default: u64) -> io::Result<Duration> {
+    let secs = match std::env::var(name) {
+        Ok(v) => v
+            .parse()
+            .map_err(|e| io::Error::other(format!("{name}: {e}")))?,
+        Err(_) => default,
+    };
+    Ok(Duration::from_secs(secs))
+}
+
+impl Deadlines {
+    /// Reads `BULLSEYE_REQUEST_TIMEOUT` (30 seconds by default), `BULLSEYE_CHUNK_STALL_TIMEOUT`
+    /// (60 seconds) and `BULLSEYE_CHUNK_TIMEOUT` (an hour).
+    pub fn from_env() -> io::Result<Self> {
+        Ok(Self {
+            request: secs_from_env_or("BULLSEYE_REQUEST_TIMEOUT", 30)?,
+            chunk_stall: secs_from_env_or("BULLSEYE_CHUNK_STALL_TIMEOUT", 60)?,
+            chunk: secs_from_env_or("BULLSEYE_CHUNK_TIMEOUT", 60 * 60)?,
+        })
+    }
+
+    /// Starts the clock on a request. For requests that send a body to be stored, like chunks,
+    /// the body is watched so that the deadline can be put off while it keeps coming.
+    pub fn start(&self, req: &mut ServiceRequest) -> Deadline {
+        let started = Instant::now();
+        let limit = |d: Duration| Some(d).filter(|d| !d.is_zero());
+        if !streams_body(req) {
+            return Deadline {
+                method: req.method().clone(),
+                path: req.path().to_string(),
+                started,
+                total: limit(self.request),
+                stall: None,
+            };
+        }
+        let progress = Arc::new(AtomicU64::new(0));
+        let stall = limit(self.chunk_stall).map(|stall| {
+            let seen = progress.clone();
+            let payload = req.take_payload().inspect(move |_| {
+                seen.store(started.elapsed().as_millis() as u64, Ordering::Relaxed);
+            });
+            req.set_payload(Payload::from(payload.boxed_local()));
+            (stall, progress)
+        });
+        Deadline {
+            method: req.method().clone(),
+            path: req.path().to_string(),
+            started,
+            total: limit(self.chunk),
+            stall,
+        }
+    }
+}
+
+/// Chunks, and the benchmark sink, which takes them too.
+fn streams_body(req: &ServiceRequest) -> bool {
+    let path = req.path();
+    req.method() == Method::PUT
+        && (path == "/benchmark/sink" || (path.starts_with("/upload/") && path.ends_with("/data")))
+}
+
+/// When a request has to be done by. See `Deadlines::start`.
+pub struct Deadline {
+    /// For the log.
+    method: Method,
+    path: String,
+    started: Instant,
+    total: Option<Duration>,
+    /// How long the body can stall for, and when it last made progress, in milliseconds after
+    /// `started`.
+    stall: Option<(Duration, Arc<AtomicU64>)>,
+}
+
+impl Deadline {
+    /// When the request will be given up on, if nothing else comes in.
+    fn next(&self) -> Option<Instant> {
+        let total = self.total.map(|total| self.started + total);
+        let stall = self.stall.as_ref().map(|(stall, progress)| {
+            let last = Duration::from_millis(progress.load(Ordering::Relaxed));
+            self.started + last + *stall
+        });
+        match (total, stall) {
+            (Some(total), Some(stall)) => Some(total.min(stall)),
+            (total, stall) => total.or(stall),
+        }
+    }
+
+    /// Runs the rest of the request, and drops it if it runs out of time.
+    pub async fn enforce<F>(self, res: F) -> Result<ServiceResponse, actix_web::Error>
+    where
+        F: Future<Output = Result<ServiceResponse, actix_web::Error>>,
+    {
+        pin_mut!(res);
+        loop {
+            let Some(deadline) = self.next() else {
+                return res.await;
+            };
+            if let Ok(res) = tokio::time::timeout_at(deadline.into(), &mut res).await {
+                return res;
+            }
+            // Unless some of the body came in while we were waiting.
+            if self.next().is_none_or(|next| next <= Instant::now()) {
+                break;
+            }
+        }
+        log::warn!(
+            "giving up on {} {} after {:?}",
+            self.method,
+            self.path,
+            self.started.elapsed()
+        );
+        Err(ApiError::Unavailable {
+            retry_after_secs: RETRY_AFTER_SECS,
+            reason: "the request took too long".to_string(),
+        }
+        .into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use actix_web::{
+        dev::{Payload, Service},
+        http::StatusCode,
+        test,
+        web::{self, Bytes},
+        App, HttpResponse,
+    };
+    use futures::{stream, StreamExt};
+
+    use super::Deadlines;
+
+    async fn drain(mut body: web::Payload) -> HttpResponse {
+        while body.next().await.is_some() {}
+        HttpResponse::Created().finish()
+    }
+
+    #[actix_web::test]
+    async fn test_deadlines() {
+        let deadlines = Deadlines {
+            request: Duration::from_millis(100),
+            chunk_stall: Duration::from_millis(200),
+            chunk: Duration::from_secs(2),
+        };
+        let app = test::init_service(
+            App::new()
+                .wrap_fn(move |mut req, srv| {
+                    let deadline = deadlines.start(&mut req);
+                    deadline.enforce(srv.call(req))
+                })
+                .route("/", web::get().to(HttpResponse::Ok))
+                .route(
+                    "/slow",
+                    web::get().to(|| async {
+                        tokio::time::sleep(Duration::from_secs(10)).await;
+                        HttpResponse::Ok().finish()
+                    }),
+                )
+                .route("/upload/{id}/data", web::put().to(drain)),
+        )
+        .await;
+
+        let resp = test::call_service(&app, test::TestRequest::get().uri("/").to_request()).await;
+        assert_eq!(resp.status(), StatusCode::OK);
+        let req = test::TestRequest::get().uri("/slow").to_request();
+        let err = app.call(req).await.unwrap_err();
+        assert_eq!(
+            err.as_response_error().status_code(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+
+        // A chunk that keeps coming can take longer than other requests...
+        let trickle = stream::iter(0..5).then(|_| async {
+            tokio::time::sleep(Duration::from_millis(100)).await;
+            Ok(Bytes::from_static(b"data"))
+        });
+        let (req, _) = test::TestRequest::put()
+            .uri("/upload/x/data")
+            .to_request()
+            .replace_payload(Payload::from(trickle.boxed_local()));
+        let resp = test::call_service(&app, req).await;
+        assert_eq!(resp.status(), StatusCode::CREATED);
+
+        // ...but not stall.
+        let stalled = stream::once(async {
+            tokio::time::sleep(Duration::from_secs(1)).await;
+            Ok(Bytes::from_static(b"data"))
+        });
+        let (req, _) = test::TestRequest::put()
+            .uri("/upload/x/data")
+            .to_request()
+            .replace_payload(Payload::from(stalled.boxed_local()));
+        let err = app.call(req).await.unwrap_err();
+        assert_eq!(
+            err.as_response_error().status_code(),
+            StatusCode::SERVICE_UNAVAILABLE
+        );
+    }
+}
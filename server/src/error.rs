@@ -0,0 +1,212 @@
+//! The error handlers return. Each kind of failure gets a fitting status code and the same JSON
+//! body clients already understand (an `ErrorablePayload` that isn't `Ok`, with a code from
+//! `common::errors` alongside), and is logged, so handlers can just use `?`.
+
+use std::{fmt, io};
+
+use actix_web::{
+    http::{header::RETRY_AFTER, StatusCode},
+    HttpResponse, ResponseError,
+};
+use common::{
+    db::DbError,
+    errors::{ErrorCode, ErrorResponse},
+};
+
+use crate::payloads::*;
+
+pub type ApiResult = Result<HttpResponse, ApiError>;
+
+/// How long to tell uploaders who are over one of their limits to wait before trying again, in
+/// seconds. See `Rejection::is_temporary`.
+const LIMIT_RETRY_AFTER: u64 = 60;
+
+#[derive(Debug)]
+pub enum ApiError {
+    Db(DbError),
+    /// A file operation failed. The client is only told what was being done; the error itself
+    /// goes in the log.
+    Io(&'static str, io::Error),
+    /// The request isn't allowed. Retrying it won't help.
+    Rejected(Rejection),
+    /// The request doesn't make sense.
+    Invalid(String),
+    /// The upload isn't in a state where this can be done.
+    Conflict(String),
+    /// Something the server should have been able to do failed.
+    Internal(String),
+    NotFound,
+    /// The request needs the admin token.
+    Unauthorized,
+    /// The server can't handle the request right now, but should be able to after waiting.
+    Unavailable {
+        retry_after_secs: u64,
+        reason: String,
+    },
+}
+
+impl ApiError {
+    /// What the client is sent.
+    fn payload(&self) -> ErrorablePayload<()> {
+        match self {
+            Self::Db(e) => e.clone().into(),
+            Self::Io(doing, _) => ErrorablePayload::Err(format!("I/O error while {doing}")),
+            Self::Rejected(rejection) => ErrorablePayload::Rejected(rejection.clone()),
+            Self::Invalid(message) | Self::Conflict(message) | Self::Internal(message) => {
+                ErrorablePayload::Err(message.clone())
+            }
+            Self::NotFound => ErrorablePayload::NotFound,
+            Self::Unauthorized => ErrorablePayload::Err("The admin token is required".to_string()),
+            Self::Unavailable {
+                retry_after_secs,
+                reason,
+            } => ErrorablePayload::Unavailable {
+                retry_after_secs: *retry_after_secs,
+                reason: reason.clone(),
+            },
+        }
+    }
+
+    /// The code for the catalogue in `common::errors`.
+    fn code(&self) -> ErrorCode {
+        match self {
+            Self::Db(e) => e.into(),
+            Self::Io(..) => ErrorCode::Io,
+            Self::Rejected(rejection) => rejection.into(),
+            Self::Invalid(_) => ErrorCode::Invalid,
+            Self::Conflict(_) => ErrorCode::Conflict,
+            Self::Internal(_) => ErrorCode::Internal,
+            Self::NotFound => ErrorCode::NotFound,
+            Self::Unauthorized => ErrorCode::Unauthorized,
+            Self::Unavailable { .. } => ErrorCode::Unavailable,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Db(e) => write!(f, "{e}"),
+            Self::Io(doing, e) => write!(f, "I/O error while {doing}: {e}"),
+            Self::Rejected(rejection) => write!(f, "rejected: {rejection}"),
+            Self::Invalid(message) | Self::Conflict(message) | Self::Internal(message) => {
+                write!(f, "{message}")
+            }
+            Self::NotFound => write!(f, "not found"),
+            Self::Unauthorized => write!(f, "missing or wrong admin token"),
+            Self::Unavailable { reason, .. } => write!(f, "unavailable: {reason}"),
+        }
+    }
+}
+
+impl ResponseError for ApiError {
+    fn status_code(&self) -> StatusCode {
+        match self {
+            Self::Db(DbError::NotFound) | Self::NotFound => StatusCode::NOT_FOUND,
+            Self::Db(DbError::WrongStatus) | Self::Conflict(_) => StatusCode::CONFLICT,
+            Self::Db(_) | Self::Io(..) | Self::Internal(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::Rejected(rejection) => rejection_status(rejection),
+            Self::Invalid(_) => StatusCode::BAD_REQUEST,
+            Self::Unauthorized => StatusCode::UNAUTHORIZED,
+            Self::Unavailable { .. } => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+
+    fn error_response(&self) -> HttpResponse {
+        match self {
+            Self::Db(_) | Self::Io(..) | Self::Internal(_) => {
+                log::error!("{self} ({})", self.code())
+            }
+            _ => log::debug!("{self} ({})", self.code()),
+        }
+        let mut resp = HttpResponse::build(self.status_code());
+        match self {
+            Self::Unavailable {
+                retry_after_secs, ..
+            } => {
+                resp.insert_header((RETRY_AFTER, *retry_after_secs));
+            }
+            Self::Rejected(rejection) if rejection.is_temporary() => {
+                resp.insert_header((RETRY_AFTER, LIMIT_RETRY_AFTER));
+            }
+            _ => {}
+        }
+        resp.json(ErrorResponse::new(self.payload(), self.code()))
+    }
+}
+
+fn rejection_status(rejection: &Rejection) -> StatusCode {
+    match rejection {
+        Rejection::FileTooLarge { .. } => StatusCode::PAYLOAD_TOO_LARGE,
+        Rejection::BadSignature | Rejection::Banned { .. } => StatusCode::FORBIDDEN,
+        Rejection::TooManyActiveUploads { .. } | Rejection::DailyQuotaExceeded { .. } => {
+            StatusCode::TOO_MANY_REQUESTS
+        }
+        Rejection::DuplicateUpload { .. } | Rejection::AlreadyReceived => StatusCode::CONFLICT,
+        _ => StatusCode::BAD_REQUEST,
+    }
+}
+
+impl From<DbError> for ApiError {
+    fn from(e: DbError) -> Self {
+        Self::Db(e)
+    }
+}
+
+impl From<Rejection> for ApiError {
+    fn from(rejection: Rejection) -> Self {
+        Self::Rejected(rejection)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, ResponseError};
+    use common::db::DbError;
+
+    use super::ApiError;
+    use crate::payloads::*;
+
+    /// Ensures that errors are still sent the way clients expect.
+    #[actix_web::test]
+    async fn test_error_response() {
+        let resp = ApiError::Db(DbError::NotFound).error_response();
+        assert_eq!(resp.status(), StatusCode::NOT_FOUND);
+        let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+        assert!(matches!(
+            serde_json::from_slice(&body).unwrap(),
+            ErrorablePayload::<()>::NotFound
+        ));
+
+        let resp = ApiError::Unavailable {
+            retry_after_secs: 3,
+            reason: "busy".to_string(),
+        }
+        .error_response();
+        assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(resp.headers().get("Retry-After").unwrap(), "3");
+
+        let resp = ApiError::from(Rejection::TooManyActiveUploads { max: 1 }).error_response();
+        assert_eq!(resp.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(resp.headers().get("Retry-After").unwrap(), "60");
+
+        let resp = ApiError::from(Rejection::BadSignature).error_response();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+        assert!(resp.headers().get("Retry-After").is_none());
+        let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+        assert!(matches!(
+            serde_json::from_slice(&body).unwrap(),
+            ErrorablePayload::<()>::Rejected(Rejection::BadSignature)
+        ));
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(body["code"], "bad_signature");
+        assert!(body["docs"].as_str().unwrap().ends_with("#bad_signature"));
+
+        let io = std::io::Error::other("disk on fire");
+        let resp = ApiError::Io("writing the chunk", io).error_response();
+        assert_eq!(resp.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        let body = actix_web::body::to_bytes(resp.into_body()).await.unwrap();
+        // The details stay in the log.
+        assert!(!String::from_utf8_lossy(&body).contains("fire"));
+    }
+}
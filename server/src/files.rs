@@ -1,56 +1,287 @@
-use futures_util::StreamExt as _;
-use nix::{sys::statvfs::statvfs, fcntl::posix_fallocate};
+use futures_util::{Stream, StreamExt as _};
 use std::{
-    io,
-    os::fd::{AsFd, AsRawFd},
-    path::PathBuf,
+    io::{self, Read, Seek, Write},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
 };
 
 use actix_web::web;
+use common::{
+    crypt::{DataFile, DataKey, FileKey},
+    data::derived_name,
+    merkle::{self, Hasher, MerkleTree},
+    platform,
+};
 use tokio::{
-    fs::{remove_file, File},
+    fs::{create_dir_all, metadata, remove_file, rename, File},
     io::{AsyncSeekExt, AsyncWriteExt},
     task::spawn_blocking,
+    time::timeout,
 };
 
 pub const DATA_DIR: &str = "data";
 
-async fn acquire_lock(file: &mut File, exclusive: bool) -> io::Result<()> {
-    let fd = file.as_raw_fd();
-    spawn_blocking(move || common::acquire_lock(fd, exclusive)).await?
+/// Where new uploads go inside the data directory, from `BULLSEYE_DATA_LAYOUT`. `{project}` and
+/// `{pipeline}` are replaced with the upload's; an empty template puts everything directly in the
+/// data directory. Each upload's directory is recorded on its row, so changing this only affects
+/// new uploads.
+#[derive(Debug)]
+pub struct Layout {
+    template: String,
 }
 
+impl Layout {
+    pub const DEFAULT_TEMPLATE: &'static str = "{project}";
+
+    pub fn from_env() -> io::Result<Self> {
+        let template = std::env::var("BULLSEYE_DATA_LAYOUT")
+            .unwrap_or_else(|_| Self::DEFAULT_TEMPLATE.to_string());
+        Self::new(template)
+    }
+
+    pub fn new(template: String) -> io::Result<Self> {
+        if Path::new(&template).is_absolute() || template.split('/').any(|c| c == "..") {
+            return Err(io::Error::other(format!(
+                "BULLSEYE_DATA_LAYOUT must stay inside the data directory: {template}"
+            )));
+        }
+        Ok(Self { template })
+    }
+
+    /// Gets the directory for a new upload.
+    pub fn dir(&self, root: &Path, project: &str, pipeline: &str) -> PathBuf {
+        let mut dir = root.to_path_buf();
+        for component in self.template.split('/').filter(|c| !c.is_empty()) {
+            let component = component
+                .replace("{project}", &path_safe(project))
+                .replace("{pipeline}", &path_safe(pipeline));
+            dir.push(component);
+        }
+        dir
+    }
+}
+
+/// Makes a name usable as a single path component. Names come from clients when no projects are
+/// configured, so they can't be trusted.
+fn path_safe(name: &str) -> String {
+    let safe: String = name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect();
+    match safe.as_str() {
+        "" | "." | ".." => "_".to_string(),
+        _ => safe,
+    }
+}
+
+/// Uploads live at `<id>.part` until they are finished, so anything looking at the data
+/// directory can tell complete files from in-progress ones.
+pub fn part_name(id: &str) -> String {
+    format!("{id}.part")
+}
+
+/// Opens a file and locks it, without waiting for the lock: if somebody else has it, the client
+/// can retry.
+async fn open_locked(path: PathBuf, write: bool, exclusive: bool) -> io::Result<File> {
+    spawn_blocking(move || {
+        let file = std::fs::File::options().read(true).write(write).open(path)?;
+        platform::acquire_lock(&file, exclusive)?;
+        Ok(File::from_std(file))
+    })
+    .await?
+}
+
+/// Opens an in-progress upload for writing.
+///
+/// This only takes a shared lock, which keeps /finish out while writes are happening. Writes to
+/// overlapping ranges are kept apart by RangeLocks instead.
 async fn get_file(path: &str) -> io::Result<File> {
-    let mut f = File::options()
-        .read(true)
-        .write(true)
-        .open(path)
-        .await?;
-    acquire_lock(&mut f, false).await?;
-    Ok(f)
+    open_locked(path.into(), true, false).await
 }
 
+/// Opens an in-progress upload with an exclusive lock, so nothing is writing to it. It's opened
+/// for writing too, so that `release_unwritten` can be used on it.
 pub async fn exclusive_lock(mut path: PathBuf, id: &str) -> io::Result<File> {
-    path.push(id);
-    let mut f = File::open(&path).await?;
-    acquire_lock(&mut f, true).await?;
-    Ok(f)
+    path.push(part_name(id));
+    open_locked(path, true, true).await
 }
 
-pub async fn new_file(mut path: PathBuf, id: &str, with_size: u64) -> io::Result<()> {
-    let with_size: i64 = match with_size.try_into() {
-        Ok(s) => s,
-        Err(_) => return Err(io::Error::other("File too large")),
-    };
-    path.push(id);
-    let file = File::create_new(&path).await?;
-    let fd = file.as_fd().as_raw_fd();
+/// Frees the space that was reserved for the parts of an abandoned upload that never came in, so
+/// it doesn't sit there until the upload is purged. `file` is the upload's part file, from
+/// `exclusive_lock`, and `missing` are the parts that weren't written. What did come in is kept, in
+/// case the upload is restored.
+///
+/// Where holes can't be punched, only a missing part at the end is freed, by cutting the file
+/// short. It grows back as the rest is sent. Returns how many bytes were freed.
+pub async fn release_unwritten(file: &File, size: u64, missing: Vec<(u64, u64)>) -> io::Result<u64> {
+    let file = file.try_clone().await?.into_std().await;
+    spawn_blocking(move || {
+        let before = platform::allocated_bytes(&file.metadata()?);
+        for &(start, end) in &missing {
+            match platform::punch_hole(&file, start, end - start) {
+                Ok(()) => {}
+                Err(e) if e.kind() == io::ErrorKind::Unsupported => {
+                    if let Some(&(start, _)) = missing.last().filter(|(_, end)| *end >= size) {
+                        file.set_len(start)?;
+                    }
+                    break;
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        let after = platform::allocated_bytes(&file.metadata()?);
+        Ok(before.saturating_sub(after))
+    })
+    .await?
+}
+
+/// What to do when the filesystem doesn't support `posix_fallocate`, like ZFS, or the OS doesn't,
+/// like macOS and Windows. From `BULLSEYE_ALLOCATION_FALLBACK`.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Fallback {
+    /// Fail the upload.
+    None,
+    /// Set the file's size with ftruncate. This is quick, but doesn't reserve the space, so the
+    /// disk can fill up partway through an upload.
+    #[default]
+    Truncate,
+    /// Write zeroes over the whole file. This takes as long as writing the file, but the space is
+    /// claimed up front, as far as the filesystem allows.
+    Zero,
+}
+
+impl Fallback {
+    pub fn from_env() -> io::Result<Self> {
+        match std::env::var("BULLSEYE_ALLOCATION_FALLBACK") {
+            Ok(value) => Self::parse(&value),
+            Err(_) => Ok(Self::default()),
+        }
+    }
+
+    fn parse(value: &str) -> io::Result<Self> {
+        match value {
+            "" | "truncate" => Ok(Self::Truncate),
+            "zero" => Ok(Self::Zero),
+            "none" => Ok(Self::None),
+            _ => Err(io::Error::other(format!(
+                "BULLSEYE_ALLOCATION_FALLBACK must be truncate, zero or none, not {value}"
+            ))),
+        }
+    }
+}
+
+/// The slowest a chunk's body can come in, from `BULLSEYE_MIN_CHUNK_RATE` (in bytes per second)
+/// and `BULLSEYE_SLOW_CLIENT_WINDOW` (in seconds). Below this for a whole window, the chunk is given
+/// up on, so that a dying client, or one doing it on purpose, doesn't hold the chunk's lock and a
+/// writer slot for as long as it likes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct MinRate {
+    bytes_per_sec: u64,
+    window: Duration,
+}
+
+impl MinRate {
+    /// 1 KiB/s over 30 seconds by default. None if `BULLSEYE_MIN_CHUNK_RATE` is 0.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        let var = |name: &str, default: u64| match std::env::var(name) {
+            Ok(v) => v.parse().map_err(|e| io::Error::other(format!("{name}: {e}"))),
+            Err(_) => Ok(default),
+        };
+        let bytes_per_sec = var("BULLSEYE_MIN_CHUNK_RATE", 1024)?;
+        let window = Duration::from_secs(var("BULLSEYE_SLOW_CLIENT_WINDOW", 30)?.max(1));
+        Ok(Some(Self { bytes_per_sec, window }).filter(|rate| rate.bytes_per_sec > 0))
+    }
+}
+
+/// How fast the current window of a chunk's body has come in. Only time spent waiting for the
+/// client counts, not writing to the disk.
+struct RateWindow {
+    min: MinRate,
+    waited: Duration,
+    received: u64,
+}
+
+impl RateWindow {
+    fn new(min: MinRate) -> Self {
+        Self { min, waited: Duration::ZERO, received: 0 }
+    }
+
+    /// How much longer to wait for the client before the window is over.
+    fn left(&self) -> Duration {
+        self.min.window.saturating_sub(self.waited)
+    }
+
+    /// Counts `received` bytes that took `waited` to come in, and fails with `TimedOut` if the
+    /// window is over and they came in too slowly.
+    fn record(&mut self, waited: Duration, received: u64) -> io::Result<()> {
+        self.waited += waited;
+        self.received += received;
+        if self.waited < self.min.window {
+            return Ok(());
+        }
+        let secs = self.waited.as_secs_f64();
+        if (self.received as f64) < self.min.bytes_per_sec as f64 * secs {
+            return Err(io::Error::new(io::ErrorKind::TimedOut, format!(
+                "the client sent {} bytes in {secs:.0} seconds, slower than {} bytes per second",
+                self.received, self.min.bytes_per_sec
+            )));
+        }
+        self.waited = Duration::ZERO;
+        self.received = 0;
+        Ok(())
+    }
+}
+
+/// Writes zeroes over the first `size` bytes of a file.
+fn write_zeroes(mut file: &std::fs::File, size: u64) -> io::Result<()> {
+    let block = vec![0; 1024 * 1024];
+    let mut left = size;
+    while left > 0 {
+        let n = left.min(block.len() as u64) as usize;
+        file.write_all(&block[..n])?;
+        left -= n as u64;
+    }
+    file.sync_data()
+}
+
+/// Makes the file `size` bytes long, reserving the space if the filesystem can. This blocks.
+fn allocate(file: &std::fs::File, size: u64, fallback: Fallback) -> io::Result<()> {
+    match (platform::allocate(file, size), fallback) {
+        (Ok(()), _) => Ok(()),
+        (Err(e), Fallback::Truncate) if e.kind() == io::ErrorKind::Unsupported => file.set_len(size),
+        (Err(e), Fallback::Zero) if e.kind() == io::ErrorKind::Unsupported => write_zeroes(file, size),
+        (Err(e), _) => Err(e),
+    }
+}
+
+/// Checks whether `posix_fallocate` works in a directory, by trying it on a scratch file.
+pub fn supports_fallocate(dir: &Path) -> io::Result<bool> {
+    std::fs::create_dir_all(dir)?;
+    let path = dir.join(".fallocate-probe");
+    let file = std::fs::File::create(&path)?;
+    let result = platform::allocate(&file, 4096);
+    drop(file);
+    std::fs::remove_file(&path)?;
+    match result {
+        Ok(()) => Ok(true),
+        Err(e) if e.kind() == io::ErrorKind::Unsupported => Ok(false),
+        Err(e) => Err(e),
+    }
+}
+
+pub async fn new_file(mut path: PathBuf, id: &str, with_size: u64, fallback: Fallback) -> io::Result<()> {
+    create_dir_all(&path).await?;
+    path.push(part_name(id));
+    let file = File::create_new(&path).await?.into_std().await;
     if with_size > 0 {
-        match spawn_blocking(move || posix_fallocate(fd, 0, with_size)).await? {
+        match spawn_blocking(move || allocate(&file, with_size, fallback)).await? {
             Ok(()) => io::Result::Ok(()),
             Err(e) => {
                 remove_file(path).await?;
-                io::Result::Err(io::Error::other(format!("{e}")))
+                io::Result::Err(e)
             }
         }
     } else {
@@ -60,55 +291,181 @@ pub async fn new_file(mut path: PathBuf, id: &str, with_size: u64) -> io::Result
 }
 
 pub async fn delete_file(mut path: PathBuf, id: &str) -> io::Result<()> {
-    path.push(id);
+    path.push(part_name(id));
     remove_file(path).await?;
     Ok(())
 }
 
+/// Deletes an upload's data, whether or not it was promoted, and its Merkle tree. Missing files
+/// are ignored.
+pub async fn delete_data(dir: PathBuf, id: &str) -> io::Result<()> {
+    for path in [dir.join(part_name(id)), dir.join(id), dir.join(derived_name(id)), merkle::tree_path(&dir, id)] {
+        match remove_file(path).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+pub async fn store_merkle_tree(dir: PathBuf, id: &str, tree: MerkleTree) -> io::Result<()> {
+    let id = id.to_string();
+    spawn_blocking(move || merkle::write(dir, &id, &tree)).await?
+}
+
+/// Gets a finished upload's Merkle tree. If it wasn't stored when the upload came in, it's built
+/// from the data now, which reads the whole file, and stored for next time. `key` is the upload's
+/// data key, if it's encrypted.
+pub async fn merkle_tree(dir: PathBuf, id: &str, key: Option<DataKey>) -> io::Result<MerkleTree> {
+    let id = id.to_string();
+    spawn_blocking(move || {
+        match merkle::read(&dir, &id) {
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            res => return res,
+        }
+        let file = DataFile::new(dir.join(&id), key, false).open()?;
+        // Shared, so this waits for the upload to be promoted if that's still happening.
+        platform::wait_for_lock(file.get_ref(), false)?;
+        let (_, tree) = merkle::hash_file(io::BufReader::with_capacity(1024 * 1024, file))?;
+        merkle::write(&dir, &id, &tree)?;
+        Ok(tree)
+    })
+    .await?
+}
+
+/// Reads `len` bytes from `start` in an encrypted upload's file and decrypts them, to send to a
+/// client. The file is read a piece at a time on the blocking pool.
+pub fn decrypted(data: DataFile, start: u64, len: u64) -> impl Stream<Item = io::Result<web::Bytes>> {
+    async_stream::try_stream! {
+        let mut reader = spawn_blocking(move || {
+            let mut reader = data.open()?;
+            reader.seek(io::SeekFrom::Start(start))?;
+            io::Result::Ok(reader)
+        })
+        .await??;
+        let mut left = len;
+        while left > 0 {
+            let want = left.min(1024 * 1024) as usize;
+            let (r, piece) = spawn_blocking(move || {
+                let mut piece = vec![0; want];
+                let n = reader.read(&mut piece)?;
+                piece.truncate(n);
+                io::Result::Ok((reader, piece))
+            })
+            .await??;
+            reader = r;
+            if piece.is_empty() {
+                Err(io::Error::new(io::ErrorKind::UnexpectedEof, "the file is shorter than it should be"))?;
+            }
+            left -= piece.len() as u64;
+            yield web::Bytes::from(piece);
+        }
+    }
+}
+
+/// Checks that an in-progress upload is complete and renames it to its final name.
+///
+/// Returns the file with an exclusive lock held, so that the caller can update the database
+/// before anyone else touches it. If the file was already promoted (e.g. the client retried
+/// `/finish`), the final file is locked and returned instead.
+pub async fn promote(dir: PathBuf, id: &str, size: u64) -> io::Result<File> {
+    let part = dir.join(part_name(id));
+    let dest = dir.join(id);
+    let f = match open_locked(part.clone(), false, true).await {
+        Ok(f) => f,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            return open_locked(dest, false, true).await;
+        }
+        Err(e) => return Err(e),
+    };
+    let len = metadata(&part).await?.len();
+    if len != size {
+        return Err(io::Error::other(format!("File is {len} bytes, expected {size}")));
+    }
+    rename(&part, &dest).await?;
+    Ok(f)
+}
+
+/// Writes a chunk to an in-progress upload, hashing it with `hasher` too if one is given, and
+/// encrypting it with `key` if the upload is encrypted. If the body comes in slower than
+/// `min_rate`, this fails with `TimedOut`.
+#[allow(clippy::too_many_arguments)]
 pub async fn write_to_file(
     mut dir: PathBuf,
     id: &str,
     size: u64,
     offset: u64,
     mut body: web::Payload,
-) -> io::Result<()> {
-    dir.push(id);
+    mut hasher: Option<&mut Hasher>,
+    key: Option<FileKey>,
+    min_rate: Option<MinRate>,
+) -> io::Result<u64> {
+    dir.push(part_name(id));
     let mut file = get_file(dir.to_str().unwrap()).await?;
     file.seek(io::SeekFrom::Start(offset)).await?;
-    while let Some(chunk) = body.next().await {
-        if let Ok(chunk) = chunk {
-            if offset + chunk.len() as u64 > size {
-                return io::Result::Err(io::Error::other("Exceeded file bounds"));
+    let mut written = 0;
+    let mut rate = min_rate.map(RateWindow::new);
+    loop {
+        let started = Instant::now();
+        let chunk = match &mut rate {
+            // Nothing at all for the rest of the window is too slow too.
+            Some(rate) => timeout(rate.left(), body.next()).await.unwrap_or(Some(Ok(web::Bytes::new()))),
+            None => body.next().await,
+        };
+        let Some(chunk) = chunk else {
+            break;
+        };
+        let chunk = match chunk {
+            Ok(chunk) => chunk,
+            // The ApiError this becomes logs it.
+            Err(e) => return io::Result::Err(io::Error::other(format!("Chunk read failed: {e}"))),
+        };
+        if let Some(rate) = &mut rate {
+            rate.record(started.elapsed(), chunk.len() as u64)?;
+        }
+        if chunk.is_empty() {
+            continue;
+        }
+        if offset + written + chunk.len() as u64 > size {
+            return io::Result::Err(io::Error::other("Exceeded file bounds"));
+        }
+        match &key {
+            Some(key) => {
+                let mut sealed = chunk.to_vec();
+                key.apply(offset + written, &mut sealed);
+                file.write_all(&sealed).await?;
             }
-            file.write_all(&chunk).await?;
-            file.flush().await?;
-            file.sync_all().await?;
-        } else {
-            dbg!(chunk.unwrap_err());
-            return io::Result::Err(io::Error::other("Chunk read failed"));
+            None => file.write_all(&chunk).await?,
+        }
+        file.flush().await?;
+        file.sync_all().await?;
+        if let Some(hasher) = hasher.as_deref_mut() {
+            hasher.update(&chunk);
         }
+        written += chunk.len() as u64;
     }
-    io::Result::Ok(())
+    io::Result::Ok(written)
 }
 
 // TODO: Tests are run in parallel, so how do I test this?
 // Other tests may have started when we check free space.
 async fn get_free_space(path: PathBuf) -> io::Result<u64> {
-    let stats = spawn_blocking(move || statvfs(&path)).await??;
-    let fragment_size = stats.fragment_size();
-    let available_blocks = stats.blocks_available();
-    Ok(fragment_size * available_blocks)
+    let space = spawn_blocking(move || platform::disk_space(&path)).await??;
+    Ok(space.free_bytes)
 }
 
 #[cfg(test)]
 mod tests {
-    use std::{mem, path::PathBuf};
+    use std::{mem, path::PathBuf, time::Duration};
 
-    use actix_web::{test::{self, TestRequest}, App};
-    use tokio::fs::{self, File, OpenOptions};
+    use actix_web::{dev, test::TestRequest, web, FromRequest};
+    use futures_util::StreamExt;
+    use tokio::fs;
 
-    use crate::files::{self, new_file};
-    use super::{get_free_space, DATA_DIR};
+    use common::crypt::{DataFile, MasterKey};
+
+    use crate::files::{self, new_file, part_name};
+    use super::{decrypted, get_free_space, release_unwritten, supports_fallocate, write_to_file, write_zeroes, Fallback, Layout, MinRate, DATA_DIR};
 
     /// Ensures that file creation and deletion works as expected.
     #[actix_web::test]
@@ -116,9 +473,9 @@ mod tests {
         const NAME: &str = "Unit-test-NewFile";
         let mut dir = std::env::current_dir().unwrap();
         dir.push(DATA_DIR);
-        files::new_file(dir.clone(), NAME, 20).await.unwrap();
+        files::new_file(dir.clone(), NAME, 20, Fallback::default()).await.unwrap();
         let mut file = dir.clone();
-        file.push(NAME);
+        file.push(part_name(NAME));
         let m = fs::metadata(file.clone()).await.unwrap();
         assert_eq!(m.len(), 20);
         files::delete_file(dir, NAME).await.unwrap();
@@ -132,26 +489,23 @@ mod tests {
         let mut dir = std::env::current_dir().unwrap();
         dir.push(DATA_DIR);
         let mut path = dir.clone();
-        path.push(NAME);
-        let mut file = OpenOptions::new().create(true).write(true).open(&path).await.unwrap();
-        let mut file2 = File::open(&path).await.unwrap();
-        let mut file3 = File::open(&path).await.unwrap();
-        let mut file4 = File::open(&path).await.unwrap();
+        path.push(part_name(NAME));
+        std::fs::File::create(&path).unwrap();
         // Shared lock. Succeeds.
-        files::acquire_lock(&mut file, false).await.unwrap();
+        let file = files::open_locked(path.clone(), false, false).await.unwrap();
         // Exclusive lock. Fails due to the preexisting shared lock.
-        files::acquire_lock(&mut file2, true).await.unwrap_err();
+        files::open_locked(path.clone(), false, true).await.unwrap_err();
         // Shared lock. Succeeds because the only other lock is shared.
-        files::acquire_lock(&mut file3, false).await.unwrap();
+        let file3 = files::open_locked(path.clone(), false, false).await.unwrap();
         // Exclusive lock. Fails due to the preexisting shared lock.
         files::exclusive_lock(dir, NAME).await.unwrap_err();
         // Close shared locks
         mem::drop(file);
         mem::drop(file3);
         // Exclusive lock. Succeeds; other locks have been closed.
-        files::acquire_lock(&mut file2, true).await.unwrap();
+        let _file2 = files::open_locked(path.clone(), false, true).await.unwrap();
         // Shared lock. Fails due to exclusive lock.
-        files::acquire_lock(&mut file4, false).await.unwrap_err();
+        files::open_locked(path, false, false).await.unwrap_err();
     }
 
     /// Ensures that new_file does not overwrite existing files.
@@ -160,9 +514,9 @@ mod tests {
         const NAME: &str = "Unit-test-Exclusivity";
         let mut dir = std::env::current_dir().unwrap();
         dir.push(DATA_DIR);
-        new_file(dir.clone(), NAME, 20).await.unwrap();
-        new_file(dir.clone(), NAME, 25).await.unwrap_err();
-        dir.push(NAME);
+        new_file(dir.clone(), NAME, 20, Fallback::default()).await.unwrap();
+        new_file(dir.clone(), NAME, 25, Fallback::default()).await.unwrap_err();
+        dir.push(part_name(NAME));
         assert_eq!(fs::metadata(dir.clone()).await.unwrap().len(), 20);
         fs::remove_file(dir).await.unwrap();
     }
@@ -173,12 +527,140 @@ mod tests {
         const NAME: &str = "Unit-test-ZeroSize";
         let mut dir = std::env::current_dir().unwrap();
         dir.push(DATA_DIR);
-        new_file(dir.clone(), NAME, 0).await.unwrap();
-        dir.push(NAME);
+        new_file(dir.clone(), NAME, 0, Fallback::default()).await.unwrap();
+        dir.push(part_name(NAME));
         assert_eq!(fs::metadata(dir.clone()).await.unwrap().len(), 0);
         fs::remove_file(dir).await.unwrap();
     }
 
+    /// Ensures that the allocation fallback is read correctly, and that zeroing fills the file.
+    #[test]
+    fn test_fallback() {
+        assert_eq!(Fallback::parse("").unwrap(), Fallback::Truncate);
+        assert_eq!(Fallback::parse("zero").unwrap(), Fallback::Zero);
+        assert_eq!(Fallback::parse("none").unwrap(), Fallback::None);
+        Fallback::parse("sparse").unwrap_err();
+
+        let mut dir = std::env::current_dir().unwrap();
+        dir.push(DATA_DIR);
+        supports_fallocate(&dir).unwrap();
+        assert!(!dir.join(".fallocate-probe").exists());
+        let path = dir.join("Unit-test-Zeroes");
+        let file = std::fs::File::create(&path).unwrap();
+        write_zeroes(&file, 3 * 1024 * 1024 + 5).unwrap();
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(data.len(), 3 * 1024 * 1024 + 5);
+        assert!(data.iter().all(|b| *b == 0));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    /// Ensures that promotion renames the file and can be repeated.
+    #[actix_web::test]
+    async fn test_promote() {
+        const NAME: &str = "Unit-test-Promote";
+        let mut dir = std::env::current_dir().unwrap();
+        dir.push(DATA_DIR);
+        new_file(dir.clone(), NAME, 20, Fallback::default()).await.unwrap();
+        // Wrong size
+        files::promote(dir.clone(), NAME, 21).await.unwrap_err();
+        let lock = files::promote(dir.clone(), NAME, 20).await.unwrap();
+        // The lock is still held.
+        files::promote(dir.clone(), NAME, 20).await.unwrap_err();
+        drop(lock);
+        files::promote(dir.clone(), NAME, 20).await.unwrap();
+        fs::metadata(dir.join(part_name(NAME))).await.unwrap_err();
+        fs::remove_file(dir.join(NAME)).await.unwrap();
+    }
+
+    /// Ensures that uploads are put where the layout says, and can't escape the data directory.
+    #[test]
+    fn test_layout() {
+        let root = PathBuf::from("/data");
+        let layout = Layout::new(Layout::DEFAULT_TEMPLATE.to_string()).unwrap();
+        assert_eq!(layout.dir(&root, "urls", "warc"), PathBuf::from("/data/urls"));
+        assert_eq!(layout.dir(&root, "../etc", "warc"), PathBuf::from("/data/.._etc"));
+        assert_eq!(layout.dir(&root, "..", "warc"), PathBuf::from("/data/_"));
+        let layout = Layout::new("by-project/{project}/{pipeline}".to_string()).unwrap();
+        assert_eq!(
+            layout.dir(&root, "urls", "warc"),
+            PathBuf::from("/data/by-project/urls/warc")
+        );
+        assert_eq!(Layout::new(String::new()).unwrap().dir(&root, "urls", "warc"), root);
+        Layout::new("/elsewhere".to_string()).unwrap_err();
+        Layout::new("{project}/../..".to_string()).unwrap_err();
+    }
+
+    /// Ensures that chunks that come in too slowly are given up on.
+    #[actix_web::test]
+    async fn test_slow_client() {
+        const NAME: &str = "Unit-test-SlowClient";
+        let mut dir = std::env::current_dir().unwrap();
+        dir.push(DATA_DIR);
+        new_file(dir.clone(), NAME, 8, Fallback::default()).await.unwrap();
+        let body = |delay: Duration| async move {
+            let stream = futures_util::stream::iter([&b"abcd"[..], b"efgh"]).then(move |part| async move {
+                tokio::time::sleep(delay).await;
+                Ok(web::Bytes::from_static(part))
+            });
+            let (req, _) = TestRequest::default().to_http_parts();
+            let mut payload = dev::Payload::from(stream.boxed_local());
+            web::Payload::from_request(&req, &mut payload).await.unwrap()
+        };
+        // At least 100 bytes a second, measured over 200 ms.
+        let rate = Some(MinRate { bytes_per_sec: 100, window: Duration::from_millis(200) });
+        let fast = body(Duration::from_millis(10)).await;
+        assert_eq!(write_to_file(dir.clone(), NAME, 8, 0, fast, None, None, rate).await.unwrap(), 8);
+        let slow = body(Duration::from_millis(150)).await;
+        let e = write_to_file(dir.clone(), NAME, 8, 0, slow, None, None, rate).await.unwrap_err();
+        assert_eq!(e.kind(), std::io::ErrorKind::TimedOut);
+        let slow = body(Duration::from_millis(150)).await;
+        assert_eq!(write_to_file(dir.clone(), NAME, 8, 0, slow, None, None, None).await.unwrap(), 8);
+        files::delete_file(dir, NAME).await.unwrap();
+    }
+
+    /// Ensures that abandoned uploads give back the space for what wasn't sent, and keep the rest.
+    #[actix_web::test]
+    async fn test_release_unwritten() {
+        const NAME: &str = "Unit-test-ReleaseUnwritten";
+        let mut dir = std::env::current_dir().unwrap();
+        dir.push(DATA_DIR);
+        new_file(dir.clone(), NAME, 3 * 4096, Fallback::default()).await.unwrap();
+        let data = vec![7; 4096];
+        let (req, mut payload) = TestRequest::default().set_payload(data.clone()).to_http_parts();
+        let body = web::Payload::from_request(&req, &mut payload).await.unwrap();
+        write_to_file(dir.clone(), NAME, 3 * 4096, 0, body, None, None, None).await.unwrap();
+        let lock = files::exclusive_lock(dir.clone(), NAME).await.unwrap();
+        release_unwritten(&lock, 3 * 4096, vec![(4096, 3 * 4096)]).await.unwrap();
+        drop(lock);
+        let path = dir.join(part_name(NAME));
+        let contents = fs::read(&path).await.unwrap();
+        assert_eq!(&contents[..4096], &data[..]);
+        assert!(contents.len() == 4096 || contents[4096..].iter().all(|b| *b == 0));
+        files::delete_file(dir, NAME).await.unwrap();
+    }
+
+    /// Ensures that encrypted uploads can be written in any order and read back.
+    #[actix_web::test]
+    async fn test_encrypted() {
+        const NAME: &str = "Unit-test-Encrypted";
+        let mut dir = std::env::current_dir().unwrap();
+        dir.push(DATA_DIR);
+        let (key, _) = MasterKey::from_hex(&"ab".repeat(32)).unwrap().new_key(NAME).unwrap();
+        let data: Vec<u8> = (0..3000).map(|i| (i % 251) as u8).collect();
+        new_file(dir.clone(), NAME, 3000, Fallback::default()).await.unwrap();
+        for (start, end) in [(1000, 3000), (0, 1000)] {
+            let (req, mut payload) = TestRequest::default().set_payload(data[start..end].to_vec()).to_http_parts();
+            let body = web::Payload::from_request(&req, &mut payload).await.unwrap();
+            write_to_file(dir.clone(), NAME, 3000, start as u64, body, None, Some(key.data()), None).await.unwrap();
+        }
+        let path = dir.join(part_name(NAME));
+        assert_ne!(fs::read(&path).await.unwrap(), data);
+        let stream = decrypted(DataFile::new(path, Some(key), false), 999, 1500);
+        let read: Vec<u8> = stream.map(|piece| piece.unwrap().to_vec()).concat().await;
+        assert_eq!(read, &data[999..2499]);
+        files::delete_file(dir, NAME).await.unwrap();
+    }
+
     #[actix_web::test]
     async fn test_free_space_works() {
         let pb: PathBuf = [DATA_DIR].iter().collect();
@@ -0,0 +1,177 @@
+//! Hashing uploads as their chunks come in, turned on with `BULLSEYE_HASH_ON_INGEST=1`, so that
+//! the verifier doesn't have to read every file again. This only works while the chunks arrive in
+//! order, each once: once one skips ahead or is sent again, the upload is left for the verifier to
+//! hash as usual. The state is only kept in memory, so uploads that were in progress when the
+//! server restarted are left to the verifier too, unless the client starts again from the
+//! beginning.
+//!
+//! The upload's Merkle tree (see `common::merkle`) is built at the same time.
+
+use std::{collections::HashMap, sync::Mutex};
+
+use common::merkle::{Hasher, MerkleTree};
+
+enum State {
+    /// Everything up to `next` has been hashed.
+    Idle { next: u64, hasher: Box<Hasher> },
+    /// A chunk is being written and hashed.
+    Busy,
+    /// A chunk came out of order.
+    Abandoned,
+}
+
+/// The hashes of the uploads this instance is receiving. Shared between all workers.
+#[derive(Default)]
+pub struct IngestHashes {
+    enabled: bool,
+    uploads: Mutex<HashMap<String, State>>,
+}
+
+impl IngestHashes {
+    pub fn from_env() -> Self {
+        Self {
+            enabled: std::env::var("BULLSEYE_HASH_ON_INGEST")
+                .is_ok_and(|v| !v.is_empty() && v != "0"),
+            uploads: Mutex::default(),
+        }
+    }
+
+    /// Gets the hasher for a chunk starting at `offset`, if the chunk carries on where the
+    /// upload's hash left off. It has to be given back with `put` once the chunk is written, or
+    /// with `abandon` if that fails.
+    ///
+    /// Chunks that skip ahead spoil the hash, and so do chunks that write over data that was
+    /// already hashed, like retries, since they could change it without changing the hash.
+    pub fn take(&self, id: &str, offset: u64) -> Option<Hasher> {
+        if !self.enabled {
+            return None;
+        }
+        let mut uploads = self.uploads.lock().unwrap();
+        let Some(state) = uploads.get_mut(id) else {
+            if offset != 0 {
+                return None;
+            }
+            uploads.insert(id.to_string(), State::Busy);
+            return Some(Hasher::default());
+        };
+        match state {
+            State::Idle { next, .. } if offset == *next => {
+                let State::Idle { hasher, .. } = std::mem::replace(state, State::Busy)
+                else {
+                    unreachable!();
+                };
+                Some(*hasher)
+            }
+            State::Abandoned => None,
+            _ => {
+                *state = State::Abandoned;
+                None
+            }
+        }
+    }
+
+    /// Gives the hasher back after the chunk it was taken for was written, up to `next`.
+    pub fn put(&self, id: &str, next: u64, hasher: Hasher) {
+        let mut uploads = self.uploads.lock().unwrap();
+        if let Some(state @ State::Busy) = uploads.get_mut(id) {
+            *state = State::Idle {
+                next,
+                hasher: Box::new(hasher),
+            };
+        }
+    }
+
+    /// Gives up on hashing the upload, because a chunk couldn't be written.
+    pub fn abandon(&self, id: &str) {
+        if let Some(state) = self.uploads.lock().unwrap().get_mut(id) {
+            *state = State::Abandoned;
+        }
+    }
+
+    /// Stops tracking the upload, and gets its hash and tree if all `size` bytes of it were hashed.
+    pub fn finish(&self, id: &str, size: u64) -> Option<(String, MerkleTree)> {
+        if !self.enabled {
+            return None;
+        }
+        match self.uploads.lock().unwrap().remove(id) {
+            Some(State::Idle { next, hasher }) if next == size => Some(hasher.finish()),
+            None if size == 0 => Some(Hasher::default().finish()),
+            _ => None,
+        }
+    }
+
+    /// Stops tracking an upload that won't be finished.
+    pub fn forget(&self, id: &str) {
+        self.uploads.lock().unwrap().remove(id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::merkle::hash_file;
+
+    use super::IngestHashes;
+
+    fn enabled() -> IngestHashes {
+        IngestHashes {
+            enabled: true,
+            ..Default::default()
+        }
+    }
+
+    /// Sends `data[offset..end]` as a chunk.
+    fn send(hashes: &IngestHashes, data: &[u8], offset: usize, end: usize) {
+        if let Some(mut hasher) = hashes.take("id", offset as u64) {
+            hasher.update(&data[offset..end]);
+            hashes.put("id", end as u64, hasher);
+        }
+    }
+
+    #[test]
+    fn in_order() {
+        let data = b"0123456789abcdef";
+        let hashes = enabled();
+        send(&hashes, data, 0, 4);
+        send(&hashes, data, 4, 8);
+        send(&hashes, data, 8, 16);
+        assert_eq!(hashes.finish("id", 16), Some(hash_file(&data[..]).unwrap()));
+        assert_eq!(hashes.finish("id", 0), Some(hash_file(&[][..]).unwrap()));
+    }
+
+    #[test]
+    fn out_of_order() {
+        let data = b"0123456789abcdef";
+        let hashes = enabled();
+        send(&hashes, data, 0, 4);
+        send(&hashes, data, 8, 16);
+        send(&hashes, data, 4, 8);
+        assert_eq!(hashes.finish("id", 16), None);
+
+        // A retry of a chunk that was already hashed could have changed it.
+        send(&hashes, data, 0, 4);
+        send(&hashes, data, 4, 8);
+        send(&hashes, data, 4, 8);
+        send(&hashes, data, 8, 16);
+        assert_eq!(hashes.finish("id", 16), None);
+
+        // The same chunk, sent again before the first try finished.
+        let busy = hashes.take("id", 0).unwrap();
+        assert!(hashes.take("id", 0).is_none());
+        hashes.put("id", 16, busy);
+        assert_eq!(hashes.finish("id", 16), None);
+
+        // Chunks sent at the same time.
+        let busy = hashes.take("id", 0).unwrap();
+        assert!(hashes.take("id", 8).is_none());
+        hashes.put("id", 8, busy);
+        assert_eq!(hashes.finish("id", 16), None);
+
+        // Never started from the beginning, like after a restart.
+        send(&hashes, data, 8, 16);
+        assert_eq!(hashes.finish("id", 16), None);
+
+        let off = IngestHashes::default();
+        assert!(off.take("id", 0).is_none());
+        assert_eq!(off.finish("id", 0), None);
+    }
+}
@@ -0,0 +1,431 @@
+//! End-to-end tests: real uploads through the handlers, against a real RethinkDB. Run them with
+//! `cargo test --features integration`.
+//!
+//! The database is a RethinkDB container started with testcontainers, so Docker has to be
+//! running, unless `RETHINKDB_HOST` (and `RETHINKDB_PORT`) point at a server already. The
+//! uploads are sent the way the client sends them, with the payload types from `common`.
+
+use std::{path::PathBuf, sync::Arc, time::Duration};
+
+use actix_web::{
+    body::MessageBody,
+    dev::{Service, ServiceResponse},
+    http::StatusCode,
+    test, web, App,
+};
+use common::{
+    crypt::MasterKey,
+    data::{File, Metadata, Status, UploadRow},
+    db::{migrations, DatabaseHandle},
+    payloads::*,
+    registry::{Project, Registry},
+};
+use serde::de::DeserializeOwned;
+use testcontainers::{
+    core::{IntoContainerPort, WaitFor},
+    runners::AsyncRunner,
+    ContainerAsync, GenericImage,
+};
+
+use crate::{
+    bans::BanCache,
+    files::{self, Layout},
+    ingest::IngestHashes,
+    maintenance::Maintenance,
+    ranges::{RangeLocks, WriterLimit},
+    reload::LiveRegistry,
+    stats::Leaderboard,
+    tasks, SharedCtx,
+};
+
+/// Accepts anything.
+const OPEN: &str = "integration";
+/// Uses strict offsets.
+const STRICT: &str = "integration-strict";
+/// Abandons uploads as soon as they go idle.
+const IDLE: &str = "integration-idle";
+/// Abandons uploads that go 4 seconds without data.
+const STALLED: &str = "integration-stalled";
+
+/// Starts RethinkDB, unless it's running already, and brings the database up to date. The
+/// container is stopped when it's dropped.
+async fn database() -> (Option<ContainerAsync<GenericImage>>, DatabaseHandle) {
+    let container = match std::env::var("RETHINKDB_HOST") {
+        Ok(_) => None,
+        Err(_) => {
+            let container = GenericImage::new("rethinkdb", "2.4.4")
+                .with_exposed_port(28015.tcp())
+                .with_wait_for(WaitFor::message_on_stdout("Server ready"))
+                .start()
+                .await
+                .expect("couldn't start RethinkDB; is Docker running?");
+            let host = container.get_host().await.unwrap();
+            let port = container.get_host_port_ipv4(28015).await.unwrap();
+            std::env::set_var("RETHINKDB_HOST", host.to_string());
+            std::env::set_var("RETHINKDB_PORT", port.to_string());
+            Some(container)
+        }
+    };
+    let db = DatabaseHandle::new().unwrap();
+    migrations::migrate(&db).await.unwrap();
+    (container, db)
+}
+
+fn registry() -> Registry {
+    let mut registry = Registry::default();
+    registry
+        .projects
+        .insert(OPEN.to_string(), Project::default());
+    let strict = Project {
+        strict_offsets: true,
+        ..Default::default()
+    };
+    registry.projects.insert(STRICT.to_string(), strict);
+    let idle = Project {
+        idle_timeout: Some(0),
+        ..Default::default()
+    };
+    registry.projects.insert(IDLE.to_string(), idle);
+    let stalled = Project {
+        stall_timeout: Some(4),
+        ..Default::default()
+    };
+    registry.projects.insert(STALLED.to_string(), stalled);
+    registry
+}
+
+fn ctx(pool: Arc<DatabaseHandle>, cwd: PathBuf, registry: Registry) -> SharedCtx {
+    SharedCtx {
+        pool,
+        cwd,
+        registry: Arc::new(LiveRegistry::new(registry)),
+        ranges: Arc::new(RangeLocks::default()),
+        writers: Arc::new(WriterLimit::new(8, false)),
+        admin_token: None,
+        signing_key: None,
+        node: None,
+        leaderboard: Arc::new(Leaderboard::from_env().unwrap()),
+        bans: Arc::new(BanCache::from_env().unwrap()),
+        layout: Arc::new(Layout::new(Layout::DEFAULT_TEMPLATE.to_string()).unwrap()),
+        allocation: files::Fallback::Truncate,
+        min_rate: None,
+        maintenance: Arc::new(Maintenance::default()),
+        ingest: Arc::new(IngestHashes::from_env()),
+        master_key: None,
+    }
+}
+
+/// Different for every upload, so they aren't turned away as duplicates of each other or of
+/// earlier runs.
+fn file_data(len: usize) -> Vec<u8> {
+    let seed = uuidv7::create();
+    seed.bytes().cycle().take(len).collect()
+}
+
+fn init_payload(
+    project: &str,
+    data: &[u8],
+    chunk_size: Option<u64>,
+) -> UploadInitialisationPayload {
+    UploadInitialisationPayload {
+        file: File {
+            hash: common::hash_file(data).unwrap(),
+            name: "integration.bin".to_string(),
+            size: data.len() as u64,
+        },
+        project: project.to_string(),
+        pipeline: "default".to_string(),
+        metadata: Metadata {
+            uploader: "integration".to_string(),
+            items: vec![format!("integration-{}", uuidv7::create())],
+            encryption: None,
+        },
+        chunk_size,
+        members: Vec::new(),
+        shard: None,
+        nonce: None,
+    }
+}
+
+/// Sends the request, and returns the status and the payload of the response.
+async fn send<S, R, B, T>(app: &S, req: R) -> (StatusCode, ErrorablePayload<T>)
+where
+    S: Service<R, Response = ServiceResponse<B>, Error = actix_web::Error>,
+    B: MessageBody,
+    T: DeserializeOwned,
+{
+    let resp = test::call_service(app, req).await;
+    let status = resp.status();
+    (status, test::read_body_json(resp).await)
+}
+
+#[actix_web::test]
+async fn test_uploads() {
+    let (_container, db) = database().await;
+    let pool = Arc::new(db);
+    let cwd = std::env::temp_dir().join(format!("bullseye-integration-{}", std::process::id()));
+    let registry = registry();
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(ctx(
+                pool.clone(),
+                cwd.clone(),
+                registry.clone(),
+            )))
+            .configure(crate::configure),
+    )
+    .await;
+
+    let new_upload = |project: &str, data: &[u8], chunk_size: Option<u64>| {
+        test::TestRequest::post()
+            .uri("/upload")
+            .set_json(init_payload(project, data, chunk_size))
+            .to_request()
+    };
+    let chunk = |id: &str, offset: u64, data: &[u8]| {
+        test::TestRequest::put()
+            .uri(&format!("/upload/{id}/data?offset={offset}"))
+            .set_payload(data.to_vec())
+            .to_request()
+    };
+    let finish = |id: &str| {
+        test::TestRequest::post()
+            .uri(&format!("/upload/{id}/finish"))
+            .to_request()
+    };
+    let get = |id: &str| {
+        test::TestRequest::get()
+            .uri(&format!("/upload/{id}"))
+            .to_request()
+    };
+
+    // The happy path: create, send every chunk, check nothing is missing, finish.
+    let data = file_data(10_000);
+    let (status, info) =
+        send::<_, _, _, NewUploadResponse>(&app, new_upload(OPEN, &data, None)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let ErrorablePayload::Ok(info) = info else {
+        panic!("{info:?}")
+    };
+    for (i, part) in data.chunks(4096).enumerate() {
+        let (status, _) =
+            send::<_, _, _, UploadChunkResponse>(&app, chunk(&info.id, i as u64 * 4096, part))
+                .await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+    let ranges = test::TestRequest::get()
+        .uri(&format!("/upload/{}/ranges", info.id))
+        .to_request();
+    let (_, ranges) = send::<_, _, _, ReceivedRangesResponse>(&app, ranges).await;
+    let ErrorablePayload::Ok(ranges) = ranges else {
+        panic!("{ranges:?}")
+    };
+    assert_eq!(ranges.received, vec![(0, 10_000)]);
+    assert!(ranges.missing.is_empty());
+    let (status, finished) = send::<_, _, _, FinishResponse>(&app, finish(&info.id)).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    let ErrorablePayload::Ok(finished) = finished else {
+        panic!("{finished:?}")
+    };
+    assert_eq!(finished, Status::Verifying);
+    let (_, row) = send::<_, _, _, SingleUploadResponse>(&app, get(&info.id)).await;
+    let ErrorablePayload::Ok(row) = row else {
+        panic!("{row:?}")
+    };
+    assert_eq!(row.status(), &Status::Verifying);
+    let written = tokio::fs::read(cwd.join(OPEN).join(&info.id))
+        .await
+        .unwrap();
+    assert_eq!(written, data);
+
+    // Finishing twice, like a client retrying after a timeout, gives the same answer, and
+    // chunks aren't accepted any more.
+    let (status, again) = send::<_, _, _, FinishResponse>(&app, finish(&info.id)).await;
+    assert_eq!(status, StatusCode::ACCEPTED);
+    assert!(matches!(again, ErrorablePayload::Ok(Status::Verifying)));
+    let (status, _) =
+        send::<_, _, _, UploadChunkResponse>(&app, chunk(&info.id, 0, &data[..10])).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    // Nor can it be abandoned any more.
+    let abandon = test::TestRequest::post()
+        .uri(&format!("/upload/{}/abandon", info.id))
+        .to_request();
+    let (status, _) = send::<_, _, _, ()>(&app, abandon).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+
+    // Starting an upload again gets it back, but only with the nonce it was started with, since
+    // the answer has its secret.
+    let data = file_data(4096);
+    let mut payload = init_payload(OPEN, &data, None);
+    payload.nonce = Some("first".to_string());
+    let init = |payload: &UploadInitialisationPayload| {
+        test::TestRequest::post()
+            .uri("/upload")
+            .set_json(payload)
+            .to_request()
+    };
+    let (status, first) = send::<_, _, _, NewUploadResponse>(&app, init(&payload)).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let ErrorablePayload::Ok(first) = first else {
+        panic!("{first:?}")
+    };
+    let (status, again) = send::<_, _, _, NewUploadResponse>(&app, init(&payload)).await;
+    assert_eq!(status, StatusCode::OK);
+    assert!(matches!(again, ErrorablePayload::Ok(again) if again.id == first.id));
+    payload.nonce = Some("guessed".to_string());
+    let (status, again) = send::<_, _, _, NewUploadResponse>(&app, init(&payload)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert!(matches!(
+        again,
+        ErrorablePayload::Rejected(Rejection::DuplicateUpload { id, .. }) if id == first.id
+    ));
+
+    // Offsets that don't make sense.
+    let data = file_data(4096);
+    let (_, info) = send::<_, _, _, NewUploadResponse>(&app, new_upload(OPEN, &data, None)).await;
+    let ErrorablePayload::Ok(info) = info else {
+        panic!("{info:?}")
+    };
+    let (status, _) = send::<_, _, _, UploadChunkResponse>(&app, chunk(&info.id, 4097, b"x")).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    // An upload can't be finished before all of it has come in.
+    let (status, _) = send::<_, _, _, FinishResponse>(&app, finish(&info.id)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert!(!cwd.join(OPEN).join(&info.id).exists());
+    let (status, _) =
+        send::<_, _, _, NewUploadResponse>(&app, new_upload(STRICT, &data, None)).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    let (_, info) =
+        send::<_, _, _, NewUploadResponse>(&app, new_upload(STRICT, &data, Some(1024))).await;
+    let ErrorablePayload::Ok(info) = info else {
+        panic!("{info:?}")
+    };
+    assert_eq!(info.chunk_size, Some(1024));
+    let (status, rejected) =
+        send::<_, _, _, UploadChunkResponse>(&app, chunk(&info.id, 100, &data[100..200])).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(matches!(
+        rejected,
+        ErrorablePayload::Rejected(Rejection::MisalignedOffset { chunk_size: 1024 })
+    ));
+    for offset in [0, 1024, 2048] {
+        let part = &data[offset..offset + 1024];
+        let (status, _) =
+            send::<_, _, _, UploadChunkResponse>(&app, chunk(&info.id, offset as u64, part)).await;
+        assert_eq!(status, StatusCode::CREATED);
+    }
+    // Sending the last chunk again is fine, but going back further isn't.
+    let (status, _) =
+        send::<_, _, _, UploadChunkResponse>(&app, chunk(&info.id, 2048, &data[2048..3072])).await;
+    assert_eq!(status, StatusCode::CREATED);
+    let (status, rejected) =
+        send::<_, _, _, UploadChunkResponse>(&app, chunk(&info.id, 0, &data[..1024])).await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert!(matches!(
+        rejected,
+        ErrorablePayload::Rejected(Rejection::OffsetRegressed {
+            high_water_mark: 3072
+        })
+    ));
+
+    // The reaper abandons uploads that have been idle for longer than the project allows.
+    let data = file_data(4096);
+    let (_, info) = send::<_, _, _, NewUploadResponse>(&app, new_upload(IDLE, &data, None)).await;
+    let ErrorablePayload::Ok(info) = info else {
+        panic!("{info:?}")
+    };
+    let (status, _) =
+        send::<_, _, _, UploadChunkResponse>(&app, chunk(&info.id, 0, &data[..1024])).await;
+    assert_eq!(status, StatusCode::CREATED);
+    // Activity is recorded to the second.
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    let ingest = IngestHashes::default();
+    tasks::reap_idle(&pool, &ingest, &registry).await;
+    let row = UploadRow::from_database(&pool, info.id.clone())
+        .await
+        .unwrap();
+    assert_eq!(row.status(), &Status::Deleted);
+    let (status, _) =
+        send::<_, _, _, UploadChunkResponse>(&app, chunk(&info.id, 1024, &data[1024..2048])).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    // Finishing it doesn't move its half-written file into place either.
+    let (status, _) = send::<_, _, _, FinishResponse>(&app, finish(&info.id)).await;
+    assert_eq!(status, StatusCode::CONFLICT);
+    assert!(!cwd.join(IDLE).join(&info.id).exists());
+
+    // Uploads that don't get any data are warned about, then abandoned, even if they aren't
+    // idle.
+    let (_, info) =
+        send::<_, _, _, NewUploadResponse>(&app, new_upload(STALLED, &data, None)).await;
+    let ErrorablePayload::Ok(info) = info else {
+        panic!("{info:?}")
+    };
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    tasks::reap_stalled(&pool, &ingest, &registry).await;
+    let mut row = UploadRow::from_database(&pool, info.id.clone())
+        .await
+        .unwrap();
+    assert_eq!(row.status(), &Status::Uploading);
+    let stall = row.stalled().unwrap();
+    assert_eq!(stall.abandon_at, row.created() + 4);
+    row.enter(&pool).await.unwrap();
+    tokio::time::sleep(Duration::from_secs(2)).await;
+    tasks::reap_stalled(&pool, &ingest, &registry).await;
+    let row = UploadRow::from_database(&pool, info.id.clone())
+        .await
+        .unwrap();
+    assert_eq!(row.status(), &Status::Deleted);
+
+    tokio::fs::remove_dir_all(cwd).await.unwrap();
+}
+
+#[actix_web::test]
+async fn test_encrypted_rewrites() {
+    let (_container, db) = database().await;
+    let cwd =
+        std::env::temp_dir().join(format!("bullseye-integration-crypt-{}", std::process::id()));
+    let mut ctx = ctx(Arc::new(db), cwd.clone(), registry());
+    ctx.master_key = Some(Arc::new(MasterKey::from_hex(&"ab".repeat(32)).unwrap()));
+    let app = test::init_service(
+        App::new()
+            .app_data(web::Data::new(ctx))
+            .configure(crate::configure),
+    )
+    .await;
+    let chunk = |id: &str, offset: u64, data: &[u8]| {
+        test::TestRequest::put()
+            .uri(&format!("/upload/{id}/data?offset={offset}"))
+            .set_payload(data.to_vec())
+            .to_request()
+    };
+
+    let data = file_data(4096);
+    let new_upload = test::TestRequest::post()
+        .uri("/upload")
+        .set_json(init_payload(OPEN, &data, None))
+        .to_request();
+    let (_, info) = send::<_, _, _, NewUploadResponse>(&app, new_upload).await;
+    let ErrorablePayload::Ok(info) = info else {
+        panic!("{info:?}")
+    };
+    let (status, _) =
+        send::<_, _, _, UploadChunkResponse>(&app, chunk(&info.id, 0, &data[..2048])).await;
+    assert_eq!(status, StatusCode::CREATED);
+    // Neither the same chunk again nor one that overlaps it is written, so the keystream isn't
+    // reused.
+    for offset in [0, 1024] {
+        let part = &data[offset..offset + 2048];
+        let (status, rejected) =
+            send::<_, _, _, UploadChunkResponse>(&app, chunk(&info.id, offset as u64, part)).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert!(matches!(
+            rejected,
+            ErrorablePayload::Rejected(Rejection::AlreadyReceived)
+        ));
+    }
+    let (status, _) =
+        send::<_, _, _, UploadChunkResponse>(&app, chunk(&info.id, 2048, &data[2048..])).await;
+    assert_eq!(status, StatusCode::CREATED);
+
+    tokio::fs::remove_dir_all(cwd).await.unwrap();
+}
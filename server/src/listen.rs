@@ -0,0 +1,232 @@
+//! Where the server listens: TCP port 7000 on `HOST`, a Unix socket (not on Windows), or both.
+//! The socket is for a reverse proxy or pipelines on the same machine. It's set with
+//! `BULLSEYE_UNIX_SOCKET`, and its permissions with `BULLSEYE_UNIX_SOCKET_MODE` (octal, 660 by
+//! default), since they decide who can use it. `BULLSEYE_TCP=0` turns the TCP listener off.
+//!
+//! Also how connections behave. The TCP listener speaks HTTP/2 without TLS (h2c) to clients that
+//! start with it, unless `BULLSEYE_H2C=0`; HTTP/2 over TLS is up to a reverse proxy. Idle
+//! connections are kept open for `BULLSEYE_KEEPALIVE` seconds (5 by default, 0 to close them after
+//! each request), so clients can send chunk after chunk without reconnecting.
+//! `BULLSEYE_H2_WINDOW` and `BULLSEYE_H2_CONNECTION_WINDOW` set HTTP/2's flow control windows, in
+//! bytes, which need to be bigger on high-latency links. `BULLSEYE_MAX_STREAMS` limits how many
+//! requests one connection can have in flight at once.
+
+use std::{
+    any::Any,
+    io,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use actix_web::{
+    dev::{Extensions, ServiceRequest},
+    http::KeepAlive,
+    HttpRequest,
+};
+
+use crate::error::ApiError;
+
+pub const PORT: u16 = 7000;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnixSocket {
+    pub path: PathBuf,
+    pub mode: u32,
+}
+
+impl UnixSocket {
+    /// Sets the socket's permissions. Call it once the socket's been bound.
+    #[cfg(unix)]
+    pub fn set_permissions(&self) -> io::Result<()> {
+        use std::{
+            fs::{self, Permissions},
+            os::unix::fs::PermissionsExt,
+        };
+
+        fs::set_permissions(&self.path, Permissions::from_mode(self.mode))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Listen {
+    /// The host to listen on over TCP, if at all.
+    pub tcp: Option<String>,
+    pub unix: Option<UnixSocket>,
+}
+
+impl Listen {
+    pub fn from_env() -> io::Result<Self> {
+        Self::parse(
+            var("HOST")?,
+            var("BULLSEYE_TCP")?,
+            var("BULLSEYE_UNIX_SOCKET")?,
+            var("BULLSEYE_UNIX_SOCKET_MODE")?,
+        )
+    }
+
+    fn parse(
+        host: Option<String>,
+        tcp: Option<String>,
+        socket: Option<String>,
+        mode: Option<String>,
+    ) -> io::Result<Self> {
+        let tcp = match tcp.as_deref() {
+            None | Some("1") => Some(host.unwrap_or("127.0.0.1".to_string())),
+            Some("0") => None,
+            Some(value) => {
+                return Err(io::Error::other(format!(
+                    "BULLSEYE_TCP must be 0 or 1, not {value}"
+                )))
+            }
+        };
+        let mode = match mode {
+            Some(mode) => u32::from_str_radix(&mode, 8)
+                .ok()
+                .filter(|mode| *mode <= 0o777)
+                .ok_or_else(|| {
+                    io::Error::other(format!(
+                        "BULLSEYE_UNIX_SOCKET_MODE must be an octal mode like 660, not {mode}"
+                    ))
+                })?,
+            None => 0o660,
+        };
+        let unix = socket.map(|path| UnixSocket {
+            path: path.into(),
+            mode,
+        });
+        if tcp.is_none() && unix.is_none() {
+            return Err(io::Error::other(
+                "BULLSEYE_TCP is 0, so BULLSEYE_UNIX_SOCKET has to be set",
+            ));
+        }
+        Ok(Self { tcp, unix })
+    }
+}
+
+fn var<T: std::str::FromStr>(name: &str) -> io::Result<Option<T>>
+where
+    T::Err: std::fmt::Display,
+{
+    match std::env::var(name) {
+        Ok(v) if !v.is_empty() => v
+            .parse()
+            .map(Some)
+            .map_err(|e| io::Error::other(format!("{name}: {e}"))),
+        _ => Ok(None),
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Connections {
+    pub h2c: bool,
+    keep_alive_secs: u64,
+    /// None leaves actix's default.
+    pub h2_window: Option<u32>,
+    /// None leaves actix's default.
+    pub h2_connection_window: Option<u32>,
+    /// 0 is no limit.
+    max_streams: usize,
+}
+
+impl Connections {
+    pub fn from_env() -> io::Result<Self> {
+        Ok(Self {
+            h2c: var::<u8>("BULLSEYE_H2C")?.unwrap_or(1) != 0,
+            keep_alive_secs: var("BULLSEYE_KEEPALIVE")?.unwrap_or(5),
+            h2_window: var("BULLSEYE_H2_WINDOW")?,
+            h2_connection_window: var("BULLSEYE_H2_CONNECTION_WINDOW")?,
+            max_streams: var("BULLSEYE_MAX_STREAMS")?.unwrap_or(0),
+        })
+    }
+
+    pub fn keep_alive(&self) -> KeepAlive {
+        match self.keep_alive_secs {
+            0 => KeepAlive::Disabled,
+            secs => KeepAlive::Timeout(Duration::from_secs(secs)),
+        }
+    }
+
+    /// Lets a request in if its connection has room for it. It counts as in flight until the
+    /// permit is dropped.
+    pub fn admit(&self, req: &ServiceRequest) -> Result<Option<StreamPermit>, ApiError> {
+        let Some(InFlight(count)) = req.conn_data::<InFlight>().filter(|_| self.max_streams > 0)
+        else {
+            return Ok(None);
+        };
+        if count.fetch_add(1, Ordering::Relaxed) >= self.max_streams {
+            count.fetch_sub(1, Ordering::Relaxed);
+            return Err(ApiError::Unavailable {
+                retry_after_secs: 1,
+                reason: "too many requests at once on this connection".to_string(),
+            });
+        }
+        Ok(Some(StreamPermit(count.clone())))
+    }
+}
+
+/// How many requests a connection has in flight. Each connection gets one in `on_connect`.
+#[derive(Default)]
+struct InFlight(Arc<AtomicUsize>);
+
+pub fn on_connect(_: &dyn Any, data: &mut Extensions) {
+    data.insert(InFlight::default());
+}
+
+pub struct StreamPermit(Arc<AtomicUsize>);
+
+impl Drop for StreamPermit {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Works out the URL of an upload from the request that created it, so it's reachable the same
+/// way. That's the Host header, or what a proxy put in X-Forwarded-Host. Requests over the Unix
+/// socket might have neither, and then actix makes up 127.0.0.1:8080; they get `localhost`
+/// instead, which the client sends down the socket again.
+pub fn upload_url(req: &HttpRequest, id: &str) -> String {
+    let over_socket = req.peer_addr().is_none();
+    if over_socket && req.connection_info().host() == req.app_config().host() {
+        return format!("http://localhost/upload/{id}");
+    }
+    // It only fails if the route doesn't exist.
+    req.url_for("get_upload", [id])
+        .expect("get_upload is a route")
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Listen, UnixSocket};
+
+    fn s(value: &str) -> Option<String> {
+        Some(value.to_string())
+    }
+
+    #[test]
+    fn test_parse() {
+        let listen = Listen::parse(None, None, None, None).unwrap();
+        assert_eq!(listen.tcp.as_deref(), Some("127.0.0.1"));
+        assert_eq!(listen.unix, None);
+        let listen = Listen::parse(s("::"), s("0"), s("/run/bullseye.sock"), s("600")).unwrap();
+        assert_eq!(listen.tcp, None);
+        assert_eq!(
+            listen.unix,
+            Some(UnixSocket {
+                path: "/run/bullseye.sock".into(),
+                mode: 0o600
+            })
+        );
+        let listen = Listen::parse(s("::"), None, s("/run/bullseye.sock"), None).unwrap();
+        assert_eq!(listen.tcp.as_deref(), Some("::"));
+        assert_eq!(listen.unix.unwrap().mode, 0o660);
+        Listen::parse(None, s("0"), None, None).unwrap_err();
+        Listen::parse(None, s("yes"), None, None).unwrap_err();
+        Listen::parse(None, None, s("/run/bullseye.sock"), s("999")).unwrap_err();
+        Listen::parse(None, None, s("/run/bullseye.sock"), s("7777")).unwrap_err();
+    }
+}
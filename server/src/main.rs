@@ -1,164 +1,655 @@
-use std::{io, path::{Path, PathBuf}};
+use std::{io, path::{Path, PathBuf}, sync::Arc, time::{Instant, SystemTime}};
 
-use actix_web::{get, post, put, web::{self, Bytes}, App, HttpRequest, HttpResponse, HttpServer, Responder};
+use actix_files::NamedFile;
+use actix_files::HttpRange;
+use actix_web::{dev::Service, get, http::header::{ContentDisposition, ContentType, DispositionParam, DispositionType, ACCEPT_RANGES, CONTENT_LENGTH, CONTENT_RANGE, LOCATION, RANGE}, post, put, web::{self, Bytes}, App, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder, HttpServer, Responder, ResponseError};
 
 use async_stream::stream;
 use serde::Deserialize;
-use futures::{pin_mut, StreamExt};
+use futures::{future::{ready, Either}, pin_mut, FutureExt, StreamExt};
 
-use common::db::*;
+use common::crypt::{DataKey, MasterKey};
+use common::db::{archive::merge_archived, migrations, *};
+use common::pipeline::Pipeline;
+use common::registry::Registry;
+use common::signing::{nonce_hash, upload_secret, SignedRequest};
+mod access;
+mod admin;
+mod audit;
+mod bans;
+mod benchmark;
+mod breaker;
+mod compress;
+mod deadline;
+mod error;
+use error::{ApiError, ApiResult};
 mod payloads;
+mod projects;
+mod reload;
 use payloads::*;
 mod files;
+mod ingest;
+mod listen;
+mod ranges;
+use ranges::{RangeLocks, WriterLimit};
+mod maintenance;
+mod metrics;
+mod notify;
+mod overload;
+mod tasks;
+mod scrub;
+mod stats;
+#[cfg(all(test, feature = "integration"))]
+mod integration;
 
 #[get("/")]
 async fn slash() -> impl Responder {
     HttpResponse::Ok().body("no shenanigans please >:(")
 }
 
-type NewUploadResp = ErrorablePayload<NewUploadResponse>;
-
 #[post("/upload")]
 async fn new_upload(
     conn: web::Data<SharedCtx>,
     req: HttpRequest,
     pdetails: web::Json<UploadInitialisationPayload>,
-) -> impl Responder {
-    let id = uuidv7::create();
+) -> ApiResult {
+    conn.maintenance.check()?;
     let mut details = pdetails.clone();
     details.file.name = Path::new(&details.file.name).file_name().unwrap().to_str().unwrap().to_string();
-    if let io::Result::Err(e) = files::new_file(conn.cwd.clone(), &id, details.file.size).await {
-        dbg!(e);
-        return NewUploadResp::Err("I/O error".to_string()).to_response(HttpResponse::Created());
-    }
-    let res = UploadRow::new(
-        &conn.pool,
-        conn.cwd.to_str().unwrap().to_string(),
-        id.clone(),
-        details.file,
-        details.pipeline,
-        details.project,
-        details.metadata,
-    )
-    .await;
-
-    match res {
-        Ok(entry) => {
-            NewUploadResp::Ok(UploadInformation {
-                id: entry.id().clone(),
-                // I would like to fix this abomination
-                base_url: req
-                    .url_for("get_upload", [entry.id()])
-                    .unwrap()
-                    .as_str()
-                    .to_string(),
-            })
+    for member in &mut details.members {
+        member.name = Path::new(&member.name).file_name().unwrap_or_default().to_str().unwrap().to_string();
+    }
+    let registry = conn.registry.get();
+    registry.check_upload(
+        &details.project,
+        &details.pipeline,
+        &details.file,
+        &details.members,
+        details.metadata.encryption.is_some(),
+    )?;
+    let strict = registry.project(&details.project).is_some_and(|p| p.strict_offsets);
+    let chunk_size = match (strict, details.chunk_size) {
+        (false, _) => None,
+        (true, Some(c)) if c > 0 => Some(c),
+        (true, _) => return Err(Rejection::ChunkSizeRequired.into()),
+    };
+    conn.bans.check_new(&conn.pool, &details.metadata.uploader).await?;
+    // Shards of one file can have the same data, like runs of zeroes.
+    if details.shard.is_none() {
+        if let Some(existing) = check_duplicate(&conn, &details).await? {
+            return Ok(upload_information(&conn, &req, &existing, HttpResponse::Ok()));
+        }
+    }
+    let uploader = details.metadata.uploader.clone();
+    check_uploader_limits(&conn, &uploader, details.file.size, false).await?;
+    let id = uuidv7::create();
+    let encryption = match &conn.master_key {
+        Some(master) => Some(master.new_key(&id).map_err(|e| ApiError::Io("making the upload's key", e))?.1),
+        None => None,
+    };
+    let dir = conn.layout.dir(&conn.cwd, &details.project, &details.pipeline);
+    let size = details.file.size;
+    let nonce = details.nonce.take();
+    let entry = UploadRow::builder(id.clone(), details.file, details.project, details.pipeline, details.metadata)
+        .dir(dir.to_str().unwrap())
+        .chunk_size(chunk_size)
+        .node(conn.node.clone())
+        .members(details.members)
+        .shard(details.shard)
+        .encryption(encryption)
+        .nonce_hash(nonce.as_deref().map(nonce_hash))
+        .build()
+        .map_err(ApiError::Invalid)?;
+    files::new_file(dir.clone(), &id, size, conn.allocation)
+        .await
+        .map_err(|e| ApiError::Io("allocating the file", e))?;
+
+    if let Err(e) = entry.insert(&conn.pool).await {
+        let _ = files::delete_file(dir, &id).await;
+        return Err(e.into());
+    }
+    // Uploads started at the same time could all have passed the check above, so check again now
+    // that this one counts, and back out if it went over.
+    if let Err(e) = check_uploader_limits(&conn, &uploader, size, true).await {
+        let _ = entry.remove(&conn.pool).await;
+        let _ = files::delete_file(dir, &id).await;
+        return Err(e);
+    }
+    entry.record_manifest().await;
+    req.extensions_mut().insert(audit::AuditUploads(vec![entry.id().clone()]));
+    Ok(upload_information(&conn, &req, &entry, HttpResponse::Created()))
+}
+
+/// Tells the client where to send an upload's data.
+fn upload_information(conn: &SharedCtx, req: &HttpRequest, entry: &UploadRow, builder: HttpResponseBuilder) -> HttpResponse {
+    // Point the client straight at the instance that owns it, so it doesn't have to be redirected.
+    let base_url = match entry.node() {
+        Some(node) => format!("{node}/upload/{}", entry.id()),
+        None => listen::upload_url(req, entry.id()),
+    };
+    ErrorablePayload::Ok(UploadInformation {
+        id: entry.id().clone(),
+        base_url,
+        chunk_size: entry.chunk_size(),
+        secret: conn.signing_key.as_ref().map(|key| upload_secret(key, entry.id())),
+    })
+    .to_response(builder)
+}
+
+/// Enforces the limits the registry sets on the uploader, for a new upload of `size` bytes. If
+/// `counted`, the new upload is already in the database, so it's in the totals.
+async fn check_uploader_limits(conn: &SharedCtx, uploader: &str, size: u64, counted: bool) -> Result<(), ApiError> {
+    let limits = conn.registry.get().uploader_limits(uploader);
+    // What this upload adds to the totals, if it's in them. It's left out, so they're compared
+    // the same way either way.
+    let (this_upload, this_size) = match counted {
+        true => (1, size),
+        false => (0, 0),
+    };
+    if let Some(max) = limits.max_active_uploads {
+        let active = UploadRow::count_active(&conn.pool, uploader.to_string()).await?;
+        if active.saturating_sub(this_upload) >= max {
+            return Err(Rejection::TooManyActiveUploads { max }.into());
         }
-        Err(e) => {
-            let _ = files::delete_file(conn.cwd.clone(), &id).await;
-            NewUploadResp::from(e)
+    }
+    if let Some(max_bytes) = limits.max_bytes_per_day {
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let used = UploadRow::bytes_started_since(&conn.pool, uploader.to_string(), now.saturating_sub(DAY)).await?;
+        let used = used.saturating_sub(this_size);
+        if used.saturating_add(size) > max_bytes {
+            return Err(Rejection::DailyQuotaExceeded { max_bytes, used }.into());
         }
     }
-    .to_response(HttpResponse::Created())
+    Ok(())
 }
 
-type GetUploadResp = ErrorablePayload<SingleUploadResponse>;
+/// Turns away a second upload of a file that's already being uploaded to the project, and says
+/// which upload that is so the client can attach to it.
+///
+/// If it's the same upload being started again, because the client didn't get the answer the
+/// first time, that upload is returned instead, as long as no data has been sent to it yet. The
+/// client proves that by sending the nonce it started the upload with, since the answer has the
+/// upload's secret in it.
+async fn check_duplicate(conn: &SharedCtx, details: &UploadInitialisationPayload) -> Result<Option<UploadRow>, ApiError> {
+    let Some(existing) =
+        UploadRow::find_active_by_hash(&conn.pool, details.project.clone(), details.file.hash.clone(), details.file.size).await?
+    else {
+        return Ok(None);
+    };
+    let retried = existing.status() == &Status::Uploading
+        && existing.received_ranges().is_empty()
+        && existing.pipeline() == &details.pipeline
+        && existing.file() == &details.file
+        && existing.metadata().uploader == details.metadata.uploader
+        && existing.metadata().items == details.metadata.items
+        && existing.nonce_matches(details.nonce.as_deref());
+    if retried {
+        return Ok(Some(existing));
+    }
+    Err(Rejection::DuplicateUpload {
+        id: existing.id().clone(),
+        status: existing.status().clone(),
+        high_water_mark: existing.high_water_mark(),
+        progress: existing.progress(),
+    }
+    .into())
+}
+
+/// The window for UploaderLimits::max_bytes_per_day, in seconds.
+const DAY: u64 = 24 * 60 * 60;
+
+/// For listings that can include the archive (see `common::db::archive`).
+#[derive(Deserialize)]
+struct ArchivedQuery {
+    /// Look in the archive too.
+    #[serde(default)]
+    archived: bool,
+}
 
 #[get("/upload/{uuid}")]
-async fn get_upload(conn: web::Data<SharedCtx>, path: web::Path<String>) -> impl Responder {
+async fn get_upload(conn: web::Data<SharedCtx>, path: web::Path<String>, query: web::Query<ArchivedQuery>) -> ApiResult {
+    let uuid = path.into_inner();
+    let upload = match query.archived {
+        true => UploadRow::from_either(&conn.pool, uuid).await?,
+        false => UploadRow::from_database(&conn.pool, uuid).await?,
+    };
+    Ok(ErrorablePayload::Ok(upload).to_response(HttpResponse::Ok()))
+}
+
+/// Says which parts of the file the server already has, for clients resuming an upload.
+#[get("/upload/{uuid}/ranges")]
+async fn get_received_ranges(conn: web::Data<SharedCtx>, path: web::Path<String>) -> ApiResult {
     let uuid = path.into_inner();
-    let upload = UploadRow::from_database(&conn.pool, uuid).await;
-    match upload {
-        Ok(payload) => GetUploadResp::Ok(payload),
-        Err(e) => GetUploadResp::from(e),
+    let row = UploadRow::from_database(&conn.pool, uuid).await?;
+    Ok(ErrorablePayload::Ok(ReceivedRangesResponse {
+        size: row.size(),
+        received: row.received_ranges(),
+        missing: row.missing_ranges(),
+    })
+    .to_response(HttpResponse::Ok()))
+}
+
+/// Makes sure the upload's data can be read: it has to be finished, and not deleted yet.
+fn check_data_available(row: &UploadRow) -> Result<(), ApiError> {
+    if row.status() == &Status::Uploading {
+        return Err(ApiError::Conflict("Item is still in the UPLOADING status".to_string()));
     }
-    .to_response(HttpResponse::Ok())
+    if row.files_removed() {
+        return Err(ApiError::Conflict("The upload's data has been deleted".to_string()));
+    }
+    Ok(())
 }
 
-type UploadChunkResp = ErrorablePayload<UploadChunkResponse>;
+/// For errors reading the data of an upload that should have it.
+fn data_error(e: io::Error) -> ApiError {
+    match e.kind() {
+        io::ErrorKind::NotFound => ApiError::Internal("The upload's data is no longer on disk".to_string()),
+        _ => ApiError::Io("reading the upload's data", e),
+    }
+}
+
+/// Gets the Merkle tree of a finished upload, so that parts of the file can be checked or sent
+/// again on their own.
+#[get("/upload/{uuid}/manifest")]
+async fn get_merkle_tree(conn: web::Data<SharedCtx>, req: HttpRequest, path: web::Path<String>) -> ApiResult {
+    let uuid = path.into_inner();
+    let row = UploadRow::from_database(&conn.pool, uuid).await?;
+    if let Some(redirect) = redirect_to_owner(&conn, &req, &row) {
+        return Ok(redirect);
+    }
+    check_data_available(&row)?;
+    let key = row.data_key(conn.master_key.as_deref()).map_err(data_error)?;
+    let tree = files::merkle_tree(row.dir().into(), row.id(), key).await.map_err(data_error)?;
+    Ok(ErrorablePayload::Ok(tree).to_response(HttpResponse::Ok()))
+}
+
+/// Sends an upload's data, or what it was derived into if it was, for workers that don't share the
+/// data directory. Range requests are supported, and the file is streamed from disk in chunks
+/// rather than read into memory.
+#[derive(Deserialize)]
+struct DataQuery {
+    /// Send the upload's own data, even if a file was derived from it. The shard assembler uses
+    /// this to fetch shards from other instances.
+    #[serde(default)]
+    original: bool,
+}
+
+#[get("/upload/{uuid}/data")]
+async fn get_data(conn: web::Data<SharedCtx>, req: HttpRequest, path: web::Path<String>, query: web::Query<DataQuery>) -> ApiResult {
+    let uuid = path.into_inner();
+    let row = UploadRow::from_database(&conn.pool, uuid).await?;
+    if let Some(redirect) = redirect_to_owner(&conn, &req, &row) {
+        return Ok(redirect);
+    }
+    check_data_available(&row)?;
+    let (data, file) = match query.original {
+        true => (row.original_file(conn.master_key.as_deref()), row.file()),
+        false => (row.data_file(conn.master_key.as_deref()), row.current_file()),
+    };
+    let data = data.map_err(data_error)?;
+    let disposition = ContentDisposition {
+        disposition: DispositionType::Attachment,
+        parameters: vec![DispositionParam::Filename(file.name.clone())],
+    };
+    if !data.is_encrypted() {
+        let file = NamedFile::open_async(&data.path).await.map_err(data_error)?;
+        return Ok(file
+            .set_content_type(ContentType::octet_stream().0)
+            .set_content_disposition(disposition)
+            .into_response(&req));
+    }
+    // NamedFile would send what's on disk, so ranges are handled here instead.
+    let size = file.size;
+    let range = match req.headers().get(RANGE).and_then(|v| v.to_str().ok()) {
+        Some(header) => match HttpRange::parse(header, size) {
+            Ok(ranges) => ranges.first().map(|r| (r.start, r.length)),
+            Err(_) => {
+                return Ok(HttpResponse::RangeNotSatisfiable().insert_header((CONTENT_RANGE, format!("bytes */{size}"))).finish());
+            }
+        },
+        None => None,
+    };
+    let (mut res, start, len) = match range {
+        Some((start, len)) => {
+            let mut res = HttpResponse::PartialContent();
+            res.insert_header((CONTENT_RANGE, format!("bytes {start}-{}/{size}", start + len - 1)));
+            (res, start, len)
+        }
+        None => (HttpResponse::Ok(), 0, size),
+    };
+    Ok(res
+        .insert_header(ContentType::octet_stream())
+        .insert_header((ACCEPT_RANGES, "bytes"))
+        .insert_header(disposition)
+        .no_chunking(len)
+        .streaming(files::decrypted(data, start, len)))
+}
+
+/// Finds the uploads containing an item. It's an admin route, since item names can be guessed.
+#[get("/items/{name}")]
+async fn search_item(conn: web::Data<SharedCtx>, req: HttpRequest, path: web::Path<String>, query: web::Query<ArchivedQuery>) -> ApiResult {
+    admin::check_auth(&req, &conn)?;
+    let name = path.into_inner();
+    let mut rows: ItemSearchResponse = UploadRow::find_by_item(&conn.pool, name.clone()).await?;
+    if query.archived {
+        let archived = UploadRow::find_archived_by_item(&conn.pool, name).await?;
+        rows = merge_archived(rows, archived, |row| &row.id);
+    }
+    Ok(ErrorablePayload::Ok(rows).to_response(HttpResponse::Ok()))
+}
+
+#[derive(Deserialize)]
+struct HashLookupQuery {
+    project: String,
+    size: u64,
+    /// Look in the archive too.
+    #[serde(default)]
+    archived: bool,
+}
+
+/// Lets a client check whether a file is already in the project before uploading it.
+#[get("/hash/{sha256}")]
+async fn lookup_hash(
+    conn: web::Data<SharedCtx>,
+    path: web::Path<String>,
+    query: web::Query<HashLookupQuery>,
+) -> ApiResult {
+    let hash = path.into_inner().to_ascii_lowercase();
+    let mut exists = !UploadRow::find_by_hash(&conn.pool, query.project.clone(), hash.clone(), query.size).await?.is_empty();
+    if query.archived && !exists {
+        exists = !UploadRow::find_archived_by_hash(&conn.pool, query.project.clone(), hash, query.size).await?.is_empty();
+    }
+    Ok(ErrorablePayload::<HashLookupResponse>::Ok(exists).to_response(HttpResponse::Ok()))
+}
+
+/// Sends requests that touch the file of an upload owned by another instance to that instance.
+/// The redirect keeps the method and the body.
+fn redirect_to_owner(conn: &SharedCtx, req: &HttpRequest, row: &UploadRow) -> Option<HttpResponse> {
+    let owner = row.node()?;
+    if conn.node.as_deref() == Some(owner) {
+        return None;
+    }
+    let mut location = format!("{owner}{}", req.path());
+    if !req.query_string().is_empty() {
+        location = format!("{location}?{}", req.query_string());
+    }
+    Some(HttpResponse::TemporaryRedirect().insert_header((LOCATION, location)).finish())
+}
+
+#[derive(Deserialize)]
+struct SignatureQueryString {
+    expires: Option<u64>,
+    signature: Option<String>,
+}
+
+/// Checks the signature of a chunk, finish or abandon request, if this server requires them.
+/// `length` is the length of the chunk, or 0 for the others.
+fn check_signature(
+    conn: &SharedCtx,
+    req: &HttpRequest,
+    action: &str,
+    row: &UploadRow,
+    offset: u64,
+    length: Option<u64>,
+) -> Result<(), Rejection> {
+    let Some(key) = &conn.signing_key else {
+        return Ok(());
+    };
+    let qs = web::Query::<SignatureQueryString>::from_query(req.query_string())
+        .map_err(|_| Rejection::BadSignature)?;
+    let (Some(length), Some(expires), Some(signature)) = (length, qs.expires, &qs.signature) else {
+        return Err(Rejection::BadSignature);
+    };
+    let request = SignedRequest {
+        action,
+        id: row.id(),
+        offset,
+        length,
+        expires,
+    };
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs();
+    match request.verify(&upload_secret(key, row.id()), signature, now) {
+        true => Ok(()),
+        false => Err(Rejection::BadSignature),
+    }
+}
 
 #[derive(Deserialize)]
 struct UploadChunkQueryString {
     offset: u64,
+    /// How many times the client has tried to send this chunk before. Only used for statistics.
+    #[serde(default)]
+    attempt: u32,
 }
 
 #[put("/upload/{uuid}/data")]
 async fn put_upload_chunk(
+    req: HttpRequest,
     body: web::Payload,
     conn: web::Data<SharedCtx>,
     path: web::Path<String>,
     qs: web::Query<UploadChunkQueryString>,
-) -> impl Responder {
+) -> ApiResult {
     let uuid = path.into_inner();
-    let offset = qs.into_inner().offset;
-    let row = UploadRow::from_database(&conn.pool, uuid).await;
-    let mut res = UploadChunkResp::Ok(());
-    if let Ok(mut row) = row {
-        if row.status() != &Status::Uploading {
-            res = UploadChunkResp::Err("Item is not in the UPLOADING status".to_string());
-        } else if offset > row.size() {
-            res = UploadChunkResp::Err("Offset too large".to_string());
-        } else if let Err(e) = row.enter(&conn.pool).await {
-            res = UploadChunkResp::from(e);
-        } else {
-            let r = files::write_to_file(conn.cwd.clone(), row.id(), row.size(), offset, body).await;
-            if let Err(e) = r {
-                dbg!(e);
-                res = UploadChunkResp::Err("I/O error".to_string());
-            }
+    let UploadChunkQueryString { offset, attempt } = qs.into_inner();
+    let mut row = UploadRow::from_database(&conn.pool, uuid).await?;
+    let length = req
+        .headers()
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<u64>().ok());
+    if let Some(redirect) = redirect_to_owner(&conn, &req, &row) {
+        return Ok(redirect);
+    }
+    check_signature(&conn, &req, "data", &row, offset, length)?;
+    conn.bans.check_chunk(&conn.pool, &row.metadata().uploader).await?;
+    if row.status() != &Status::Uploading {
+        return Err(ApiError::Conflict("Item is not in the UPLOADING status".to_string()));
+    }
+    if offset > row.size() {
+        return Err(ApiError::Invalid("Offset too large".to_string()));
+    }
+    row.check_offset(offset)?;
+    let key = row.data_key(conn.master_key.as_deref()).map_err(|e| ApiError::Io("getting the upload's key", e))?;
+    row.enter(&conn.pool).await?;
+    // Without a Content-Length we don't know where the chunk ends, so lock everything
+    // after the offset.
+    let end = length.map_or(row.size(), |len| offset.saturating_add(len).min(row.size()));
+    let _writer = conn.writers.enter(row.id()).await?;
+    let _guard = conn.ranges.lock(row.id(), offset..end).await;
+    // Each part of an encrypted file is only written once, so the keystream is never reused. The
+    // row is read again, since the chunks that held the lock before us may have filled the range.
+    if key.is_some() {
+        let received = UploadRow::from_database(&conn.pool, row.id().clone()).await?.received_ranges();
+        if received.iter().any(|&(start, stop)| start < end && offset < stop) {
+            return Err(Rejection::AlreadyReceived.into());
         }
     }
-    res.to_response(HttpResponse::Created())
+    let mut hasher = conn.ingest.take(row.id(), offset);
+    let started = Instant::now();
+    let key = key.as_ref().map(DataKey::data);
+    let r = files::write_to_file(row.dir().into(), row.id(), row.size(), offset, body, hasher.as_mut(), key, conn.min_rate).await;
+    match (&r, hasher) {
+        (Ok(written), Some(hasher)) => conn.ingest.put(row.id(), offset + written, hasher),
+        (Err(_), Some(_)) => conn.ingest.abandon(row.id()),
+        (_, None) => {}
+    }
+    let written = r.map_err(|e| match e.kind() {
+        // The client can try again, hopefully over a better connection.
+        io::ErrorKind::TimedOut => ApiError::Unavailable { retry_after_secs: 1, reason: e.to_string() },
+        _ => ApiError::Io("writing the chunk", e),
+    })?;
+    // Statistics aren't worth failing the chunk over.
+    if let Err(e) = row.record_chunk(&conn.pool, written, started.elapsed(), attempt > 0).await {
+        log::warn!("failed to record transfer statistics for {}: {e}", row.id());
+    }
+    // At worst, a client resuming the upload sends the chunk again.
+    if let Err(e) = row.record_received(&conn.pool, offset, offset + written).await {
+        log::warn!("failed to record the chunk at {offset} of {}: {e}", row.id());
+    }
+    if row.strict_offsets() {
+        row.acknowledge(&conn.pool, offset + written).await?;
+    }
+    Ok(ErrorablePayload::<UploadChunkResponse>::Ok(()).to_response(HttpResponse::Created()))
+}
+
+#[derive(Deserialize)]
+struct EventsQuery {
+    /// Comma-separated extras to send with the events. Only `row` is supported.
+    #[serde(default)]
+    include: String,
+    /// Comma-separated kinds of event to send; see `EventKind`. All of them if not given.
+    events: Option<String>,
 }
 
 #[get("/upload/{uuid}/events")]
-async fn upload_subscribe(conn: web::Data<SharedCtx>, path: web::Path<String>) -> impl Responder {
+async fn upload_subscribe(conn: web::Data<SharedCtx>, req: HttpRequest, path: web::Path<String>, query: web::Query<EventsQuery>) -> ApiResult {
     let uuid = path.into_inner();
+    let kinds = query.events.as_deref().map(EventKind::parse_list).transpose().map_err(ApiError::Invalid)?;
+    // Asking for row events on their own is enough to get them.
+    let include_row = query.include.split(',').any(|i| i == "row")
+        || kinds.as_ref().is_some_and(|kinds| kinds.contains(&EventKind::Row));
+    let wanted = move |event: &UploadEvent| match (&kinds, event.kind()) {
+        (Some(kinds), Some(kind)) => kinds.contains(&kind),
+        _ => true,
+    };
     let conn = conn.into_inner();
-    let row = UploadRow::from_database(&conn.pool, uuid).await;
-    match row {
-        Ok(mut row) => {
-            HttpResponse::Ok()
-                .streaming(stream! {
-                    let iter = row.stream_status_changes(&conn.pool);
-                    pin_mut!(iter);
-                    while let Some(change) = iter.next().await {
-                        let event = UploadEvent::StatusChange(change);
-                        if let Ok(mut serialized) = serde_json::to_vec(&event) {
-                            serialized.push(0xA); // add newline to make this JSONL
-                            yield Ok(Bytes::from(serialized));
-                        } else {
-                            yield Err("JSON serialize error\n");
-                        }
-                    }
-                })
-        },
-        Err(e) => {
-            let e: ErrorablePayload<()> = e.into();
-            e.to_response(HttpResponse::InternalServerError())
+    let mut row = UploadRow::from_database(&conn.pool, uuid).await?;
+    Ok(compress::streaming(&req, stream! {
+        let iter = row.stream_events(&conn.pool, include_row);
+        pin_mut!(iter);
+        while let Some(event) = iter.next().await {
+            if !wanted(&event) {
+                continue;
+            }
+            if let Ok(mut serialized) = serde_json::to_vec(&event) {
+                serialized.push(0xA); // add newline to make this JSONL
+                yield Ok(Bytes::from(serialized));
+            } else {
+                yield Err("JSON serialize error\n");
+            }
         }
-    }
+    }))
 }
 
 #[post("/upload/{uuid}/finish")]
-async fn upload_finish(conn: web::Data<SharedCtx>, path: web::Path<String>) -> impl Responder {
+async fn upload_finish(conn: web::Data<SharedCtx>, req: HttpRequest, path: web::Path<String>) -> ApiResult {
     let uuid = path.into_inner();
     let conn = conn.into_inner();
-    let resp: ErrorablePayload<()> = match UploadRow::from_database(&conn.pool, uuid).await {
-        Ok(mut row) => {
-            let lock = files::exclusive_lock(conn.cwd.clone(), row.id()).await;
-            if lock.is_err() {
-                ErrorablePayload::Err("Failed to lock file".to_string())
-            } else {
-                match row.finish(&conn.pool).await {
-                    Ok(()) => ErrorablePayload::Ok(()),
-                    Err(e) => e.into(),
-                }
-            }
-        },
-        Err(e) => e.into(),
-    };
-    resp.to_response(HttpResponse::Accepted())
+    let mut row = UploadRow::from_database(&conn.pool, uuid).await?;
+    if let Some(redirect) = redirect_to_owner(&conn, &req, &row) {
+        return Ok(redirect);
+    }
+    check_signature(&conn, &req, "finish", &row, 0, Some(0))?;
+    let pipeline = conn
+        .registry
+        .get()
+        .pipeline(row.pipeline())
+        .ok_or_else(|| ApiError::Internal(format!("Unknown pipeline {}", row.pipeline())))?;
+    match row.status() {
+        // It's already finished; this is probably a retry.
+        status if pipeline.stages.contains(status) => {}
+        Status::Uploading => finish_uploading(&conn, &pipeline, &mut row).await?,
+        _ => return Err(DbError::WrongStatus.into()),
+    }
+    Ok(ErrorablePayload::<FinishResponse>::Ok(row.status().clone()).to_response(HttpResponse::Accepted()))
+}
+
+/// Moves a fully received upload's file into place and hands it to the pipeline. Nothing is
+/// renamed until every part of the file has come in, since a preallocated file is always the
+/// right length.
+async fn finish_uploading(conn: &SharedCtx, pipeline: &Pipeline, row: &mut UploadRow) -> Result<(), ApiError> {
+    let missing = row.missing_ranges();
+    if !missing.is_empty() {
+        return Err(ApiError::Conflict(format!("Parts of the file haven't been received yet: {missing:?}")));
+    }
+    let _lock = files::promote(row.dir().into(), row.id(), row.size())
+        .await
+        .map_err(|e| ApiError::Io("finalizing the file", e))?;
+    // The verifier can use this instead of reading the file again.
+    if let Some((hash, tree)) = conn.ingest.finish(row.id(), row.size()) {
+        if let Err(e) = row.set_server_hash(&conn.pool, hash).await {
+            log::warn!("failed to record the hash of {}: {e}", row.id());
+        }
+        if let Err(e) = files::store_merkle_tree(row.dir().into(), row.id(), tree).await {
+            log::warn!("failed to store the Merkle tree of {}: {e}", row.id());
+        }
+    }
+    if row.finish(&conn.pool, pipeline).await? {
+        row.record_manifest().await;
+    }
+    Ok(())
+}
+
+#[post("/upload/{uuid}/abandon")]
+async fn upload_abandon(conn: web::Data<SharedCtx>, req: HttpRequest, path: web::Path<String>) -> ApiResult {
+    let uuid = path.into_inner();
+    let conn = conn.into_inner();
+    let mut row = UploadRow::from_database(&conn.pool, uuid).await?;
+    if let Some(redirect) = redirect_to_owner(&conn, &req, &row) {
+        return Ok(redirect);
+    }
+    check_signature(&conn, &req, "abandon", &row, 0, Some(0))?;
+    let grace = conn.registry.get().delete_grace(row.project());
+    abandon_upload(&conn.pool, &conn.ingest, grace, &mut row).await?;
+    Ok(ErrorablePayload::Ok(()).to_response(HttpResponse::Accepted()))
+}
+
+/// Soft-deletes an upload that is still in progress. Its file is kept until the project's delete
+/// grace period is over, then removed by the purge task, but the space reserved for the parts that
+/// never came in is freed straight away.
+async fn abandon_upload(
+    pool: &DatabaseHandle,
+    ingest: &ingest::IngestHashes,
+    grace: u64,
+    row: &mut UploadRow,
+) -> Result<(), ApiError> {
+    if row.status() != &Status::Uploading {
+        return Err(DbError::WrongStatus.into());
+    }
+    let lock = files::exclusive_lock(row.dir().into(), row.id()).await.map_err(|e| match e.kind() {
+        // It was finished since the row was read, so the part file has been renamed.
+        io::ErrorKind::NotFound => DbError::WrongStatus.into(),
+        // A chunk is being written to it. That won't take long.
+        io::ErrorKind::WouldBlock => ApiError::Unavailable { retry_after_secs: 1, reason: e.to_string() },
+        _ => ApiError::Io("locking the file", e),
+    })?;
+    row.abandon(pool, grace).await?;
+    ingest.forget(row.id());
+    row.record_manifest().await;
+    // The rest of the file isn't coming, so the space kept for it can go now, not when it's purged.
+    match files::release_unwritten(&lock, row.size(), row.missing_ranges()).await {
+        Ok(freed) => log::info!("released {freed} unwritten bytes of {}", row.id()),
+        Err(e) => log::warn!("failed to release the unwritten space of {}: {e}", row.id()),
+    }
+    Ok(())
+}
+
+/// Registers every endpoint except the benchmark sink.
+fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(slash)
+        .service(get_upload)
+        .service(get_received_ranges)
+        .service(get_merkle_tree)
+        .service(get_data)
+        .service(search_item)
+        .service(lookup_hash)
+        .service(new_upload)
+        .service(put_upload_chunk)
+        .service(upload_subscribe)
+        .service(upload_finish)
+        .service(upload_abandon)
+        .service(metrics::metrics)
+        .configure(admin::configure)
+        .configure(stats::configure)
+        .configure(projects::configure)
+        .configure(maintenance::configure);
 }
 
 async fn route_not_found(req: HttpRequest) -> HttpResponse {
@@ -166,37 +657,270 @@ async fn route_not_found(req: HttpRequest) -> HttpResponse {
 }
 
 struct SharedCtx {
-    pool: DatabaseHandle,
+    /// Shared between all workers.
+    pool: Arc<DatabaseHandle>,
     cwd: PathBuf,
+    /// Shared between all workers. See `reload`.
+    registry: Arc<reload::LiveRegistry>,
+    /// Shared between all workers.
+    ranges: Arc<RangeLocks>,
+    /// Shared between all workers.
+    writers: Arc<WriterLimit>,
+    /// Required by the admin endpoints. They're disabled if this isn't set.
+    admin_token: Option<String>,
+    /// From BULLSEYE_SIGNING_KEY. If it's set, chunk, finish and abandon requests have to be
+    /// signed with the upload's secret, which is derived from it.
+    signing_key: Option<Vec<u8>>,
+    /// This instance's public URL, from BULLSEYE_NODE_URL. When several instances share the
+    /// database and the data directory, each owns the uploads created through it.
+    node: Option<String>,
+    /// Shared between all workers.
+    leaderboard: Arc<stats::Leaderboard>,
+    /// Shared between all workers.
+    bans: Arc<bans::BanCache>,
+    /// Where new uploads go inside cwd.
+    layout: Arc<files::Layout>,
+    /// How new uploads are allocated if the filesystem doesn't support posix_fallocate.
+    allocation: files::Fallback,
+    /// Chunks that come in slower than this are given up on.
+    min_rate: Option<files::MinRate>,
+    /// Shared between all workers.
+    maintenance: Arc<maintenance::Maintenance>,
+    /// Shared between all workers.
+    ingest: Arc<ingest::IngestHashes>,
+    /// From BULLSEYE_MASTER_KEY_FILE. If it's set, new uploads are encrypted on disk. See
+    /// `common::crypt`.
+    master_key: Option<Arc<MasterKey>>,
 }
 
 use files::DATA_DIR;
 
+const USAGE: &str = "usage: bullseye-server [migrate]";
+
+/// Whether `bullseye-server migrate` was run, which only brings the database up to date and
+/// exits. Anything else on the command line is a mistake, so it isn't ignored.
+fn migrate_only() -> io::Result<bool> {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.iter().map(String::as_str).collect::<Vec<_>>()[..] {
+        [] => Ok(false),
+        ["migrate"] => Ok(true),
+        _ => Err(io::Error::new(io::ErrorKind::InvalidInput, USAGE)),
+    }
+}
+
 #[actix_web::main]
 async fn main() -> std::io::Result<()> {
     std::env::set_var("RUST_LOG", "debug");
-    let host = std::env::var("HOST").unwrap_or("127.0.0.1".to_string());
-    let host = host.as_str();
     let mut cwd = std::env::current_dir()?;
     cwd.push(DATA_DIR);
     env_logger::init();
-    HttpServer::new(move || {
+    let migrate_only = migrate_only()?;
+    // Bring the database up to date before anything uses it. Only one process migrates at a
+    // time; the others wait for it.
+    let db = DatabaseHandle::new().map_err(io::Error::other)?;
+    let applied = migrations::migrate(&db)
+        .await
+        .map_err(|e| io::Error::other(format!("failed to migrate the database: {e}")))?;
+    for version in applied {
+        log::info!("applied migration {version}: {}", migrations::MIGRATIONS[version as usize - 1]);
+    }
+    if migrate_only {
+        return Ok(());
+    }
+    let listen = listen::Listen::from_env()?;
+    let connections = listen::Connections::from_env()?;
+    let registry = Arc::new(reload::LiveRegistry::new(Registry::from_env()?));
+    let scrub_config = scrub::ScrubConfig::from_env()?;
+    let thresholds = metrics::DiskThresholds::from_env()?;
+    let admin_token = admin::token_from_env();
+    let access = Arc::new(access::AccessConfig::from_env()?);
+    let overload = Arc::new(overload::Overload::from_env()?);
+    let breaker = Arc::new(breaker::Breaker::from_env()?);
+    let deadlines = deadline::Deadlines::from_env()?;
+    let notify_config = notify::NotifyConfig::from_env()?;
+    let master_key = MasterKey::from_env()?.map(Arc::new);
+    let signing_key = std::env::var("BULLSEYE_SIGNING_KEY")
+        .ok()
+        .filter(|key| !key.is_empty())
+        .map(String::into_bytes);
+    let node = std::env::var("BULLSEYE_NODE_URL")
+        .ok()
+        .map(|url| url.trim_end_matches('/').to_string())
+        .filter(|url| !url.is_empty());
+    let ranges = Arc::new(RangeLocks::default());
+    let writers = Arc::new(WriterLimit::from_env()?);
+    let leaderboard = Arc::new(stats::Leaderboard::from_env()?);
+    let bans = Arc::new(bans::BanCache::from_env()?);
+    let layout = Arc::new(files::Layout::from_env()?);
+    let allocation = files::Fallback::from_env()?;
+    let min_rate = files::MinRate::from_env()?;
+    // Better to find out now than when the first upload comes in.
+    if !files::supports_fallocate(&cwd)? {
+        match allocation {
+            files::Fallback::None => {
+                return Err(io::Error::other(
+                    "the data directory doesn't support posix_fallocate; set BULLSEYE_ALLOCATION_FALLBACK to truncate or zero",
+                ))
+            }
+            files::Fallback::Truncate => log::warn!("the data directory doesn't support posix_fallocate, so space for new uploads won't be reserved"),
+            files::Fallback::Zero => log::warn!("the data directory doesn't support posix_fallocate, so new uploads will be zeroed instead"),
+        }
+    }
+    let benchmark_sink = benchmark::enabled_from_env();
+    let maintenance = Arc::new(maintenance::Maintenance::default());
+    let ingest = Arc::new(ingest::IngestHashes::from_env());
+    let db = Arc::new(db);
+    actix_web::rt::spawn({
+        let db = db.clone();
+        async move { db.keep_healthy().await }
+    });
+    actix_web::rt::spawn(breaker.clone().probe(db.clone()));
+    actix_web::rt::spawn(tasks::run(
+        DatabaseHandle::new().map_err(io::Error::other)?,
+        cwd.clone(),
+        registry.clone(),
+        ingest.clone(),
+        thresholds,
+    ));
+    actix_web::rt::spawn({
+        let registry = registry.clone();
+        async move {
+            if let Err(e) = reload::on_sighup(registry).await {
+                log::warn!("can't reload the registry on SIGHUP: {e}");
+            }
+        }
+    });
+    actix_web::rt::spawn(scrub::run(
+        DatabaseHandle::new().map_err(io::Error::other)?,
+        scrub_config,
+        master_key.clone(),
+    ));
+    if let Some(config) = notify_config {
+        actix_web::rt::spawn(notify::run(
+            DatabaseHandle::new().map_err(io::Error::other)?,
+            cwd.clone(),
+            config,
+        ));
+    }
+    let mut server = HttpServer::new(move || {
         let pool = SharedCtx {
-            pool: DatabaseHandle::new().unwrap(),
+            pool: db.clone(),
             cwd: cwd.clone(),
+            registry: registry.clone(),
+            ranges: ranges.clone(),
+            writers: writers.clone(),
+            admin_token: admin_token.clone(),
+            signing_key: signing_key.clone(),
+            node: node.clone(),
+            leaderboard: leaderboard.clone(),
+            bans: bans.clone(),
+            layout: layout.clone(),
+            allocation,
+            min_rate,
+            maintenance: maintenance.clone(),
+            ingest: ingest.clone(),
+            master_key: master_key.clone(),
         };
+        let access = access.clone();
+        let overload = overload.clone();
+        let breaker = breaker.clone();
+        let db = db.clone();
+        // Each layer's future is boxed, or the nested service and future types get big enough to
+        // take the compiler several gigabytes.
         App::new()
             .app_data(web::Data::new(pool))
-            .service(slash)
-            .service(get_upload)
-            .service(new_upload)
-            .service(put_upload_chunk)
-            .service(upload_subscribe)
-            .service(upload_finish)
+            .wrap_fn(move |mut req, srv| {
+                let deadline = deadlines.start(&mut req);
+                deadline.enforce(srv.call(req)).boxed_local()
+            })
+            .wrap_fn(move |req, srv| {
+                if let Some(busy) = overload.check(req.path(), &db) {
+                    return Either::Left(ready(Ok(req.into_response(busy.error_response()))));
+                }
+                Either::Right(srv.call(req).boxed_local())
+            })
+            .wrap_fn(move |req, srv| {
+                if let Some(open) = breaker.check(req.path()) {
+                    return Either::Left(ready(Ok(req.into_response(open.error_response()))));
+                }
+                let res = srv.call(req);
+                let breaker = breaker.clone();
+                Either::Right(async move {
+                    let res = res.await;
+                    match &res {
+                        Ok(res) => breaker.record(res.response().error()),
+                        Err(e) => breaker.record(Some(e)),
+                    }
+                    res
+                }.boxed_local())
+            })
+            .wrap_fn(move |req, srv| {
+                let permit = match connections.admit(&req) {
+                    Ok(permit) => permit,
+                    Err(busy) => return Either::Left(ready(Ok(req.into_response(busy.error_response())))),
+                };
+                let res = srv.call(req);
+                Either::Right(async move {
+                    let res = res.await;
+                    drop(permit);
+                    res
+                }.boxed_local())
+            })
+            .wrap_fn(move |req, srv| {
+                let (permitted, ip) = access.check(&req);
+                if let Some(ip) = ip {
+                    req.extensions_mut().insert(access::ClientIp(ip));
+                }
+                if !permitted {
+                    let res = HttpResponse::Forbidden().body("your address isn't allowed here");
+                    return Either::Left(ready(Ok(req.into_response(res))));
+                }
+                Either::Right(srv.call(req).boxed_local())
+            })
+            .wrap_fn(|req, srv| {
+                let res = srv.call(req);
+                async move {
+                    let res = res.await?;
+                    audit::record(&res).await;
+                    Ok(res)
+                }.boxed_local()
+            })
+            .configure(configure)
+            .configure(|cfg| {
+                if benchmark_sink {
+                    benchmark::configure(cfg);
+                }
+            })
             .default_service(web::to(route_not_found))
     })
-    .bind((host, 7000))?
-    .run()
-    .await
+    .on_connect(listen::on_connect)
+    .keep_alive(connections.keep_alive());
+    if let Some(size) = connections.h2_window {
+        server = server.h2_initial_window_size(size);
+    }
+    if let Some(size) = connections.h2_connection_window {
+        server = server.h2_initial_connection_window_size(size);
+    }
+    if let Some(host) = &listen.tcp {
+        let addr = (host.as_str(), listen::PORT);
+        server = match connections.h2c {
+            true => server.bind_auto_h2c(addr)?,
+            false => server.bind(addr)?,
+        };
+    }
+    if let Some(socket) = &listen.unix {
+        #[cfg(unix)]
+        {
+            server = server.bind_uds(&socket.path)?;
+            socket.set_permissions()?;
+            log::info!("listening on {}", socket.path.display());
+        }
+        #[cfg(not(unix))]
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            format!("can't listen on {}: there are no Unix sockets here", socket.path.display()),
+        ));
+    }
+    server.run().await
 }
 
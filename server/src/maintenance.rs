@@ -0,0 +1,58 @@
+//! Maintenance mode, for deploys and storage migrations: while the server is draining, new
+//! uploads are turned away with a retryable error, but existing ones can still be finished. It's
+//! turned on and off with `POST /admin/maintenance`, and `GET /health` says whether it's on.
+
+use std::sync::Mutex;
+
+use actix_web::{get, web, HttpResponse, Responder};
+
+use crate::{error::ApiError, payloads::*, SharedCtx};
+
+/// How long clients are told to wait if the operator doesn't say.
+const DEFAULT_RETRY_AFTER: u64 = 60;
+
+/// Whether this instance is draining. Shared between all workers.
+#[derive(Default)]
+pub struct Maintenance {
+    status: Mutex<MaintenanceStatus>,
+}
+
+impl Maintenance {
+    pub fn status(&self) -> MaintenanceStatus {
+        *self.status.lock().unwrap()
+    }
+
+    /// Turns maintenance mode on or off, and returns the new status.
+    pub fn set(&self, payload: &MaintenancePayload) -> MaintenanceStatus {
+        let status = MaintenanceStatus {
+            draining: payload.draining,
+            retry_after_secs: payload.retry_after_secs.unwrap_or(DEFAULT_RETRY_AFTER),
+        };
+        *self.status.lock().unwrap() = status;
+        status
+    }
+
+    /// Turns away new uploads if the server is draining.
+    pub fn check(&self) -> Result<(), ApiError> {
+        let status = self.status();
+        match status.draining {
+            true => Err(ApiError::Unavailable {
+                retry_after_secs: status.retry_after_secs,
+                reason: "the server is in maintenance".to_string(),
+            }),
+            false => Ok(()),
+        }
+    }
+}
+
+/// Says whether the server is draining, for load balancers and deploy scripts.
+#[get("/health")]
+async fn health(conn: web::Data<SharedCtx>) -> impl Responder {
+    let resp: ErrorablePayload<HealthResponse> = ErrorablePayload::Ok(conn.maintenance.status());
+    resp.to_response(HttpResponse::Ok())
+}
+
+/// Registers the health endpoint.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(health);
+}
@@ -0,0 +1,293 @@
+//! Prometheus-style metrics about the data directories, the database connection pool and the
+//! queues of items waiting in each stage.
+
+use std::{
+    fmt::Write as _,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use actix_web::{get, web, HttpResponse, Responder};
+use common::{
+    db::{PoolStats, QueueDepth, UploadRow},
+    platform,
+};
+use log::warn;
+use tokio::task::spawn_blocking;
+
+use crate::SharedCtx;
+
+/// Thresholds below which the maintenance task logs a warning, read from the environment.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DiskThresholds {
+    /// BULLSEYE_DISK_WARN_BYTES.
+    pub free_bytes: Option<u64>,
+    /// BULLSEYE_DISK_WARN_INODES.
+    pub free_inodes: Option<u64>,
+}
+
+impl DiskThresholds {
+    pub fn from_env() -> io::Result<Self> {
+        fn var(name: &str) -> io::Result<Option<u64>> {
+            match std::env::var(name) {
+                Ok(v) => v
+                    .parse()
+                    .map(Some)
+                    .map_err(|e| io::Error::other(format!("{name}: {e}"))),
+                Err(_) => Ok(None),
+            }
+        }
+        Ok(Self {
+            free_bytes: var("BULLSEYE_DISK_WARN_BYTES")?,
+            free_inodes: var("BULLSEYE_DISK_WARN_INODES")?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct DiskStats {
+    /// Bytes available to unprivileged users.
+    pub free_bytes: u64,
+    /// Inodes available to unprivileged users.
+    pub free_inodes: u64,
+    /// Bytes allocated to in-progress uploads. These are already taken out of free_bytes, since
+    /// new uploads are preallocated in full. Abandoned uploads only count what was sent before
+    /// they were abandoned.
+    pub preallocated_bytes: u64,
+}
+
+impl DiskStats {
+    /// Reads the stats for a data directory. This does blocking I/O.
+    pub fn read(dir: &Path) -> io::Result<Self> {
+        let space = platform::disk_space(dir)?;
+        let mut preallocated_bytes = 0;
+        for entry in fs::read_dir(dir)? {
+            let entry = entry?;
+            let name = entry.file_name();
+            let Some(name) = name.to_str() else {
+                continue;
+            };
+            if name.ends_with(".part") {
+                preallocated_bytes += platform::allocated_bytes(&entry.metadata()?);
+            }
+        }
+        Ok(Self {
+            free_bytes: space.free_bytes,
+            free_inodes: space.free_inodes,
+            preallocated_bytes,
+        })
+    }
+
+    /// Logs a warning if the stats are below the thresholds.
+    pub fn check(&self, dir: &Path, thresholds: &DiskThresholds) {
+        if thresholds.free_bytes.is_some_and(|t| self.free_bytes < t) {
+            warn!("{} is running out of space: {} bytes free", dir.display(), self.free_bytes);
+        }
+        if thresholds.free_inodes.is_some_and(|t| self.free_inodes < t) {
+            warn!("{} is running out of inodes: {} free", dir.display(), self.free_inodes);
+        }
+    }
+}
+
+/// Reads the stats for each data directory without blocking the runtime. There aren't any on
+/// platforms where the free space can't be found out.
+pub async fn read_all(dirs: Vec<PathBuf>) -> Vec<(PathBuf, io::Result<DiskStats>)> {
+    let mut rv = Vec::with_capacity(dirs.len());
+    for dir in dirs {
+        let d = dir.clone();
+        let stats = spawn_blocking(move || DiskStats::read(&d))
+            .await
+            .unwrap_or_else(|e| Err(io::Error::other(e)));
+        if matches!(&stats, Err(e) if e.kind() == io::ErrorKind::Unsupported) {
+            continue;
+        }
+        rv.push((dir, stats));
+    }
+    rv
+}
+
+/// A gauge's name and help text, and how to get its value from what's being rendered.
+type Gauge<T> = (&'static str, &'static str, fn(&T) -> u64);
+
+/// Renders the stats in the Prometheus text format.
+fn render(stats: &[(PathBuf, DiskStats)]) -> String {
+    let gauges: [Gauge<DiskStats>; 3] = [
+        ("bullseye_disk_free_bytes", "Bytes available in the data directory.", |s| s.free_bytes),
+        ("bullseye_disk_free_inodes", "Inodes available in the data directory.", |s| s.free_inodes),
+        (
+            "bullseye_disk_preallocated_bytes",
+            "Bytes reserved by in-progress uploads.",
+            |s| s.preallocated_bytes,
+        ),
+    ];
+    let mut out = String::new();
+    for (name, help, value) in gauges {
+        writeln!(out, "# HELP {name} {help}").unwrap();
+        writeln!(out, "# TYPE {name} gauge").unwrap();
+        for (dir, s) in stats {
+            let dir = dir.display().to_string().replace('\\', "\\\\").replace('"', "\\\"");
+            writeln!(out, "{name}{{dir=\"{dir}\"}} {}", value(s)).unwrap();
+        }
+    }
+    out
+}
+
+/// Like Gauge, with the metric's type (`gauge` or `counter`) after its name.
+type Series<T> = (&'static str, &'static str, &'static str, fn(&T) -> u64);
+
+/// Renders the stats of the connection pools in the Prometheus text format. Each pool is given
+/// with its name, `primary` or `reads`.
+fn render_pool(pools: &[(&str, PoolStats)]) -> String {
+    let series: [Series<PoolStats>; 6] = [
+        ("bullseye_db_pool_max_size", "gauge", "Sessions the pool can hold.", |s| s.max_size as u64),
+        ("bullseye_db_pool_size", "gauge", "Open database sessions.", |s| s.size as u64),
+        (
+            "bullseye_db_pool_available",
+            "gauge",
+            "Open database sessions not in use.",
+            |s| s.available as u64,
+        ),
+        (
+            "bullseye_db_pool_waiting",
+            "gauge",
+            "Requests waiting for a database session.",
+            |s| s.waiting as u64,
+        ),
+        (
+            "bullseye_db_health_checks_total",
+            "counter",
+            "Database session health checks run.",
+            |s| s.health_checks,
+        ),
+        (
+            "bullseye_db_sessions_discarded_total",
+            "counter",
+            "Database sessions thrown away after failing a health check.",
+            |s| s.discarded,
+        ),
+    ];
+    let mut out = String::new();
+    for (name, kind, help, value) in series {
+        writeln!(out, "# HELP {name} {help}").unwrap();
+        writeln!(out, "# TYPE {name} {kind}").unwrap();
+        for (pool, s) in pools {
+            writeln!(out, "{name}{{pool=\"{pool}\"}} {}", value(s)).unwrap();
+        }
+    }
+    out
+}
+
+/// Renders the queue depths in the Prometheus text format.
+fn render_queues(depths: &[QueueDepth]) -> String {
+    let gauges: [Gauge<QueueDepth>; 2] = [
+        ("bullseye_queue_items", "Items in the status.", |d| d.items),
+        (
+            "bullseye_queue_oldest_age_seconds",
+            "Seconds since anything happened to the item that has waited longest in the status.",
+            |d| d.oldest_age,
+        ),
+    ];
+    let escape = |s: &str| s.replace('\\', "\\\\").replace('"', "\\\"");
+    let mut out = String::new();
+    for (name, help, value) in gauges {
+        writeln!(out, "# HELP {name} {help}").unwrap();
+        writeln!(out, "# TYPE {name} gauge").unwrap();
+        for d in depths {
+            writeln!(
+                out,
+                "{name}{{project=\"{}\",pipeline=\"{}\",status=\"{}\"}} {}",
+                escape(&d.project),
+                escape(&d.pipeline),
+                d.status,
+                value(d)
+            )
+            .unwrap();
+        }
+    }
+    out
+}
+
+#[get("/metrics")]
+async fn metrics(conn: web::Data<SharedCtx>) -> impl Responder {
+    let mut stats = Vec::new();
+    for (dir, s) in read_all(vec![conn.cwd.clone()]).await {
+        match s {
+            Ok(s) => stats.push((dir, s)),
+            Err(e) => warn!("failed to read disk stats for {}: {e}", dir.display()),
+        }
+    }
+    let mut pools = vec![("primary", conn.pool.stats())];
+    if let Some(reads) = conn.pool.read_stats() {
+        pools.push(("reads", reads));
+    }
+    let depths = match UploadRow::queue_depths(&conn.pool).await {
+        Ok(depths) => depths,
+        Err(e) => {
+            warn!("failed to count the queues: {e}");
+            Vec::new()
+        }
+    };
+    HttpResponse::Ok()
+        .content_type("text/plain; version=0.0.4")
+        .body(render(&stats) + &render_pool(&pools) + &render_queues(&depths))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use common::db::{PoolStats, QueueDepth, Status};
+
+    use super::{render, render_pool, render_queues, DiskStats};
+
+    #[test]
+    fn test_render() {
+        let stats = DiskStats {
+            free_bytes: 100,
+            free_inodes: 5,
+            preallocated_bytes: 20,
+        };
+        let out = render(&[(PathBuf::from("/data/a\"b"), stats)]);
+        assert!(out.contains("# TYPE bullseye_disk_free_bytes gauge\n"));
+        assert!(out.contains("bullseye_disk_free_bytes{dir=\"/data/a\\\"b\"} 100\n"));
+        assert!(out.contains("bullseye_disk_free_inodes{dir=\"/data/a\\\"b\"} 5\n"));
+        assert!(out.contains("bullseye_disk_preallocated_bytes{dir=\"/data/a\\\"b\"} 20\n"));
+    }
+
+    #[test]
+    fn test_render_pool() {
+        let stats = PoolStats {
+            max_size: 4,
+            size: 3,
+            available: 1,
+            waiting: 0,
+            health_checks: 10,
+            discarded: 2,
+        };
+        let out = render_pool(&[("primary", stats), ("reads", PoolStats::default())]);
+        assert!(out.contains("# TYPE bullseye_db_pool_size gauge\n"));
+        assert!(out.contains("bullseye_db_pool_size{pool=\"primary\"} 3\n"));
+        assert!(out.contains("bullseye_db_pool_size{pool=\"reads\"} 0\n"));
+        assert!(out.contains("# TYPE bullseye_db_sessions_discarded_total counter\n"));
+        assert!(out.contains("bullseye_db_sessions_discarded_total{pool=\"primary\"} 2\n"));
+    }
+
+    #[test]
+    fn test_render_queues() {
+        let depth = QueueDepth {
+            project: "p".to_string(),
+            pipeline: "default".to_string(),
+            status: Status::Verifying,
+            items: 3,
+            oldest_age: 120,
+        };
+        let out = render_queues(&[depth]);
+        assert!(out.contains("# TYPE bullseye_queue_items gauge\n"));
+        assert!(out.contains(
+            "bullseye_queue_items{project=\"p\",pipeline=\"default\",status=\"VERIFYING\"} 3\n"
+        ));
+        assert!(out.contains(
+            "bullseye_queue_oldest_age_seconds{project=\"p\",pipeline=\"default\",status=\"VERIFYING\"} 120\n"
+        ));
+    }
+}
@@ -0,0 +1,453 @@
+//! Posts alerts to chat when something needs an operator: a pipeline's uploads failing more than
+//! usual, a queue piling up, or the data directory running out of space. Configured by the JSON
+//! file in `BULLSEYE_NOTIFY`, for example:
+//!
+//! ```json
+//! {
+//!     "targets": [
+//!         {"type": "discord", "url": "https://discord.com/api/webhooks/..."},
+//!         {"type": "slack", "url": "https://hooks.slack.com/services/..."},
+//!         {"type": "irc", "url": "http://irccat.internal:8045/"}
+//!     ],
+//!     "failures": {"rate": 0.2, "min_failures": 5, "window": 900},
+//!     "queue_depth": 1000,
+//!     "free_bytes": 50000000000
+//! }
+//! ```
+//!
+//! Alerts are sent when they start, again every `repeat_after` seconds while they last, and once
+//! more when they're over. When several servers share the database, only the one holding the lease
+//! sends the failure and queue alerts; each sends its own disk alerts.
+
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    io,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use common::{
+    db::{leases::Lease, DatabaseHandle, Status, UploadRow},
+    payloads::FirehoseEvent,
+};
+use futures::{pin_mut, StreamExt};
+use log::{info, warn};
+use serde::Deserialize;
+use serde_json::json;
+
+use crate::metrics;
+
+/// How often the alerts are checked.
+const INTERVAL: Duration = Duration::from_secs(60);
+
+fn default_repeat_after() -> u64 {
+    60 * 60
+}
+
+fn default_min_failures() -> u64 {
+    5
+}
+
+fn default_window() -> u64 {
+    15 * 60
+}
+
+#[derive(Deserialize, Clone, Debug)]
+pub struct NotifyConfig {
+    /// Where alerts go.
+    targets: Vec<Target>,
+    #[serde(default)]
+    failures: Option<FailureAlert>,
+    /// Alert when more than this many items of a pipeline are waiting in one status.
+    #[serde(default)]
+    queue_depth: Option<u64>,
+    /// Alert when the data directory has less than this many bytes free.
+    #[serde(default)]
+    free_bytes: Option<u64>,
+    /// How often to send an alert again while it lasts, in seconds. An hour by default.
+    #[serde(default = "default_repeat_after")]
+    repeat_after: u64,
+}
+
+/// When to alert about a pipeline's uploads failing.
+#[derive(Deserialize, Clone, Copy, Debug)]
+pub struct FailureAlert {
+    /// The share of the uploads that finished or failed in the window that failed, from 0 to 1.
+    rate: f64,
+    /// Fewer failures than this in the window aren't worth an alert, whatever the rate.
+    #[serde(default = "default_min_failures")]
+    min_failures: u64,
+    /// In seconds. 15 minutes by default.
+    #[serde(default = "default_window")]
+    window: u64,
+}
+
+/// A chat to post alerts to.
+#[derive(Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Target {
+    /// A Slack incoming webhook. Anything else that takes `{"text": ...}`, like Mattermost, works too.
+    Slack { url: String },
+    /// A Discord webhook.
+    Discord { url: String },
+    /// An HTTP relay to IRC, like irccat, which posts the body of the request to its channel.
+    Irc { url: String },
+}
+
+impl Target {
+    async fn send(&self, client: &reqwest::Client, message: &str) -> reqwest::Result<()> {
+        let req = match self {
+            Self::Slack { url } => client.post(url).json(&json!({ "text": message })),
+            Self::Discord { url } => client.post(url).json(&json!({ "content": message })),
+            Self::Irc { url } => client.post(url).body(message.to_string()),
+        };
+        req.send().await?.error_for_status()?;
+        Ok(())
+    }
+}
+
+impl NotifyConfig {
+    /// Reads the file in `BULLSEYE_NOTIFY`. None if it isn't set.
+    pub fn from_env() -> io::Result<Option<Self>> {
+        match std::env::var("BULLSEYE_NOTIFY") {
+            Ok(path) if !path.is_empty() => Self::load(Path::new(&path)).map(Some),
+            _ => Ok(None),
+        }
+    }
+
+    fn load(path: &Path) -> io::Result<Self> {
+        let config: Self = serde_json::from_slice(&std::fs::read(path)?)
+            .map_err(|e| io::Error::other(format!("{}: {e}", path.display())))?;
+        if config.targets.is_empty() {
+            return Err(io::Error::other(format!(
+                "{}: there are no targets",
+                path.display()
+            )));
+        }
+        if let Some(failures) = config.failures {
+            if !(0.0..=1.0).contains(&failures.rate) {
+                return Err(io::Error::other(format!(
+                    "{}: the failure rate must be between 0 and 1",
+                    path.display()
+                )));
+            }
+        }
+        Ok(config)
+    }
+}
+
+/// The uploads that finished or failed recently, from the firehose.
+#[derive(Default)]
+struct Outcomes {
+    /// When, which project and pipeline, and whether it failed, oldest first.
+    seen: VecDeque<(Instant, String, String, bool)>,
+}
+
+impl Outcomes {
+    fn record(&mut self, now: Instant, project: String, pipeline: String, status: &Status) {
+        let failed = match status {
+            Status::Finished => false,
+            status if status.is_failure() => true,
+            _ => return,
+        };
+        self.seen.push_back((now, project, pipeline, failed));
+    }
+
+    /// Forgets what happened before `window`, and gets the alerts for pipelines that failed too
+    /// much since.
+    fn alerts(&mut self, now: Instant, alert: &FailureAlert) -> BTreeMap<String, String> {
+        let window = Duration::from_secs(alert.window);
+        while self
+            .seen
+            .front()
+            .is_some_and(|(at, ..)| now.duration_since(*at) > window)
+        {
+            self.seen.pop_front();
+        }
+        let mut counts: BTreeMap<(&str, &str), (u64, u64)> = BTreeMap::new();
+        for (_, project, pipeline, failed) in &self.seen {
+            let (failures, total) = counts.entry((project, pipeline)).or_default();
+            *failures += u64::from(*failed);
+            *total += 1;
+        }
+        counts
+            .into_iter()
+            .filter(|(_, (failures, total))| {
+                *failures >= alert.min_failures && *failures as f64 >= alert.rate * *total as f64
+            })
+            .map(|((project, pipeline), (failures, total))| {
+                (
+                    format!("failures {project}/{pipeline}"),
+                    format!(
+                        "{failures} of the {total} uploads in {project}/{pipeline} that ended in the last {} minutes failed",
+                        alert.window / 60
+                    ),
+                )
+            })
+            .collect()
+    }
+}
+
+/// The alerts that are going, by what they're about, and when each was last sent.
+#[derive(Default)]
+struct Alerts {
+    active: HashMap<String, (Instant, String)>,
+}
+
+impl Alerts {
+    /// Given the alerts that are going now, gets the messages to send: the new ones, the ones that
+    /// haven't been sent for `repeat_after`, and the ones that are over.
+    fn update(
+        &mut self,
+        now: Instant,
+        current: BTreeMap<String, String>,
+        repeat_after: Duration,
+    ) -> Vec<String> {
+        let mut messages = Vec::new();
+        let mut over: Vec<_> = self
+            .active
+            .keys()
+            .filter(|key| !current.contains_key(*key))
+            .cloned()
+            .collect();
+        over.sort_unstable();
+        for key in over {
+            let (_, message) = self.active.remove(&key).unwrap();
+            messages.push(format!("resolved: {message}"));
+        }
+        for (key, message) in current {
+            match self.active.get_mut(&key) {
+                Some((sent, _)) if now.duration_since(*sent) < repeat_after => {}
+                Some((sent, last)) => {
+                    *sent = now;
+                    *last = message.clone();
+                    messages.push(format!("still going: {message}"));
+                }
+                None => {
+                    self.active.insert(key, (now, message.clone()));
+                    messages.push(message);
+                }
+            }
+        }
+        messages
+    }
+}
+
+/// Counts the uploads that finish and fail, for as long as the server runs.
+async fn watch_outcomes(pool: DatabaseHandle, outcomes: Arc<Mutex<Outcomes>>) {
+    loop {
+        let events = UploadRow::stream_all_events(&pool, None, None);
+        pin_mut!(events);
+        while let Some(event) = events.next().await {
+            match event {
+                FirehoseEvent::StatusChange {
+                    project,
+                    pipeline,
+                    status,
+                    ..
+                } => {
+                    outcomes
+                        .lock()
+                        .unwrap()
+                        .record(Instant::now(), project, pipeline, &status);
+                }
+                FirehoseEvent::Error(e) => warn!("the notifier lost track of uploads: {e}"),
+            }
+        }
+        tokio::time::sleep(INTERVAL).await;
+    }
+}
+
+/// Gets the alerts that any server would send, about the database.
+async fn shared_alerts(
+    pool: &DatabaseHandle,
+    config: &NotifyConfig,
+    outcomes: &Mutex<Outcomes>,
+) -> BTreeMap<String, String> {
+    let mut current = BTreeMap::new();
+    if let Some(failures) = &config.failures {
+        current.extend(outcomes.lock().unwrap().alerts(Instant::now(), failures));
+    }
+    if let Some(max) = config.queue_depth {
+        match UploadRow::queue_depths(pool).await {
+            Ok(depths) => current.extend(depths.into_iter().filter(|d| d.items > max).map(|d| {
+                (
+                    format!("queue {}/{} {}", d.project, d.pipeline, d.status),
+                    format!(
+                        "{} items of {}/{} are waiting in {}, the oldest for {} minutes",
+                        d.items,
+                        d.project,
+                        d.pipeline,
+                        d.status,
+                        d.oldest_age / 60
+                    ),
+                )
+            })),
+            Err(e) => warn!("the notifier couldn't get the queue depths: {e}"),
+        }
+    }
+    current
+}
+
+/// Gets the alerts about this server's data directory.
+async fn disk_alerts(cwd: &Path, config: &NotifyConfig) -> BTreeMap<String, String> {
+    let Some(min) = config.free_bytes else {
+        return BTreeMap::new();
+    };
+    let mut current = BTreeMap::new();
+    for (dir, stats) in metrics::read_all(vec![cwd.to_path_buf()]).await {
+        match stats {
+            Ok(s) if s.free_bytes < min => {
+                current.insert(
+                    format!("disk {}", dir.display()),
+                    format!("{} has only {} bytes free", dir.display(), s.free_bytes),
+                );
+            }
+            Ok(_) => {}
+            Err(e) => warn!(
+                "the notifier couldn't read disk stats for {}: {e}",
+                dir.display()
+            ),
+        }
+    }
+    current
+}
+
+/// Checks the alerts every minute and posts them, forever.
+pub async fn run(pool: DatabaseHandle, cwd: PathBuf, config: NotifyConfig) {
+    let client = match reqwest::Client::builder()
+        .timeout(Duration::from_secs(30))
+        .build()
+    {
+        Ok(client) => client,
+        Err(e) => {
+            warn!("can't send notifications: {e}");
+            return;
+        }
+    };
+    let outcomes = Arc::new(Mutex::new(Outcomes::default()));
+    match DatabaseHandle::new() {
+        Ok(pool) => {
+            actix_web::rt::spawn(watch_outcomes(pool, outcomes.clone()));
+        }
+        Err(e) => warn!("the notifier can't watch for failures: {e}"),
+    }
+    let repeat_after = Duration::from_secs(config.repeat_after);
+    let lease = Lease::new("notifier", INTERVAL * 3);
+    let (mut shared, mut local) = (Alerts::default(), Alerts::default());
+    let mut timer = tokio::time::interval(INTERVAL);
+    timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        timer.tick().await;
+        let mut messages = Vec::new();
+        match lease.acquire(&pool).await {
+            Ok(true) => {
+                let current = shared_alerts(&pool, &config, &outcomes).await;
+                messages.extend(shared.update(Instant::now(), current, repeat_after));
+            }
+            // Whoever has the lease now sends these.
+            Ok(false) => shared = Alerts::default(),
+            Err(e) => warn!("failed to renew the notifier lease: {e}"),
+        }
+        let current = disk_alerts(&cwd, &config).await;
+        messages.extend(local.update(Instant::now(), current, repeat_after));
+        for message in messages {
+            info!("notifying: {message}");
+            let message = format!("bullseye: {message}");
+            for target in &config.targets {
+                if let Err(e) = target.send(&client, &message).await {
+                    warn!("failed to send a notification: {e}");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        time::{Duration, Instant},
+    };
+
+    use common::{data::UploadError, db::Status};
+
+    use super::{Alerts, FailureAlert, NotifyConfig, Outcomes, Target};
+
+    #[test]
+    fn test_config() {
+        let config: NotifyConfig = serde_json::from_str(
+            r#"{"targets": [{"type": "irc", "url": "http://irccat/"}], "failures": {"rate": 0.5}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.targets,
+            [Target::Irc {
+                url: "http://irccat/".to_string()
+            }]
+        );
+        assert_eq!(config.failures.unwrap().min_failures, 5);
+        assert_eq!(config.queue_depth, None);
+        assert_eq!(config.repeat_after, 3600);
+    }
+
+    /// Ensures that alerts are sent when they start, repeated, and resolved.
+    #[test]
+    fn test_alerts() {
+        let start = Instant::now();
+        let repeat = Duration::from_secs(60);
+        let mut alerts = Alerts::default();
+        let current = |keys: &[&str]| -> BTreeMap<String, String> {
+            keys.iter()
+                .map(|k| (k.to_string(), format!("{k} is on fire")))
+                .collect()
+        };
+        assert_eq!(
+            alerts.update(start, current(&["a"]), repeat),
+            ["a is on fire"]
+        );
+        assert!(alerts
+            .update(start + repeat / 2, current(&["a"]), repeat)
+            .is_empty());
+        assert_eq!(
+            alerts.update(start + repeat, current(&["a", "b"]), repeat),
+            ["still going: a is on fire", "b is on fire"]
+        );
+        assert_eq!(
+            alerts.update(start + repeat, current(&["b"]), repeat),
+            ["resolved: a is on fire"]
+        );
+    }
+
+    /// Ensures that failure alerts need enough failures, at a high enough rate, recently enough.
+    #[test]
+    fn test_failure_rate() {
+        let start = Instant::now();
+        let alert = FailureAlert {
+            rate: 0.5,
+            min_failures: 2,
+            window: 120,
+        };
+        let failed = Status::Error(UploadError::Verify);
+        let mut outcomes = Outcomes::default();
+        let record = |outcomes: &mut Outcomes, status: &Status| {
+            outcomes.record(start, "urls".to_string(), "warc".to_string(), status)
+        };
+        record(&mut outcomes, &failed);
+        record(&mut outcomes, &Status::Finished);
+        // Not terminal, so it doesn't count.
+        record(&mut outcomes, &Status::Verifying);
+        assert!(outcomes.alerts(start, &alert).is_empty());
+        record(&mut outcomes, &Status::DeadLetter);
+        let alerts = outcomes.alerts(start, &alert);
+        assert_eq!(
+            alerts.values().collect::<Vec<_>>(),
+            ["2 of the 3 uploads in urls/warc that ended in the last 2 minutes failed"]
+        );
+        assert!(outcomes
+            .alerts(start + Duration::from_secs(121), &alert)
+            .is_empty());
+        assert!(outcomes.seen.is_empty());
+    }
+}
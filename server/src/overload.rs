@@ -0,0 +1,55 @@
+//! Turns requests away with a 503 when the database can't keep up, instead of letting them queue
+//! for a session until the client gives up.
+
+use std::io;
+
+use common::db::DatabaseHandle;
+
+use crate::error::ApiError;
+
+/// When to shed requests, and how long to tell clients to wait.
+pub struct Overload {
+    /// Requests are turned away once this many tasks are waiting for a database session. 0 never
+    /// turns them away.
+    max_waiting: usize,
+    retry_after_secs: u64,
+}
+
+fn from_env_or<T: std::str::FromStr>(name: &str, default: T) -> io::Result<T>
+where
+    T::Err: std::fmt::Display,
+{
+    match std::env::var(name) {
+        Ok(v) => v
+            .parse()
+            .map_err(|e| io::Error::other(format!("{name}: {e}"))),
+        Err(_) => Ok(default),
+    }
+}
+
+impl Overload {
+    /// Reads `BULLSEYE_MAX_DB_WAITING` (32 by default) and `BULLSEYE_BUSY_RETRY_AFTER` (5 seconds
+    /// by default).
+    pub fn from_env() -> io::Result<Self> {
+        Ok(Self {
+            max_waiting: from_env_or("BULLSEYE_MAX_DB_WAITING", 32)?,
+            retry_after_secs: from_env_or("BULLSEYE_BUSY_RETRY_AFTER", 5)?,
+        })
+    }
+
+    /// Gets the error to send instead of handling a request, if the pool is exhausted.
+    /// `/metrics` and `/health` are always let through, so there's a way to see what's going on.
+    pub fn check(&self, path: &str, db: &DatabaseHandle) -> Option<ApiError> {
+        if self.max_waiting == 0 || path == "/metrics" || path == "/health" {
+            return None;
+        }
+        let stats = db.stats();
+        if stats.available > 0 || stats.waiting < self.max_waiting {
+            return None;
+        }
+        Some(ApiError::Unavailable {
+            retry_after_secs: self.retry_after_secs,
+            reason: "the database is overloaded".to_string(),
+        })
+    }
+}
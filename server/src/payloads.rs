@@ -1,11 +1,14 @@
-use actix_web::{HttpResponse, HttpResponseBuilder};
+use actix_web::{HttpResponse, HttpResponseBuilder, ResponseError};
 pub use common::payloads::*;
 use serde::Serialize;
 
+use crate::error::ApiError;
+
 pub trait ToHttpResponse {
     /// Wraps an ErrorablePayload in an HttpResponse.
     /// on_successful is the builder used to construct an ErrorablePayload::Ok response.
     /// For example, you can set it to HttpResponse::Created() for 201 Created.
+    /// Anything else is sent like the matching ApiError.
     fn to_response(self, on_successful: HttpResponseBuilder) -> HttpResponse;
 }
 
@@ -13,8 +16,17 @@ impl<T: Serialize> ToHttpResponse for ErrorablePayload<T> {
     fn to_response(self, mut on_successful: HttpResponseBuilder) -> HttpResponse {
         match self {
             ErrorablePayload::Ok(_) => on_successful.json(self),
-            ErrorablePayload::NotFound => HttpResponse::NotFound().json(self),
-            ErrorablePayload::Err(_) => HttpResponse::InternalServerError().json(self),
+            ErrorablePayload::NotFound => ApiError::NotFound.error_response(),
+            ErrorablePayload::Err(message) => ApiError::Internal(message).error_response(),
+            ErrorablePayload::Rejected(rejection) => ApiError::Rejected(rejection).error_response(),
+            ErrorablePayload::Unavailable {
+                retry_after_secs,
+                reason,
+            } => ApiError::Unavailable {
+                retry_after_secs,
+                reason,
+            }
+            .error_response(),
         }
     }
 }
@@ -0,0 +1,42 @@
+//! Lets clients and tooling find out which projects and pipelines the server accepts, instead of
+//! guessing.
+
+use actix_web::{get, web, HttpResponse, Responder};
+
+use crate::{
+    error::{ApiError, ApiResult},
+    payloads::*,
+    SharedCtx,
+};
+
+#[get("/projects")]
+async fn projects(conn: web::Data<SharedCtx>) -> impl Responder {
+    let registry = conn.registry.get();
+    ErrorablePayload::Ok(ProjectsResponse {
+        any_project: registry.projects.is_empty(),
+        projects: registry
+            .projects
+            .iter()
+            .map(|(name, project)| (name.clone(), project.clone()))
+            .collect(),
+    })
+    .to_response(HttpResponse::Ok())
+}
+
+#[get("/projects/{name}/pipelines")]
+async fn project_pipelines(conn: web::Data<SharedCtx>, path: web::Path<String>) -> ApiResult {
+    let registry = conn.registry.get();
+    let pipelines = registry
+        .project_pipelines(&path.into_inner())
+        .ok_or(ApiError::NotFound)?;
+    Ok(ErrorablePayload::Ok(PipelinesResponse {
+        any_pipeline: registry.pipelines.is_empty(),
+        pipelines,
+    })
+    .to_response(HttpResponse::Ok()))
+}
+
+/// Registers the discovery endpoints.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(projects).service(project_pipelines);
+}
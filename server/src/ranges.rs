@@ -0,0 +1,208 @@
+use std::{
+    collections::HashMap,
+    io,
+    ops::Range,
+    sync::{Arc, Mutex},
+};
+
+use tokio::sync::Notify;
+
+use crate::error::ApiError;
+
+/// In-memory byte-range locks, keyed by upload ID.
+///
+/// Chunk PUTs to disjoint parts of the same upload can run at the same time, but overlapping
+/// ones are serialized so their writes can't interleave.
+#[derive(Debug, Default)]
+pub struct RangeLocks {
+    held: Mutex<HashMap<String, Vec<Range<u64>>>>,
+    released: Notify,
+}
+
+/// Releases the range when dropped.
+#[derive(Debug)]
+pub struct RangeGuard {
+    locks: Arc<RangeLocks>,
+    id: String,
+    range: Range<u64>,
+}
+
+fn overlaps(a: &Range<u64>, b: &Range<u64>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+impl RangeLocks {
+    /// Waits until nobody else holds an overlapping range of the upload, then takes it.
+    pub async fn lock(self: &Arc<Self>, id: &str, range: Range<u64>) -> RangeGuard {
+        loop {
+            // Register for the notification before checking, so a release between the check and
+            // the await isn't missed.
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+            {
+                let mut held = self.held.lock().unwrap();
+                let ranges = held.entry(id.to_string()).or_default();
+                if !ranges.iter().any(|r| overlaps(r, &range)) {
+                    ranges.push(range.clone());
+                    return RangeGuard {
+                        locks: self.clone(),
+                        id: id.to_string(),
+                        range,
+                    };
+                }
+            }
+            released.await;
+        }
+    }
+}
+
+impl Drop for RangeGuard {
+    fn drop(&mut self) {
+        let mut held = self.locks.held.lock().unwrap();
+        if let Some(ranges) = held.get_mut(&self.id) {
+            if let Some(i) = ranges.iter().position(|r| r == &self.range) {
+                ranges.swap_remove(i);
+            }
+            if ranges.is_empty() {
+                held.remove(&self.id);
+            }
+        }
+        self.locks.released.notify_waiters();
+    }
+}
+
+/// Limits how many chunk PUTs can write to an upload at once, so one client can't scatter writes
+/// all over the file from hundreds of connections.
+#[derive(Debug)]
+pub struct WriterLimit {
+    /// 0 is no limit.
+    max: usize,
+    /// Whether writes over the limit wait their turn, instead of being turned away.
+    queue: bool,
+    active: Mutex<HashMap<String, usize>>,
+    released: Notify,
+}
+
+/// Counts as one of the upload's writers until it's dropped.
+#[derive(Debug)]
+pub struct WriterGuard {
+    limit: Arc<WriterLimit>,
+    id: String,
+}
+
+impl WriterLimit {
+    pub fn new(max: usize, queue: bool) -> Self {
+        Self {
+            max,
+            queue,
+            active: Mutex::default(),
+            released: Notify::new(),
+        }
+    }
+
+    /// Reads `BULLSEYE_MAX_WRITERS` (8 by default) and `BULLSEYE_QUEUE_WRITERS`.
+    pub fn from_env() -> io::Result<Self> {
+        let max = match std::env::var("BULLSEYE_MAX_WRITERS") {
+            Ok(v) => v
+                .parse()
+                .map_err(|e| io::Error::other(format!("BULLSEYE_MAX_WRITERS: {e}")))?,
+            Err(_) => 8,
+        };
+        let queue =
+            std::env::var("BULLSEYE_QUEUE_WRITERS").is_ok_and(|v| !v.is_empty() && v != "0");
+        Ok(Self::new(max, queue))
+    }
+
+    /// Becomes one of the upload's writers, waiting for a turn if writes are queued. Otherwise,
+    /// if there are already as many as there can be, returns the error to send.
+    pub async fn enter(self: &Arc<Self>, id: &str) -> Result<Option<WriterGuard>, ApiError> {
+        if self.max == 0 {
+            return Ok(None);
+        }
+        loop {
+            let released = self.released.notified();
+            tokio::pin!(released);
+            released.as_mut().enable();
+            {
+                let mut active = self.active.lock().unwrap();
+                let count = active.entry(id.to_string()).or_default();
+                if *count < self.max {
+                    *count += 1;
+                    return Ok(Some(WriterGuard {
+                        limit: self.clone(),
+                        id: id.to_string(),
+                    }));
+                }
+            }
+            if !self.queue {
+                return Err(ApiError::Unavailable {
+                    retry_after_secs: 1,
+                    reason: format!("this upload already has {} chunks being written", self.max),
+                });
+            }
+            released.await;
+        }
+    }
+}
+
+impl Drop for WriterGuard {
+    fn drop(&mut self) {
+        let mut active = self.limit.active.lock().unwrap();
+        if let Some(count) = active.get_mut(&self.id) {
+            *count -= 1;
+            if *count == 0 {
+                active.remove(&self.id);
+            }
+        }
+        self.limit.released.notify_waiters();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Arc, time::Duration};
+
+    use tokio::time::timeout;
+
+    use super::{RangeLocks, WriterLimit};
+
+    /// Ensures that only overlapping ranges block each other.
+    #[actix_web::test]
+    async fn test_range_locks() {
+        let locks = Arc::new(RangeLocks::default());
+        let a = locks.lock("a", 0..10).await;
+        // Disjoint range, and the same range of another upload.
+        let _b = locks.lock("a", 10..20).await;
+        let _c = locks.lock("b", 0..10).await;
+        // Overlapping range.
+        timeout(Duration::from_millis(50), locks.lock("a", 5..15)).await.unwrap_err();
+        drop(a);
+        // Still overlaps 10..20.
+        timeout(Duration::from_millis(50), locks.lock("a", 5..15)).await.unwrap_err();
+        timeout(Duration::from_millis(50), locks.lock("a", 0..10)).await.unwrap();
+    }
+
+    #[actix_web::test]
+    async fn test_writer_limit() {
+        let rejecting = Arc::new(WriterLimit::new(2, false));
+        let a = rejecting.enter("a").await.unwrap();
+        let _b = rejecting.enter("a").await.unwrap();
+        let _c = rejecting.enter("b").await.unwrap();
+        assert!(rejecting.enter("a").await.is_err());
+        drop(a);
+        rejecting.enter("a").await.unwrap();
+
+        let queueing = Arc::new(WriterLimit::new(1, true));
+        let a = queueing.enter("a").await.unwrap();
+        timeout(Duration::from_millis(50), queueing.enter("a")).await.unwrap_err();
+        drop(a);
+        timeout(Duration::from_millis(50), queueing.enter("a")).await.unwrap().unwrap();
+
+        let unlimited = Arc::new(WriterLimit::new(0, false));
+        let mut writers = Vec::new();
+        for _ in 0..100 {
+            writers.push(unlimited.enter("a").await.unwrap());
+        }
+    }
+}
@@ -0,0 +1,60 @@
+//! The registry (projects, pipelines and uploader limits) can be changed without restarting the
+//! server: it's read again on SIGHUP (except on Windows, which doesn't have it) or
+//! `POST /admin/reload`. Requests that are already being
+//! handled, like chunk uploads, keep using the registry they started with.
+
+use std::{
+    io,
+    sync::{Arc, RwLock},
+};
+
+use common::registry::Registry;
+use log::{info, warn};
+
+/// The current registry, shared between all workers.
+pub struct LiveRegistry {
+    current: RwLock<Arc<Registry>>,
+}
+
+impl LiveRegistry {
+    pub fn new(registry: Registry) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(registry)),
+        }
+    }
+
+    /// Gets the current registry.
+    pub fn get(&self) -> Arc<Registry> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Reads the registry again. If it's invalid, the old one is kept.
+    pub fn reload(&self) -> io::Result<()> {
+        let registry = Registry::from_env()?;
+        *self.current.write().unwrap() = Arc::new(registry);
+        Ok(())
+    }
+}
+
+/// Reloads the registry every time the server gets a SIGHUP, forever.
+#[cfg(unix)]
+pub async fn on_sighup(registry: Arc<LiveRegistry>) -> io::Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = signal(SignalKind::hangup())?;
+    while hangups.recv().await.is_some() {
+        match registry.reload() {
+            Ok(()) => info!("reloaded the registry"),
+            Err(e) => warn!("failed to reload the registry, keeping the old one: {e}"),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn on_sighup(_: Arc<LiveRegistry>) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "there's no SIGHUP here",
+    ))
+}
@@ -0,0 +1,222 @@
+//! Background scrubber that re-hashes finished files to catch bit-rot before they are packed.
+//! When a file doesn't match and its Merkle tree was stored, the alert says which parts of it are
+//! damaged.
+
+use std::{
+    io::{self, Read},
+    sync::Arc,
+    thread,
+    time::{Duration, Instant, SystemTime},
+};
+
+use common::{
+    crypt::{DataFile, MasterKey},
+    db::{leases::Lease, DatabaseHandle, UploadRow},
+    merkle::{self, MerkleTree},
+    platform,
+};
+use log::{error, info, warn};
+use tokio::task::spawn_blocking;
+
+/// How long to wait between looking for files to scrub.
+const INTERVAL: Duration = Duration::from_secs(10 * 60);
+/// Default for BULLSEYE_SCRUB_RATE.
+const DEFAULT_RATE: u64 = 8 * 1024 * 1024;
+/// Default for BULLSEYE_SCRUB_EVERY.
+const DEFAULT_EVERY: u64 = 7 * 24 * 60 * 60;
+
+/// How the scrubber is paced, read from the environment.
+#[derive(Clone, Copy, Debug)]
+pub struct ScrubConfig {
+    /// Maximum bytes per second to read (BULLSEYE_SCRUB_RATE).
+    pub rate: u64,
+    /// How many seconds to wait before checking the same file again (BULLSEYE_SCRUB_EVERY).
+    pub every: u64,
+}
+
+impl ScrubConfig {
+    pub fn from_env() -> io::Result<Self> {
+        fn var(name: &str, default: u64) -> io::Result<u64> {
+            match std::env::var(name) {
+                Ok(v) => v
+                    .parse()
+                    .map_err(|e| io::Error::other(format!("{name}: {e}"))),
+                Err(_) => Ok(default),
+            }
+        }
+        Ok(Self {
+            rate: var("BULLSEYE_SCRUB_RATE", DEFAULT_RATE)?,
+            every: var("BULLSEYE_SCRUB_EVERY", DEFAULT_EVERY)?,
+        })
+    }
+}
+
+/// A reader that sleeps as needed to stay under a byte rate.
+struct Throttled<R> {
+    inner: R,
+    rate: u64,
+    start: Instant,
+    read: u64,
+}
+
+impl<R: Read> Read for Throttled<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.read += n as u64;
+        if self.rate > 0 {
+            let due = Duration::from_secs_f64(self.read as f64 / self.rate as f64);
+            if let Some(ahead) = due.checked_sub(self.start.elapsed()) {
+                thread::sleep(ahead);
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// Hashes a finished file, and builds its tree. This does blocking I/O.
+fn hash(data: &DataFile, rate: u64) -> io::Result<(String, MerkleTree)> {
+    let file = data.open()?;
+    // Shared, so anything that needs the file exclusively isn't blocked for long.
+    platform::acquire_lock(file.get_ref(), false)?;
+    merkle::hash_file(Throttled {
+        inner: io::BufReader::with_capacity(1024 * 1024, file),
+        rate,
+        start: Instant::now(),
+        read: 0,
+    })
+}
+
+/// Describes which parts of a corrupted file differ from its stored tree, if it has one. The tree
+/// is of the upload as it was sent, so derived files can't be compared with it.
+async fn damage(row: &UploadRow, tree: &MerkleTree) -> String {
+    if row.derived().is_some() {
+        return String::new();
+    }
+    let (dir, id) = (row.dir().to_string(), row.id().to_string());
+    let stored = match spawn_blocking(move || merkle::read(dir, &id)).await {
+        Ok(Ok(stored)) => stored,
+        _ => return String::new(),
+    };
+    match stored.diff(tree) {
+        Some(leaves) if !leaves.is_empty() => {
+            let ranges: Vec<String> = leaves
+                .into_iter()
+                .map(|i| stored.leaf_range(i))
+                .map(|(start, end)| format!("{start}..{end}"))
+                .collect();
+            format!(" (damaged bytes: {})", ranges.join(", "))
+        }
+        _ => String::new(),
+    }
+}
+
+/// Re-hashes one file and flags it if it doesn't match.
+async fn scrub(pool: &DatabaseHandle, rate: u64, master: Option<&MasterKey>, row: &mut UploadRow) {
+    let data = match row.data_file(master) {
+        Ok(data) => data,
+        Err(e) => {
+            warn!("failed to scrub {}: {e}", row.id());
+            return;
+        }
+    };
+    let (actual, tree) = match spawn_blocking(move || hash(&data, rate)).await {
+        Ok(Ok(h)) => h,
+        Ok(Err(e)) => {
+            warn!("failed to scrub {}: {e}", row.id());
+            return;
+        }
+        Err(e) => {
+            warn!("scrubber task for {} panicked: {e}", row.id());
+            return;
+        }
+    };
+    if actual == row.expected_hash() {
+        if let Err(e) = row.mark_scrubbed(pool).await {
+            warn!("failed to mark {} as scrubbed: {e}", row.id());
+        }
+        return;
+    }
+    error!(
+        "ALERT: {} is corrupted on disk: expected {}, got {actual}{}",
+        row.id(),
+        row.expected_hash(),
+        damage(row, &tree).await
+    );
+    match row.flag_corrupt(pool).await {
+        Ok(()) => row.record_manifest().await,
+        Err(e) => warn!("failed to flag {} as corrupted: {e}", row.id()),
+    }
+}
+
+/// Whether we hold the scrubber's lease. Errors count as not holding it.
+async fn is_leader(pool: &DatabaseHandle, lease: &Lease) -> bool {
+    match lease.acquire(pool).await {
+        Ok(leader) => leader,
+        Err(e) => {
+            warn!("failed to renew the scrubber lease: {e}");
+            false
+        }
+    }
+}
+
+/// Slowly re-hashes finished files forever. When several servers share the database, only the
+/// one holding the lease does. `master` decrypts uploads that are encrypted at rest.
+pub async fn run(pool: DatabaseHandle, config: ScrubConfig, master: Option<Arc<MasterKey>>) {
+    let lease = Lease::new("scrubber", INTERVAL * 3);
+    let mut timer = tokio::time::interval(INTERVAL);
+    timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        timer.tick().await;
+        if !is_leader(&pool, &lease).await {
+            continue;
+        }
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let rows = match UploadRow::list_unscrubbed(&pool, now.saturating_sub(config.every)).await {
+            Ok(rows) => rows,
+            Err(e) => {
+                warn!("failed to list files to scrub: {e}");
+                continue;
+            }
+        };
+        if !rows.is_empty() {
+            info!("scrubbing {} files", rows.len());
+        }
+        // One at a time, so the scrubber never uses more than its share of the disk. Hashing a
+        // file can take a while, so renew the lease before each one.
+        for mut row in rows {
+            if !is_leader(&pool, &lease).await {
+                break;
+            }
+            scrub(&pool, config.rate, master.as_deref(), &mut row).await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::Read,
+        time::{Duration, Instant},
+    };
+
+    use super::Throttled;
+
+    /// Ensures the throttled reader doesn't go faster than its rate.
+    #[test]
+    fn test_throttle() {
+        let data = [0u8; 1000];
+        let mut reader = Throttled {
+            inner: &data[..],
+            rate: 10_000,
+            start: Instant::now(),
+            read: 0,
+        };
+        let mut out = Vec::new();
+        reader.read_to_end(&mut out).unwrap();
+        assert_eq!(out.len(), 1000);
+        assert!(reader.start.elapsed() >= Duration::from_millis(100));
+    }
+}
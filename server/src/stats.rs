@@ -0,0 +1,95 @@
+//! Public statistics: about uploaders, for the leaderboard, and about the queues.
+
+use std::{
+    io,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{get, web, HttpResponse};
+use common::db::{DatabaseHandle, DbError, UploadRow, UploaderStats};
+use serde::Deserialize;
+
+use crate::{
+    error::{ApiError, ApiResult},
+    payloads::*,
+    SharedCtx,
+};
+
+/// The most uploaders on the leaderboard.
+const MAX_LEADERBOARD: usize = 1000;
+
+/// The leaderboard, cached for `BULLSEYE_LEADERBOARD_CACHE` seconds (60 by default; 0 turns the
+/// cache off), so that lots of people watching it doesn't mean lots of queries.
+pub struct Leaderboard {
+    ttl: Duration,
+    cached: Mutex<Option<(Instant, Vec<UploaderStats>)>>,
+}
+
+impl Leaderboard {
+    pub fn from_env() -> io::Result<Self> {
+        let ttl = match std::env::var("BULLSEYE_LEADERBOARD_CACHE") {
+            Ok(v) => v
+                .parse()
+                .map_err(|e| io::Error::other(format!("BULLSEYE_LEADERBOARD_CACHE: {e}")))?,
+            Err(_) => 60,
+        };
+        Ok(Self {
+            ttl: Duration::from_secs(ttl),
+            cached: Mutex::new(None),
+        })
+    }
+
+    /// Gets the top `limit` uploaders.
+    async fn get(
+        &self,
+        conn: &DatabaseHandle,
+        limit: usize,
+    ) -> Result<Vec<UploaderStats>, DbError> {
+        if self.ttl.is_zero() {
+            return UploaderStats::leaderboard(conn, limit).await;
+        }
+        if let Some((at, board)) = &*self.cached.lock().unwrap() {
+            if at.elapsed() < self.ttl {
+                return Ok(board.iter().take(limit).cloned().collect());
+            }
+        }
+        // Several requests might refresh it at once when it runs out, which is fine.
+        let board = UploaderStats::leaderboard(conn, MAX_LEADERBOARD).await?;
+        let top = board.iter().take(limit).cloned().collect();
+        *self.cached.lock().unwrap() = Some((Instant::now(), board));
+        Ok(top)
+    }
+}
+
+#[derive(Deserialize)]
+struct LeaderboardQuery {
+    limit: Option<usize>,
+}
+
+#[get("/stats/uploaders")]
+async fn leaderboard(conn: web::Data<SharedCtx>, query: web::Query<LeaderboardQuery>) -> ApiResult {
+    let limit = query.limit.unwrap_or(100).min(MAX_LEADERBOARD);
+    let board: LeaderboardResponse = conn.leaderboard.get(&conn.pool, limit).await?;
+    Ok(ErrorablePayload::Ok(board).to_response(HttpResponse::Ok()))
+}
+
+#[get("/stats/uploaders/{name}")]
+async fn uploader(conn: web::Data<SharedCtx>, path: web::Path<String>) -> ApiResult {
+    let stats = UploaderStats::get(&conn.pool, path.into_inner())
+        .await?
+        .ok_or(ApiError::NotFound)?;
+    Ok(ErrorablePayload::Ok(stats).to_response(HttpResponse::Ok()))
+}
+
+/// How many items wait in each stage, and for how long, so stuck stages show up.
+#[get("/stats/queues")]
+async fn queues(conn: web::Data<SharedCtx>) -> ApiResult {
+    let depths: QueuesResponse = UploadRow::queue_depths(&conn.pool).await?;
+    Ok(ErrorablePayload::Ok(depths).to_response(HttpResponse::Ok()))
+}
+
+/// Registers the statistics endpoints.
+pub fn configure(cfg: &mut web::ServiceConfig) {
+    cfg.service(leaderboard).service(uploader).service(queues);
+}
@@ -0,0 +1,237 @@
+//! Background maintenance tasks.
+
+use std::{collections::BTreeMap, path::{Path, PathBuf}, sync::Arc, time::{Duration, SystemTime}};
+
+use common::{
+    data::UploadError,
+    db::{chunks, leases::Lease, DatabaseHandle, Stall, Status, UploadRow},
+    registry::Registry,
+};
+use log::{info, warn};
+
+use crate::{
+    abandon_upload, files,
+    ingest::IngestHashes,
+    metrics::{self, DiskThresholds},
+    reload::LiveRegistry,
+};
+
+/// How often the maintenance tasks run.
+const INTERVAL: Duration = Duration::from_secs(60);
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .as_secs()
+}
+
+/// Abandons uploads that have been idle for longer than their project's idle timeout.
+pub async fn reap_idle(pool: &DatabaseHandle, ingest: &IngestHashes, registry: &Registry) {
+    for (name, project) in &registry.projects {
+        let Some(timeout) = project.idle_timeout else {
+            continue;
+        };
+        let cutoff = now().saturating_sub(timeout);
+        let rows = match UploadRow::list_stale(pool, name.clone(), Status::Uploading, cutoff).await {
+            Ok(rows) => rows,
+            Err(e) => {
+                warn!("failed to list idle uploads for {name}: {e}");
+                continue;
+            }
+        };
+        for mut row in rows {
+            match abandon_upload(pool, ingest, project.delete_grace(), &mut row).await {
+                Ok(()) => info!("abandoned idle upload {}", row.id()),
+                Err(e) => warn!("failed to abandon idle upload {}: {e}", row.id()),
+            }
+        }
+    }
+}
+
+/// Warns about uploads that have gone a while without receiving data, and abandons them once
+/// their project's stall timeout is up.
+pub async fn reap_stalled(pool: &DatabaseHandle, ingest: &IngestHashes, registry: &Registry) {
+    let now = now();
+    let cutoffs: BTreeMap<String, u64> = registry
+        .projects
+        .iter()
+        .filter_map(|(name, project)| {
+            let timeout = project.stall_timeout?;
+            Some((name.clone(), now.saturating_sub(timeout / 2).saturating_add(1)))
+        })
+        .collect();
+    if cutoffs.is_empty() {
+        return;
+    }
+    let rows = match UploadRow::list_stalled(pool, cutoffs).await {
+        Ok(rows) => rows,
+        Err(e) => {
+            warn!("failed to list stalled uploads: {e}");
+            return;
+        }
+    };
+    for mut row in rows {
+        let Some(project) = registry.project(row.project()) else {
+            continue;
+        };
+        let Some(timeout) = project.stall_timeout else {
+            continue;
+        };
+        let Some(stall) = Stall::check(row.last_received(), timeout, now) else {
+            continue;
+        };
+        if now >= stall.abandon_at {
+            match abandon_upload(pool, ingest, project.delete_grace(), &mut row).await {
+                Ok(()) => info!("abandoned stalled upload {}", row.id()),
+                Err(e) => warn!("failed to abandon stalled upload {}: {e}", row.id()),
+            }
+        } else if row.stalled() != Some(stall) {
+            if let Err(e) = row.mark_stalled(pool, stall).await {
+                warn!("failed to mark {} as stalled: {e}", row.id());
+            }
+        }
+    }
+}
+
+/// Soft-deletes failed uploads once their project's retention period is over.
+async fn expire_failed(pool: &DatabaseHandle, registry: &Registry) {
+    let statuses = [
+        Status::Error(UploadError::Checksum),
+        Status::Error(UploadError::Verify),
+        Status::Error(UploadError::Other),
+    ];
+    for (name, project) in &registry.projects {
+        let Some(retention) = project.retention else {
+            continue;
+        };
+        let cutoff = now().saturating_sub(retention);
+        for status in &statuses {
+            let rows = match UploadRow::list_stale(pool, name.clone(), status.clone(), cutoff).await {
+                Ok(rows) => rows,
+                Err(e) => {
+                    warn!("failed to list expired uploads for {name}: {e}");
+                    continue;
+                }
+            };
+            for mut row in rows {
+                match row.soft_delete(pool, project.delete_grace()).await {
+                    Ok(()) => {
+                        row.record_manifest().await;
+                        info!("soft-deleted expired upload {}", row.id());
+                    }
+                    Err(e) => warn!("failed to soft-delete {}: {e}", row.id()),
+                }
+            }
+        }
+    }
+}
+
+/// Deletes the data of soft-deleted uploads whose grace period is over.
+async fn purge_deleted(pool: &DatabaseHandle) {
+    let rows = match UploadRow::list_purgeable(pool).await {
+        Ok(rows) => rows,
+        Err(e) => {
+            warn!("failed to list purgeable uploads: {e}");
+            return;
+        }
+    };
+    for mut row in rows {
+        if let Err(e) = files::delete_data(row.dir().into(), row.id()).await {
+            warn!("failed to delete data of {}: {e}", row.id());
+            continue;
+        }
+        match row.mark_files_removed(pool).await {
+            Ok(()) => info!("purged upload {}", row.id()),
+            Err(e) => warn!("failed to mark {} as removed: {e}", row.id()),
+        }
+    }
+}
+
+/// Moves uploads that are done with to the archive once their project's `archive_after` is over.
+async fn archive_old(pool: &DatabaseHandle, registry: &Registry) {
+    for (name, project) in &registry.projects {
+        let Some(after) = project.archive_after else {
+            continue;
+        };
+        match UploadRow::archive_old(pool, name.clone(), now().saturating_sub(after)).await {
+            Ok(0) => {}
+            Ok(n) => info!("archived {n} uploads from {name}"),
+            Err(e) => warn!("failed to archive uploads from {name}: {e}"),
+        }
+    }
+}
+
+/// Removes chunks from the chunk index that haven't been seen for their project's
+/// `chunk_retention`.
+async fn prune_chunks(pool: &DatabaseHandle, registry: &Registry) {
+    for (name, project) in &registry.projects {
+        let before = now().saturating_sub(project.chunk_retention());
+        match chunks::prune(pool, name.clone(), before).await {
+            Ok(0) => {}
+            Ok(n) => info!("pruned {n} chunks from {name}'s chunk index"),
+            Err(e) => warn!("failed to prune {name}'s chunk index: {e}"),
+        }
+    }
+}
+
+/// Upgrades rows written by older builds, which keep writing them during a rolling upgrade.
+async fn upgrade_rows(pool: &DatabaseHandle) {
+    match UploadRow::upgrade_old_rows(pool).await {
+        Ok(0) => {}
+        Ok(n) => info!("upgraded {n} rows written by older builds"),
+        Err(e) => warn!("failed to upgrade old rows: {e}"),
+    }
+}
+
+/// Warns if the data directory is running low on space or inodes.
+async fn check_disk(cwd: &Path, thresholds: &DiskThresholds) {
+    for (dir, stats) in metrics::read_all(vec![cwd.to_path_buf()]).await {
+        match stats {
+            Ok(s) => s.check(&dir, thresholds),
+            Err(e) => warn!("failed to read disk stats for {}: {e}", dir.display()),
+        }
+    }
+}
+
+/// Runs the reaper, retention, purge, archive and chunk index tasks and the disk check forever.
+///
+/// When several servers share the database, only the one holding the lease runs the reaper,
+/// retention, purge, archive and chunk index tasks. Every server checks its own disk.
+pub async fn run(
+    pool: DatabaseHandle,
+    cwd: PathBuf,
+    registry: Arc<LiveRegistry>,
+    ingest: Arc<IngestHashes>,
+    thresholds: DiskThresholds,
+) {
+    let lease = Lease::new("maintenance", INTERVAL * 3);
+    let mut leader = false;
+    let mut timer = tokio::time::interval(INTERVAL);
+    timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        timer.tick().await;
+        let was_leader = leader;
+        leader = match lease.acquire(&pool).await {
+            Ok(leader) => leader,
+            Err(e) => {
+                warn!("failed to renew the maintenance lease: {e}");
+                false
+            }
+        };
+        if leader != was_leader {
+            info!("{} running the maintenance tasks", if leader { "now" } else { "no longer" });
+        }
+        if leader {
+            let registry = registry.get();
+            reap_idle(&pool, &ingest, &registry).await;
+            reap_stalled(&pool, &ingest, &registry).await;
+            expire_failed(&pool, &registry).await;
+            purge_deleted(&pool).await;
+            archive_old(&pool, &registry).await;
+            prune_chunks(&pool, &registry).await;
+            upgrade_rows(&pool).await;
+        }
+        check_disk(&cwd, &thresholds).await;
+    }
+}
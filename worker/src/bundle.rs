@@ -0,0 +1,442 @@
+//! Looks inside bundles (uploads of several files, see `BundleMember`) and tar archives: checks
+//! that each member's data has the SHA-256 it should, and that every one of the upload's items
+//! has a member named after it, either exactly or followed by an extension (`job1` matches
+//! `job1.warc.gz`). The members of a tar archive, with their checksums, are recorded on the row,
+//! like a bundle's. Long names and sizes from GNU and PAX extended headers are understood, and an
+//! archive whose headers don't make sense is rejected.
+//!
+//! When something is wrong, the item fails with `FAILED_VERIFY`, and its history gets a JSON
+//! report like `{"missing": ["job2"], "corrupt": ["job1.json: has SHA-256 ..., not ..."]}`.
+
+use std::io::{self, BufReader, Read, Seek};
+
+use common::{
+    crypt::DataFile,
+    data::{BundleMember, Progress, UploadError},
+    db::UploadRow,
+    StreamHasher,
+};
+use serde::{Deserialize, Serialize};
+use tokio::task::spawn_blocking;
+
+use crate::processor::{Outcome, ProgressSender, Report};
+
+const BLOCK: u64 = 512;
+/// The biggest GNU long name or PAX extended header that's read.
+const MAX_EXTENDED: u64 = 1024 * 1024;
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct BundleInspector {
+    /// Don't fail uploads with items that have no member named after them.
+    #[serde(default)]
+    pub allow_missing_items: bool,
+}
+
+/// What's wrong with a bundle.
+#[derive(Serialize, Debug, Default, PartialEq, Eq)]
+struct Problems {
+    /// Items with no member named after them.
+    missing: Vec<String>,
+    /// Members whose data is wrong, and why.
+    corrupt: Vec<String>,
+}
+
+/// Hashes the next `size` bytes. Returns None if there aren't that many.
+fn hash_next(reader: &mut impl Read, size: u64) -> io::Result<Option<String>> {
+    let mut hasher = StreamHasher::default();
+    let mut buf = vec![0; 64 * 1024];
+    let mut left = size;
+    let mut reader = reader.take(size);
+    while left > 0 {
+        let n = reader.read(&mut buf)?;
+        if n == 0 {
+            return Ok(None);
+        }
+        hasher.update(&buf[..n]);
+        left -= n as u64;
+    }
+    Ok(Some(hasher.finish()))
+}
+
+/// Checks a bundle's members against the hashes the client gave for them.
+fn check_bundle(
+    data: &DataFile,
+    members: &[BundleMember],
+    problems: &mut Problems,
+    progress: &ProgressSender,
+) -> io::Result<()> {
+    let mut reader = BufReader::new(data.open()?);
+    let total = members.iter().map(|m| m.file.size).sum();
+    for m in members {
+        reader.seek(io::SeekFrom::Start(m.offset))?;
+        match hash_next(&mut reader, m.file.size)? {
+            Some(hash) if hash == m.file.hash => {}
+            Some(hash) => problems.corrupt.push(format!(
+                "{}: has SHA-256 {hash}, not {}",
+                m.file.name, m.file.hash
+            )),
+            None => problems
+                .corrupt
+                .push(format!("{}: the upload is too short", m.file.name)),
+        }
+        progress.send_replace(Some(Progress {
+            done: m.offset + m.file.size,
+            total,
+        }));
+    }
+    Ok(())
+}
+
+/// Reads a number from a tar header: octal, or GNU tar's base-256 for big ones.
+fn parse_number(field: &[u8]) -> Option<u64> {
+    if field[0] & 0x80 != 0 {
+        let mut n: u64 = (field[0] & 0x7f) as u64;
+        for b in &field[1..] {
+            n = n.checked_mul(256)? + *b as u64;
+        }
+        return Some(n);
+    }
+    let s = std::str::from_utf8(field).ok()?;
+    let s = s.trim_matches(|c: char| c == '\0' || c == ' ');
+    match s {
+        "" => Some(0),
+        s => u64::from_str_radix(s, 8).ok(),
+    }
+}
+
+/// Reads a NUL-terminated string from a tar header.
+fn parse_name(field: &[u8]) -> String {
+    let end = field.iter().position(|b| *b == 0).unwrap_or(field.len());
+    String::from_utf8_lossy(&field[..end]).into_owned()
+}
+
+/// Reads the records in a PAX extended header, like `30 path=some/very/long/name\n`. Returns
+/// None if they aren't well-formed.
+fn parse_pax(mut data: &[u8]) -> Option<Vec<(String, String)>> {
+    let mut records = Vec::new();
+    // The header is padded with NULs.
+    while data.first().is_some_and(|b| *b != 0) {
+        let space = data.iter().position(|b| *b == b' ')?;
+        let len: usize = std::str::from_utf8(&data[..space]).ok()?.parse().ok()?;
+        if len <= space + 1 || len > data.len() || data[len - 1] != b'\n' {
+            return None;
+        }
+        let record = std::str::from_utf8(&data[space + 1..len - 1]).ok()?;
+        let (key, value) = record.split_once('=')?;
+        records.push((key.to_string(), value.to_string()));
+        data = &data[len..];
+    }
+    Some(records)
+}
+
+/// Lists a tar archive's regular files, hashing each one. Problems with the archive itself, like
+/// a truncated member, are added to `problems`.
+fn read_tar(
+    data: &DataFile,
+    problems: &mut Problems,
+    progress: &ProgressSender,
+) -> io::Result<Vec<BundleMember>> {
+    let total = std::fs::metadata(&data.path)?.len();
+    let mut reader = BufReader::new(data.open()?);
+    let mut members = Vec::new();
+    let mut offset = 0;
+    // Set by a GNU long name entry or a PAX extended header, for the entry after it.
+    let mut long_name = None;
+    // Set by a PAX extended header, for the entry after it, since the header's size field only
+    // goes up to 8 GiB.
+    let mut long_size = None;
+    loop {
+        let mut header = [0; BLOCK as usize];
+        if reader.read_exact(&mut header).is_err() {
+            problems
+                .corrupt
+                .push(format!("the archive ends without a trailer at {offset}"));
+            break;
+        }
+        offset += BLOCK;
+        if header.iter().all(|b| *b == 0) {
+            break;
+        }
+        let expected = parse_number(&header[148..156]);
+        header[148..156].fill(b' ');
+        let sum: u64 = header.iter().map(|b| *b as u64).sum();
+        let Some(size) = parse_number(&header[124..136]).filter(|_| expected == Some(sum)) else {
+            problems
+                .corrupt
+                .push(format!("bad header at {}", offset - BLOCK));
+            break;
+        };
+        let size = match header[156] {
+            b'0' | 0 => long_size.take().unwrap_or(size),
+            _ => size,
+        };
+        // A size this big can't be in the file, so the header is garbage.
+        let Some((padded, skip)) = size
+            .checked_next_multiple_of(BLOCK)
+            .and_then(|padded| Some((padded, i64::try_from(padded).ok()?)))
+        else {
+            problems
+                .corrupt
+                .push(format!("bad size {size} at {}", offset - BLOCK));
+            break;
+        };
+        let mut name = parse_name(&header[..100]);
+        if &header[257..262] == b"ustar" && header[345] != 0 {
+            name = format!("{}/{name}", parse_name(&header[345..500]));
+        }
+        match header[156] {
+            b'0' | 0 => {
+                let name = long_name.take().unwrap_or(name);
+                let Some(hash) = hash_next(&mut reader, size)? else {
+                    problems.corrupt.push(format!("{name}: truncated"));
+                    break;
+                };
+                members.push(BundleMember {
+                    file: common::data::File { hash, name, size },
+                    offset,
+                });
+                reader.seek_relative((padded - size) as i64)?;
+            }
+            b'L' => {
+                let mut data = vec![0; size.min(MAX_EXTENDED) as usize];
+                if size > MAX_EXTENDED || reader.read_exact(&mut data).is_err() {
+                    problems
+                        .corrupt
+                        .push(format!("bad long name at {}", offset - BLOCK));
+                    break;
+                }
+                long_name = Some(parse_name(&data));
+                reader.seek_relative((padded - size) as i64)?;
+            }
+            b'x' => {
+                let mut data = vec![0; size.min(MAX_EXTENDED) as usize];
+                let records = match size > MAX_EXTENDED || reader.read_exact(&mut data).is_err() {
+                    true => None,
+                    false => parse_pax(&data),
+                };
+                let Some(records) = records else {
+                    problems
+                        .corrupt
+                        .push(format!("bad extended header at {}", offset - BLOCK));
+                    break;
+                };
+                for (key, value) in records {
+                    match key.as_str() {
+                        "path" => long_name = Some(value),
+                        "size" => match value.parse() {
+                            Ok(size) => long_size = Some(size),
+                            Err(_) => problems.corrupt.push(format!(
+                                "bad size {value:?} in the extended header at {}",
+                                offset - BLOCK
+                            )),
+                        },
+                        _ => {}
+                    }
+                }
+                if !problems.corrupt.is_empty() {
+                    break;
+                }
+                reader.seek_relative((padded - size) as i64)?;
+            }
+            // Directories, links, global extended headers and so on.
+            _ => reader.seek_relative(skip)?,
+        }
+        let Some(next) = offset.checked_add(padded) else {
+            problems
+                .corrupt
+                .push(format!("bad size {size} at {}", offset - BLOCK));
+            break;
+        };
+        offset = next;
+        progress.send_replace(Some(Progress {
+            done: offset.min(total),
+            total,
+        }));
+    }
+    Ok(members)
+}
+
+/// Whether a member is named after an item.
+fn is_named_after(member: &str, item: &str) -> bool {
+    let name = member.rsplit('/').next().unwrap_or(member);
+    name == item
+        || name
+            .strip_prefix(item)
+            .is_some_and(|rest| rest.starts_with('.'))
+}
+
+/// Inspects an upload. Returns the members of a tar archive, which aren't on the row yet, and
+/// what's wrong with it. This blocks.
+fn inspect(
+    row: &UploadRow,
+    data: DataFile,
+    allow_missing_items: bool,
+    progress: &ProgressSender,
+) -> io::Result<(Option<Vec<BundleMember>>, Problems)> {
+    let mut problems = Problems::default();
+    let found = if !row.members().is_empty() {
+        check_bundle(&data, row.members(), &mut problems, progress)?;
+        None
+    } else if row.file().name.to_lowercase().ends_with(".tar") {
+        Some(read_tar(&data, &mut problems, progress)?)
+    } else {
+        problems
+            .corrupt
+            .push("the upload is neither a bundle nor a tar archive".to_string());
+        return Ok((None, problems));
+    };
+    if !allow_missing_items {
+        let members = found.as_deref().unwrap_or(row.members());
+        problems.missing = row
+            .metadata()
+            .items
+            .iter()
+            .filter(|item| !members.iter().any(|m| is_named_after(&m.file.name, item)))
+            .cloned()
+            .collect();
+    }
+    Ok((found, problems))
+}
+
+impl BundleInspector {
+    pub async fn process(
+        &self,
+        row: &UploadRow,
+        data: DataFile,
+        progress: &ProgressSender,
+    ) -> Report {
+        let (row, allow, progress) = (row.clone(), self.allow_missing_items, progress.clone());
+        let (found, problems) =
+            match spawn_blocking(move || inspect(&row, data, allow, &progress)).await {
+                Ok(Ok(result)) => result,
+                Ok(Err(e)) => return Report::retry(format!("failed to read file: {e}")),
+                Err(e) => return Report::retry(format!("inspector panicked: {e}")),
+            };
+        if problems != Problems::default() {
+            return Report {
+                outcome: Outcome::Fail(UploadError::Verify),
+                note: Some(serde_json::to_string(&problems).unwrap()),
+            };
+        }
+        match found {
+            Some(members) => Report {
+                note: Some(format!("{} files in the archive", members.len())),
+                outcome: Outcome::Members(members),
+            },
+            None => Report {
+                outcome: Outcome::Advance(None),
+                note: Some("every file in the bundle is intact".to_string()),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{process::Command, sync::Arc};
+
+    use common::{
+        data::{File, Metadata},
+        db::UploadRow,
+        hash_file,
+    };
+
+    use super::{inspect, is_named_after, parse_number, parse_pax, DataFile, Problems};
+
+    #[test]
+    fn names() {
+        assert!(is_named_after("job1.warc.gz", "job1"));
+        assert!(is_named_after("job1", "job1"));
+        assert!(is_named_after("dir/job1.json", "job1"));
+        assert!(!is_named_after("job10.warc.gz", "job1"));
+        assert_eq!(parse_number(b"0000644\0"), Some(0o644));
+        assert_eq!(parse_number(&[0x80, 0, 0, 1, 0]), Some(256));
+        assert_eq!(
+            parse_pax(b"17 path=a/b.warc\n12 size=100\n\0\0"),
+            Some(vec![
+                ("path".to_string(), "a/b.warc".to_string()),
+                ("size".to_string(), "100".to_string())
+            ])
+        );
+        assert_eq!(parse_pax(b"99 path=a\n"), None);
+    }
+
+    #[test]
+    fn bundle() {
+        let dir = std::env::temp_dir().join(format!("bullseye-bundle-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = |name: &str, data: &[u8]| File {
+            hash: hash_file(data).unwrap(),
+            name: name.to_string(),
+            size: data.len() as u64,
+        };
+        let metadata = |items: &[&str]| Metadata {
+            uploader: "someone".to_string(),
+            items: items.iter().map(|i| i.to_string()).collect(),
+            encryption: None,
+        };
+        let (tx, _rx) = tokio::sync::watch::channel(None);
+        let tx = Arc::new(tx);
+
+        std::fs::write(dir.join("bundle"), b"WARC{}").unwrap();
+        let members = vec![file("job1.warc", b"WARC"), file("job1.json", b"{}")];
+        let row = UploadRow::builder(
+            "bundle",
+            file("b", b"WARC{}"),
+            "p",
+            "p",
+            metadata(&["job1"]),
+        )
+        .members(members.clone())
+        .build()
+        .unwrap();
+        let (found, problems) =
+            inspect(&row, DataFile::plain(dir.join("bundle")), false, &tx).unwrap();
+        assert!(found.is_none());
+        assert_eq!(problems, Problems::default());
+
+        std::fs::write(dir.join("bundle"), b"WARC[]").unwrap();
+        let row = UploadRow::builder(
+            "bundle",
+            file("b", b"WARC{}"),
+            "p",
+            "p",
+            metadata(&["job2"]),
+        )
+        .members(members)
+        .build()
+        .unwrap();
+        let (_, problems) = inspect(&row, DataFile::plain(dir.join("bundle")), false, &tx).unwrap();
+        assert_eq!(problems.missing, ["job2"]);
+        assert_eq!(problems.corrupt.len(), 1);
+        assert!(problems.corrupt[0].starts_with("job1.json"));
+
+        // A tar archive, made by tar itself.
+        std::fs::write(dir.join("job1.warc"), b"WARC").unwrap();
+        let made = Command::new("tar")
+            .args(["cf", "job.tar", "job1.warc"])
+            .current_dir(&dir)
+            .status();
+        if made.is_ok_and(|s| s.success()) {
+            let data = std::fs::read(dir.join("job.tar")).unwrap();
+            let row =
+                UploadRow::builder("t", file("job.tar", &data), "p", "p", metadata(&["job1"]))
+                    .build()
+                    .unwrap();
+            let (found, problems) =
+                inspect(&row, DataFile::plain(dir.join("job.tar")), false, &tx).unwrap();
+            assert_eq!(problems, Problems::default());
+            let found = found.unwrap();
+            assert_eq!(found.len(), 1);
+            assert_eq!(found[0].file.name, "job1.warc");
+            assert_eq!(found[0].file.hash, hash_file(&b"WARC"[..]).unwrap());
+            assert_eq!(&data[found[0].offset as usize..][..4], b"WARC");
+
+            std::fs::write(dir.join("job.tar"), &data[..600]).unwrap();
+            let (_, problems) =
+                inspect(&row, DataFile::plain(dir.join("job.tar")), true, &tx).unwrap();
+            assert_eq!(problems.corrupt.len(), 1);
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
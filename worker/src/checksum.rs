@@ -0,0 +1,78 @@
+//! Checks that the file has the SHA-256 the client said it would. If the server hashed the data
+//! as it came in (`BULLSEYE_HASH_ON_INGEST`), that hash is used, so the file isn't read again.
+//!
+//! When the file is read, its Merkle tree (see `common::merkle`) is stored too, if the server
+//! didn't already store one. After a deriving stage, like `recompress`, the derived file is
+//! checked against the hash recorded for it instead.
+//!
+//! A file with the wrong hash fails with `FAILED_CHECKSUM`. When the upload itself (not a derived
+//! file) matches, the server's digest of it is recorded on the row.
+
+use std::io;
+
+use common::{
+    crypt::DataFile,
+    data::{Digest, UploadError},
+    db::UploadRow,
+    merkle,
+};
+use serde::{Deserialize, Serialize};
+use tokio::task::spawn_blocking;
+
+use crate::processor::{Outcome, Report};
+
+fn default_trust_server_hash() -> bool {
+    true
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ChecksumVerifier {
+    /// Use the hash the server computed on ingest, if there is one. Turn this off to always read
+    /// the file, for example if the disk it was written to isn't trusted.
+    #[serde(default = "default_trust_server_hash")]
+    pub trust_server_hash: bool,
+}
+
+/// Hashes the file, storing its tree if there isn't one yet and `store` is set. This does
+/// blocking I/O.
+fn hash_and_store(dir: &str, id: &str, data: DataFile, store: bool) -> io::Result<String> {
+    let (hash, tree) = merkle::hash_file(io::BufReader::new(data.open()?))?;
+    if store && !merkle::tree_path(dir, id).exists() {
+        merkle::write(dir, id, &tree)?;
+    }
+    Ok(hash)
+}
+
+impl ChecksumVerifier {
+    pub async fn process(&self, row: &UploadRow, data: DataFile) -> Report {
+        let (dir, id) = (row.dir().to_string(), row.id().to_string());
+        // The tree and the server's hash are of the upload, not of a derived file.
+        let original = row.derived().is_none();
+        let (hash, source) = match row.server_hash() {
+            Some(hash) if self.trust_server_hash && original => {
+                (hash.to_string(), "hashed on ingest")
+            }
+            _ => match spawn_blocking(move || hash_and_store(&dir, &id, data, original)).await {
+                Ok(Ok(hash)) => (hash, "read from disk"),
+                Ok(Err(e)) => return Report::retry(format!("failed to hash the file: {e}")),
+                Err(e) => return Report::retry(format!("hasher panicked: {e}")),
+            },
+        };
+        let expected = &row.current_file().hash;
+        if hash == *expected {
+            return Report {
+                outcome: match original {
+                    true => Outcome::Verified(Digest::sha256(hash)),
+                    false => Outcome::Advance(None),
+                },
+                note: Some(format!("checksum matched ({source})")),
+            };
+        }
+        Report {
+            outcome: Outcome::Fail(UploadError::Checksum),
+            note: Some(format!(
+                "the file has SHA-256 {hash} ({source}), but {expected} was recorded for it"
+            )),
+        }
+    }
+}
@@ -0,0 +1,298 @@
+//! Runs an external command as a processor.
+//!
+//! The command gets the item's row as JSON on stdin, and these environment variables:
+//! - `BULLSEYE_FILE`: the path to the item's data. Commands can't work on uploads that are
+//!   encrypted at rest (see `common::crypt`), so those are retried until they're dead-lettered.
+//! - `BULLSEYE_ID`, `BULLSEYE_PROJECT`, `BULLSEYE_PIPELINE`, `BULLSEYE_STATUS`
+//!
+//! Its exit code decides what happens next:
+//! - 0: the item moves on. If the last line of stdout is a JSON object like
+//!   `{"status": "PACKING"}`, the item moves to that status instead of the next one, as long as
+//!   the pipeline allows it.
+//! - 2: the file is invalid (`FAILED_VERIFY`).
+//! - 3: the file is corrupted (`FAILED_CHECKSUM`).
+//! - Anything else, including being killed or timing out: the item is retried later. Once the
+//!   stage runs out of attempts, it's dead-lettered.
+//!
+//! While it runs, the command can report its progress by printing lines like
+//! `{"done": 1024, "total": 4096}` to stdout. Stderr is recorded in the item's history.
+//!
+//! The pipeline's nice, io_class, io_level and max_memory limits are applied to the command.
+
+use std::{io, process::Stdio, time::Duration};
+
+use common::{
+    crypt::DataFile,
+    data::{Progress, Status, UploadError},
+    db::UploadRow,
+};
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+    process::Command,
+};
+
+use crate::{
+    config::{IoClass, Limits},
+    processor::{Outcome, ProgressSender, Report},
+};
+
+/// At most this much of stderr is kept. The end is kept, since that's usually where the error is.
+const MAX_NOTE: usize = 64 * 1024;
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct CommandProcessor {
+    pub program: String,
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Kill the command if it takes longer than this many seconds.
+    pub timeout: Option<u64>,
+}
+
+#[derive(Deserialize)]
+struct CommandOutput {
+    status: Status,
+}
+
+/// See ioprio_set(2).
+const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+const IOPRIO_CLASS_SHIFT: u32 = 13;
+
+/// Applies the limits to the current process. This runs in the child between fork and exec, so it
+/// must only make system calls.
+fn apply_limits(limits: &Limits) -> io::Result<()> {
+    if let Some(nice) = limits.nice {
+        if unsafe { libc::setpriority(libc::PRIO_PROCESS as _, 0, nice) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    if let Some(class) = limits.io_class {
+        let prio = match class {
+            IoClass::BestEffort => {
+                (2 << IOPRIO_CLASS_SHIFT) | u32::from(limits.io_level.unwrap_or(4))
+            }
+            IoClass::Idle => 3 << IOPRIO_CLASS_SHIFT,
+        };
+        if unsafe { libc::syscall(libc::SYS_ioprio_set, IOPRIO_WHO_PROCESS, 0, prio) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    if let Some(max) = limits.max_memory {
+        let rlim = libc::rlimit {
+            rlim_cur: max as _,
+            rlim_max: max as _,
+        };
+        if unsafe { libc::setrlimit(libc::RLIMIT_AS, &rlim) } == -1 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+fn truncate(stderr: &[u8]) -> Option<String> {
+    let stderr = &stderr[stderr.len().saturating_sub(MAX_NOTE)..];
+    let stderr = String::from_utf8_lossy(stderr);
+    let stderr = stderr.trim();
+    (!stderr.is_empty()).then(|| stderr.to_string())
+}
+
+impl CommandProcessor {
+    pub async fn process(
+        &self,
+        row: &UploadRow,
+        data: DataFile,
+        limits: &Limits,
+        progress: &ProgressSender,
+    ) -> Report {
+        if data.is_encrypted() {
+            return Report::retry(format!(
+                "{} can't read the upload, because it's encrypted",
+                self.program
+            ));
+        }
+        let input = match serde_json::to_vec(row) {
+            Ok(i) => i,
+            Err(e) => return Report::retry(format!("failed to serialize row: {e}")),
+        };
+        let mut command = Command::new(&self.program);
+        let limits = limits.clone();
+        // SAFETY: apply_limits only makes async-signal-safe system calls.
+        unsafe {
+            command.pre_exec(move || apply_limits(&limits));
+        }
+        let child = command
+            .args(&self.args)
+            .env("BULLSEYE_FILE", data.path)
+            .env("BULLSEYE_ID", row.id())
+            .env("BULLSEYE_PROJECT", row.project())
+            .env("BULLSEYE_PIPELINE", row.pipeline())
+            .env("BULLSEYE_STATUS", row.status().to_string())
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn();
+        let mut child = match child {
+            Ok(c) => c,
+            Err(e) => return Report::retry(format!("failed to run {}: {e}", self.program)),
+        };
+        let mut stdin = child.stdin.take().unwrap();
+        let stdout = child.stdout.take().unwrap();
+        let mut stderr = child.stderr.take().unwrap();
+        let write_stdin = async move {
+            // The command doesn't have to read its stdin, so a broken pipe is fine.
+            let _ = stdin.write_all(&input).await;
+        };
+        let read_stdout = async {
+            let mut lines = BufReader::new(stdout).lines();
+            let mut last = String::new();
+            while let Some(line) = lines.next_line().await? {
+                match serde_json::from_str::<Progress>(&line) {
+                    Ok(p) => {
+                        progress.send_replace(Some(p));
+                    }
+                    Err(_) if !line.trim().is_empty() => last = line,
+                    Err(_) => {}
+                }
+            }
+            io::Result::Ok(last)
+        };
+        let read_stderr = async {
+            let mut buf = Vec::new();
+            stderr.read_to_end(&mut buf).await?;
+            io::Result::Ok(buf)
+        };
+        let run = async {
+            let ((), stdout, stderr) = tokio::join!(write_stdin, read_stdout, read_stderr);
+            let status = child.wait().await?;
+            io::Result::Ok((status, stdout?, stderr?))
+        };
+        let output = match self.timeout {
+            Some(t) => match tokio::time::timeout(Duration::from_secs(t), run).await {
+                Ok(o) => o,
+                Err(_) => return Report::retry(format!("{} timed out after {t}s", self.program)),
+            },
+            None => run.await,
+        };
+        let (status, stdout, stderr) = match output {
+            Ok(o) => o,
+            Err(e) => return Report::retry(format!("failed to wait for {}: {e}", self.program)),
+        };
+
+        let note = truncate(&stderr);
+        let outcome = match status.code() {
+            Some(0) => {
+                let stdout = stdout.trim();
+                if stdout.is_empty() {
+                    Outcome::Advance(None)
+                } else {
+                    match serde_json::from_str::<CommandOutput>(stdout) {
+                        Ok(o) => Outcome::Advance(Some(o.status)),
+                        Err(e) => {
+                            return Report::retry(format!("bad output from {}: {e}", self.program))
+                        }
+                    }
+                }
+            }
+            Some(2) => Outcome::Fail(UploadError::Verify),
+            Some(3) => Outcome::Fail(UploadError::Checksum),
+            _ => Outcome::Retry,
+        };
+        Report { outcome, note }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use common::{
+        crypt::DataFile,
+        data::{Progress, Status, UploadError},
+        db::UploadRow,
+    };
+    use tokio::sync::watch;
+
+    use super::CommandProcessor;
+    use crate::{
+        config::Limits,
+        processor::{Outcome, ProgressSender},
+    };
+
+    fn row() -> UploadRow {
+        serde_json::from_str(
+            r#"{
+                "id": "cmd-test", "dir": "", "status": "DERIVING",
+                "file": {"hash": "", "name": "a", "size": 0},
+                "last_activity": 0, "pipeline": "default", "project": "p", "processing": true,
+                "metadata": {"uploader": "", "items": []}
+            }"#,
+        )
+        .unwrap()
+    }
+
+    async fn run(script: &str) -> crate::processor::Report {
+        run_limited(script, &Limits::default()).await
+    }
+
+    async fn run_limited(script: &str, limits: &Limits) -> crate::processor::Report {
+        run_with_progress(script, limits, &Arc::new(watch::channel(None).0)).await
+    }
+
+    async fn run_with_progress(
+        script: &str,
+        limits: &Limits,
+        progress: &ProgressSender,
+    ) -> crate::processor::Report {
+        let p = CommandProcessor {
+            program: "sh".to_string(),
+            args: vec!["-c".to_string(), script.to_string()],
+            timeout: Some(5),
+        };
+        p.process(&row(), DataFile::plain("/nonexistent"), limits, progress)
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_exit_codes() {
+        let r =
+            run(r#"test "$BULLSEYE_ID" = cmd-test && test "$BULLSEYE_STATUS" = DERIVING"#).await;
+        assert_eq!(r.outcome, Outcome::Advance(None));
+        assert_eq!(r.note, None);
+
+        let r = run(r#"echo '{"status": "PACKING"}'"#).await;
+        assert_eq!(r.outcome, Outcome::Advance(Some(Status::Packing)));
+
+        let r = run("echo broken >&2; exit 2").await;
+        assert_eq!(r.outcome, Outcome::Fail(UploadError::Verify));
+        assert_eq!(r.note.as_deref(), Some("broken"));
+
+        let r = run("exit 1").await;
+        assert_eq!(r.outcome, Outcome::Retry);
+    }
+
+    #[tokio::test]
+    async fn test_stdin() {
+        let r = run(r#"grep -q '"id":"cmd-test"'"#).await;
+        assert_eq!(r.outcome, Outcome::Advance(None));
+    }
+
+    #[tokio::test]
+    async fn test_limits() {
+        let limits = Limits {
+            nice: Some(19),
+            ..Default::default()
+        };
+        let r = run_limited(r#"test "$(nice)" = 19"#, &limits).await;
+        assert_eq!(r.outcome, Outcome::Advance(None));
+    }
+
+    #[tokio::test]
+    async fn test_progress() {
+        let (tx, rx) = watch::channel(None);
+        let script = r#"echo '{"done": 1, "total": 4}'; echo '{"done": 2, "total": 4}'; echo '{"status": "PACKING"}'"#;
+        let r = run_with_progress(script, &Limits::default(), &Arc::new(tx)).await;
+        assert_eq!(r.outcome, Outcome::Advance(Some(Status::Packing)));
+        assert_eq!(*rx.borrow(), Some(Progress { done: 2, total: 4 }));
+    }
+}
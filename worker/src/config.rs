@@ -0,0 +1,212 @@
+//! The worker's config file.
+
+use std::{collections::HashMap, fs, io, path::Path};
+
+use common::data::Status;
+use serde::{Deserialize, Serialize};
+
+use crate::processor::ProcessorConfig;
+
+const CONFIG_ENV: &str = "BULLSEYE_WORKER_CONFIG";
+const DEFAULT_CONFIG_PATH: &str = "worker.json";
+
+/// A status of a pipeline that this worker handles.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Stage {
+    pub project: String,
+    pub pipeline: String,
+    /// The status items are picked up in. Once processed, they move on to the next stage of the
+    /// pipeline.
+    pub status: Status,
+    pub processor: ProcessorConfig,
+    #[serde(default)]
+    pub retry: RetryPolicy,
+}
+
+/// How transient failures are retried.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+#[serde(default)]
+pub struct RetryPolicy {
+    /// How many attempts an item gets before it's failed for good.
+    pub max_attempts: u32,
+    /// The delay before the first retry, in seconds. It doubles with every attempt.
+    pub base_delay: u64,
+    /// The longest delay between attempts, in seconds.
+    pub max_delay: u64,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            max_attempts: 5,
+            base_delay: 60,
+            max_delay: 6 * 60 * 60,
+        }
+    }
+}
+
+impl RetryPolicy {
+    /// Gets how long to wait before the next attempt, given how many have failed so far.
+    pub fn delay(&self, attempts: u32) -> u64 {
+        let factor = 1u64
+            .checked_shl(attempts.saturating_sub(1))
+            .unwrap_or(u64::MAX);
+        self.base_delay.saturating_mul(factor).min(self.max_delay)
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum IoClass {
+    BestEffort,
+    /// Only gets disk time when nobody else wants it.
+    Idle,
+}
+
+/// Resource limits for the processors of a pipeline. nice, io_class and io_level only apply to
+/// command processors, since WASM plugins run inside the worker.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq, Eq)]
+pub struct Limits {
+    /// How many items of the pipeline can be processed at once, across all its stages.
+    /// Defaults to 1.
+    pub concurrency: Option<usize>,
+    /// The niceness commands run at, from -20 to 19.
+    pub nice: Option<i32>,
+    pub io_class: Option<IoClass>,
+    /// The priority within the best-effort IO class, from 0 (highest) to 7.
+    pub io_level: Option<u8>,
+    /// The most memory a processor may use, in bytes. Commands that go over it will fail to
+    /// allocate.
+    pub max_memory: Option<u64>,
+}
+
+impl Limits {
+    pub fn concurrency(&self) -> usize {
+        self.concurrency.unwrap_or(1)
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.concurrency == Some(0) {
+            return Err("concurrency must be at least 1".to_string());
+        }
+        if self.nice.is_some_and(|n| !(-20..=19).contains(&n)) {
+            return Err("nice must be between -20 and 19".to_string());
+        }
+        if self.io_level.is_some_and(|l| l > 7) {
+            return Err("io_level must be between 0 and 7".to_string());
+        }
+        if self.io_level.is_some() && self.io_class != Some(IoClass::BestEffort) {
+            return Err("io_level only applies to the best_effort io_class".to_string());
+        }
+        Ok(())
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct WorkerConfig {
+    pub stages: Vec<Stage>,
+    /// Resource limits, by pipeline name. Pipelines that aren't listed get the defaults.
+    #[serde(default)]
+    pub limits: HashMap<String, Limits>,
+}
+
+impl WorkerConfig {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let data = fs::read(path)?;
+        let config: Self = serde_json::from_slice(&data)?;
+        config.validate().map_err(io::Error::other)?;
+        Ok(config)
+    }
+
+    /// Reads the config from the path in `BULLSEYE_WORKER_CONFIG`, or `worker.json`.
+    pub fn from_env() -> io::Result<Self> {
+        let path = std::env::var(CONFIG_ENV).unwrap_or(DEFAULT_CONFIG_PATH.to_string());
+        Self::load(Path::new(&path))
+    }
+
+    /// Gets the limits for a pipeline.
+    pub fn limits(&self, pipeline: &str) -> Limits {
+        self.limits.get(pipeline).cloned().unwrap_or_default()
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        for (name, limits) in &self.limits {
+            limits
+                .validate()
+                .map_err(|e| format!("limits for {name}: {e}"))?;
+        }
+        for stage in &self.stages {
+            if matches!(stage.status, Status::Uploading) || stage.status.is_terminal() {
+                return Err(format!(
+                    "{}/{}: items in {} can't be processed",
+                    stage.project, stage.pipeline, stage.status
+                ));
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{IoClass, RetryPolicy, WorkerConfig};
+
+    #[test]
+    fn test_parse() {
+        let config: WorkerConfig = serde_json::from_str(
+            r#"{"stages": [{
+                "project": "p",
+                "pipeline": "default",
+                "status": "DERIVING",
+                "processor": {"type": "command", "program": "derive.sh"}
+            }]}"#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        let bad: WorkerConfig = serde_json::from_str(
+            r#"{"stages": [{
+                "project": "p",
+                "pipeline": "default",
+                "status": "FINISHED",
+                "processor": {"type": "command", "program": "derive.sh"}
+            }]}"#,
+        )
+        .unwrap();
+        bad.validate().unwrap_err();
+    }
+
+    #[test]
+    fn test_limits() {
+        let config: WorkerConfig = serde_json::from_str(
+            r#"{"stages": [], "limits": {
+                "packing": {"concurrency": 2, "nice": 10, "io_class": "idle"}
+            }}"#,
+        )
+        .unwrap();
+        config.validate().unwrap();
+        let limits = config.limits("packing");
+        assert_eq!(limits.concurrency(), 2);
+        assert_eq!(limits.io_class, Some(IoClass::Idle));
+        assert_eq!(config.limits("other").concurrency(), 1);
+
+        let bad: WorkerConfig = serde_json::from_str(
+            r#"{"stages": [], "limits": {"packing": {"io_class": "idle", "io_level": 3}}}"#,
+        )
+        .unwrap();
+        bad.validate().unwrap_err();
+    }
+
+    #[test]
+    fn test_retry_delay() {
+        let policy = RetryPolicy {
+            max_attempts: 10,
+            base_delay: 60,
+            max_delay: 600,
+        };
+        assert_eq!(policy.delay(1), 60);
+        assert_eq!(policy.delay(2), 120);
+        assert_eq!(policy.delay(4), 480);
+        assert_eq!(policy.delay(5), 600);
+        assert_eq!(policy.delay(100), 600);
+    }
+}
@@ -0,0 +1,190 @@
+//! Works out how much of an upload was already in earlier uploads to the same project, to help
+//! spot uploaders that keep sending the same content.
+//!
+//! The file is cut into content-defined chunks (with a gear hash, like FastCDC), so data that
+//! moved because something was inserted before it still lines up with what was seen before. The
+//! chunks' SHA-256s are looked up in the chunk index (see `common::db::chunks`), and the totals
+//! and the ratio of duplicate bytes go in the row's `dedup`. This works on the bytes as stored, so
+//! compressed files only share chunks where their compressed data is the same, like WARCs with a
+//! gzip member per record that have records in common.
+
+use std::io::{self, Read};
+
+use common::{
+    crypt::DataFile,
+    data::{Chunk, Progress},
+    StreamHasher,
+};
+use serde::{Deserialize, Serialize};
+use tokio::task::spawn_blocking;
+
+use crate::processor::{Outcome, ProgressSender, Report};
+
+fn default_chunk_size() -> u64 {
+    1024 * 1024
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct DedupAnalyzer {
+    /// The average chunk size, in bytes. It's rounded up to a power of two. Chunks are between a
+    /// quarter of it and four times it.
+    #[serde(default = "default_chunk_size")]
+    pub chunk_size: u64,
+}
+
+/// A random number for each byte value, for the gear hash.
+const GEAR: [u64; 256] = {
+    let mut table = [0; 256];
+    // splitmix64
+    let mut state: u64 = 0x6275_6c6c_7365_7965;
+    let mut i = 0;
+    while i < 256 {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// Cuts data into chunks as it's fed in, hashing each one.
+struct Chunker {
+    min: u64,
+    max: u64,
+    /// A chunk ends where the gear hash has none of these bits set.
+    mask: u64,
+    gear: u64,
+    len: u64,
+    hasher: StreamHasher,
+    chunks: Vec<Chunk>,
+}
+
+impl Chunker {
+    fn new(average: u64) -> Self {
+        let average = average.max(64).next_power_of_two();
+        // The top bits depend on more of the bytes before the cut than the bottom ones do.
+        let bits = average.trailing_zeros();
+        Self {
+            min: average / 4,
+            max: average * 4,
+            mask: !(u64::MAX >> bits),
+            gear: 0,
+            len: 0,
+            hasher: StreamHasher::default(),
+            chunks: Vec::new(),
+        }
+    }
+
+    fn cut(&mut self) {
+        let hasher = std::mem::take(&mut self.hasher);
+        self.chunks.push(Chunk {
+            hash: hasher.finish(),
+            size: self.len,
+        });
+        self.gear = 0;
+        self.len = 0;
+    }
+
+    fn update(&mut self, data: &[u8]) {
+        let mut start = 0;
+        for (i, b) in data.iter().enumerate() {
+            self.gear = (self.gear << 1).wrapping_add(GEAR[*b as usize]);
+            self.len += 1;
+            if (self.len >= self.min && self.gear & self.mask == 0) || self.len >= self.max {
+                self.hasher.update(&data[start..=i]);
+                start = i + 1;
+                self.cut();
+            }
+        }
+        self.hasher.update(&data[start..]);
+    }
+
+    fn finish(mut self) -> Vec<Chunk> {
+        if self.len > 0 {
+            self.cut();
+        }
+        self.chunks
+    }
+}
+
+/// Cuts a file into chunks. This blocks.
+fn chunk_file(data: &DataFile, average: u64, progress: &ProgressSender) -> io::Result<Vec<Chunk>> {
+    let mut file = data.open()?;
+    let total = file.get_ref().metadata()?.len();
+    let mut chunker = Chunker::new(average);
+    let mut buf = vec![0; 1024 * 1024];
+    let mut done = 0;
+    loop {
+        let n = file.read(&mut buf)?;
+        if n == 0 {
+            break;
+        }
+        chunker.update(&buf[..n]);
+        done += n as u64;
+        progress.send_replace(Some(Progress { done, total }));
+    }
+    Ok(chunker.finish())
+}
+
+impl DedupAnalyzer {
+    pub async fn process(&self, data: DataFile, progress: &ProgressSender) -> Report {
+        let (average, progress) = (self.chunk_size, progress.clone());
+        match spawn_blocking(move || chunk_file(&data, average, &progress)).await {
+            Ok(Ok(chunks)) => Report {
+                note: Some(format!("{} chunks", chunks.len())),
+                outcome: Outcome::Chunks(chunks),
+            },
+            Ok(Err(e)) => Report::retry(format!("failed to read file: {e}")),
+            Err(e) => Report::retry(format!("chunker panicked: {e}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::hash_file;
+
+    use super::Chunker;
+
+    fn chunks(data: &[u8]) -> Vec<common::data::Chunk> {
+        let mut chunker = Chunker::new(1024);
+        // In uneven pieces, so some chunks span several.
+        for piece in data.chunks(1000) {
+            chunker.update(piece);
+        }
+        chunker.finish()
+    }
+
+    #[test]
+    fn content_defined() {
+        let mut x = 1u64;
+        let data: Vec<u8> = (0..200_000)
+            .map(|_| {
+                x ^= x << 13;
+                x ^= x >> 7;
+                x ^= x << 17;
+                x as u8
+            })
+            .collect();
+        let before = chunks(&data);
+        assert_eq!(before.iter().map(|c| c.size).sum::<u64>(), 200_000);
+        assert!(before[..before.len() - 1]
+            .iter()
+            .all(|c| (256..=4096).contains(&c.size)));
+        assert_eq!(
+            before[0].hash,
+            hash_file(&data[..before[0].size as usize]).unwrap()
+        );
+
+        // Inserting something near the start only changes the chunks around it.
+        let mut after = data.clone();
+        after.splice(5000..5000, *b"inserted");
+        let after = chunks(&after);
+        let shared = after.iter().filter(|c| before.contains(c)).count();
+        assert!(shared + 3 >= before.len(), "{shared} of {}", before.len());
+
+        assert!(chunks(b"").is_empty());
+    }
+}
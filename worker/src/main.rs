@@ -0,0 +1,269 @@
+//! Picks items up from the database and runs them through the processors configured for each
+//! stage of their pipeline.
+
+use std::{
+    collections::HashMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use common::{
+    crypt::MasterKey,
+    db::{migrations, DatabaseHandle, Status, UploadRow},
+    registry::Registry,
+};
+use log::{info, warn};
+use tokio::sync::{watch, Semaphore};
+
+mod bundle;
+mod checksum;
+mod command;
+mod config;
+mod dedup;
+mod megawarc;
+mod processor;
+mod reassemble;
+#[cfg(feature = "warc")]
+mod recompress;
+#[cfg(feature = "warc")]
+mod warc;
+#[cfg(feature = "wasm")]
+mod wasm;
+
+use config::{Limits, Stage, WorkerConfig};
+use processor::{Context, Outcome, Report};
+
+/// How long to wait before looking again when there's nothing to do.
+const POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// How often to touch an item while it's being processed. This has to be well under the 60
+/// seconds after which check_out considers a claim stale.
+const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(20);
+/// How often new progress is written to the row.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(2);
+
+/// Processes one item that has been checked out.
+async fn process(
+    pool: &Arc<DatabaseHandle>,
+    registry: &Registry,
+    stage: &Stage,
+    limits: &Limits,
+    master_key: Option<&MasterKey>,
+    mut row: UploadRow,
+) {
+    let Some(pipeline) = registry.pipeline(row.pipeline()) else {
+        warn!("{} is on unknown pipeline {}", row.id(), row.pipeline());
+        return;
+    };
+    info!("processing {} ({})", row.id(), row.status());
+
+    // Keep the claim alive while the processor runs, and pass its progress on to the row.
+    let (progress, mut progress_rx) = watch::channel(None);
+    let heartbeat = {
+        let pool = pool.clone();
+        let mut row = row.clone();
+        tokio::spawn(async move {
+            let mut timer = tokio::time::interval(PROGRESS_INTERVAL);
+            timer.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            let mut last_write = Instant::now();
+            loop {
+                timer.tick().await;
+                let result = if progress_rx.has_changed().unwrap_or(false) {
+                    let Some(p) = *progress_rx.borrow_and_update() else {
+                        continue;
+                    };
+                    row.set_progress(&pool, p).await
+                } else if last_write.elapsed() >= HEARTBEAT_INTERVAL {
+                    row.enter(&pool).await
+                } else {
+                    continue;
+                };
+                last_write = Instant::now();
+                if let Err(e) = result {
+                    warn!("failed to heartbeat {}: {e}", row.id());
+                }
+            }
+        })
+    };
+    let ctx = Context {
+        pool,
+        pipeline: &pipeline,
+        master_key,
+    };
+    let report = match row.data_file(master_key) {
+        Ok(data) => {
+            stage
+                .processor
+                .process(&row, data, &ctx, limits, &Arc::new(progress))
+                .await
+        }
+        Err(e) => Report::retry(format!("can't read the upload: {e}")),
+    };
+    heartbeat.abort();
+
+    let result = match report.outcome {
+        Outcome::Advance(None) => row.advance(pool, &pipeline).await,
+        Outcome::Advance(Some(status)) => row.transition(pool, &pipeline, status).await,
+        Outcome::Packed(packed) => match row.set_packed(pool, packed).await {
+            Ok(()) => row.advance(pool, &pipeline).await,
+            Err(e) => Err(e),
+        },
+        Outcome::Members(members) => match row.set_members(pool, members).await {
+            Ok(()) => row.advance(pool, &pipeline).await,
+            Err(e) => Err(e),
+        },
+        Outcome::Derived(derived) => match row.set_derived(pool, derived).await {
+            Ok(()) => row.advance(pool, &pipeline).await,
+            Err(e) => Err(e),
+        },
+        Outcome::Verified(digest) => match row.set_digest(pool, digest).await {
+            Ok(()) => row.advance(pool, &pipeline).await,
+            Err(e) => Err(e),
+        },
+        Outcome::Chunks(chunks) => match row.record_dedup(pool, &chunks).await {
+            Ok(dedup) => {
+                info!(
+                    "{} had {:.1}% of its bytes in earlier uploads",
+                    row.id(),
+                    dedup.ratio * 100.0
+                );
+                row.advance(pool, &pipeline).await
+            }
+            Err(e) => Err(e),
+        },
+        // The rest of the pipeline happens to the upload it was assembled into.
+        Outcome::Assembled(_) => row.change_status(pool, Status::Finished).await,
+        Outcome::Wait(delay) => row.postpone(pool, delay).await,
+        Outcome::Fail(error) => row.transition(pool, &pipeline, Status::Error(error)).await,
+        Outcome::Retry if row.attempts() + 1 >= stage.retry.max_attempts => {
+            warn!(
+                "{} failed {} times, dead-lettering it",
+                row.id(),
+                row.attempts() + 1
+            );
+            let error = report.note.clone().unwrap_or_default();
+            row.dead_letter(pool, error).await
+        }
+        Outcome::Retry => {
+            let delay = stage.retry.delay(row.attempts() + 1);
+            let error = report.note.clone().unwrap_or_default();
+            info!("{} failed, retrying in {delay}s: {error}", row.id());
+            row.schedule_retry(pool, error, delay).await
+        }
+    };
+    match result {
+        Ok(()) => info!("{} is now {}", row.id(), row.status()),
+        Err(e) => warn!("failed to update {}: {e}", row.id()),
+    }
+    if report.note.is_some() {
+        row.record_manifest_note(report.note).await;
+    }
+}
+
+/// Works on one stage forever. `permits` is shared by every stage of the pipeline, so that it
+/// never has more than its concurrency limit of items in progress.
+async fn work(
+    pool: Arc<DatabaseHandle>,
+    registry: Arc<Registry>,
+    stage: Arc<Stage>,
+    limits: Arc<Limits>,
+    master_key: Option<Arc<MasterKey>>,
+    permits: Arc<Semaphore>,
+) {
+    loop {
+        let permit = permits.acquire().await.unwrap();
+        // Pick up fresh items first, then ones whose worker seems to have died.
+        let mut row = UploadRow::check_out(
+            &pool,
+            stage.project.clone(),
+            stage.pipeline.clone(),
+            stage.status.clone(),
+            false,
+        )
+        .await;
+        if let Ok(None) = row {
+            row = UploadRow::check_out(
+                &pool,
+                stage.project.clone(),
+                stage.pipeline.clone(),
+                stage.status.clone(),
+                true,
+            )
+            .await;
+        }
+        match row {
+            Ok(Some(row)) => {
+                process(
+                    &pool,
+                    &registry,
+                    &stage,
+                    &limits,
+                    master_key.as_deref(),
+                    row,
+                )
+                .await
+            }
+            Ok(None) => {
+                drop(permit);
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+            Err(e) => {
+                drop(permit);
+                warn!(
+                    "failed to check out from {}/{}: {e}",
+                    stage.project, stage.pipeline
+                );
+                tokio::time::sleep(POLL_INTERVAL).await;
+            }
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> std::io::Result<()> {
+    env_logger::init();
+    let config = WorkerConfig::from_env()?;
+    let registry = Arc::new(Registry::from_env()?);
+    let master_key = MasterKey::from_env()?.map(Arc::new);
+    let pool = Arc::new(DatabaseHandle::new().map_err(std::io::Error::other)?);
+    let applied = migrations::migrate(&pool)
+        .await
+        .map_err(|e| std::io::Error::other(format!("failed to migrate the database: {e}")))?;
+    for version in applied {
+        info!(
+            "applied migration {version}: {}",
+            migrations::MIGRATIONS[version as usize - 1]
+        );
+    }
+    tokio::spawn({
+        let pool = pool.clone();
+        async move { pool.keep_healthy().await }
+    });
+    let mut permits: HashMap<String, Arc<Semaphore>> = HashMap::new();
+    let mut tasks = Vec::new();
+    for stage in config.stages.iter().cloned() {
+        let limits = Arc::new(config.limits(&stage.pipeline));
+        let sem = permits
+            .entry(stage.pipeline.clone())
+            .or_insert_with(|| Arc::new(Semaphore::new(limits.concurrency())))
+            .clone();
+        info!(
+            "working on {} in {}/{}",
+            stage.status, stage.project, stage.pipeline
+        );
+        let stage = Arc::new(stage);
+        for _ in 0..limits.concurrency() {
+            tasks.push(tokio::spawn(work(
+                pool.clone(),
+                registry.clone(),
+                stage.clone(),
+                limits.clone(),
+                master_key.clone(),
+                sem.clone(),
+            )));
+        }
+    }
+    for task in tasks {
+        task.await?;
+    }
+    Ok(())
+}
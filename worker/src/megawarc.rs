@@ -0,0 +1,41 @@
+//! Packs items into a megawarc. WARCs are appended to its `.warc.gz` or `.warc.zst` and everything
+//! else goes in its tar, and either way the item is added to its index. Several workers can pack
+//! into the same megawarc at once.
+
+use std::path::PathBuf;
+
+use common::{crypt::DataFile, data::Packed, db::UploadRow, helpers::Megawarc};
+use serde::{Deserialize, Serialize};
+use tokio::task::spawn_blocking;
+
+use crate::processor::{Outcome, Report};
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct MegawarcPacker {
+    /// The directory the megawarc is written to.
+    pub dir: PathBuf,
+    /// The megawarc's name, without the extensions.
+    pub name: String,
+}
+
+impl MegawarcPacker {
+    pub async fn process(&self, row: &UploadRow, data: DataFile) -> Report {
+        let megawarc = Megawarc::new(&self.dir, &self.name);
+        let row = row.clone();
+        let result = spawn_blocking(move || megawarc.append(&row, data.open()?)).await;
+        match result {
+            Ok(Ok(target)) => Report {
+                note: Some(format!(
+                    "packed into {} ({:?} at {}, {} bytes)",
+                    self.name, target.container, target.offset, target.size
+                )),
+                outcome: Outcome::Packed(Packed {
+                    megawarc: self.name.clone(),
+                    target,
+                }),
+            },
+            Ok(Err(e)) => Report::retry(format!("failed to pack into {}: {e}", self.name)),
+            Err(e) => Report::retry(format!("packer panicked: {e}")),
+        }
+    }
+}
@@ -0,0 +1,151 @@
+//! Processors do the actual work of a stage.
+
+use std::sync::Arc;
+
+use common::{
+    crypt::{DataFile, MasterKey},
+    data::{BundleMember, Chunk, Digest, File, Packed, Progress, Status, UploadError},
+    db::{DatabaseHandle, UploadRow},
+    pipeline::Pipeline,
+};
+use serde::{Deserialize, Serialize};
+use tokio::sync::watch;
+
+#[cfg(feature = "wasm")]
+use crate::wasm::WasmProcessor;
+use crate::{
+    bundle::BundleInspector, checksum::ChecksumVerifier, command::CommandProcessor, config::Limits,
+    dedup::DedupAnalyzer, megawarc::MegawarcPacker, reassemble::ShardAssembler,
+};
+#[cfg(feature = "warc")]
+use crate::{recompress::Recompressor, warc::WarcVerifier};
+
+/// Where processors report their progress. The worker writes it to the row every so often.
+pub type ProgressSender = Arc<watch::Sender<Option<Progress>>>;
+
+/// What processors can use besides the item itself.
+pub struct Context<'a> {
+    pub pool: &'a DatabaseHandle,
+    /// The item's pipeline.
+    pub pipeline: &'a Pipeline,
+    /// For uploads that are encrypted at rest. See `common::crypt`.
+    pub master_key: Option<&'a MasterKey>,
+}
+
+/// What should happen to an item after it has been processed.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// Move on to the next stage of the pipeline, or to a specific status if one is given.
+    Advance(Option<Status>),
+    /// Move on to the next stage, recording where the item was packed.
+    Packed(Packed),
+    /// Move on to the next stage, recording the files found in the item.
+    Members(Vec<BundleMember>),
+    /// Move on to the next stage, recording the file derived from the item, which later stages
+    /// work on instead.
+    Derived(File),
+    /// Move on to the next stage, recording the digest the item's data was verified against.
+    Verified(Digest),
+    /// Move on to the next stage, after looking the item's chunks up in the chunk index.
+    Chunks(Vec<Chunk>),
+    /// Finish the item, because its data is now part of the upload with this ID.
+    Assembled(String),
+    /// Put the item back and look at it again after this many seconds, without counting it as a
+    /// failed attempt.
+    Wait(u64),
+    /// Give up on the item.
+    Fail(UploadError),
+    /// Something went wrong that might not happen next time. The item is tried again later, up
+    /// to the stage's retry limit.
+    Retry,
+}
+
+/// The result of processing an item.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Report {
+    pub outcome: Outcome,
+    /// Recorded in the item's history, if present.
+    pub note: Option<String>,
+}
+
+impl Report {
+    pub fn retry(error: String) -> Self {
+        Self {
+            outcome: Outcome::Retry,
+            note: Some(error),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ProcessorConfig {
+    /// Runs an external command on the file.
+    Command(CommandProcessor),
+    /// Checks the file's SHA-256.
+    Checksum(ChecksumVerifier),
+    /// Checks the files in a bundle or tar archive.
+    Bundle(BundleInspector),
+    /// Works out how much of the file earlier uploads already had.
+    Dedup(DedupAnalyzer),
+    /// Puts files that were uploaded in shards back together.
+    Reassemble(ShardAssembler),
+    /// Appends the file to a megawarc.
+    Megawarc(MegawarcPacker),
+    /// Checks that the file is a structurally sound WARC.
+    #[cfg(feature = "warc")]
+    Warc(WarcVerifier),
+    /// Recompresses a `.warc.gz` file.
+    #[cfg(feature = "warc")]
+    Recompress(Recompressor),
+    /// Runs a sandboxed WASM plugin on the file.
+    #[cfg(feature = "wasm")]
+    Wasm(WasmProcessor),
+}
+
+impl ProcessorConfig {
+    /// Whether the processor still does its job when the client encrypted the data, which it
+    /// only ever sees as ciphertext. The others fail those uploads rather than pass them unchecked.
+    fn reads_encrypted(&self) -> bool {
+        match self {
+            ProcessorConfig::Bundle(_) => false,
+            #[cfg(feature = "warc")]
+            ProcessorConfig::Warc(_) | ProcessorConfig::Recompress(_) => false,
+            _ => true,
+        }
+    }
+
+    /// Processes the item, whose current file is `data`.
+    pub async fn process(
+        &self,
+        row: &UploadRow,
+        data: DataFile,
+        ctx: &Context<'_>,
+        limits: &Limits,
+        progress: &ProgressSender,
+    ) -> Report {
+        if row.metadata().encryption.is_some() && !self.reads_encrypted() {
+            return Report {
+                outcome: Outcome::Fail(UploadError::Verify),
+                note: Some(
+                    "the client encrypted the upload, so this stage can't look inside it"
+                        .to_string(),
+                ),
+            };
+        }
+        match self {
+            ProcessorConfig::Command(c) => c.process(row, data, limits, progress).await,
+            ProcessorConfig::Checksum(c) => c.process(row, data).await,
+            ProcessorConfig::Bundle(b) => b.process(row, data, progress).await,
+            ProcessorConfig::Dedup(d) => d.process(data, progress).await,
+            ProcessorConfig::Reassemble(r) => r.process(row, data, ctx, progress).await,
+            ProcessorConfig::Megawarc(m) => m.process(row, data).await,
+            #[cfg(feature = "warc")]
+            ProcessorConfig::Warc(w) => w.process(row, data, progress).await,
+            #[cfg(feature = "warc")]
+            ProcessorConfig::Recompress(r) => r.process(row, data, progress).await,
+            #[cfg(feature = "wasm")]
+            ProcessorConfig::Wasm(w) => w.process(row, data, limits, progress).await,
+        }
+    }
+}
@@ -0,0 +1,419 @@
+//! Puts files that the client split into shards (see `common::data::Shard`) back together. Each
+//! shard waits at this stage until every shard of its file has been verified and got here. Then
+//! the first shard's upload gets the whole file as its derived file, which later stages work on.
+//! The other shards wait until it has finished, and then finish too, since their data is in it;
+//! if it fails instead, they're still there to try again with.
+//!
+//! The whole file is checked against the size and SHA-256 the client gave for it. Shards that
+//! were uploaded to another server instance (see `--shard-endpoint` in the client) are fetched
+//! from it with `GET /upload/{uuid}/data?original=true`, using the admin token in
+//! `BULLSEYE_ADMIN_TOKEN`; the others are read from the data directory. If the shards are
+//! encrypted at rest, the whole file is encrypted with the first shard's key. Uploads that aren't
+//! shards go straight through.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::{self, BufWriter, Read, Write},
+    path::Path,
+    time::SystemTime,
+};
+
+use common::{
+    crypt::{DataFile, MasterKey},
+    data::{self, Progress, Shard, Status, UploadError},
+    db::UploadRow,
+    pipeline::Pipeline,
+    StreamHasher,
+};
+use serde::{Deserialize, Serialize};
+use tokio::task::spawn_blocking;
+
+use crate::processor::{Context, Outcome, ProgressSender, Report};
+
+/// Where the admin token is, for fetching shards from other server instances. The same as the
+/// server's.
+const ADMIN_TOKEN_ENV: &str = "BULLSEYE_ADMIN_TOKEN";
+
+fn default_poll() -> u64 {
+    60
+}
+
+fn default_timeout() -> u64 {
+    7 * 24 * 60 * 60
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct ShardAssembler {
+    /// How often to look at the other shards while they aren't all here, in seconds.
+    #[serde(default = "default_poll")]
+    pub poll: u64,
+    /// How long after it was uploaded a shard waits for the others before it fails, in seconds.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+/// What the assembler needs to know about a shard's upload.
+#[derive(Clone, Debug)]
+struct Member {
+    id: String,
+    shard: Shard,
+    size: u64,
+    /// How far through the pipeline it is, or None if it's not on its way through it (because it
+    /// failed, for example).
+    stage: Option<usize>,
+    /// Whether it has got all the way through the pipeline.
+    finished: bool,
+}
+
+impl Member {
+    fn new(row: &UploadRow, pipeline: &Pipeline) -> Option<Self> {
+        Some(Self {
+            id: row.id().clone(),
+            shard: row.shard()?.clone(),
+            size: row.file().size,
+            stage: pipeline.stages.iter().position(|s| s == row.status()),
+            finished: row.status() == &Status::Finished,
+        })
+    }
+}
+
+/// Where a shard's data is read from.
+enum Source {
+    /// The data directory.
+    Local(DataFile),
+    /// The server instance it was uploaded to, at this URL.
+    Remote(String),
+}
+
+impl Source {
+    fn new(row: &UploadRow, this: &UploadRow, master: Option<&MasterKey>) -> io::Result<Self> {
+        match row.node() {
+            Some(node) if row.node() != this.node() => Ok(Self::Remote(format!(
+                "{node}/upload/{}/data?original=true",
+                row.id()
+            ))),
+            _ => row.original_file(master).map(Self::Local),
+        }
+    }
+
+    /// Opens the data. This blocks.
+    fn open(&self, client: &reqwest::blocking::Client) -> io::Result<Box<dyn Read>> {
+        match self {
+            Self::Local(data) => Ok(Box::new(data.open()?)),
+            Self::Remote(url) => {
+                let mut request = client.get(url);
+                if let Some(token) = std::env::var(ADMIN_TOKEN_ENV).ok().filter(|t| !t.is_empty()) {
+                    request = request.bearer_auth(token);
+                }
+                let response = request
+                    .send()
+                    .and_then(|r| r.error_for_status())
+                    .map_err(|e| io::Error::other(format!("fetching {url}: {e}")))?;
+                Ok(Box::new(response))
+            }
+        }
+    }
+}
+
+// Uploads are told apart by their IDs.
+impl PartialEq for Member {
+    fn eq(&self, other: &Self) -> bool {
+        self.id == other.id
+    }
+}
+
+impl Eq for Member {}
+
+/// What a shard should do.
+#[derive(Debug, PartialEq, Eq)]
+enum Plan {
+    /// Some of the shards aren't here yet.
+    Wait(Vec<u64>),
+    /// Put the file together from these shards, in order. This upload gets it.
+    Assemble(Vec<Member>),
+    /// Another upload is putting the file together, or has and hasn't finished yet.
+    Assembling(String),
+    /// Another upload has the whole file, and has finished.
+    Assembled(String),
+}
+
+/// Works out what `this` should do, from every shard of its file. Shards that failed don't count,
+/// and if a piece was uploaded more than once, the oldest upload of it is used.
+fn plan(this: &Member, group: &[Member]) -> Plan {
+    let mut chosen: BTreeMap<u64, &Member> = BTreeMap::new();
+    for m in group {
+        // Shards of another split of the file can't be mixed with this one.
+        if m.shard.count != this.shard.count || m.shard.whole != this.shard.whole {
+            continue;
+        }
+        if m.stage.is_none() || m.stage < this.stage {
+            continue;
+        }
+        chosen
+            .entry(m.shard.index)
+            .and_modify(|c| {
+                if m.id < c.id {
+                    *c = m;
+                }
+            })
+            .or_insert(m);
+    }
+    let missing: Vec<u64> = (0..this.shard.count)
+        .filter(|i| !chosen.contains_key(i))
+        .collect();
+    if !missing.is_empty() {
+        return Plan::Wait(missing);
+    }
+    let primary = chosen[&0];
+    if primary.id == this.id {
+        Plan::Assemble(chosen.into_values().cloned().collect())
+    } else if primary.finished {
+        Plan::Assembled(primary.id.clone())
+    } else {
+        Plan::Assembling(primary.id.clone())
+    }
+}
+
+/// Makes sure the shards cover the whole file, without gaps or overlaps.
+fn check_layout(shards: &[Member], whole: &data::File) -> Result<(), String> {
+    let mut offset = 0;
+    for m in shards {
+        if m.shard.offset != offset {
+            return Err(format!(
+                "shard {} ({}) starts at {}, not {offset}",
+                m.shard.index, m.id, m.shard.offset
+            ));
+        }
+        offset += m.size;
+    }
+    if offset != whole.size {
+        return Err(format!(
+            "the shards add up to {offset} bytes, not {}",
+            whole.size
+        ));
+    }
+    Ok(())
+}
+
+/// Concatenates the shards' data into `dest`, which is `this` upload's derived file, returning
+/// its SHA-256. This blocks.
+fn concatenate(
+    shards: &[(Source, u64)],
+    total: u64,
+    this: &DataFile,
+    dest: &Path,
+    progress: &ProgressSender,
+) -> io::Result<String> {
+    let mut tmp = dest.to_path_buf().into_os_string();
+    tmp.push(".tmp");
+    let mut out = this.derived_writer(BufWriter::new(File::create(&tmp)?))?;
+    let mut hasher = StreamHasher::default();
+    let mut buf = vec![0; 1024 * 1024];
+    let mut done = 0;
+    let client = reqwest::blocking::Client::builder()
+        .timeout(None)
+        .build()
+        .map_err(io::Error::other)?;
+    for (shard, size) in shards {
+        let mut file = shard.open(&client)?;
+        let start = done;
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            out.write_all(&buf[..n])?;
+            hasher.update(&buf[..n]);
+            done += n as u64;
+            progress.send_replace(Some(Progress { done, total }));
+        }
+        // A connection that dropped part of the way through looks like the end of the data.
+        if done - start != *size {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                format!("got {} bytes of a shard, not {size}", done - start),
+            ));
+        }
+    }
+    let file = out.into_inner().into_inner().map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    fs::rename(tmp, dest)?;
+    Ok(hasher.finish())
+}
+
+impl ShardAssembler {
+    pub async fn process(
+        &self,
+        row: &UploadRow,
+        data: DataFile,
+        ctx: &Context<'_>,
+        progress: &ProgressSender,
+    ) -> Report {
+        let Some(this) = Member::new(row, ctx.pipeline) else {
+            return Report {
+                outcome: Outcome::Advance(None),
+                note: None,
+            };
+        };
+        let rows = match UploadRow::list_shard_group(ctx.pool, this.shard.group.clone()).await {
+            Ok(rows) => rows,
+            Err(e) => return Report::retry(format!("failed to look up the other shards: {e}")),
+        };
+        let group: Vec<Member> = rows
+            .iter()
+            .filter_map(|r| Member::new(r, ctx.pipeline))
+            .collect();
+        let now = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let shards = match plan(&this, &group) {
+            Plan::Assemble(shards) => shards,
+            Plan::Assembled(id) => {
+                return Report {
+                    outcome: Outcome::Assembled(id.clone()),
+                    note: Some(format!("assembled into {id}")),
+                }
+            }
+            Plan::Wait(missing) if now > row.created() + self.timeout => {
+                let missing: Vec<String> = missing.iter().map(u64::to_string).collect();
+                return Report {
+                    outcome: Outcome::Fail(UploadError::Other),
+                    note: Some(format!(
+                        "shards {} of {} never arrived",
+                        missing.join(", "),
+                        this.shard.count
+                    )),
+                };
+            }
+            Plan::Wait(_) | Plan::Assembling(_) => {
+                return Report {
+                    outcome: Outcome::Wait(self.poll),
+                    note: None,
+                }
+            }
+        };
+
+        let whole = this.shard.whole.clone();
+        if let Err(e) = check_layout(&shards, &whole) {
+            return Report {
+                outcome: Outcome::Fail(UploadError::Verify),
+                note: Some(e),
+            };
+        }
+        // The shards' own data, even if an earlier stage derived something from them.
+        let files: io::Result<Vec<(Source, u64)>> = shards
+            .iter()
+            .filter_map(|m| rows.iter().find(|r| r.id() == &m.id))
+            .map(|r| Ok((Source::new(r, row, ctx.master_key)?, r.file().size)))
+            .collect();
+        let files = match files {
+            Ok(files) => files,
+            Err(e) => return Report::retry(format!("can't read the shards: {e}")),
+        };
+        let dest = Path::new(row.dir()).join(data::derived_name(row.id()));
+        let (progress, target) = (progress.clone(), dest.clone());
+        let hash = match spawn_blocking(move || {
+            concatenate(&files, whole.size, &data, &target, &progress)
+        })
+        .await
+        {
+            Ok(Ok(hash)) => hash,
+            Ok(Err(e)) => return Report::retry(format!("failed to put the shards together: {e}")),
+            Err(e) => return Report::retry(format!("assembler panicked: {e}")),
+        };
+        if hash != whole.hash {
+            let _ = fs::remove_file(&dest);
+            return Report {
+                outcome: Outcome::Fail(UploadError::Verify),
+                note: Some(format!(
+                    "the shards put together have SHA-256 {hash}, not {}",
+                    whole.hash
+                )),
+            };
+        }
+        Report {
+            note: Some(format!(
+                "put {} back together from {} shards",
+                whole.name, this.shard.count
+            )),
+            outcome: Outcome::Derived(whole),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use common::data::{File, Shard};
+
+    use super::{check_layout, plan, Member, Plan};
+
+    fn member(id: &str, index: u64, stage: Option<usize>) -> Member {
+        Member {
+            id: id.to_string(),
+            shard: Shard {
+                group: "g".to_string(),
+                index,
+                count: 3,
+                offset: index * 10,
+                whole: File {
+                    hash: "abc".to_string(),
+                    name: "big.warc.gz".to_string(),
+                    size: 25,
+                },
+            },
+            size: if index == 2 { 5 } else { 10 },
+            stage,
+            finished: false,
+        }
+    }
+
+    #[test]
+    fn plans() {
+        let a = member("a", 0, Some(1));
+        let b = member("b", 1, Some(1));
+        let c = member("c", 2, Some(1));
+
+        // A missing piece, and one that failed.
+        let failed = member("0", 2, None);
+        let group = [a.clone(), b.clone(), failed.clone()];
+        assert_eq!(plan(&b, &group), Plan::Wait(vec![2]));
+        // One still being verified.
+        let early = member("0", 2, Some(0));
+        assert_eq!(
+            plan(&b, &[a.clone(), b.clone(), early]),
+            Plan::Wait(vec![2])
+        );
+
+        let group = [c.clone(), a.clone(), b.clone(), failed];
+        assert_eq!(
+            plan(&a, &group),
+            Plan::Assemble(vec![a.clone(), b.clone(), c.clone()])
+        );
+        assert_eq!(plan(&b, &group), Plan::Assembling("a".to_string()));
+        check_layout(&[a.clone(), b.clone(), c.clone()], &a.shard.whole).unwrap();
+        assert!(check_layout(&[a.clone(), c.clone(), b.clone()], &a.shard.whole).is_err());
+        assert!(check_layout(&[a.clone(), b.clone()], &a.shard.whole).is_err());
+
+        // The first shard moved on with the whole file, but the others wait until it's finished,
+        // in case it fails.
+        let mut done = member("a", 0, Some(2));
+        let group = [done.clone(), b.clone(), c.clone()];
+        assert_eq!(plan(&b, &group), Plan::Assembling("a".to_string()));
+        done.finished = true;
+        let group = [done, b.clone(), c.clone()];
+        assert_eq!(plan(&b, &group), Plan::Assembled("a".to_string()));
+
+        // An older upload of the same piece is used instead.
+        let older = member("1", 0, Some(1));
+        let group = [a.clone(), older.clone(), b.clone(), c.clone()];
+        assert_eq!(plan(&a, &group), Plan::Assembling("1".to_string()));
+        assert!(matches!(plan(&older, &group), Plan::Assemble(_)));
+
+        // Shards of a different split don't count.
+        let mut other = member("d", 2, Some(1));
+        other.shard.count = 4;
+        assert_eq!(plan(&a, &[a.clone(), b, other]), Plan::Wait(vec![2]));
+    }
+}
@@ -0,0 +1,197 @@
+//! Recompresses `.warc.gz` uploads, either to `.warc.zst` or to gzip at another level, to save
+//! space in long-term storage. Each gzip member is recompressed on its own, so a WARC with a member
+//! per record still has a zstd frame or gzip member per record afterwards.
+//!
+//! The result is kept next to the upload as its derived file, and its name, size and SHA-256 are
+//! recorded on the row next to the original's. Later stages, like the packer, use it instead of
+//! the upload. Anything other than a `.warc.gz` is left as it is.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufRead, BufReader, BufWriter, Seek, Write},
+    path::Path,
+};
+
+use common::{
+    crypt::DataFile,
+    data::{self, Progress},
+    db::UploadRow,
+    StreamHasher,
+};
+use flate2::{bufread::GzDecoder, write::GzEncoder, Compression};
+use serde::{Deserialize, Serialize};
+use tokio::task::spawn_blocking;
+
+use crate::processor::{Outcome, ProgressSender, Report};
+
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Format {
+    Zstd,
+    Gzip,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct Recompressor {
+    pub format: Format,
+    /// The compression level. Defaults to 19 for zstd and 9 for gzip.
+    #[serde(default)]
+    pub level: Option<i32>,
+}
+
+/// Hashes and counts what goes through it.
+struct Hashing<W> {
+    inner: W,
+    hasher: StreamHasher,
+    size: u64,
+}
+
+impl<W: Write> Write for Hashing<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.size += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Recompresses `src` into `dest`, its upload's derived file, returning the new file's SHA-256
+/// and size. This blocks.
+fn recompress(
+    src: &DataFile,
+    dest: &Path,
+    format: Format,
+    level: Option<i32>,
+    progress: &ProgressSender,
+) -> io::Result<(String, u64)> {
+    let total = fs::metadata(&src.path)?.len();
+    let mut reader = BufReader::new(src.open()?);
+    let mut tmp = dest.to_path_buf().into_os_string();
+    tmp.push(".tmp");
+    let mut out = Hashing {
+        inner: src.derived_writer(BufWriter::new(File::create(&tmp)?))?,
+        hasher: StreamHasher::default(),
+        size: 0,
+    };
+    while !reader.fill_buf()?.is_empty() {
+        let mut member = GzDecoder::new(&mut reader);
+        match format {
+            Format::Zstd => {
+                let mut encoder = zstd::Encoder::new(&mut out, level.unwrap_or(19))?;
+                io::copy(&mut member, &mut encoder)?;
+                encoder.finish()?;
+            }
+            Format::Gzip => {
+                let level = Compression::new(level.unwrap_or(9).clamp(0, 9) as u32);
+                let mut encoder = GzEncoder::new(&mut out, level);
+                io::copy(&mut member, &mut encoder)?;
+                encoder.finish()?;
+            }
+        }
+        progress.send_replace(Some(Progress {
+            done: reader.stream_position()?,
+            total,
+        }));
+    }
+    let file = out
+        .inner
+        .into_inner()
+        .into_inner()
+        .map_err(|e| e.into_error())?;
+    file.sync_all()?;
+    fs::rename(tmp, dest)?;
+    Ok((out.hasher.finish(), out.size))
+}
+
+impl Recompressor {
+    pub async fn process(
+        &self,
+        row: &UploadRow,
+        data: DataFile,
+        progress: &ProgressSender,
+    ) -> Report {
+        let original = row.current_file().clone();
+        let Some(stem) = original.name.strip_suffix(".warc.gz") else {
+            return Report {
+                outcome: Outcome::Advance(None),
+                note: Some("not a .warc.gz, so it was left as it is".to_string()),
+            };
+        };
+        let name = match self.format {
+            Format::Zstd => format!("{stem}.warc.zst"),
+            Format::Gzip => original.name.clone(),
+        };
+        let dest = Path::new(row.dir()).join(data::derived_name(row.id()));
+        let (format, level, progress) = (self.format, self.level, progress.clone());
+        let result =
+            spawn_blocking(move || recompress(&data, &dest, format, level, &progress)).await;
+        let (hash, size) = match result {
+            Ok(Ok(result)) => result,
+            Ok(Err(e)) => return Report::retry(format!("failed to recompress: {e}")),
+            Err(e) => return Report::retry(format!("recompressor panicked: {e}")),
+        };
+        Report {
+            note: Some(format!(
+                "recompressed to {name}: {} bytes, down from {}",
+                size, original.size
+            )),
+            outcome: Outcome::Derived(data::File { hash, name, size }),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        sync::Arc,
+    };
+
+    use common::hash_file;
+    use flate2::{read::MultiGzDecoder, write::GzEncoder, Compression};
+
+    use super::{recompress, DataFile, Format};
+
+    #[test]
+    fn members() {
+        let dir = std::env::temp_dir().join(format!("bullseye-recompress-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let mut data = Vec::new();
+        for record in ["one", "two"] {
+            let mut e = GzEncoder::new(Vec::new(), Compression::fast());
+            e.write_all(record.as_bytes()).unwrap();
+            data.extend(e.finish().unwrap());
+        }
+        std::fs::write(dir.join("in"), &data).unwrap();
+        let (tx, _rx) = tokio::sync::watch::channel(None);
+        let tx = Arc::new(tx);
+        let input = DataFile::plain(dir.join("in"));
+
+        let (hash, size) = recompress(&input, &dir.join("out"), Format::Zstd, None, &tx).unwrap();
+        let out = std::fs::read(dir.join("out")).unwrap();
+        assert_eq!(
+            (hash, size),
+            (hash_file(&out[..]).unwrap(), out.len() as u64)
+        );
+        // A frame per member.
+        let first = zstd::decode_all(&out[..]).unwrap();
+        assert_eq!(first, b"onetwo");
+        let frame = zstd::zstd_safe::find_frame_compressed_size(&out).unwrap();
+        assert_eq!(zstd::decode_all(&out[..frame]).unwrap(), b"one");
+
+        recompress(&input, &dir.join("out"), Format::Gzip, Some(9), &tx).unwrap();
+        let mut text = String::new();
+        MultiGzDecoder::new(&std::fs::read(dir.join("out")).unwrap()[..])
+            .read_to_string(&mut text)
+            .unwrap();
+        assert_eq!(text, "onetwo");
+
+        std::fs::write(dir.join("in"), b"not gzip").unwrap();
+        recompress(&input, &dir.join("out"), Format::Zstd, None, &tx).unwrap_err();
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,297 @@
+//! Checks that WARCs are structurally sound: that every gzip member or zstd frame decompresses,
+//! that every record has a valid header, and that its block is as long as its Content-Length says.
+//!
+//! When a file is broken, the item fails with `FAILED_VERIFY`, and its history gets a JSON report
+//! like `{"records": 12, "offset": 34567, "error": "record 12: missing Content-Length"}`, where
+//! `offset` is where the broken gzip member or zstd frame starts in the file.
+
+use std::io::{self, BufRead, BufReader, Read, Seek};
+
+use common::{
+    crypt::DataFile,
+    data::{Progress, UploadError},
+    db::UploadRow,
+    warc::{decode_dictionary, is_warc_zst, read_dictionary_frame},
+};
+use flate2::bufread::GzDecoder;
+use serde::{Deserialize, Serialize};
+use tokio::task::spawn_blocking;
+
+use crate::processor::{Outcome, ProgressSender, Report};
+
+/// Record headers longer than this are considered broken.
+const MAX_HEADER: u64 = 1024 * 1024;
+/// Headers every record must have.
+const REQUIRED_HEADERS: [&str; 4] = ["WARC-Type", "WARC-Record-ID", "WARC-Date", "Content-Length"];
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default)]
+pub struct WarcVerifier {}
+
+/// What's wrong with a file.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+struct Broken {
+    /// How many records were fine before the gzip member or zstd frame with the error.
+    records: u64,
+    /// Where the gzip member or zstd frame with the error starts, in the compressed file.
+    offset: u64,
+    error: String,
+}
+
+/// Keeps track of how far into the underlying file we are.
+struct Counting<R> {
+    inner: R,
+    pos: u64,
+}
+
+impl<R: Read> Read for Counting<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl<R: BufRead> BufRead for Counting<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.inner.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt as u64;
+        self.inner.consume(amt)
+    }
+}
+
+/// Checks the records in a decompressed stream, adding them to `records`.
+fn check_records<R: BufRead>(mut reader: R, records: &mut u64) -> Result<(), String> {
+    let mut line = Vec::new();
+    loop {
+        // The version line
+        line.clear();
+        let n = reader
+            .read_until(b'\n', &mut line)
+            .map_err(|e| e.to_string())?;
+        if n == 0 {
+            return Ok(());
+        }
+        if !(line == b"WARC/1.0\r\n" || line == b"WARC/1.1\r\n") {
+            return Err(format!(
+                "record {records}: doesn't start with a WARC version"
+            ));
+        }
+
+        let mut headers = Vec::new();
+        let mut header_len = 0;
+        loop {
+            line.clear();
+            let n = (&mut reader)
+                .take(MAX_HEADER - header_len)
+                .read_until(b'\n', &mut line)
+                .map_err(|e| e.to_string())?;
+            header_len += n as u64;
+            if n == 0 || !line.ends_with(b"\r\n") {
+                return Err(format!("record {records}: header is truncated or too long"));
+            }
+            if line == b"\r\n" {
+                break;
+            }
+            let line = String::from_utf8_lossy(&line);
+            let Some((name, value)) = line.split_once(':') else {
+                return Err(format!("record {records}: malformed header line"));
+            };
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+        let get = |name: &str| {
+            headers
+                .iter()
+                .find(|(n, _)| n.eq_ignore_ascii_case(name))
+                .map(|(_, v)| v.as_str())
+        };
+        for name in REQUIRED_HEADERS {
+            if get(name).is_none() {
+                return Err(format!("record {records}: missing {name}"));
+            }
+        }
+        let length: u64 = get("Content-Length")
+            .unwrap()
+            .parse()
+            .map_err(|_| format!("record {records}: bad Content-Length"))?;
+
+        let read = io::copy(&mut (&mut reader).take(length), &mut io::sink())
+            .map_err(|e| e.to_string())?;
+        if read != length {
+            return Err(format!(
+                "record {records}: block is {read} bytes, but Content-Length is {length}"
+            ));
+        }
+        let mut end = [0; 4];
+        reader
+            .read_exact(&mut end)
+            .map_err(|_| format!("record {records}: truncated after the block"))?;
+        if &end != b"\r\n\r\n" {
+            return Err(format!(
+                "record {records}: block is longer than its Content-Length"
+            ));
+        }
+        *records += 1;
+    }
+}
+
+/// Checks a file. This blocks.
+fn check_file(
+    data: DataFile,
+    name: &str,
+    progress: &ProgressSender,
+) -> io::Result<Result<u64, Broken>> {
+    let total = std::fs::metadata(&data.path)?.len();
+    let mut reader = Counting {
+        inner: BufReader::new(data.open()?),
+        pos: 0,
+    };
+    let mut records = 0;
+    let broken = |records, offset, error| {
+        Ok(Err(Broken {
+            records,
+            offset,
+            error,
+        }))
+    };
+
+    if name.ends_with(".warc.gz") {
+        while !reader.fill_buf()?.is_empty() {
+            let (offset, before) = (reader.pos, records);
+            let decoder = BufReader::new(GzDecoder::new(&mut reader));
+            if let Err(e) = check_records(decoder, &mut records) {
+                return broken(before, offset, e);
+            }
+            progress.send_replace(Some(Progress {
+                done: reader.pos,
+                total,
+            }));
+        }
+    } else if is_warc_zst(name) {
+        let dictionary = match read_dictionary_frame(&mut reader.inner)? {
+            Some(d) => match decode_dictionary(&d) {
+                Ok(d) => d,
+                Err(e) => return broken(0, 0, format!("bad dictionary: {e}")),
+            },
+            None => Vec::new(),
+        };
+        reader.pos = reader.inner.stream_position()?;
+        while !reader.fill_buf()?.is_empty() {
+            let (offset, before) = (reader.pos, records);
+            let decoder = match dictionary.is_empty() {
+                true => zstd::Decoder::with_buffer(&mut reader),
+                false => zstd::Decoder::with_dictionary(&mut reader, &dictionary),
+            }?;
+            let decoder = BufReader::new(decoder.single_frame());
+            if let Err(e) = check_records(decoder, &mut records) {
+                return broken(before, offset, e);
+            }
+            progress.send_replace(Some(Progress {
+                done: reader.pos,
+                total,
+            }));
+        }
+    } else if name.ends_with(".warc") {
+        if let Err(e) = check_records(&mut reader, &mut records) {
+            return broken(records, 0, e);
+        }
+    } else {
+        return broken(0, 0, "not a WARC".to_string());
+    }
+    if records == 0 {
+        return broken(0, 0, "file has no records".to_string());
+    }
+    Ok(Ok(records))
+}
+
+impl WarcVerifier {
+    pub async fn process(
+        &self,
+        row: &UploadRow,
+        data: DataFile,
+        progress: &ProgressSender,
+    ) -> Report {
+        let name = row.current_file().name.clone();
+        let progress = progress.clone();
+        match spawn_blocking(move || check_file(data, &name, &progress)).await {
+            Ok(Ok(Ok(records))) => Report {
+                outcome: Outcome::Advance(None),
+                note: Some(format!("{records} records")),
+            },
+            Ok(Ok(Err(broken))) => Report {
+                outcome: Outcome::Fail(UploadError::Verify),
+                note: Some(serde_json::to_string(&broken).unwrap()),
+            },
+            Ok(Err(e)) => Report::retry(format!("failed to read file: {e}")),
+            Err(e) => Report::retry(format!("verifier panicked: {e}")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Write, sync::Arc};
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::{check_file, check_records, DataFile};
+
+    fn record(block: &str, length: usize) -> String {
+        format!(
+            "WARC/1.1\r\nWARC-Type: resource\r\nWARC-Record-ID: <urn:uuid:1>\r\n\
+             WARC-Date: 2024-01-01T00:00:00Z\r\nContent-Length: {length}\r\n\r\n{block}\r\n\r\n"
+        )
+    }
+
+    fn check(data: &str) -> Result<u64, String> {
+        let mut records = 0;
+        check_records(data.as_bytes(), &mut records).map(|()| records)
+    }
+
+    #[test]
+    fn test_records() {
+        let good = record("hello", 5) + &record("", 0);
+        assert_eq!(check(&good), Ok(2));
+        assert!(check(&record("hello", 4)).unwrap_err().contains("longer"));
+        assert!(check(&record("hello", 6)).is_err());
+        assert!(check(&record("hello", 500)).unwrap_err().contains("500"));
+        assert!(check(&record("", 0).replace("WARC-Date", "Date"))
+            .unwrap_err()
+            .contains("WARC-Date"));
+        assert!(check("<html></html>").is_err());
+    }
+
+    #[test]
+    fn test_gzip() {
+        let path =
+            std::env::temp_dir().join(format!("bullseye-warc-{}.warc.gz", std::process::id()));
+        let member = |block: &str| {
+            let mut e = GzEncoder::new(Vec::new(), Compression::fast());
+            e.write_all(record(block, 3).as_bytes()).unwrap();
+            e.finish().unwrap()
+        };
+        let mut data = member("one");
+        let second = data.len() as u64;
+        data.extend(member("two"));
+        let name = path.file_name().unwrap().to_str().unwrap().to_string();
+        let (tx, rx) = tokio::sync::watch::channel(None);
+        let tx = Arc::new(tx);
+        std::fs::write(&path, &data).unwrap();
+        assert_eq!(
+            check_file(DataFile::plain(&path), &name, &tx).unwrap(),
+            Ok(2)
+        );
+        assert_eq!(rx.borrow().unwrap().done, data.len() as u64);
+
+        // Break the second member.
+        data.truncate(data.len() - 4);
+        std::fs::write(&path, &data).unwrap();
+        let broken = check_file(DataFile::plain(&path), &name, &tx)
+            .unwrap()
+            .unwrap_err();
+        assert_eq!((broken.records, broken.offset), (1, second));
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,313 @@
+//! Runs sandboxed WASM modules as processors.
+//!
+//! A plugin module must export:
+//! - `memory`
+//! - `alloc(len: u32) -> u32`, returning a pointer to `len` free bytes
+//! - `process(ptr: u32, len: u32) -> u64`, which gets the item's row as JSON and returns a
+//!   pointer to its output in the upper 32 bits and the output's length in the lower 32 bits
+//!
+//! It may import `bullseye.read(offset: u64, ptr: u32, len: u32) -> i64` to read the item's data.
+//! This returns the number of bytes read (0 at the end of the file), or -1 on error. It may also
+//! import `bullseye.progress(done: u64, total: u64)` to report its progress. Plugins can't do
+//! anything else to the outside world.
+//!
+//! The output is JSON, for example `{"outcome": "advance"}`, `{"outcome": "advance", "status":
+//! "PACKING"}` or `{"outcome": "fail", "error": "FAILED_VERIFY", "note": "bad record at 1234"}`.
+//! `{"outcome": "retry", "note": "..."}` asks for the item to be tried again later. Traps,
+//! running out of fuel and bad output are also retried.
+//!
+//! The module is reloaded whenever the file changes, so plugins can be updated without
+//! restarting the worker.
+
+use std::{
+    fmt,
+    fs::{self, File},
+    io::{Read, Seek, SeekFrom},
+    path::PathBuf,
+    sync::{Arc, Mutex, OnceLock},
+    time::SystemTime,
+};
+
+use common::{
+    crypt::{DataFile, Decrypting},
+    data::{Progress, Status, UploadError},
+    db::UploadRow,
+};
+use serde::{Deserialize, Serialize};
+use tokio::task::spawn_blocking;
+use wasmtime::{Caller, Config, Engine, Linker, Module, Store, StoreLimits, StoreLimitsBuilder};
+
+use crate::{
+    config::Limits,
+    processor::{Outcome, ProgressSender, Report},
+};
+
+/// Default for WasmProcessor::fuel.
+const DEFAULT_FUEL: u64 = 10_000_000_000;
+/// Default for WasmProcessor::max_memory.
+const DEFAULT_MAX_MEMORY: usize = 256 * 1024 * 1024;
+
+fn engine() -> &'static Engine {
+    static ENGINE: OnceLock<Engine> = OnceLock::new();
+    ENGINE.get_or_init(|| {
+        let mut config = Config::new();
+        config.consume_fuel(true);
+        Engine::new(&config).expect("failed to set up WASM engine")
+    })
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct WasmProcessor {
+    /// Path to the module (`.wasm` or `.wat`).
+    pub path: PathBuf,
+    /// How much fuel the plugin gets per item. Roughly one unit per instruction.
+    pub fuel: Option<u64>,
+    /// The most memory the plugin may use, in bytes. Defaults to the pipeline's max_memory if
+    /// it has one.
+    pub max_memory: Option<usize>,
+    #[serde(skip)]
+    loaded: Loaded,
+}
+
+/// The compiled module and the modification time of the file it was compiled from.
+#[derive(Clone, Default)]
+struct Loaded(Arc<Mutex<Option<(SystemTime, Module)>>>);
+
+impl fmt::Debug for Loaded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Loaded")
+    }
+}
+
+struct HostState {
+    file: Option<Decrypting<File>>,
+    limits: StoreLimits,
+    progress: ProgressSender,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+enum PluginOutcome {
+    Advance {
+        #[serde(default)]
+        status: Option<Status>,
+    },
+    Fail {
+        error: UploadError,
+    },
+    Retry,
+}
+
+#[derive(Deserialize)]
+struct PluginOutput {
+    #[serde(flatten)]
+    outcome: PluginOutcome,
+    #[serde(default)]
+    note: Option<String>,
+}
+
+impl WasmProcessor {
+    /// Gets the module, recompiling it if the file has changed.
+    fn module(&self) -> wasmtime::Result<Module> {
+        let mtime = fs::metadata(&self.path)?.modified()?;
+        let mut loaded = self.loaded.0.lock().unwrap();
+        match &*loaded {
+            Some((t, module)) if *t == mtime => Ok(module.clone()),
+            _ => {
+                let module = Module::from_file(engine(), &self.path)?;
+                *loaded = Some((mtime, module.clone()));
+                Ok(module)
+            }
+        }
+    }
+
+    /// Runs the plugin. This blocks.
+    fn run(
+        &self,
+        input: &[u8],
+        file: Option<Decrypting<File>>,
+        max_memory: usize,
+        progress: ProgressSender,
+    ) -> wasmtime::Result<PluginOutput> {
+        let module = self.module()?;
+        let limits = StoreLimitsBuilder::new().memory_size(max_memory).build();
+        let mut store = Store::new(
+            engine(),
+            HostState {
+                file,
+                limits,
+                progress,
+            },
+        );
+        store.limiter(|s| &mut s.limits);
+        store.set_fuel(self.fuel.unwrap_or(DEFAULT_FUEL))?;
+
+        let mut linker = Linker::new(engine());
+        linker.func_wrap(
+            "bullseye",
+            "read",
+            |mut caller: Caller<'_, HostState>, offset: u64, ptr: u32, len: u32| -> i64 {
+                let Some(memory) = caller.get_export("memory").and_then(|e| e.into_memory()) else {
+                    return -1;
+                };
+                let Some(file) = &mut caller.data_mut().file else {
+                    return -1;
+                };
+                let mut buf = vec![0; len as usize];
+                let Ok(n) = file
+                    .seek(SeekFrom::Start(offset))
+                    .and_then(|_| file.read(&mut buf))
+                else {
+                    return -1;
+                };
+                match memory.write(&mut caller, ptr as usize, &buf[..n]) {
+                    Ok(()) => n as i64,
+                    Err(_) => -1,
+                }
+            },
+        )?;
+        linker.func_wrap(
+            "bullseye",
+            "progress",
+            |caller: Caller<'_, HostState>, done: u64, total: u64| {
+                caller
+                    .data()
+                    .progress
+                    .send_replace(Some(Progress { done, total }));
+            },
+        )?;
+        let instance = linker.instantiate(&mut store, &module)?;
+        let memory = instance
+            .get_memory(&mut store, "memory")
+            .ok_or_else(|| wasmtime::Error::msg("plugin doesn't export memory"))?;
+        let alloc = instance.get_typed_func::<u32, u32>(&mut store, "alloc")?;
+        let process = instance.get_typed_func::<(u32, u32), u64>(&mut store, "process")?;
+
+        let len = u32::try_from(input.len())?;
+        let ptr = alloc.call(&mut store, len)?;
+        memory.write(&mut store, ptr as usize, input)?;
+        let rv = process.call(&mut store, (ptr, len))?;
+        let (out_ptr, out_len) = ((rv >> 32) as usize, (rv & 0xffff_ffff) as usize);
+        let output = memory
+            .data(&store)
+            .get(out_ptr..out_ptr + out_len)
+            .ok_or_else(|| wasmtime::Error::msg("plugin returned an out-of-bounds output"))?;
+        Ok(serde_json::from_slice(output)?)
+    }
+
+    pub async fn process(
+        &self,
+        row: &UploadRow,
+        data: DataFile,
+        limits: &Limits,
+        progress: &ProgressSender,
+    ) -> Report {
+        let input = match serde_json::to_vec(row) {
+            Ok(i) => i,
+            Err(e) => return Report::retry(format!("failed to serialize row: {e}")),
+        };
+        // The plugin isn't told the path; it can only read this file through the import.
+        let file = data.open().ok();
+        let max_memory = self
+            .max_memory
+            .or(limits.max_memory.and_then(|m| m.try_into().ok()))
+            .unwrap_or(DEFAULT_MAX_MEMORY);
+        let this = self.clone();
+        let progress = progress.clone();
+        let output =
+            match spawn_blocking(move || this.run(&input, file, max_memory, progress)).await {
+                Ok(Ok(o)) => o,
+                Ok(Err(e)) => {
+                    return Report::retry(format!("plugin {} failed: {e:#}", self.path.display()))
+                }
+                Err(e) => return Report::retry(format!("plugin panicked: {e}")),
+            };
+        let outcome = match output.outcome {
+            PluginOutcome::Advance { status } => Outcome::Advance(status),
+            PluginOutcome::Fail { error } => Outcome::Fail(error),
+            PluginOutcome::Retry => Outcome::Retry,
+        };
+        Report {
+            outcome,
+            note: output.note,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, sync::Arc};
+
+    use common::{
+        crypt::DataFile,
+        data::{Progress, UploadError},
+        db::UploadRow,
+    };
+    use tokio::sync::watch;
+
+    use super::WasmProcessor;
+    use crate::{config::Limits, processor::Outcome};
+
+    /// Reads the first byte of the file and fails the item if it isn't "W".
+    const PLUGIN: &str = r#"(module
+        (import "bullseye" "read" (func $read (param i64 i32 i32) (result i64)))
+        (import "bullseye" "progress" (func $progress (param i64 i64)))
+        (memory (export "memory") 1)
+        (data (i32.const 0) "{\"outcome\": \"advance\"}")
+        (data (i32.const 32) "{\"outcome\": \"fail\", \"error\": \"FAILED_VERIFY\"}")
+        (func (export "alloc") (param i32) (result i32) (i32.const 1024))
+        (func (export "process") (param i32 i32) (result i64)
+            (drop (call $read (i64.const 0) (i32.const 512) (i32.const 1)))
+            (call $progress (i64.const 1) (i64.const 1))
+            (if (result i64) (i32.eq (i32.load8_u (i32.const 512)) (i32.const 87))
+                (then (i64.const 22))
+                (else (i64.or (i64.shl (i64.const 32) (i64.const 32)) (i64.const 45))))))"#;
+
+    fn row() -> UploadRow {
+        serde_json::from_str(
+            r#"{
+                "id": "wasm-test", "dir": "", "status": "VERIFYING",
+                "file": {"hash": "", "name": "a", "size": 0},
+                "last_activity": 0, "pipeline": "default", "project": "p", "processing": true,
+                "metadata": {"uploader": "", "items": []}
+            }"#,
+        )
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_plugin() {
+        let dir = std::env::temp_dir().join(format!("bullseye-wasm-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let plugin = dir.join("plugin.wat");
+        fs::write(&plugin, PLUGIN).unwrap();
+        let p = WasmProcessor {
+            path: plugin,
+            fuel: None,
+            max_memory: None,
+            loaded: Default::default(),
+        };
+
+        fs::write(dir.join("good"), "WARC/1.1").unwrap();
+        let limits = Limits::default();
+        let (tx, rx) = watch::channel(None);
+        let tx = Arc::new(tx);
+        let r = p
+            .process(&row(), DataFile::plain(dir.join("good")), &limits, &tx)
+            .await;
+        assert_eq!(r.outcome, Outcome::Advance(None));
+        assert_eq!(*rx.borrow(), Some(Progress { done: 1, total: 1 }));
+
+        fs::write(dir.join("bad"), "nope").unwrap();
+        let r = p
+            .process(&row(), DataFile::plain(dir.join("bad")), &limits, &tx)
+            .await;
+        assert_eq!(r.outcome, Outcome::Fail(UploadError::Verify));
+
+        let r = p
+            .process(&row(), DataFile::plain("/nonexistent"), &limits, &tx)
+            .await;
+        assert_eq!(r.outcome, Outcome::Fail(UploadError::Verify));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}